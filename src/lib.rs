@@ -42,13 +42,13 @@ mod lib {
 
 		while running {
 			match state.run(None) {
-				Outcome::Yielded => {}
+				Outcome::Yielded | Outcome::SleepRequested(_) => {}
 				Outcome::GlobalInstructionLimitReached
 				| Outcome::LocalInstructionLimitReached
 				| Outcome::Ended => running = false,
 				Outcome::Error(e) => {
 					return Err(JsValue::from(format!(
-						"Error in VM at pc={}: {:?}",
+						"Error in VM at pc={}: {}",
 						state.pc(),
 						e
 					)));
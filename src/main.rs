@@ -1,16 +1,26 @@
 extern crate clap;
 mod pwlp;
+mod test;
 
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use eui48::MacAddress;
 use pwlp::client::Client;
+use pwlp::debugger::Stepper;
+use pwlp::instructions::{Prefix, UserCommand};
+use pwlp::parser;
 use pwlp::program::Program;
-use pwlp::server::{DeviceConfig, Server};
+use pwlp::protocol;
+use pwlp::repl::Repl;
+use pwlp::server::{DeviceConfig, GroupConfig, Server};
 use pwlp::strip;
-use pwlp::vm::{Outcome, VM};
+use pwlp::vm::{self, Fuel, Outcome, State, TraceEvent, VMError, WordWidth, VM};
+#[cfg(feature = "watch")]
+use pwlp::watch::SourceWatcher;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{stdin, Read, Write};
+use std::io::{stdin, BufRead, Read, Write};
+use std::path::Path;
 use std::time::{Duration, SystemTime};
 
 #[cfg(feature = "raspberrypi")]
@@ -33,6 +43,9 @@ struct ClientConfig {
 	server_address: Option<String>,
 	secret: Option<String>,
 	fps_limit: Option<usize>,
+	mac: Option<String>,
+	mtu: Option<usize>,
+	discover: Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -42,12 +55,15 @@ struct ServerConfig {
 	secret: Option<String>,
 	program: Option<String>,
 	devices: Option<HashMap<String, DeviceConfig>>,
+	groups: Option<HashMap<String, GroupConfig>>,
+	offline_timeout: Option<u64>,
+	heartbeat_interval: Option<u64>,
+	read_timeout: Option<u64>,
+	mtu: Option<usize>,
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-	env_logger::init();
-
 	let mut serve_subcommand = SubCommand::with_name("serve")
 		.about("start server")
 		.arg(
@@ -81,6 +97,13 @@ async fn main() -> std::io::Result<()> {
 				.value_name("config.toml")
 				.help("Config file to read")
 				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("mtu")
+				.long("mtu")
+				.value_name("1500")
+				.help("Size of the buffer for receiving messages in bytes (up to 9000 for jumbo frames; overrides value set in config)")
+				.takes_value(true),
 		);
 
 	#[cfg(feature = "api")]
@@ -101,10 +124,150 @@ async fn main() -> std::io::Result<()> {
 		);
 	}
 
+	let mut run_subcommand = SubCommand::with_name("run")
+		.about("run a script")
+		.arg(Arg::with_name("file")
+			.index(1)
+			.takes_value(true)
+			.help("the file to run")
+		)
+		.arg(Arg::with_name("binary")
+				.short("b")
+				.long("binary")
+				.takes_value(false)
+				.help("interpret source as binary"))
+		.arg(Arg::with_name("hex")
+				.long("hex")
+				.takes_value(false)
+				.conflicts_with("binary")
+				.help("interpret source as program binary in hex"))
+		.arg(Arg::with_name("hardware")
+				.short("h")
+				.long("hardware")
+				.takes_value(false)
+				.help("output to actual hardware (if supported)"))
+		.arg(Arg::with_name("length")
+				.long("length")
+				.short("l")
+				.takes_value(true)
+				.value_name("10")
+				.help("length of the LED strip (the lengths of strips wired in sequence can be separated by '+', e.g. 10+20; independent strips, chosen with select_strip, by ',', e.g. 10,20)"))
+		.arg(Arg::with_name("bus")
+				.long("bus")
+				.takes_value(true)
+				.value_name("0")
+				.help("number of SPI bus to use"))
+		.arg(Arg::with_name("ss")
+				.long("ss")
+				.takes_value(true)
+				.value_name("0")
+				.help("the slave-select port to use for the SPI bus"))
+		.arg(Arg::with_name("driver")
+				.long("driver")
+				.takes_value(true)
+				.possible_values(&["raw", "apa102", "ws2812"])
+				.value_name("raw")
+				.help("the type of LED strip connected to the SPI bus (raw RGB bytes, APA102/SK9822 or WS2812)"))
+		.arg(Arg::with_name("instruction-limit")
+				.long("instruction-limit")
+				.takes_value(true)
+				.value_name("0")
+				.help("the maximum number of instructions to execute (default: 0 = no limit)"))
+		.arg(Arg::with_name("fuel-cost")
+				.long("fuel-cost")
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1)
+				.value_name("blit=10")
+				.help("the amount of the instruction limit an instruction (e.g. PUSHI) or user command (e.g. blit) consumes (default: 1)"))
+		.arg(Arg::with_name("start-at")
+				.long("start-at")
+				.takes_value(true)
+				.value_name("0")
+				.help("address of the instruction to start executing at (e.g. a routine in a library of routines)"))
+		.arg(Arg::with_name("fps-limit")
+				.long("fps-limit")
+				.takes_value(true)
+				.value_name("0")
+				.help("the maximum number of frames per second to execute (default = no limit)"))
+		.arg(Arg::with_name("max-frames")
+				.long("max-frames")
+				.takes_value(true)
+				.value_name("0")
+				.help("stop after the program has yielded this many times (default = no limit)"))
+		.arg(Arg::with_name("until-stable")
+				.long("until-stable")
+				.takes_value(false)
+				.help("run without output and report the frame after which the pixels no longer change (within --max-frames, default 1000)"))
+		.arg(Arg::with_name("deterministic")
+				.long("deterministic")
+				.takes_value(false)
+				.help("make output of non-deterministic functions (time, randomness) deterministic (For testing purposes)"))
+		.arg(Arg::with_name("deterministic-time")
+				.long("deterministic-time")
+				.takes_value(true)
+				.requires("deterministic")
+				.value_name("0,1")
+				.help("simulated clock in deterministic mode: the wall time to start at (seconds since the epoch) and the milliseconds each instruction takes"))
+		.arg(Arg::with_name("fill")
+				.long("fill")
+				.takes_value(true)
+				.value_name("0,0,0")
+				.help("color all pixels have when the program starts, as hue (in degrees), saturation and value (0...1)"))
+		.arg(Arg::with_name("word-width")
+				.long("word-width")
+				.takes_value(true)
+				.possible_values(&["16", "32"])
+				.value_name("32")
+				.help("number of bits literals and arithmetic results are truncated to (to simulate 16-bit devices)"))
+		.arg(Arg::with_name("strip")
+				.long("strip")
+				.takes_value(true)
+				.possible_values(&["dummy", "null"])
+				.hidden(true)
+				.help("the strip to output to when not using hardware (null discards all output; for benchmarking)"))
+		.arg(Arg::with_name("benchmark")
+				.long("benchmark")
+				.takes_value(false)
+				.hidden(true)
+				.help("report the number of instructions executed per second"))
+		.arg(Arg::with_name("trace")
+				.short("t")
+				.long("trace")
+				.takes_value(false)
+				.help("show instructions as they are executed")
+		)
+		.arg(Arg::with_name("step")
+				.long("step")
+				.takes_value(false)
+				.help("execute one instruction at a time (enter: next instruction, c: continue until yield, q: quit)")
+		);
+
+	#[cfg(feature = "watch")]
+	{
+		run_subcommand = run_subcommand.arg(
+			Arg::with_name("watch")
+				.short("w")
+				.long("watch")
+				.takes_value(false)
+				.requires("file")
+				.conflicts_with_all(&["binary", "hex"])
+				.help("restart the script whenever the source file changes"),
+		);
+	}
+
 	let matches = App::new("pwlp-server")
 		.version("1.0")
 		.about("Pixelspark wireless LED protocol server")
 		.author("Pixelspark")
+		.arg(
+			Arg::with_name("verbose")
+				.short("v")
+				.long("verbose")
+				.multiple(true)
+				.global(true)
+				.help("Log more (-v: info, -vv: debug, -vvv: trace; overrides RUST_LOG)"),
+		)
 		.subcommand(
 			SubCommand::with_name("compile")
 				.about("compiles a script to binary")
@@ -119,6 +282,21 @@ async fn main() -> std::io::Result<()> {
 						.index(2)
 						.takes_value(true)
 						.help("the file to write binary output to"),
+				)
+				.arg(
+					Arg::with_name("format")
+						.long("format")
+						.alias("output-format")
+						.takes_value(true)
+						.possible_values(&["binary", "hex", "c"])
+						.value_name("binary")
+						.help("write the program as binary, hex string or C array (for embedding in firmware)"),
+				)
+				.arg(
+					Arg::with_name("optimize")
+						.long("optimize")
+						.short("O")
+						.help("remove unreachable code and redundant instructions"),
 				),
 		)
 		.subcommand(
@@ -128,63 +306,26 @@ async fn main() -> std::io::Result<()> {
 					Arg::with_name("file")
 						.takes_value(true)
 						.help("the binary to disassemble"),
-				),
-		)
-		.subcommand(
-			SubCommand::with_name("run")
-				.about("run a script")
-				.arg(Arg::with_name("file")
-					.index(1)
-					.takes_value(true)
-					.help("the file to run")
 				)
-				.arg(Arg::with_name("binary")
-						.short("b")
-						.long("binary")
-						.takes_value(false)
-						.help("interpret source as binary"))
-				.arg(Arg::with_name("hardware")
-						.short("h")
-						.long("hardware")
-						.takes_value(false)
-						.help("output to actual hardware (if supported)"))
-				.arg(Arg::with_name("length")
-						.long("length")
-						.short("l")
-						.takes_value(true)
-						.value_name("10")
-						.help("length of the LED strip"))
-				.arg(Arg::with_name("bus")
-						.long("bus")
-						.takes_value(true)
-						.value_name("0")
-						.help("number of SPI bus to use"))
-				.arg(Arg::with_name("ss")
-						.long("ss")
-						.takes_value(true)
-						.value_name("0")
-						.help("the slave-select port to use for the SPI bus"))
-				.arg(Arg::with_name("instruction-limit")
-						.long("instruction-limit")
-						.takes_value(true)
-						.value_name("0")
-						.help("the maximum number of instructions to execute (default: 0 = no limit)"))
-				.arg(Arg::with_name("fps-limit")
-						.long("fps-limit")
-						.takes_value(true)
-						.value_name("0")
-						.help("the maximum number of frames per second to execute (default = no limit)"))
-				.arg(Arg::with_name("deterministic")
-						.long("deterministic")
+				.arg(
+					Arg::with_name("hex")
+						.long("hex")
 						.takes_value(false)
-						.help("make output of non-deterministic functions (time, randomness) deterministic (For testing purposes)"))
-				.arg(Arg::with_name("trace")
-						.short("t")
-						.long("trace")
+						.help("interpret the binary as hex"),
+				)
+				.arg(
+					Arg::with_name("json")
+						.long("json")
 						.takes_value(false)
-						.help("show instructions as they are executed")
+						.help("output instructions as JSON"),
 				),
 		)
+		.subcommand(
+			SubCommand::with_name("regen-builtins")
+				.about("recompiles the built-in programs from their sources (for development)")
+				.setting(AppSettings::Hidden),
+		)
+		.subcommand(run_subcommand)
 		.subcommand(
 			SubCommand::with_name("client")
 				.about("run as client")
@@ -206,17 +347,31 @@ async fn main() -> std::io::Result<()> {
 						.takes_value(true)
 						.value_name("secret")
 						.help("secret key used to sign communications with the server"))
+				.arg(Arg::with_name("mac")
+						.long("mac")
+						.takes_value(true)
+						.value_name("00:00:00:00:00:00")
+						.help("MAC address to identify as (default = detected from the network interface)"))
+				.arg(Arg::with_name("mtu")
+						.long("mtu")
+						.takes_value(true)
+						.value_name("1500")
+						.help("size of the buffer for receiving messages in bytes (up to 9000 for jumbo frames)"))
 				.arg(Arg::with_name("server")
 						.long("server")
 						.takes_value(true)
 						.value_name("0.0.0.0:33333")
 						.help("address of the server"))
+				.arg(Arg::with_name("discover")
+						.long("discover")
+						.takes_value(false)
+						.help("treat the server address as a broadcast/multicast address and use the first server that answers"))
 				.arg(Arg::with_name("length")
 						.long("length")
 						.short("l")
 						.takes_value(true)
 						.value_name("10")
-						.help("length of the LED strip"))
+						.help("length of the LED strip (the lengths of strips wired in sequence can be separated by '+', e.g. 10+20; independent strips, chosen with select_strip, by ',', e.g. 10,20)"))
 				.arg(Arg::with_name("bus")
 						.long("bus")
 						.takes_value(true)
@@ -227,6 +382,12 @@ async fn main() -> std::io::Result<()> {
 						.takes_value(true)
 						.value_name("0")
 						.help("the slave-select port to use for the SPI bus"))
+				.arg(Arg::with_name("driver")
+						.long("driver")
+						.takes_value(true)
+						.possible_values(&["raw", "apa102", "ws2812"])
+						.value_name("raw")
+						.help("the type of LED strip connected to the SPI bus (raw RGB bytes, APA102/SK9822 or WS2812)"))
 				.arg(Arg::with_name("trace")
 						.short("t")
 						.long("trace")
@@ -248,9 +409,30 @@ async fn main() -> std::io::Result<()> {
 						.help("interpret initial program file as binary"))
 		)
 		.subcommand(serve_subcommand)
+		.subcommand(
+			SubCommand::with_name("repl")
+				.about("interactively enter and run statements")
+				.arg(Arg::with_name("length")
+						.long("length")
+						.short("l")
+						.takes_value(true)
+						.value_name("10")
+						.help("number of LEDs in the strip (the lengths of independent strips, chosen with select_strip, can be separated by ',', e.g. 10,20)"))
+				.arg(Arg::with_name("deterministic")
+						.long("deterministic")
+						.takes_value(false)
+						.help("make output of non-deterministic functions (time, randomness) deterministic"))
+		)
 		.setting(AppSettings::ArgRequiredElseHelp)
 		.get_matches();
 
+	// Also counts flags given after the subcommand, as the argument is global
+	logger(
+		matches.occurrences_of("verbose"),
+		std::env::var("RUST_LOG").ok(),
+	)
+	.init();
+
 	// Read configuration file
 	let config_file = matches.value_of("config").unwrap_or("config.toml");
 	let mut config_string = String::new();
@@ -273,6 +455,10 @@ async fn main() -> std::io::Result<()> {
 		return compile(matches);
 	} else if let Some(matches) = matches.subcommand_matches("disassemble") {
 		return disassemble(matches);
+	} else if let Some(matches) = matches.subcommand_matches("repl") {
+		return repl(matches);
+	} else if matches.subcommand_matches("regen-builtins").is_some() {
+		return regen_builtins();
 	} else if let Some(matches) = matches.subcommand_matches("serve") {
 		return serve(config, matches).await;
 	};
@@ -284,6 +470,9 @@ fn client(config: Config, client_matches: &ArgMatches) -> std::io::Result<()> {
 	let mut secret: String = String::from("secret");
 	let mut server_address: String = String::from("224.0.0.1:33333");
 	let mut fps_limit = Some(60);
+	let mut mac: Option<String> = None;
+	let mut mtu: Option<usize> = None;
+	let mut discover = false;
 
 	// Read configured values
 	if let Some(client_config) = config.client {
@@ -299,6 +488,15 @@ fn client(config: Config, client_matches: &ArgMatches) -> std::io::Result<()> {
 		if let Some(v) = client_config.fps_limit {
 			fps_limit = Some(v);
 		}
+		if let Some(v) = client_config.mac {
+			mac = Some(v);
+		}
+		if let Some(v) = client_config.mtu {
+			mtu = Some(v);
+		}
+		if let Some(v) = client_config.discover {
+			discover = v;
+		}
 	}
 
 	// Read arguments
@@ -314,6 +512,27 @@ fn client(config: Config, client_matches: &ArgMatches) -> std::io::Result<()> {
 	if let Some(v) = client_matches.value_of("fps-limit") {
 		fps_limit = Some(v.parse().unwrap());
 	}
+	if let Some(v) = client_matches.value_of("mac") {
+		mac = Some(v.to_string());
+	}
+	if let Some(v) = client_matches.value_of("mtu") {
+		mtu = Some(parse_mtu(v)?);
+	}
+	if client_matches.is_present("discover") {
+		discover = true;
+	}
+	let buffer_size = protocol::buffer_size(mtu)
+		.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+	let mac_address = match mac {
+		Some(m) => Some(MacAddress::parse_str(&m).map_err(|e| {
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!("invalid MAC address '{}': {}", m, e),
+			)
+		})?),
+		None => None,
+	};
 
 	let initial_program = match client_matches.value_of("initial") {
 		Some(path) => {
@@ -340,8 +559,12 @@ fn client(config: Config, client_matches: &ArgMatches) -> std::io::Result<()> {
 		fps_limit = None;
 	}
 
-	let vm = vm_from_options(&client_matches);
+	let mut vm = vm_from_options(&client_matches);
+	vm.set_trace(client_matches.is_present("trace"));
 	let mut client = Client::new(vm, &secret.as_bytes(), fps_limit);
+	client.set_mac_address(mac_address);
+	client.set_mtu(buffer_size);
+	client.set_discovery(discover);
 	client
 		.run(&bind_address, &server_address, initial_program)
 		.expect("running the client failed");
@@ -349,29 +572,98 @@ fn client(config: Config, client_matches: &ArgMatches) -> std::io::Result<()> {
 }
 
 fn run(run_matches: &ArgMatches) -> std::io::Result<()> {
-	let interpret_as_binary = run_matches.is_present("binary");
-
-	let program = if interpret_as_binary {
-		let mut source = Vec::<u8>::new();
-		if let Some(source_file) = run_matches.value_of("file") {
-			File::open(source_file)?.read_to_end(&mut source)?;
-		} else {
-			stdin().read_to_end(&mut source)?;
+	#[cfg(feature = "watch")]
+	{
+		if run_matches.is_present("watch") {
+			return run_watched(run_matches);
 		}
-		Program::from_binary(source)
+	}
+
+	let program = if run_matches.is_present("binary") || run_matches.is_present("hex") {
+		read_binary(run_matches.value_of("file"), run_matches.is_present("hex"))?
 	} else {
-		let mut source = String::new();
-		if let Some(source_file) = run_matches.value_of("file") {
-			File::open(source_file)?.read_to_string(&mut source)?;
-		} else {
-			stdin().read_to_string(&mut source)?;
-		}
-		match Program::from_source(&source) {
+		// Debug information allows errors to be reported at a source line, and tracing to show variable names
+		match compile_source(
+			run_matches.value_of("file"),
+			Program::new().with_debug_info(),
+		)? {
 			Ok(prg) => prg,
 			Err(s) => panic!("Parsing failed: {}", s),
 		}
 	};
 
+	if run_matches.is_present("until-stable") {
+		println!("{}", until_stable(run_matches, program));
+		return Ok(());
+	}
+
+	run_program(run_matches, program, &mut || false)?;
+	Ok(())
+}
+
+/* Runs the program deterministically (without output) and describes after which frame its output stops changing */
+fn until_stable(run_matches: &ArgMatches, program: Program) -> String {
+	let max_frames = run_matches
+		.value_of("max-frames")
+		.map(|v| v.parse::<usize>().expect("invalid frame count"))
+		.unwrap_or(1000);
+	let length = strip_lengths(run_matches)[0].iter().sum();
+	match vm::run_until_stable(
+		program,
+		Box::new(strip::DummyStrip::new(length, false)),
+		max_frames,
+	) {
+		Some(frame) => format!("Output is stable after frame {}", frame),
+		None => format!("Output did not become stable within {} frames", max_frames),
+	}
+}
+
+/* Runs the script and restarts it from scratch whenever its source file changes. Parse errors are reported, after
+which the file is watched for the next change. */
+#[cfg(feature = "watch")]
+fn run_watched(run_matches: &ArgMatches) -> std::io::Result<()> {
+	let path = Path::new(run_matches.value_of("file").unwrap());
+	let watcher = SourceWatcher::new(path).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+	let mut next = Some(watcher.load());
+	while let Some(result) = next.take() {
+		match result {
+			Ok(program) => {
+				let mut changed = None;
+				let result = run_program(run_matches, program, &mut || {
+					changed = watcher.poll();
+					changed.is_some()
+				});
+				if let Err(e) = result {
+					log::error!("{}", e);
+				}
+
+				if changed.is_none() {
+					println!("Program ended; waiting for changes to {}", path.display());
+					changed = watcher.wait();
+				}
+				next = changed;
+			}
+			Err(e) => {
+				log::error!("Parsing failed: {}", e);
+				next = watcher.wait();
+			}
+		}
+
+		if next.is_some() {
+			println!("{} changed, restarting", path.display());
+		}
+	}
+	Ok(())
+}
+
+/* Runs a program until it ends, or until `interrupted` returns true (which is checked each time the program
+yields). Returns whether the program was interrupted. */
+fn run_program(
+	run_matches: &ArgMatches,
+	program: Program,
+	interrupted: &mut dyn FnMut() -> bool,
+) -> std::io::Result<bool> {
 	let instruction_limit: Option<usize> = if run_matches.is_present("instruction-limit") {
 		Some(
 			run_matches
@@ -396,19 +688,102 @@ fn run(run_matches: &ArgMatches) -> std::io::Result<()> {
 		None
 	};
 
+	let max_frames: Option<usize> = run_matches
+		.value_of("max-frames")
+		.map(|v| v.parse::<usize>().expect("invalid frame count"));
+
+	let start_at: Option<usize> = run_matches
+		.value_of("start-at")
+		.map(|v| v.parse::<usize>().expect("invalid start address"));
+
+	let fuel_costs = run_matches
+		.values_of("fuel-cost")
+		.map(|costs| {
+			costs
+				.map(|c| parse_fuel_cost(c).unwrap_or_else(|e| panic!("invalid fuel cost: {}", e)))
+				.collect::<HashMap<Fuel, usize>>()
+		})
+		.unwrap_or_default();
+
 	let mut vm = vm_from_options(&run_matches);
-	let mut state = vm.start(program, instruction_limit);
-	let mut last_yield_time = SystemTime::now();
+	vm.set_fuel_costs(fuel_costs);
+	let source = program.clone();
+	vm.set_print_handler(Box::new(move |pc, value| {
+		println!("{}", print_message(&source, pc, value))
+	}));
+	if run_matches.is_present("trace") {
+		let source = program.clone();
+		let mut current_line = None;
+		vm.set_trace_handler(Box::new(move |event: TraceEvent| {
+			if let Some(header) = trace_line_header(&source, event.pc, &mut current_line) {
+				println!("{}", header);
+			}
+			event.print();
+		}));
+	}
+	let mut state = match start_at {
+		Some(offset) => vm
+			.start_at(program, offset, instruction_limit)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+		None => vm.start(program, instruction_limit),
+	};
 	let frame_time = if let Some(fps) = fps {
 		Some(Duration::from_millis(1000 / fps))
 	} else {
 		None
 	};
 	let mut running = true;
+	let start_time = SystemTime::now();
 
-	while running {
+	if run_matches.is_present("step") {
+		let stdin = stdin();
+		let mut stepper = Stepper::new(stdin.lock(), std::io::stdout());
+		while running {
+			match stepper.run(&mut state)? {
+				Some(Outcome::Yielded) | Some(Outcome::SleepRequested(_)) => {}
+				Some(Outcome::Error(e)) => return Err(vm_error(&state, e)),
+				Some(_) | None => running = false,
+			}
+		}
+	}
+
+	if running && run_frames(&mut state, frame_time, max_frames, interrupted)? {
+		return Ok(true);
+	}
+
+	if run_matches.is_present("benchmark") {
+		let duration = SystemTime::now().duration_since(start_time).unwrap();
+		println!(
+			"{} instructions in {:.3}s ({:.0} instructions/s)",
+			state.instruction_count(),
+			duration.as_secs_f64(),
+			state.instruction_count() as f64 / duration.as_secs_f64()
+		);
+	}
+	Ok(false)
+}
+
+/* Runs the program until it ends, fails or has yielded `max_frames` times, waiting `frame_time` between frames.
+Returns true when execution was stopped because `interrupted` returned true. */
+fn run_frames(
+	state: &mut State,
+	frame_time: Option<Duration>,
+	max_frames: Option<usize>,
+	interrupted: &mut dyn FnMut() -> bool,
+) -> std::io::Result<bool> {
+	let mut last_yield_time = SystemTime::now();
+	let mut frames = 0;
+	loop {
 		match state.run(None) {
+			Outcome::Yielded | Outcome::SleepRequested(_) if interrupted() => {
+				return Ok(true);
+			}
 			Outcome::Yielded => {
+				frames += 1;
+				if max_frames == Some(frames) {
+					return Ok(false);
+				}
+
 				if let Some(frame_time) = frame_time {
 					let now = SystemTime::now();
 					let passed = now.duration_since(last_yield_time).unwrap();
@@ -419,32 +794,164 @@ fn run(run_matches: &ArgMatches) -> std::io::Result<()> {
 					last_yield_time = now;
 				}
 			}
+			Outcome::SleepRequested(duration) => {
+				std::thread::sleep(duration);
+				last_yield_time = SystemTime::now();
+			}
 			Outcome::GlobalInstructionLimitReached
 			| Outcome::LocalInstructionLimitReached
-			| Outcome::Ended => running = false,
-			Outcome::Error(e) => {
-				println!("Error in VM at pc={}: {:?}", state.pc(), e);
+			| Outcome::Ended => return Ok(false),
+			Outcome::Error(e) => return Err(vm_error(state, e)),
+		}
+	}
+}
+
+/* Formats a value printed by the print statement at `pc`, mentioning its source line when the program has debug
+information */
+fn print_message(program: &Program, pc: usize, value: u32) -> String {
+	match program.source_line(pc) {
+		Some(line) => format!("PRINT {:04} (line {}): {}", pc, line, value),
+		None => format!("PRINT {:04}: {}", pc, value),
+	}
+}
+
+/* Returns the header that is traced before the instruction at `pc` when it belongs to another source line than the
+previously traced instruction (when the program has debug information) */
+fn trace_line_header(
+	program: &Program,
+	pc: usize,
+	current_line: &mut Option<usize>,
+) -> Option<String> {
+	let line = program.source_line(pc);
+	if line == *current_line {
+		return None;
+	}
+	*current_line = line;
+	line.map(|line| format!("line {}:", line))
+}
+
+/* Describes an error that stopped the program, including where it occurred */
+fn vm_error(state: &State, error: VMError) -> std::io::Error {
+	std::io::Error::other(format!("error in VM at {}: {}", state.location(), error))
+}
+
+fn repl(matches: &ArgMatches) -> std::io::Result<()> {
+	let mut vm = VM::with_strips(
+		strip_lengths(matches)
+			.iter()
+			.map(|sequence| {
+				Box::new(strip::DummyStrip::new(sequence.iter().sum(), false))
+					as Box<dyn strip::Strip>
+			})
+			.collect(),
+	);
+	vm.set_deterministic(matches.is_present("deterministic"));
+	// Addresses mean little when entering statements one line at a time, so only values are printed
+	vm.set_print_handler(Box::new(|_, value| println!("{}", value)));
+	let mut repl = Repl::new(&mut vm);
+
+	let stdin = stdin();
+	let mut line = String::new();
+	loop {
+		print!("> ");
+		std::io::stdout().flush()?;
+		line.clear();
+		if stdin.lock().read_line(&mut line)? == 0 {
+			println!();
+			break;
+		}
+
+		if line.trim().is_empty() {
+			continue;
+		}
+
+		match repl.eval(&line) {
+			Ok(()) => {
+				let mut index = 0;
+				while let Some(strip) = repl.state().vm.strip_at(index) {
+					if index > 0 {
+						print!("| ");
+					}
+					for idx in 0..strip.length() {
+						let color = strip.get_pixel(idx);
+						print!("{:02x}{:02x}{:02x} ", color.r, color.g, color.b);
+					}
+					index += 1;
+				}
+				println!();
 			}
+			Err(e) => println!("Error: {}", e),
 		}
 	}
 	Ok(())
 }
 
+/* Compiles the given source file, or source read from stdin. Included files are resolved relative to the source
+file (or the working directory for stdin). */
+fn compile_source(
+	file: Option<&str>,
+	program: Program,
+) -> std::io::Result<Result<Program, String>> {
+	let compiled = match file {
+		Some(path) => program.compile_file_with_warnings(Path::new(path)),
+		None => {
+			let mut source = String::new();
+			stdin().read_to_string(&mut source)?;
+			parser::resolve_includes(&source, Path::new("."))
+				.and_then(|source| program.compile_with_warnings(&source))
+		}
+	};
+
+	// Warnings go to stderr, so that they do not end up in compiled output written to stdout
+	Ok(compiled.map(|(program, warnings)| {
+		for warning in warnings {
+			eprintln!("Warning: {}", warning);
+		}
+		program
+	}))
+}
+
+/* Formats program code as text: a hex string or a C array. Returns None for binary output. */
+fn format_code(code: &[u8], format: &str) -> Option<String> {
+	match format {
+		"hex" => Some(format!(
+			"{}\n",
+			Program::from_binary(code.to_vec()).to_hex()
+		)),
+		"c" => {
+			let lines: Vec<String> = code
+				.chunks(12)
+				.map(|chunk| {
+					let bytes: Vec<String> = chunk.iter().map(|b| format!("0x{:02x}", b)).collect();
+					format!("\t{},\n", bytes.join(", "))
+				})
+				.collect();
+			Some(format!(
+				"const uint8_t program[] = {{\n{}}};\n",
+				lines.concat()
+			))
+		}
+		_ => None,
+	}
+}
+
 fn compile(matches: &ArgMatches) -> std::io::Result<()> {
-	let mut source = String::new();
-	if let Some(source_file) = matches.value_of("file") {
-		File::open(source_file)?.read_to_string(&mut source)?;
+	let program = if matches.is_present("optimize") {
+		Program::new().with_optimization()
 	} else {
-		stdin().read_to_string(&mut source)?;
-	}
+		Program::new()
+	};
 
-	match Program::from_source(&source) {
+	match compile_source(matches.value_of("file"), program)? {
 		Ok(prg) => {
-			if !matches.is_present("output") {
-				println!("Program:\n{:?}", &prg);
-			}
-			if let Some(out_file) = matches.value_of("output") {
-				File::create(out_file)?.write_all(&prg.code)?;
+			let text = format_code(&prg.code, matches.value_of("format").unwrap_or("binary"));
+			match (text, matches.value_of("output")) {
+				(None, None) => println!("Program:\n{:?}", &prg),
+				(None, Some(out_file)) => File::create(out_file)?.write_all(&prg.code)?,
+				(Some(text), None) => print!("{}", text),
+				(Some(text), Some(out_file)) => {
+					File::create(out_file)?.write_all(text.as_bytes())?
+				}
 			}
 		}
 		Err(s) => println!("Error: {}", s),
@@ -452,16 +959,36 @@ fn compile(matches: &ArgMatches) -> std::io::Result<()> {
 	Ok(())
 }
 
-fn disassemble(matches: &ArgMatches) -> std::io::Result<()> {
+/* Reads a program binary (as raw bytes or hex) from the file or stdin */
+fn read_binary(file: Option<&str>, hex: bool) -> std::io::Result<Program> {
 	let mut source = Vec::<u8>::new();
-	if let Some(source_file) = matches.value_of("file") {
+	if let Some(source_file) = file {
 		File::open(source_file)?.read_to_end(&mut source)?;
 	} else {
 		stdin().read_to_end(&mut source)?;
 	}
 
-	let program = Program::from_binary(source);
-	println!("{:?}", program);
+	if !hex {
+		return Ok(Program::from_binary(source));
+	}
+
+	let text = String::from_utf8(source).map_err(|_| {
+		std::io::Error::new(std::io::ErrorKind::InvalidData, "hex is not valid UTF-8")
+	})?;
+	Program::from_hex(&text).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn disassemble(matches: &ArgMatches) -> std::io::Result<()> {
+	let program = read_binary(matches.value_of("file"), matches.is_present("hex"))?;
+	if matches.is_present("json") {
+		println!(
+			"{}",
+			serde_json::to_string_pretty(&program.to_instructions())
+				.expect("serializing instructions failed")
+		);
+	} else {
+		println!("{:?}", program);
+	}
 	Ok(())
 }
 
@@ -476,7 +1003,10 @@ async fn serve(config: Config, serve_matches: &ArgMatches<'_>) -> std::io::Resul
 			Err(t) => log::error!("PWLP server ended with error: {:?}", t),
 		});
 
-		let mut api_config = config.api.clone().unwrap_or_else(pwlp::api::APIConfig::new);
+		let mut api_config = match &config.api {
+			Some(api) => api.clone(),
+			None => pwlp::api::APIConfig::new(),
+		};
 
 		if let Some(v) = serve_matches.value_of("bind-api") {
 			api_config.bind_address = Some(v.to_string());
@@ -498,7 +1028,12 @@ fn build_server(config: &Config, serve_matches: &ArgMatches<'_>) -> std::io::Res
 	let mut global_secret = String::from("secret");
 	let mut default_program_path: Option<String> = None;
 	let mut devices: HashMap<String, DeviceConfig> = HashMap::new();
+	let mut groups: HashMap<String, GroupConfig> = HashMap::new();
 	let mut bind_address = String::from("0.0.0.0:33333");
+	let mut offline_timeout: Option<Duration> = None;
+	let mut heartbeat_interval: Option<Duration> = None;
+	let mut read_timeout: Option<Duration> = None;
+	let mut mtu: Option<usize> = None;
 
 	// Read configured values
 	if let Some(server_config) = &config.server {
@@ -514,9 +1049,29 @@ fn build_server(config: &Config, serve_matches: &ArgMatches<'_>) -> std::io::Res
 			devices = d.clone();
 		}
 
+		if let Some(g) = &server_config.groups {
+			groups = g.clone();
+		}
+
 		if let Some(v) = server_config.bind_address.clone() {
 			bind_address = v;
 		}
+
+		if let Some(v) = server_config.offline_timeout {
+			offline_timeout = Some(Duration::from_secs(v));
+		}
+
+		if let Some(v) = server_config.heartbeat_interval {
+			heartbeat_interval = Some(Duration::from_secs(v));
+		}
+
+		if let Some(v) = server_config.read_timeout {
+			read_timeout = Some(Duration::from_secs(v));
+		}
+
+		if let Some(v) = server_config.mtu {
+			mtu = Some(v);
+		}
 	}
 
 	log::info!("PWLP will listen at {}", bind_address);
@@ -528,32 +1083,153 @@ fn build_server(config: &Config, serve_matches: &ArgMatches<'_>) -> std::io::Res
 	if let Some(v) = serve_matches.value_of("secret") {
 		global_secret = v.to_string();
 	}
+	if let Some(v) = serve_matches.value_of("mtu") {
+		mtu = Some(parse_mtu(v)?);
+	}
+	let buffer_size = protocol::buffer_size(mtu)
+		.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
 	let default_program = match default_program_path {
-		Some(path) => Program::from_file(&path).expect("error reading specified program file"),
+		Some(path) => Program::from_file(&path)?,
 		None => default_serve_program(),
 	};
 
-	Server::new(devices, &global_secret, default_program, &bind_address)
+	let mut server = Server::new(devices, &global_secret, default_program, &bind_address)?;
+	if let Some(t) = offline_timeout {
+		server.set_offline_timeout(t);
+	}
+	server.set_heartbeat_interval(heartbeat_interval);
+	server.set_groups(groups);
+	if let Some(t) = read_timeout {
+		server.set_read_timeout(t);
+	}
+	server.set_mtu(buffer_size);
+	Ok(server)
 }
 
-fn vm_from_options(options: &ArgMatches) -> VM {
-	let length = options
+fn parse_mtu(value: &str) -> std::io::Result<usize> {
+	value.parse::<usize>().map_err(|e| {
+		std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			format!("invalid MTU '{}': {}", value, e),
+		)
+	})
+}
+
+/* The lengths of the strips given with --length, for each independent strip (separated by ','). Strips wired in
+sequence are listed separated by '+'. */
+fn strip_lengths(options: &ArgMatches) -> Vec<Vec<u32>> {
+	let lengths: Vec<Vec<u32>> = options
 		.value_of("length")
 		.unwrap_or("10")
-		.parse::<u32>()
-		.expect("length must be >0");
+		.split(',')
+		.map(|strip| {
+			strip
+				.split('+')
+				.map(|l| l.trim().parse::<u32>().expect("length must be >0"))
+				.collect()
+		})
+		.collect();
 
-	if length == 0 {
+	if lengths.iter().flatten().any(|l| *l == 0) {
 		panic!("length cannot be zero");
 	}
+	lengths
+}
+
+/* Parses a color written as hue (in degrees), saturation and value (0...1), separated by commas (e.g. "120,1,0.5") */
+fn parse_hsv(value: &str) -> Result<strip::Color, String> {
+	let components = value
+		.split(',')
+		.map(|c| {
+			c.trim()
+				.parse::<f32>()
+				.map_err(|e| format!("'{}': {}", c, e))
+		})
+		.collect::<Result<Vec<f32>, String>>()?;
+	match components.as_slice() {
+		[h, s, v] => Ok(strip::Color::from_hsv(*h, *s, *v)),
+		_ => Err(format!(
+			"'{}' should consist of hue, saturation and value",
+			value
+		)),
+	}
+}
+
+/* Parses a fuel cost given as `name=cost`, where the name is either a user command (e.g. `blit`) or an instruction
+prefix (e.g. `PUSHI`) */
+fn parse_fuel_cost(value: &str) -> Result<(Fuel, usize), String> {
+	let mut parts = value.splitn(2, '=');
+	let name = parts.next().unwrap_or("").trim();
+	let cost = parts
+		.next()
+		.ok_or_else(|| format!("'{}' should be of the form name=cost", value))?
+		.trim()
+		.parse::<usize>()
+		.map_err(|e| format!("'{}': {}", value, e))?;
+
+	let fuel = (0..=255u8)
+		.filter_map(UserCommand::from)
+		.find(|command| command.name() == name)
+		.map(Fuel::User)
+		.or_else(|| {
+			(0..=255u8)
+				.step_by(0x10)
+				.filter_map(Prefix::from)
+				.find(|prefix| prefix.to_string().eq_ignore_ascii_case(name))
+				.map(Fuel::Instruction)
+		})
+		.ok_or_else(|| format!("unknown instruction or user command '{}'", name))?;
+	Ok((fuel, cost))
+}
+
+/* Parses the simulated clock given as the wall time to start at (in seconds) and the number of milliseconds each
+instruction takes, separated by a comma (e.g. "1600000000,10") */
+fn parse_deterministic_time(value: &str) -> Result<(u32, u32), String> {
+	let components = value
+		.split(',')
+		.map(|c| {
+			c.trim()
+				.parse::<u32>()
+				.map_err(|e| format!("'{}': {}", c, e))
+		})
+		.collect::<Result<Vec<u32>, String>>()?;
+	match components.as_slice() {
+		[base, tick] => Ok((*base, *tick)),
+		_ => Err(format!(
+			"'{}' should consist of a start time and a tick length",
+			value
+		)),
+	}
+}
 
-	let strip = strip::DummyStrip::new(length, true);
-	let mut vm = VM::new(Box::new(strip));
+fn vm_from_options(options: &ArgMatches) -> VM {
+	let lengths = strip_lengths(options);
+
+	let dummy_strip = |length: u32| -> Box<dyn strip::Strip> {
+		match options.value_of("strip") {
+			Some("null") => Box::new(strip::NullStrip::new(length)),
+			_ => Box::new(strip::DummyStrip::new(length, true)),
+		}
+	};
+
+	// Strips wired in sequence are addressed as one long strip
+	let mut vm = VM::with_strips(
+		lengths
+			.iter()
+			.map(|sequence| match sequence.as_slice() {
+				[length] => dummy_strip(*length),
+				_ => Box::new(strip::ConcatStrip::new(
+					sequence.iter().map(|l| dummy_strip(*l)).collect(),
+				)),
+			})
+			.collect(),
+	);
 
 	#[cfg(feature = "raspberrypi")]
 	{
 		if options.is_present("hardware") {
+			let length: u32 = lengths.iter().flatten().sum();
 			let spi_bus = match options.value_of("bus") {
 				Some(bus_str) => match bus_str {
 					"0" => spi::Bus::Spi0,
@@ -574,18 +1250,353 @@ fn vm_from_options(options: &ArgMatches) -> VM {
 				None => spi::SlaveSelect::Ss0,
 			};
 
-			let spi = spi::Spi::new(spi_bus, ss, 1_000_000, spi::Mode::Mode0)
+			let driver = options.value_of("driver").unwrap_or("raw");
+			let clock_speed = match driver {
+				"ws2812" => strip::WS2812_SPI_CLOCK_SPEED,
+				_ => 1_000_000,
+			};
+			let spi = spi::Spi::new(spi_bus, ss, clock_speed, spi::Mode::Mode0)
 				.expect("spi bus could not be created");
-			let strip = strip::spi_strip::SPIStrip::new(spi, length);
-			vm = VM::new(Box::new(strip));
+			vm = match driver {
+				"apa102" => VM::new(Box::new(strip::spi_strip::Apa102Strip::new(spi, length))),
+				"ws2812" => VM::new(Box::new(strip::spi_strip::Ws2812Strip::new(spi, length))),
+				_ => VM::new(Box::new(strip::spi_strip::SPIStrip::new(spi, length))),
+			};
+		}
+	}
+
+	if let Some(fill) = options.value_of("fill") {
+		let color = parse_hsv(fill).unwrap_or_else(|e| panic!("invalid fill color: {}", e));
+		for index in 0..lengths.len() {
+			if let Some(strip) = vm.strip_at(index) {
+				strip.fill(color.r, color.g, color.b);
+			}
 		}
 	}
 
-	vm.set_trace(options.is_present("trace"));
 	vm.set_deterministic(options.is_present("deterministic"));
+	if let Some(time) = options.value_of("deterministic-time") {
+		let (base, tick) = parse_deterministic_time(time)
+			.unwrap_or_else(|e| panic!("invalid deterministic time: {}", e));
+		vm.set_deterministic_time(base, tick);
+	}
+	if options.value_of("word-width") == Some("16") {
+		vm.set_word_width(WordWidth::U16);
+	}
 	vm
 }
 
 fn default_serve_program() -> Program {
 	Program::from_binary(include_bytes!("./programs/default_serve.bin").to_vec())
 }
+
+/* Programs (paths relative to the crate, without extension) whose compiled '.bin' is embedded in the binary. The
+binaries need to be regenerated from the '.txt' sources using the regen-builtins subcommand when the compiler output
+changes. */
+const BUILTIN_PROGRAMS: [&str; 2] = ["src/programs/off", "src/programs/default_serve"];
+
+/* Compiles the source of a built-in program and returns it together with the path its binary is stored at */
+fn compile_builtin(name: &str) -> std::io::Result<(Program, std::path::PathBuf)> {
+	let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(name);
+	let source_path = path.with_extension("txt");
+	let source = std::fs::read_to_string(&source_path)?;
+	let program = Program::from_source(&source).map_err(|e| {
+		std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("{}: {}", source_path.display(), e),
+		)
+	})?;
+	Ok((program, path.with_extension("bin")))
+}
+
+fn regen_builtins() -> std::io::Result<()> {
+	for name in BUILTIN_PROGRAMS.iter() {
+		let (program, bin_path) = compile_builtin(name)?;
+		std::fs::write(&bin_path, &program.code)?;
+		println!("{}: {} bytes", bin_path.display(), program.code.len());
+	}
+	Ok(())
+}
+
+/* Configures logging. Without -v flags, RUST_LOG determines what is logged (warnings and errors when it is not set).
+Each -v flag raises the level. */
+fn logger(verbosity: u64, rust_log: Option<String>) -> env_logger::Builder {
+	let mut builder = env_logger::Builder::new();
+	match (verbosity, rust_log) {
+		(0, Some(filters)) => builder.parse_filters(&filters),
+		(0, None) => builder.filter_level(log::LevelFilter::Warn),
+		(1, _) => builder.filter_level(log::LevelFilter::Info),
+		(2, _) => builder.filter_level(log::LevelFilter::Debug),
+		(_, _) => builder.filter_level(log::LevelFilter::Trace),
+	};
+	builder
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn log_level() {
+		let emits = |verbosity, rust_log: Option<&str>, level| {
+			let record = log::Record::builder()
+				.level(level)
+				.target("pwlp::server")
+				.build();
+			logger(verbosity, rust_log.map(|s| s.to_string()))
+				.build()
+				.matches(&record)
+		};
+		assert!(emits(0, None, log::Level::Warn));
+		assert!(!emits(0, None, log::Level::Info));
+		assert!(emits(1, None, log::Level::Info));
+		assert!(!emits(1, None, log::Level::Debug));
+		assert!(emits(2, None, log::Level::Debug));
+		assert!(emits(3, None, log::Level::Trace));
+		assert!(emits(0, Some("info"), log::Level::Info));
+		assert!(!emits(0, Some("pwlp::client=debug"), log::Level::Info));
+		assert!(emits(1, Some("error"), log::Level::Info));
+	}
+
+	#[test]
+	fn concatenated_strips() {
+		let vm_with_length = |length: &str| {
+			let matches = App::new("run")
+				.arg(Arg::with_name("length").long("length").takes_value(true))
+				.get_matches_from(vec!["run", "--length", length]);
+			vm_from_options(&matches)
+		};
+		assert_eq!(vm_with_length("10").strip().length(), 10);
+
+		let mut vm = vm_with_length("10+ 20+5");
+		assert_eq!(vm.strip().length(), 35);
+		vm.strip().set_pixel(34, 1, 2, 3);
+		assert_eq!(vm.strip().get_pixel(34), strip::Color::new(1, 2, 3));
+		assert!(vm.strip_at(1).is_none());
+	}
+
+	#[test]
+	fn independent_strips() {
+		let matches = App::new("run")
+			.arg(Arg::with_name("length").long("length").takes_value(true))
+			.arg(Arg::with_name("fill").long("fill").takes_value(true))
+			.get_matches_from(vec!["run", "--length", "10,2+3", "--fill", "0,1,1"]);
+		assert_eq!(strip_lengths(&matches), vec![vec![10], vec![2, 3]]);
+
+		let mut vm = vm_from_options(&matches);
+		assert_eq!(vm.strip_at(0).unwrap().length(), 10);
+		assert_eq!(vm.strip_at(1).unwrap().length(), 5);
+		assert_eq!(
+			vm.strip_at(1).unwrap().get_pixel(4),
+			strip::Color::new(255, 0, 0)
+		);
+
+		let program = Program::from_source("select_strip(1); set_pixel(4, 1, 2, 3)").unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+		assert_eq!(
+			vm.strip_at(1).unwrap().get_pixel(4),
+			strip::Color::new(1, 2, 3)
+		);
+	}
+
+	#[test]
+	fn fill() {
+		assert_eq!(parse_hsv("120, 1, 0.5"), Ok(strip::Color::new(0, 128, 0)));
+		assert!(parse_hsv("120,1").is_err());
+		assert!(parse_hsv("red,1,1").is_err());
+
+		let matches = App::new("run")
+			.arg(Arg::with_name("length").long("length").takes_value(true))
+			.arg(Arg::with_name("fill").long("fill").takes_value(true))
+			.get_matches_from(vec!["run", "--length", "3", "--fill", "240,1,1"]);
+		let mut vm = vm_from_options(&matches);
+		for idx in 0..3 {
+			assert_eq!(vm.strip().get_pixel(idx), strip::Color::new(0, 0, 255));
+		}
+	}
+
+	#[test]
+	fn fuel_cost() {
+		assert_eq!(
+			parse_fuel_cost("blit=10"),
+			Ok((Fuel::User(UserCommand::BLIT), 10))
+		);
+		assert_eq!(
+			parse_fuel_cost("pushi = 3"),
+			Ok((Fuel::Instruction(Prefix::PUSHI), 3))
+		);
+		assert!(parse_fuel_cost("blit").is_err());
+		assert!(parse_fuel_cost("frobnicate=1").is_err());
+	}
+
+	#[test]
+	fn deterministic_time() {
+		assert_eq!(parse_deterministic_time("1000, 500"), Ok((1000, 500)));
+		assert!(parse_deterministic_time("1000").is_err());
+		assert!(parse_deterministic_time("-1,500").is_err());
+
+		let matches = App::new("run")
+			.arg(Arg::with_name("deterministic").long("deterministic"))
+			.arg(
+				Arg::with_name("deterministic-time")
+					.long("deterministic-time")
+					.takes_value(true),
+			)
+			.get_matches_from(vec![
+				"run",
+				"--deterministic",
+				"--deterministic-time",
+				"1000,500",
+			]);
+		let mut vm = vm_from_options(&matches);
+		let program =
+			Program::from_source("t = get_wall_time; set_pixel(0, t - 1000, 0, 0)").unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+		// get_wall_time is the first instruction, and each instruction takes half a second
+		assert_eq!(vm.strip().get_pixel(0).r, 0);
+	}
+
+	#[test]
+	fn until_stable() {
+		let report = |source: &str| {
+			let matches = App::new("run")
+				.arg(Arg::with_name("length").long("length").takes_value(true))
+				.arg(
+					Arg::with_name("max-frames")
+						.long("max-frames")
+						.takes_value(true),
+				)
+				.get_matches_from(vec!["run", "--length", "2+1", "--max-frames", "20"]);
+			super::until_stable(&matches, Program::from_source(source).unwrap())
+		};
+		assert_eq!(
+			report("for(i=3) { set_pixel(i, 255, 0, 0); blit; yield }; loop { yield }"),
+			"Output is stable after frame 3"
+		);
+		assert_eq!(
+			report("loop { set_pixel(0, get_frame, 0, 0); blit; yield }"),
+			"Output did not become stable within 20 frames"
+		);
+	}
+
+	/* Runs the code the way the run command does, expecting it to stop with an error at the first instruction */
+	fn assert_run_fails(code: &[u8]) {
+		let matches = App::new("run")
+			.arg(Arg::with_name("strip").long("strip").takes_value(true))
+			.get_matches_from(vec!["run", "--strip", "null"]);
+		let program = Program::from_binary(code.to_vec());
+		let error = run_program(&matches, program, &mut || false).unwrap_err();
+		assert!(error.to_string().contains("pc=0"));
+
+		let mut vm = VM::new(Box::new(strip::NullStrip::new(1)));
+		let mut state = vm.start(Program::from_binary(code.to_vec()), None);
+		assert!(run_frames(&mut state, None, None, &mut || false).is_err());
+	}
+
+	#[test]
+	fn print_message() {
+		let program = Program::new()
+			.with_debug_info()
+			.compile("a = 5;\nprint(a)")
+			.unwrap();
+		let pc = program.as_bytes().iter().position(|b| *b == 0xfb).unwrap();
+		assert_eq!(
+			super::print_message(&program, pc, 5),
+			format!("PRINT {:04} (line 2): 5", pc)
+		);
+		assert_eq!(
+			super::print_message(&Program::from_binary(program.as_bytes().to_vec()), pc, 5),
+			format!("PRINT {:04}: 5", pc)
+		);
+	}
+
+	#[test]
+	fn trace_line_header() {
+		let program = Program::new()
+			.with_debug_info()
+			.compile("a = 5;\nb = a;\nprint(b)")
+			.unwrap();
+		let headers: Vec<String> = {
+			let mut current_line = None;
+			(0..program.byte_len())
+				.filter_map(|pc| super::trace_line_header(&program, pc, &mut current_line))
+				.collect()
+		};
+		assert_eq!(headers, vec!["line 1:", "line 2:", "line 3:"]);
+
+		let mut current_line = None;
+		let binary = Program::from_binary(program.as_bytes().to_vec());
+		assert_eq!(
+			super::trace_line_header(&binary, 0, &mut current_line),
+			None
+		);
+	}
+
+	#[test]
+	fn start_at() {
+		let run_from = |offset: &str| {
+			let matches = App::new("run")
+				.arg(Arg::with_name("strip").long("strip").takes_value(true))
+				.arg(
+					Arg::with_name("start-at")
+						.long("start-at")
+						.takes_value(true),
+				)
+				.get_matches_from(vec!["run", "--strip", "null", "--start-at", offset]);
+			// PUSHB 5; POP 1
+			run_program(
+				&matches,
+				Program::from_binary(vec![0x11, 0x05, 0x01]),
+				&mut || false,
+			)
+		};
+		assert!(run_from("0").is_ok());
+
+		// Starting at the POP skips the push, so the stack underflows
+		assert!(run_from("2").unwrap_err().to_string().contains("pc=2"));
+		assert_eq!(
+			run_from("1").unwrap_err().kind(),
+			std::io::ErrorKind::InvalidInput
+		);
+	}
+
+	#[test]
+	fn unknown_instruction_ends_run() {
+		assert_run_fails(&[0xA0]);
+	}
+
+	#[test]
+	fn stack_underflow_ends_run() {
+		// POP 1 on an empty stack
+		assert_run_fails(&[0x01]);
+	}
+
+	#[test]
+	fn max_frames() {
+		let program =
+			Program::from_source("x = 0; loop { x += 1; set_pixel(0, x, 0, 0); yield }").unwrap();
+		let mut vm = VM::new(Box::new(strip::DummyStrip::new(1, false)));
+		let mut state = vm.start(program, None);
+		assert!(!run_frames(&mut state, None, Some(5), &mut || false).unwrap());
+		drop(state);
+		assert_eq!(vm.strip().get_pixel(0).r, 5);
+	}
+
+	#[test]
+	fn format_code_as_c_array() {
+		let code: Vec<u8> = (0..14).collect();
+		assert_eq!(
+			format_code(&code, "c").unwrap(),
+			"const uint8_t program[] = {\n\
+			\t0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,\n\
+			\t0x0c, 0x0d,\n\
+			};\n"
+		);
+		assert_eq!(format_code(&[0x0a, 0xff], "hex").unwrap(), "0aff\n");
+		assert_eq!(format_code(&code, "binary"), None);
+	}
+}
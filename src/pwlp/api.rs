@@ -1,15 +1,23 @@
 use super::program::Program;
-use super::protocol::{Message, MessageType};
-use super::server::{DeviceStatus, ServerState};
+use super::protocol::{Message, MessageType, Setting, MAX_PAYLOAD_SIZE};
+use super::server::{DeviceStatus, Metrics, ServerState};
+use super::strip::DummyStrip;
+use super::vm::{Outcome, VM};
 use eui48::MacAddress;
+use futures::{SinkExt, StreamExt};
 use phf::phf_map;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::ws::{WebSocket, Ws};
 use warp::{Filter, Rejection, Reply};
 
 static BUILTIN_PROGRAMS: phf::Map<&'static str, &'static [u8]> = phf_map! {
@@ -17,16 +25,31 @@ static BUILTIN_PROGRAMS: phf::Map<&'static str, &'static [u8]> = phf_map! {
 	"default" => include_bytes!("../programs/default_serve.bin")
 };
 
+/* Maximum number of instructions a program may execute when validated using /validate */
+const VALIDATE_INSTRUCTION_LIMIT: usize = 100_000;
+
+/* Largest strip length that can be requested from /validate */
+const VALIDATE_MAX_LENGTH: u32 = 10_000;
+
+/* Minimum time between two frames streamed from /preview */
+const PREVIEW_FRAME_INTERVAL: Duration = Duration::from_millis(1000 / 30);
+
+/* Time after which a preview started using /preview is stopped */
+const PREVIEW_MAX_DURATION: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct APIConfig {
 	pub enabled: bool,
 	pub bind_address: Option<String>,
+	pub api_token: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum APIError {
 	NotFound(String),     // An entity was not found
 	NetworkError(String), // Communicating with a device failed
+	BadRequest(String),   // The request could not be processed (e.g. invalid program)
+	Unauthorized,         // The request did not carry the configured API token
 }
 
 #[derive(Serialize)]
@@ -35,8 +58,46 @@ struct ErrorReply {
 	message: Option<String>,
 }
 
-#[derive(Serialize)]
-struct SetReply {}
+#[derive(Serialize, Default)]
+struct SetReply {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	warning: Option<String>,
+}
+
+/* Device settings to change; settings that are not specified are left unchanged */
+#[derive(Deserialize, Debug)]
+struct SettingsRequest {
+	fps_limit: Option<u32>,
+	brightness: Option<u8>,
+	length: Option<u32>,
+}
+
+/* New secret for a device */
+#[derive(Deserialize, Debug)]
+struct SecretRequest {
+	secret: String,
+}
+
+/* Options for /validate: the length of the simulated strip and whether the body is source code or a binary */
+#[derive(Deserialize, Debug)]
+struct ValidateQuery {
+	length: Option<u32>,
+	source: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PreviewQuery {
+	length: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+struct ValidateReply {
+	valid: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+	instruction_count: usize,
+	yielded: bool,
+}
 
 impl warp::reject::Reject for APIError {}
 
@@ -45,6 +106,8 @@ impl APIError {
 		match self {
 			APIError::NotFound(_) => StatusCode::NOT_FOUND,
 			APIError::NetworkError(_) => StatusCode::BAD_GATEWAY,
+			APIError::BadRequest(_) => StatusCode::BAD_REQUEST,
+			APIError::Unauthorized => StatusCode::UNAUTHORIZED,
 		}
 	}
 
@@ -58,15 +121,30 @@ impl APIError {
 				code: "network_error".into(),
 				message: Some(e.clone()),
 			},
+			APIError::BadRequest(e) => ErrorReply {
+				code: "bad_request".into(),
+				message: Some(e.clone()),
+			},
+			APIError::Unauthorized => ErrorReply {
+				code: "unauthorized".into(),
+				message: None,
+			},
 		}
 	}
 }
 
 impl APIConfig {
 	pub fn new() -> APIConfig {
+		APIConfig::default()
+	}
+}
+
+impl Default for APIConfig {
+	fn default() -> APIConfig {
 		APIConfig {
 			enabled: true,
 			bind_address: None,
+			api_token: None,
 		}
 	}
 }
@@ -80,65 +158,492 @@ pub struct DevicesReply<'a> {
 }
 
 async fn get_devices(state: Arc<Mutex<ServerState>>) -> Result<Box<dyn Reply>, Rejection> {
-	let s = state.lock().unwrap();
+	let mut s = state.lock().unwrap();
+	s.update_online_status();
 	let sa = &(*s);
 	Ok(Box::new(warp::reply::json(&DevicesReply {
 		devices: &sa.devices,
 	})))
 }
 
+async fn stream_devices(socket: WebSocket, state: Arc<Mutex<ServerState>>) {
+	let (mut tx, _) = socket.split();
+
+	// Subscribe before taking the snapshot so no update is missed in between
+	let (snapshot, mut events) = {
+		let mut s = state.lock().unwrap();
+		s.update_online_status();
+		let snapshot = serde_json::to_string(&DevicesReply {
+			devices: &s.devices,
+		});
+		(snapshot, s.events.subscribe())
+	};
+
+	if let Ok(snapshot) = snapshot {
+		if tx.send(warp::ws::Message::text(snapshot)).await.is_err() {
+			return;
+		}
+	}
+
+	loop {
+		match events.recv().await {
+			Ok(event) => {
+				if let Ok(json) = serde_json::to_string(&event) {
+					if tx.send(warp::ws::Message::text(json)).await.is_err() {
+						break;
+					}
+				}
+			}
+			Err(broadcast::RecvError::Lagged(n)) => {
+				log::warn!(
+					"WebSocket client lagged behind, skipped {} device events",
+					n
+				);
+			}
+			Err(broadcast::RecvError::Closed) => break,
+		}
+	}
+}
+
 async fn get_index(_state: Arc<Mutex<ServerState>>) -> Result<Box<dyn Reply>, Rejection> {
 	Ok(Box::new(warp::reply::json(&IndexReply {})))
 }
 
+async fn get_metrics(state: Arc<Mutex<ServerState>>) -> Result<Box<dyn Reply>, Rejection> {
+	let metrics = state.lock().unwrap().metrics.clone();
+	Ok(Box::new(warp::reply::with_header(
+		metrics.render(),
+		"content-type",
+		"text/plain; version=0.0.4",
+	)))
+}
+
+/* MAC addresses are stored in canonical (lowercase) form, but may be queried in any case */
+fn canonical_mac(device: &str) -> String {
+	device.to_lowercase()
+}
+
+fn device_not_found(device: &str) -> Rejection {
+	warp::reject::custom(APIError::NotFound(format!("device '{}' not found", device)))
+}
+
 async fn get_device(
 	state: Arc<Mutex<ServerState>>,
 	device: String,
 ) -> Result<Box<dyn Reply>, Rejection> {
-	let s = state.lock().unwrap();
+	let device = canonical_mac(&device);
+	let mut s = state.lock().unwrap();
+	s.update_online_status();
 	if s.devices.contains_key(&device) {
 		Ok(Box::new(warp::reply::json(&s.devices[&device])))
 	} else {
-		return Err(warp::reject::custom(APIError::NotFound(
-			"dveice not found".to_string(),
-		)));
+		Err(device_not_found(&device))
 	}
 }
 
+async fn delete_device(
+	state: Arc<Mutex<ServerState>>,
+	device: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+	let device = canonical_mac(&device);
+	let mut s = state.lock().unwrap();
+	if s.devices.remove(&device).is_some() {
+		Ok(Box::new(warp::reply::json(&SetReply::default())))
+	} else {
+		Err(device_not_found(&device))
+	}
+}
+
+fn send_program(
+	s: &mut ServerState,
+	device_address: String,
+	program: Program,
+) -> Result<Box<dyn Reply>, Rejection> {
+	if !s.devices.contains_key(&device_address) {
+		return Err(device_not_found(&device_address));
+	}
+
+	let mut device_state = s.devices[&device_address].clone();
+	let mut warnings = vec![];
+	if !program.has_yield_in_loops() {
+		warnings.push("program contains a loop that never yields".to_string());
+	}
+	warnings.extend(device_state.strip_length_warning(&program));
+
+	let mut reply = SetReply::default();
+	if !warnings.is_empty() {
+		let warning = warnings.join("; ");
+		log::warn!("Program sent to {}: {}", device_address, warning);
+		reply.warning = Some(warning);
+	}
+
+	device_state.program = Some(program.clone());
+	device_state.sent_program_hash = Some(program.code_hash());
+
+	// Send off the program
+	let msg = Message::new(MessageType::Run, MacAddress::nil(), Some(&program.code)).unwrap();
+	s.socket
+		.send_to(
+			&msg.signed(device_state.secret.as_bytes()),
+			device_state.address,
+		)
+		.map_err(|e| warp::reject::custom(APIError::NetworkError(format!("{}", e))))?;
+	s.update_device(device_address, device_state);
+
+	Ok(Box::new(warp::reply::json(&reply)))
+}
+
 async fn set_builtin_program(
 	state: Arc<Mutex<ServerState>>,
 	device_address: String,
 	program_name: String,
 ) -> Result<Box<dyn Reply>, Rejection> {
+	let device_address = canonical_mac(&device_address);
 	let mut s = state.lock().unwrap();
-	if s.devices.contains_key(&device_address) {
-		if !BUILTIN_PROGRAMS.contains_key(program_name.as_str()) {
-			return Err(warp::reject::custom(APIError::NotFound(
-				"built-in program not found".to_string(),
-			)));
-		}
-
-		let program_code = BUILTIN_PROGRAMS[program_name.as_str()];
-		let program = Program::from_binary(program_code.to_vec());
-		let mut device_state = s.devices[&device_address].clone();
-		device_state.program = Some(program.clone());
-
-		// Send off the program
-		let msg = Message::new(MessageType::Run, MacAddress::nil(), Some(&program.code)).unwrap();
-		s.socket
-			.send_to(
-				&msg.signed(device_state.secret.as_bytes()),
-				device_state.address,
-			)
-			.map_err(|e| warp::reject::custom(APIError::NetworkError(format!("{}", e))))?;
-		s.devices.insert(device_address, device_state);
-
-		Ok(Box::new(warp::reply::json(&SetReply {})))
-	} else {
-		return Err(warp::reject::custom(APIError::NotFound(
-			"device not found".to_string(),
+	if !s.devices.contains_key(&device_address) {
+		return Err(device_not_found(&device_address));
+	}
+
+	if !BUILTIN_PROGRAMS.contains_key(program_name.as_str()) {
+		return Err(warp::reject::custom(APIError::NotFound(format!(
+			"built-in program '{}' not found",
+			program_name
+		))));
+	}
+
+	let program_code = BUILTIN_PROGRAMS[program_name.as_str()];
+	send_program(
+		&mut s,
+		device_address,
+		Program::from_binary(program_code.to_vec()),
+	)
+}
+
+async fn set_program(
+	state: Arc<Mutex<ServerState>>,
+	device_address: String,
+	body: Bytes,
+) -> Result<Box<dyn Reply>, Rejection> {
+	let device_address = canonical_mac(&device_address);
+	let mut s = state.lock().unwrap();
+	if !s.devices.contains_key(&device_address) {
+		return Err(device_not_found(&device_address));
+	}
+
+	if body.len() > MAX_PAYLOAD_SIZE {
+		return Err(warp::reject::custom(APIError::BadRequest(format!(
+			"program size {} exceeds maximum of {} bytes",
+			body.len(),
+			MAX_PAYLOAD_SIZE
+		))));
+	}
+
+	let program = Program::from_binary(body.to_vec());
+	program
+		.validate()
+		.map_err(|e| warp::reject::custom(APIError::BadRequest(e)))?;
+	send_program(&mut s, device_address, program)
+}
+
+async fn set_settings(
+	state: Arc<Mutex<ServerState>>,
+	device_address: String,
+	request: SettingsRequest,
+) -> Result<Box<dyn Reply>, Rejection> {
+	let device_address = canonical_mac(&device_address);
+	let s = state.lock().unwrap();
+	let device_state = match s.devices.get(&device_address) {
+		Some(d) => d,
+		None => return Err(device_not_found(&device_address)),
+	};
+
+	let mut settings = vec![];
+	if let Some(fps_limit) = request.fps_limit {
+		settings.push(Setting::FpsLimit(fps_limit));
+	}
+	if let Some(brightness) = request.brightness {
+		settings.push(Setting::Brightness(brightness));
+	}
+	if let Some(length) = request.length {
+		settings.push(Setting::StripLength(length));
+	}
+
+	if settings.is_empty() {
+		return Err(warp::reject::custom(APIError::BadRequest(
+			"no settings specified".to_string(),
 		)));
 	}
+
+	let payload = Setting::encode(&settings);
+	let msg = Message::new(MessageType::Set, MacAddress::nil(), Some(&payload)).unwrap();
+	s.socket
+		.send_to(
+			&msg.signed(device_state.secret.as_bytes()),
+			device_state.address,
+		)
+		.map_err(|e| warp::reject::custom(APIError::NetworkError(format!("{}", e))))?;
+
+	Ok(Box::new(warp::reply::json(&SetReply::default())))
+}
+
+/* Sends the new secret to the device (signed using the old secret, which is the only secret the device knows) and
+only then starts using it for the device. Messages signed using the old secret are rejected from then on. */
+async fn set_secret(
+	state: Arc<Mutex<ServerState>>,
+	device_address: String,
+	request: SecretRequest,
+) -> Result<Box<dyn Reply>, Rejection> {
+	let device_address = canonical_mac(&device_address);
+	let mut s = state.lock().unwrap();
+	let mut device_state = match s.devices.get(&device_address) {
+		Some(d) => d.clone(),
+		None => return Err(device_not_found(&device_address)),
+	};
+
+	if request.secret.is_empty() || request.secret.len() > MAX_PAYLOAD_SIZE {
+		return Err(warp::reject::custom(APIError::BadRequest(format!(
+			"secret must be between 1 and {} bytes",
+			MAX_PAYLOAD_SIZE
+		))));
+	}
+
+	let msg = Message::new(
+		MessageType::Secret,
+		MacAddress::nil(),
+		Some(request.secret.as_bytes()),
+	)
+	.unwrap();
+	s.socket
+		.send_to(
+			&msg.signed(device_state.secret.as_bytes()),
+			device_state.address,
+		)
+		.map_err(|e| warp::reject::custom(APIError::NetworkError(format!("{}", e))))?;
+
+	// The server switches to the new secret once the device uses it (see Server::handle_datagram)
+	device_state.pending_secret = Some(request.secret);
+	s.update_device(device_address, device_state);
+	Ok(Box::new(warp::reply::json(&SetReply::default())))
+}
+
+async fn compile(body: Bytes) -> Result<Box<dyn Reply>, Rejection> {
+	let source = String::from_utf8(body.to_vec()).map_err(|_| {
+		warp::reject::custom(APIError::BadRequest(
+			"source is not valid UTF-8".to_string(),
+		))
+	})?;
+
+	let program =
+		Program::from_source(&source).map_err(|e| warp::reject::custom(APIError::BadRequest(e)))?;
+
+	Ok(Box::new(warp::reply::with_header(
+		program.code,
+		"content-type",
+		"application/octet-stream",
+	)))
+}
+
+/* Runs the program on a simulated strip until it ends, fails or reaches the instruction limit. Reaching the limit is
+only an error when the program never yielded (i.e. it would hang the device). */
+fn dry_run(program: Program, length: u32) -> ValidateReply {
+	let mut reply = ValidateReply {
+		valid: false,
+		error: None,
+		instruction_count: 0,
+		yielded: false,
+	};
+
+	if let Err(e) = program.validate() {
+		reply.error = Some(e);
+		return reply;
+	}
+
+	let mut vm = VM::new(Box::new(DummyStrip::new(length, false)));
+	vm.set_deterministic(true);
+	let mut state = vm.start(program, Some(VALIDATE_INSTRUCTION_LIMIT));
+	let error = loop {
+		match state.run(None) {
+			Outcome::Yielded | Outcome::SleepRequested(_) => reply.yielded = true,
+			Outcome::Ended => break None,
+			Outcome::GlobalInstructionLimitReached | Outcome::LocalInstructionLimitReached => {
+				if reply.yielded {
+					break None;
+				}
+				break Some(format!(
+					"program did not yield within {} instructions",
+					VALIDATE_INSTRUCTION_LIMIT
+				));
+			}
+			Outcome::Error(e) => break Some(format!("{} at {}", e, state.location())),
+		}
+	};
+
+	reply.instruction_count = state.instruction_count();
+	reply.valid = error.is_none();
+	reply.error = error;
+	reply
+}
+
+async fn validate(query: ValidateQuery, body: Bytes) -> Result<Box<dyn Reply>, Rejection> {
+	let length = query.length.unwrap_or(100);
+	if length > VALIDATE_MAX_LENGTH {
+		return Err(warp::reject::custom(APIError::BadRequest(format!(
+			"strip length {} exceeds maximum of {}",
+			length, VALIDATE_MAX_LENGTH
+		))));
+	}
+
+	let program = if query.source.unwrap_or(false) {
+		let source = String::from_utf8(body.to_vec()).map_err(|_| {
+			warp::reject::custom(APIError::BadRequest(
+				"source is not valid UTF-8".to_string(),
+			))
+		})?;
+		match Program::new().with_debug_info().compile(&source) {
+			Ok(p) => p,
+			Err(e) => {
+				return Ok(Box::new(warp::reply::json(&ValidateReply {
+					valid: false,
+					error: Some(e),
+					instruction_count: 0,
+					yielded: false,
+				})))
+			}
+		}
+	} else {
+		Program::from_binary(body.to_vec())
+	};
+
+	Ok(Box::new(warp::reply::json(&dry_run(program, length))))
+}
+
+/* Runs the program on a simulated strip, sending each blitted frame to `frames` until the program ends, fails, the
+receiving end goes away, `cancelled` is set or PREVIEW_MAX_DURATION has passed. Returns the reason the program
+stopped, if it did not simply end. */
+fn run_preview(
+	program: Program,
+	length: u32,
+	frames: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+	cancelled: &AtomicBool,
+) -> Option<String> {
+	if let Err(e) = program.validate() {
+		return Some(e);
+	}
+
+	// Sleeps until `deadline`, waking up regularly to see whether the preview was cancelled
+	let sleep_until = |deadline: Instant| {
+		while !cancelled.load(Ordering::Relaxed) {
+			let now = Instant::now();
+			if now >= deadline {
+				break;
+			}
+			std::thread::sleep(std::cmp::min(deadline - now, PREVIEW_FRAME_INTERVAL));
+		}
+	};
+
+	let (sink, snapshots) = std::sync::mpsc::channel();
+	let mut vm = VM::new(Box::new(DummyStrip::new(length, false)));
+	vm.set_frame_sink(sink);
+	let mut state = vm.start(program, None);
+	let preview_started = Instant::now();
+	loop {
+		let started = Instant::now();
+		let outcome = state.run(Some(VALIDATE_INSTRUCTION_LIMIT));
+		for frame in snapshots.try_iter() {
+			if frames.send(frame).is_err() {
+				return None;
+			}
+		}
+
+		if cancelled.load(Ordering::Relaxed) || preview_started.elapsed() >= PREVIEW_MAX_DURATION {
+			return None;
+		}
+
+		match outcome {
+			Outcome::Yielded => sleep_until(started + PREVIEW_FRAME_INTERVAL),
+			Outcome::SleepRequested(duration) => sleep_until(Instant::now() + duration),
+			Outcome::Ended | Outcome::GlobalInstructionLimitReached => return None,
+			Outcome::LocalInstructionLimitReached => {
+				return Some(format!(
+					"program did not yield within {} instructions",
+					VALIDATE_INSTRUCTION_LIMIT
+				))
+			}
+			Outcome::Error(e) => return Some(format!("{} at {}", e, state.location())),
+		}
+	}
+}
+
+/* Streams a live preview of a program: the first message received is the program (source code as text, or a
+compiled binary). Each frame it blits is sent back as a binary message holding the r, g and b value of each pixel. When
+the program cannot be run, an error is sent as text message. The program is stopped when the socket is closed. */
+async fn stream_preview(socket: WebSocket, query: PreviewQuery, metrics: Arc<Metrics>) {
+	let (mut tx, mut rx) = socket.split();
+	let length = query.length.unwrap_or(100);
+
+	let program = match rx.next().await {
+		Some(Ok(message)) if message.is_text() => {
+			Program::from_source(message.to_str().unwrap_or("")).map_err(APIError::BadRequest)
+		}
+		Some(Ok(message)) if message.is_binary() => Ok(Program::from_binary(message.into_bytes())),
+		_ => return,
+	};
+	let program = program.and_then(|p| {
+		if length > VALIDATE_MAX_LENGTH {
+			Err(APIError::BadRequest(format!(
+				"strip length {} exceeds maximum of {}",
+				length, VALIDATE_MAX_LENGTH
+			)))
+		} else {
+			Ok(p)
+		}
+	});
+
+	let error = match program {
+		Ok(program) => {
+			let (frames_tx, mut frames) = tokio::sync::mpsc::unbounded_channel();
+			let cancelled = Arc::new(AtomicBool::new(false));
+			let runner_cancelled = cancelled.clone();
+			Metrics::increment(&metrics.previews_started);
+			let runner = std::thread::spawn(move || {
+				let result = run_preview(program, length, frames_tx, &runner_cancelled);
+				Metrics::increment(&metrics.previews_finished);
+				result
+			});
+
+			// Forward frames until the program stops, or stop the program when the socket is closed
+			let disconnected = loop {
+				tokio::select! {
+					frame = frames.recv() => match frame {
+						Some(frame) => {
+							if tx.send(warp::ws::Message::binary(frame)).await.is_err() {
+								break true;
+							}
+						}
+						None => break false,
+					},
+					message = rx.next() => match message {
+						Some(Ok(message)) if !message.is_close() => {}
+						_ => break true,
+					},
+				}
+			};
+
+			if disconnected {
+				cancelled.store(true, Ordering::Relaxed);
+				return;
+			}
+			runner.join().unwrap_or(None).map(APIError::BadRequest)
+		}
+		Err(e) => Some(e),
+	};
+
+	if let Some(e) = error {
+		if let Ok(json) = serde_json::to_string(&e.reply()) {
+			let _ = tx.send(warp::ws::Message::text(json)).await;
+		}
+	}
+	let _ = tx.send(warp::ws::Message::close()).await;
 }
 
 pub async fn handle_rejection(err: Rejection) -> Result<Box<dyn Reply>, Infallible> {
@@ -168,10 +673,27 @@ pub async fn handle_rejection(err: Rejection) -> Result<Box<dyn Reply>, Infallib
 	Ok(Box::new(warp::reply::with_status(json, status)))
 }
 
-pub async fn serve_http(config: &APIConfig, state: Arc<Mutex<ServerState>>) {
-	if !config.enabled {
-		return;
-	}
+/* Requires an 'Authorization: Bearer <token>' header when an API token is configured */
+fn authorized(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+	warp::header::optional::<String>("authorization")
+		.and_then(move |header: Option<String>| {
+			let token = token.clone();
+			async move {
+				match token {
+					None => Ok(()),
+					Some(t) if header == Some(format!("Bearer {}", t)) => Ok(()),
+					Some(_) => Err(warp::reject::custom(APIError::Unauthorized)),
+				}
+			}
+		})
+		.untuple_one()
+}
+
+fn routes(
+	config: &APIConfig,
+	state: Arc<Mutex<ServerState>>,
+) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+	let auth = authorized(config.api_token.clone());
 
 	let a = state.clone();
 	let device = warp::get()
@@ -179,22 +701,112 @@ pub async fn serve_http(config: &APIConfig, state: Arc<Mutex<ServerState>>) {
 		.and(warp::path!("devices" / String).and(warp::path::end()))
 		.and_then(get_device);
 
+	let g = state.clone();
+	let device_delete = warp::delete()
+		.map(move || g.clone())
+		.and(warp::path!("devices" / String).and(warp::path::end()))
+		.and(auth.clone())
+		.and_then(delete_device);
+
 	let b = state.clone();
 	let device_off = warp::get()
 		.map(move || b.clone())
 		.and(warp::path!("devices" / String / String).and(warp::path::end()))
+		.and(auth.clone())
 		.and_then(set_builtin_program);
 
+	let e = state.clone();
+	let device_program = warp::post()
+		.map(move || e.clone())
+		.and(warp::path!("devices" / String / "program").and(warp::path::end()))
+		.and(auth.clone())
+		.and(warp::body::bytes())
+		.and_then(set_program);
+
+	let h = state.clone();
+	let device_settings = warp::post()
+		.map(move || h.clone())
+		.and(warp::path!("devices" / String / "settings").and(warp::path::end()))
+		.and(auth.clone())
+		.and(warp::body::json())
+		.and_then(set_settings);
+
+	let j = state.clone();
+	let device_secret = warp::put()
+		.map(move || j.clone())
+		.and(warp::path!("devices" / String / "secret").and(warp::path::end()))
+		.and(auth.clone())
+		.and(warp::body::json())
+		.and_then(set_secret);
+
 	let c = state.clone();
 	let devices = warp::path!("devices")
 		.and(warp::path::end())
 		.map(move || c.clone())
 		.and_then(get_devices);
 
-	let d = state.clone();
+	let compile = warp::post()
+		.and(warp::path!("compile").and(warp::path::end()))
+		.and(warp::body::bytes())
+		.and_then(compile);
+
+	let validate = warp::post()
+		.and(warp::path!("validate").and(warp::path::end()))
+		.and(warp::query::<ValidateQuery>())
+		.and(warp::body::bytes())
+		.and_then(validate);
+
+	let f = state.clone();
+	let ws = warp::path!("ws")
+		.and(warp::path::end())
+		.and(warp::ws())
+		.map(move |ws: Ws| {
+			let state = f.clone();
+			ws.on_upgrade(move |socket| stream_devices(socket, state))
+		});
+
+	let k = state.clone();
+	let preview = warp::path!("preview")
+		.and(warp::path::end())
+		.and(auth)
+		.and(warp::query::<PreviewQuery>())
+		.and(warp::ws())
+		.map(move |query: PreviewQuery, ws: Ws| {
+			let metrics = k.lock().unwrap().metrics.clone();
+			ws.on_upgrade(move |socket| stream_preview(socket, query, metrics))
+		});
+
+	let i = state.clone();
+	let metrics = warp::get()
+		.and(warp::path!("metrics").and(warp::path::end()))
+		.map(move || i.clone())
+		.and_then(get_metrics);
+
+	let d = state;
 	let index = warp::path::end().map(move || d.clone()).and_then(get_index);
 
-	let routes = warp::any().and(device).or(device_off).or(devices).or(index);
+	warp::any()
+		.and(device)
+		.or(device_delete)
+		.or(device_off)
+		.or(device_program)
+		.or(device_settings)
+		.or(device_secret)
+		.or(devices)
+		.or(compile)
+		.or(validate)
+		.or(ws)
+		.or(preview)
+		.or(metrics)
+		.or(index)
+		.recover(handle_rejection)
+}
+
+pub async fn serve_http(config: &APIConfig, state: Arc<Mutex<ServerState>>) {
+	if !config.enabled {
+		return;
+	}
+
 	let mut bind_address = String::from("127.0.0.1:33334");
 
 	if let Some(b) = &config.bind_address {
@@ -203,7 +815,491 @@ pub async fn serve_http(config: &APIConfig, state: Arc<Mutex<ServerState>>) {
 
 	log::info!("HTTP API server listening at {}", bind_address);
 	let address: SocketAddr = bind_address.parse().expect("valid IP address");
-	warp::serve(routes.recover(handle_rejection))
-		.run(address)
-		.await;
+	warp::serve(routes(config, state)).run(address).await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::UdpSocket;
+	use std::sync::atomic::Ordering;
+	use std::time::Instant;
+
+	fn test_state() -> Arc<Mutex<ServerState>> {
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let address = socket.local_addr().unwrap();
+		let mut state = ServerState::new(HashMap::new(), Box::new(socket));
+		state.devices.insert(
+			"aa-bb-cc-dd-ee-ff".to_string(),
+			DeviceStatus {
+				address,
+				program: None,
+				secret: String::from("secret"),
+				pending_secret: None,
+				last_seen: Instant::now(),
+				sent_program_hash: None,
+				strip_length: None,
+				online: true,
+			},
+		);
+		Arc::new(Mutex::new(state))
+	}
+
+	#[tokio::test]
+	async fn compile() {
+		let res = warp::test::request()
+			.method("POST")
+			.path("/compile")
+			.body("loop{blit;yield}")
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(
+			res.body().to_vec(),
+			Program::from_source("loop{blit;yield}").unwrap().code
+		);
+
+		let res = warp::test::request()
+			.method("POST")
+			.path("/compile")
+			.body("loop{blit;yield")
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+		let reply = String::from_utf8(res.body().to_vec()).unwrap();
+		assert!(reply.contains("\"code\":\"bad_request\""));
+
+		// Semantic errors are reported the same way as syntax errors
+		let res = warp::test::request()
+			.method("POST")
+			.path("/compile")
+			.body("x = y")
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+		let reply = String::from_utf8(res.body().to_vec()).unwrap();
+		assert!(reply.contains("variable not found: y"));
+	}
+
+	#[tokio::test]
+	async fn metrics() {
+		let state = test_state();
+		{
+			let metrics = state.lock().unwrap().metrics.clone();
+			metrics.messages_received.fetch_add(3, Ordering::Relaxed);
+			metrics.signature_failures.fetch_add(1, Ordering::Relaxed);
+		}
+
+		let res = warp::test::request()
+			.path("/metrics")
+			.reply(&routes(&APIConfig::new(), state))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let text = String::from_utf8(res.body().to_vec()).unwrap();
+		assert!(text.contains("\npwlp_messages_received_total 3\n"));
+		assert!(text.contains("\npwlp_signature_failures_total 1\n"));
+		assert!(text.contains("\npwlp_pings_total 0\n"));
+	}
+
+	#[tokio::test]
+	async fn stream_devices() {
+		let state = test_state();
+		let mut client = warp::test::ws()
+			.path("/ws")
+			.handshake(routes(&APIConfig::new(), state.clone()))
+			.await
+			.unwrap();
+
+		// Initial snapshot
+		let snapshot = client.recv().await.unwrap();
+		assert!(snapshot.to_str().unwrap().contains("aa-bb-cc-dd-ee-ff"));
+
+		// Device registration
+		{
+			let mut s = state.lock().unwrap();
+			let status = s.devices["aa-bb-cc-dd-ee-ff"].clone();
+			s.update_device("11-22-33-44-55-66".to_string(), status);
+		}
+		let update = client.recv().await.unwrap();
+		assert!(update.to_str().unwrap().contains("11-22-33-44-55-66"));
+	}
+
+	#[tokio::test]
+	async fn stream_preview() {
+		// The test client does not pass on the query string, so the preview runs on the default strip length
+		let mut client = warp::test::ws()
+			.path("/preview")
+			.handshake(routes(&APIConfig::new(), test_state()))
+			.await
+			.unwrap();
+		client
+			.send_text("set_pixel(0, 1, 2, 3); blit; yield; set_pixel(1, 4, 5, 6); blit")
+			.await;
+		let frame = client.recv().await.unwrap();
+		assert_eq!(frame.as_bytes().len(), 300);
+		assert_eq!(&frame.as_bytes()[0..6], &[1, 2, 3, 0, 0, 0]);
+		let frame = client.recv().await.unwrap();
+		assert_eq!(&frame.as_bytes()[0..6], &[1, 2, 3, 4, 5, 6]);
+		assert!(client.recv_closed().await.is_ok());
+
+		let mut client = warp::test::ws()
+			.path("/preview")
+			.handshake(routes(&APIConfig::new(), test_state()))
+			.await
+			.unwrap();
+		client.send_text("set_pixel(").await;
+		assert!(client
+			.recv()
+			.await
+			.unwrap()
+			.to_str()
+			.unwrap()
+			.contains("bad_request"));
+	}
+
+	#[tokio::test]
+	async fn stream_preview_disconnect() {
+		let state = test_state();
+		let metrics = state.lock().unwrap().metrics.clone();
+		let mut client = warp::test::ws()
+			.path("/preview")
+			.handshake(routes(&APIConfig::new(), state))
+			.await
+			.unwrap();
+
+		// A program that never blits keeps running until the client goes away
+		client.send_text("loop { yield }").await;
+		while metrics.previews_started.load(Ordering::Relaxed) == 0 {
+			tokio::time::delay_for(Duration::from_millis(10)).await;
+		}
+		tokio::time::delay_for(Duration::from_millis(100)).await;
+		assert_eq!(metrics.previews_finished.load(Ordering::Relaxed), 0);
+
+		drop(client);
+		let disconnected = Instant::now();
+		while metrics.previews_finished.load(Ordering::Relaxed) == 0 {
+			assert!(disconnected.elapsed() < Duration::from_secs(5));
+			tokio::time::delay_for(Duration::from_millis(10)).await;
+		}
+	}
+
+	#[tokio::test]
+	async fn get_device_case_insensitive() {
+		let state = test_state();
+		let lower = warp::test::request()
+			.path("/devices/aa-bb-cc-dd-ee-ff")
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		let upper = warp::test::request()
+			.path("/devices/AA-BB-CC-DD-EE-FF")
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		assert_eq!(lower.status(), StatusCode::OK);
+		assert_eq!(upper.status(), StatusCode::OK);
+		assert_eq!(lower.body(), upper.body());
+
+		let res = warp::test::request()
+			.path("/devices/00-00-00-00-00-00")
+			.reply(&routes(&APIConfig::new(), state))
+			.await;
+		assert_eq!(res.status(), StatusCode::NOT_FOUND);
+		let reply = String::from_utf8(res.body().to_vec()).unwrap();
+		assert!(reply.contains("00-00-00-00-00-00"));
+	}
+
+	#[tokio::test]
+	async fn delete_device() {
+		let state = test_state();
+		let res = warp::test::request()
+			.method("DELETE")
+			.path("/devices/aa-bb-cc-dd-ee-ff")
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+
+		let res = warp::test::request()
+			.path("/devices")
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let reply = String::from_utf8(res.body().to_vec()).unwrap();
+		assert!(!reply.contains("aa-bb-cc-dd-ee-ff"));
+
+		let res = warp::test::request()
+			.method("DELETE")
+			.path("/devices/aa-bb-cc-dd-ee-ff")
+			.reply(&routes(&APIConfig::new(), state))
+			.await;
+		assert_eq!(res.status(), StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn api_token() {
+		let mut config = APIConfig::new();
+		config.api_token = Some("s3cr3t".to_string());
+		let state = test_state();
+
+		let res = warp::test::request()
+			.method("DELETE")
+			.path("/devices/aa-bb-cc-dd-ee-ff")
+			.reply(&routes(&config, state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+		let res = warp::test::request()
+			.method("DELETE")
+			.path("/devices/aa-bb-cc-dd-ee-ff")
+			.header("authorization", "Bearer wrong")
+			.reply(&routes(&config, state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+		let res = warp::test::request()
+			.method("DELETE")
+			.path("/devices/aa-bb-cc-dd-ee-ff")
+			.header("authorization", "Bearer s3cr3t")
+			.reply(&routes(&config, state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+
+		// Previews run arbitrary programs on the server
+		assert!(warp::test::ws()
+			.path("/preview")
+			.handshake(routes(&config, state.clone()))
+			.await
+			.is_err());
+		assert!(warp::test::ws()
+			.path("/preview")
+			.header("authorization", "Bearer s3cr3t")
+			.handshake(routes(&config, state))
+			.await
+			.is_ok());
+	}
+
+	#[tokio::test]
+	async fn set_program() {
+		let state = test_state();
+		let code = Program::from_source("loop{blit;yield}").unwrap().code;
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/program")
+			.body(code.clone())
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(res.body(), "{}");
+		let s = state.lock().unwrap();
+		assert_eq!(
+			s.devices["aa-bb-cc-dd-ee-ff"]
+				.program
+				.as_ref()
+				.unwrap()
+				.code,
+			code
+		);
+	}
+
+	#[tokio::test]
+	async fn set_program_beyond_strip_length() {
+		let state = test_state();
+		state
+			.lock()
+			.unwrap()
+			.devices
+			.get_mut("aa-bb-cc-dd-ee-ff")
+			.unwrap()
+			.strip_length = Some(10);
+
+		let program = |source| Program::from_source(source).unwrap().code;
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/program")
+			.body(program("loop{set_pixel(50, 255, 0, 0); blit; yield}"))
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let reply = String::from_utf8(res.body().to_vec()).unwrap();
+		assert!(
+			reply.contains("program sets pixel 50 but the strip of the device has only 10 pixels")
+		);
+
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/program")
+			.body(program("loop{set_pixel(9, 255, 0, 0); blit; yield}"))
+			.reply(&routes(&APIConfig::new(), state))
+			.await;
+		assert_eq!(res.body(), "{}");
+	}
+
+	#[tokio::test]
+	async fn set_program_without_yield() {
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/program")
+			.body(Program::from_source("loop{blit}").unwrap().code)
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(
+			res.body(),
+			"{\"warning\":\"program contains a loop that never yields\"}"
+		);
+	}
+
+	#[tokio::test]
+	async fn set_settings() {
+		let state = test_state();
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/settings")
+			.body("{\"brightness\": 128}")
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+
+		// The test device listens at the server socket itself
+		{
+			let s = state.lock().unwrap();
+			let mut buf = [0u8; 1500];
+			let (amt, _) = s.socket.recv_from(&mut buf).unwrap();
+			let msg = Message::from_buffer(&buf[0..amt], b"secret").unwrap();
+			assert!(matches!(msg.message_type, MessageType::Set));
+			assert_eq!(
+				Setting::decode(&msg.payload.unwrap()).unwrap(),
+				vec![Setting::Brightness(128)]
+			);
+		}
+
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/settings")
+			.body("{}")
+			.reply(&routes(&APIConfig::new(), state))
+			.await;
+		assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+	}
+
+	#[tokio::test]
+	async fn rotate_secret() {
+		let state = test_state();
+		let res = warp::test::request()
+			.method("PUT")
+			.path("/devices/aa-bb-cc-dd-ee-ff/secret")
+			.body("{\"secret\": \"new secret\"}")
+			.reply(&routes(&APIConfig::new(), state.clone()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+
+		// The new secret is sent to the device signed using the old secret
+		{
+			let s = state.lock().unwrap();
+			let mut buf = [0u8; 1500];
+			let (amt, _) = s.socket.recv_from(&mut buf).unwrap();
+			let msg = Message::from_buffer(&buf[0..amt], b"secret").unwrap();
+			assert!(matches!(msg.message_type, MessageType::Secret));
+			assert_eq!(msg.payload.unwrap(), b"new secret");
+
+			// The old secret remains in use until the device signs a message using the new secret
+			let device = &s.devices["aa-bb-cc-dd-ee-ff"];
+			assert_eq!(device.secret, "secret");
+			assert_eq!(device.pending_secret, Some("new secret".to_string()));
+		}
+
+		let res = warp::test::request()
+			.method("PUT")
+			.path("/devices/aa-bb-cc-dd-ee-ff/secret")
+			.body("{\"secret\": \"\"}")
+			.reply(&routes(&APIConfig::new(), state))
+			.await;
+		assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+	}
+
+	#[tokio::test]
+	async fn validate() {
+		let program = Program::from_source("loop{set_pixel(9, 255, 0, 0); blit; yield}").unwrap();
+		let res = warp::test::request()
+			.method("POST")
+			.path("/validate?length=10")
+			.body(program.code)
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let reply: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+		assert_eq!(reply["valid"], true);
+		assert_eq!(reply["yielded"], true);
+		assert!(reply["instruction_count"].as_u64().unwrap() > 0);
+
+		// Out of range pixel write, reported at its source line
+		let res = warp::test::request()
+			.method("POST")
+			.path("/validate?length=10&source=true")
+			.body("set_pixel(0, 255, 0, 0);\nset_pixel(10, 255, 0, 0)")
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let reply: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+		assert_eq!(reply["valid"], false);
+		assert_eq!(reply["yielded"], false);
+		assert!(reply["error"].as_str().unwrap().contains("(line 2)"));
+
+		// Source that does not compile
+		let res = warp::test::request()
+			.method("POST")
+			.path("/validate?source=true")
+			.body("x = 1;\nset_pixel(0, x, y, 0)")
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let reply: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+		assert_eq!(reply["valid"], false);
+		assert_eq!(reply["instruction_count"], 0);
+		assert_eq!(reply["error"], "variable not found: y");
+
+		// Loop that never yields
+		let res = warp::test::request()
+			.method("POST")
+			.path("/validate")
+			.body(Program::from_source("loop{blit}").unwrap().code)
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		let reply: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+		assert_eq!(reply["valid"], false);
+		assert_eq!(
+			reply["instruction_count"].as_u64().unwrap() as usize,
+			VALIDATE_INSTRUCTION_LIMIT
+		);
+	}
+
+	#[tokio::test]
+	async fn set_program_unknown_device() {
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/00-00-00-00-00-00/program")
+			.body([0x00])
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn set_program_invalid() {
+		// Unknown instruction prefix
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/program")
+			.body([0xA0])
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+		// Too large to fit in a message
+		let res = warp::test::request()
+			.method("POST")
+			.path("/devices/aa-bb-cc-dd-ee-ff/program")
+			.body(vec![0u8; MAX_PAYLOAD_SIZE + 1])
+			.reply(&routes(&APIConfig::new(), test_state()))
+			.await;
+		assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+	}
 }
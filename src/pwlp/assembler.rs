@@ -0,0 +1,162 @@
+/* Declarative macro for assembling programs in Rust code. Statements are separated by ";" and expand to calls to the
+builder methods of Program:
+
+* `push 5`, `pop 1`, `peek 0` and `poke 0` push, pop, peek or poke the given number
+* `add`, `dup`, `blit`, `yield`, ...: calls the builder method without arguments with the same name
+* `unary(Unary::SHL8)`, ...: calls the builder method with the given arguments
+* `if { ... }` and `unless { ... }`: runs the block when the top of the stack is non-zero (or zero)
+* `loop { ... }`: repeats the block forever
+* `for 5 { ... }`: repeats the block five times (the counter is on top of the stack while the block runs)
+* `for { ... }`: repeats the block, counting down the number on top of the stack (which is left on the stack)
+* `while { condition } { ... }`: repeats the block while the condition pushes a non-zero value
+
+For example, `program! { push 1; loop { inc; yield } }` builds a program that counts up forever. */
+#[macro_export]
+macro_rules! program {
+	(@emit $p:ident;) => {};
+	(@emit $p:ident; ; $($rest:tt)*) => {
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; if { $($body:tt)* } $($rest:tt)*) => {
+		$p.if_not_zero(|$p| { $crate::program!(@emit $p; $($body)*); });
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; unless { $($body:tt)* } $($rest:tt)*) => {
+		$p.if_zero(|$p| { $crate::program!(@emit $p; $($body)*); });
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; loop { $($body:tt)* } $($rest:tt)*) => {
+		$p.repeat_forever(|$p| { $crate::program!(@emit $p; $($body)*); });
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; for { $($body:tt)* } $($rest:tt)*) => {
+		$p.repeat(|$p| { $crate::program!(@emit $p; $($body)*); });
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; for $times:tt { $($body:tt)* } $($rest:tt)*) => {
+		$p.repeat_times($times, |$p| { $crate::program!(@emit $p; $($body)*); });
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; while { $($condition:tt)* } { $($body:tt)* } $($rest:tt)*) => {
+		$p.repeat_while(
+			|$p| { $crate::program!(@emit $p; $($condition)*); },
+			|$p| { $crate::program!(@emit $p; $($body)*); },
+		);
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; yield $($rest:tt)*) => {
+		$p.r#yield();
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; mod $($rest:tt)*) => {
+		$p.r#mod();
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; push $value:expr $(; $($rest:tt)*)?) => {
+		$p.push($value);
+		$crate::program!(@emit $p; $($($rest)*)?);
+	};
+	(@emit $p:ident; pop $n:expr $(; $($rest:tt)*)?) => {
+		$p.pop($n);
+		$crate::program!(@emit $p; $($($rest)*)?);
+	};
+	(@emit $p:ident; peek $n:expr $(; $($rest:tt)*)?) => {
+		$p.peek($n);
+		$crate::program!(@emit $p; $($($rest)*)?);
+	};
+	(@emit $p:ident; poke $n:expr $(; $($rest:tt)*)?) => {
+		$p.poke($n);
+		$crate::program!(@emit $p; $($($rest)*)?);
+	};
+	(@emit $p:ident; $op:ident ( $($arg:expr),* ) $($rest:tt)*) => {
+		$p.$op($($arg),*);
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	(@emit $p:ident; $op:ident $($rest:tt)*) => {
+		$p.$op();
+		$crate::program!(@emit $p; $($rest)*);
+	};
+	($($body:tt)*) => {{
+		let mut program = $crate::pwlp::program::Program::new();
+		{
+			let p = &mut program;
+			$crate::program!(@emit p; $($body)*);
+		}
+		program
+	}};
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::pwlp::instructions::Unary;
+	use crate::pwlp::program::Program;
+
+	#[test]
+	fn instructions() {
+		let assembled = program! {
+			push 5; push 300; add; dup; peek 1; pop 2;
+			unary(Unary::SHL8); swap; mod; yield; blit
+		};
+
+		let mut expected = Program::new();
+		expected
+			.push(5)
+			.push(300)
+			.add()
+			.dup()
+			.peek(1)
+			.pop(2)
+			.unary(Unary::SHL8)
+			.swap()
+			.r#mod()
+			.r#yield()
+			.blit();
+		assert_eq!(assembled.as_bytes(), expected.as_bytes());
+		assert_eq!(assembled.max_stack_depth(), expected.max_stack_depth());
+	}
+
+	#[test]
+	fn control_flow() {
+		let assembled = program! {
+			push 0;
+			loop {
+				inc;
+				if { dup; print };
+				unless { dump };
+				for 3 { peek 1; set_pixel };
+				push 2;
+				for { yield };
+				pop 1;
+				while { dup; push 10; lt } { inc }
+			}
+		};
+
+		let mut expected = Program::new();
+		expected.push(0).repeat_forever(|p| {
+			p.inc();
+			p.if_not_zero(|q| {
+				q.dup().print();
+			});
+			p.if_zero(|q| {
+				q.dump();
+			});
+			p.repeat_times(3, |q| {
+				q.peek(1).set_pixel();
+			});
+			p.push(2);
+			p.repeat(|q| {
+				q.r#yield();
+			});
+			p.pop(1);
+			p.repeat_while(
+				|q| {
+					q.dup().push(10).lt();
+				},
+				|q| {
+					q.inc();
+				},
+			);
+		});
+		assert_eq!(assembled.as_bytes(), expected.as_bytes());
+	}
+}
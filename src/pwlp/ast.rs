@@ -1,5 +1,13 @@
 use super::instructions;
 use super::program::Program;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/* Name of the hidden variable that holds the number of remaining iterations of a for loop (which cannot clash with
+user-defined variables as it is not a valid variable name) */
+const FOR_COUNTER: &str = "#remaining";
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Node {
@@ -12,14 +20,59 @@ pub enum Node {
 	If(Expression, Vec<Node>),
 	IfElse(Expression, Vec<Node>, Vec<Node>),
 	Assignment(String, Expression),
+	CompoundAssignment(String, instructions::Binary, Expression),
 	For(String, Expression, Vec<Node>),
+	ForStep(Box<Node>, Expression, Box<Node>, Vec<Node>),
+	Print(Expression),
+	Assert(Expression),
+	Yield(Expression),
+	Sleep(Expression),
+	Nop,
+	// A statement and the (1-based) source line it starts at
+	Line(usize, Box<Node>),
+}
+
+/* The number of single-character insertions, deletions and substitutions needed to turn one string into the other */
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut previous: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut current = vec![i + 1];
+		for (j, cb) in b.iter().enumerate() {
+			let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+			current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+		}
+		previous = current;
+	}
+	previous[b.len()]
+}
+
+/* Non-fatal problems found while assembling a program */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+	// A variable defined in a nested scope hides the variable with the same name in an outer scope
+	ShadowedVariable(String),
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Warning::ShadowedVariable(name) => write!(
+				f,
+				"variable {} shadows a variable with the same name in an outer scope",
+				name
+			),
+		}
+	}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Scope<'a> {
 	variables: Vec<String>,
 	level: u32,
 	parent: Option<&'a Scope<'a>>,
+	warnings: Rc<RefCell<Vec<Warning>>>,
+	errors: Rc<RefCell<Vec<String>>>,
 }
 
 impl<'a> Scope<'a> {
@@ -28,6 +81,8 @@ impl<'a> Scope<'a> {
 			variables: vec![],
 			level: 0,
 			parent: None,
+			warnings: Rc::new(RefCell::new(vec![])),
+			errors: Rc::new(RefCell::new(vec![])),
 		}
 	}
 
@@ -36,9 +91,26 @@ impl<'a> Scope<'a> {
 			parent: Some(&self),
 			level: 0,
 			variables: vec![],
+			warnings: self.warnings.clone(),
+			errors: self.errors.clone(),
 		}
 	}
 
+	/* The warnings collected while assembling in this scope and the scopes nested in it (or its parents) */
+	pub fn warnings(&self) -> Vec<Warning> {
+		self.warnings.borrow().clone()
+	}
+
+	/* The errors collected while assembling in this scope and the scopes nested in it (or its parents). Code
+	assembled with errors is not valid and must not be run. */
+	pub fn errors(&self) -> Vec<String> {
+		self.errors.borrow().clone()
+	}
+
+	fn error(&self, message: String) {
+		self.errors.borrow_mut().push(message);
+	}
+
 	pub fn unnest(&mut self, program: &mut Program) {
 		match self.parent {
 			Some(_) => {
@@ -62,11 +134,82 @@ impl<'a> Scope<'a> {
 		}
 	}
 
+	/* The names of the variables that are visible from this scope, nearest (innermost, most recently defined) first.
+	Variables that are shadowed by a variable with the same name in a nested scope are only listed once. Hidden
+	variables used by the compiler (e.g. loop counters) are not listed. */
+	pub fn visible_variables(&self) -> Vec<String> {
+		let mut names: Vec<String> = vec![];
+		let mut scope = Some(self);
+		while let Some(s) = scope {
+			for name in s.variables.iter().rev() {
+				if !name.starts_with('#') && !names.contains(name) {
+					names.push(name.clone());
+				}
+			}
+			scope = s.parent;
+		}
+		names
+	}
+
+	/* The number of scopes this scope is nested in (zero for the outermost scope) */
+	#[allow(dead_code)]
+	pub fn depth(&self) -> u32 {
+		match self.parent {
+			Some(p) => p.depth() + 1,
+			None => 0,
+		}
+	}
+
+	/* Error message for a reference to a variable that is not visible, suggesting a visible variable with a similar name */
+	fn variable_not_found(&self, variable_name: &str) -> String {
+		let suggestion = self
+			.visible_variables()
+			.into_iter()
+			.map(|name| (edit_distance(&name, variable_name), name))
+			.filter(|(distance, _)| *distance <= 2 && *distance < variable_name.len())
+			.min_by_key(|(distance, _)| *distance);
+
+		match suggestion {
+			Some((_, name)) => format!(
+				"variable not found: {} (did you mean {}?)",
+				variable_name, name
+			),
+			None => format!("variable not found: {}", variable_name),
+		}
+	}
+
+	/* The stack depth of each variable that is visible from this scope */
+	pub(crate) fn variable_depths(&self) -> BTreeMap<String, u32> {
+		let mut depths = BTreeMap::new();
+		let mut scope = Some(self);
+		while let Some(s) = scope {
+			for name in s.variables.iter() {
+				if !depths.contains_key(name) {
+					depths.insert(name.clone(), self.index_of(name).unwrap());
+				}
+			}
+			scope = s.parent;
+		}
+		depths
+	}
+
 	pub fn define_variable(&mut self, variable_name: &str) {
 		if self.variables.iter().any(|r| r == variable_name) {
 			panic!("variable already defined")
 		}
 
+		let mut parent = self.parent;
+		let mut shadows = false;
+		while let Some(p) = parent {
+			shadows |= p.variables.iter().any(|r| r == variable_name);
+			parent = p.parent;
+		}
+		if shadows && !variable_name.starts_with('#') {
+			self.warnings
+				.borrow_mut()
+				.push(Warning::ShadowedVariable(variable_name.to_string()));
+		}
+
 		self.variables.push(variable_name.to_string());
 		// A variable was already pushed, but we are now counting it througn variables.len()
 	}
@@ -81,6 +224,7 @@ impl<'a> Scope<'a> {
 
 	pub(crate) fn assemble_teardown(&self, program: &mut Program) {
 		if !self.variables.is_empty() {
+			program.record_scope(self);
 			program.pop(self.variables.len() as u8);
 		}
 	}
@@ -97,6 +241,39 @@ impl Node {
 			Node::Special(s) => {
 				program.special(*s);
 			}
+			Node::Nop => {
+				program.nop();
+			}
+			Node::Print(e) => {
+				e.assemble(program, scope);
+				program.print();
+				scope.level -= 1;
+			}
+			Node::Assert(e) => {
+				e.assemble(program, scope);
+				program.assert();
+				scope.level -= 1;
+			}
+			Node::Sleep(e) => {
+				e.assemble(program, scope);
+				program.special(instructions::Special::SLEEP);
+				scope.level -= 1;
+			}
+			Node::Yield(e) => {
+				if let Some(1) = e.const_value() {
+					program.special(instructions::Special::YIELD);
+				} else {
+					// Yield n times (or not at all when n is zero)
+					e.assemble(program, scope);
+					program.if_not_zero(|q| {
+						q.repeat(|r| {
+							r.special(instructions::Special::YIELD);
+						});
+					});
+					program.pop(1);
+					scope.level -= 1;
+				}
+			}
 			Node::User(s) => {
 				program.user(*s);
 			}
@@ -143,9 +320,11 @@ impl Node {
 						scope.level = pre_level;
 					}
 					_ => {
+						let pre_level = scope.level;
 						for param in e.iter() {
 							param.assemble(program, scope);
 						}
+						scope.level = pre_level;
 					}
 				}
 				program.user(*s);
@@ -166,20 +345,65 @@ impl Node {
 				});
 			}
 			Node::For(variable_name, expression, stmts) => {
+				// The loop variable counts up from zero, while a hidden counter (on top) counts the remaining iterations
+				program.push(0);
+				scope.level += 1;
 				expression.assemble(program, scope);
 				scope.define_variable(variable_name);
-				program.repeat(|q| {
-					let mut child_scope = scope.nest();
-					for i in stmts.iter() {
-						i.assemble(q, &mut child_scope);
+				scope.define_variable(FOR_COUNTER);
+
+				let body = |q: &mut Program| {
+					q.repeat(|r| {
+						let mut child_scope = scope.nest();
+						for i in stmts.iter() {
+							i.assemble(r, &mut child_scope);
+						}
+						child_scope.unnest(r);
+
+						// Increment the loop variable, which sits right below the counter
+						r.peek(1);
+						r.inc();
+						r.poke(1);
+					});
+				};
+
+				// The body does not run at all when the count is zero
+				match expression.const_value() {
+					Some(n) if n != 0 => body(program),
+					_ => {
+						program.if_not_zero(body);
 					}
-					child_scope.unnest(q);
-				});
+				}
 
-				// Undefine variable
+				// Undefine variables
+				scope.undefine_variable(FOR_COUNTER);
 				scope.undefine_variable(variable_name);
-				scope.level -= 1;
-				program.pop(1);
+				scope.level -= 2;
+				program.pop(2);
+			}
+			Node::ForStep(init, condition, step, stmts) => {
+				// Variables defined in the initializer are visible in the condition, step and body
+				let loop_scope = RefCell::new(scope.nest());
+				init.assemble(program, &mut loop_scope.borrow_mut());
+				program.repeat_while(
+					|q| condition.assemble(q, &mut loop_scope.borrow_mut()),
+					|q| {
+						// The condition value is popped before the body runs
+						loop_scope.borrow_mut().level -= 1;
+
+						let parent_scope = loop_scope.borrow();
+						let mut child_scope = parent_scope.nest();
+						for i in stmts.iter() {
+							i.assemble(q, &mut child_scope);
+						}
+						child_scope.unnest(q);
+
+						let mut step_scope = parent_scope.nest();
+						step.assemble(q, &mut step_scope);
+						step_scope.unnest(q);
+					},
+				);
+				loop_scope.into_inner().unnest(program);
 			}
 			Node::If(e, ss) => {
 				let old_level = scope.level;
@@ -216,7 +440,37 @@ impl Node {
 			}
 			Node::Assignment(variable_name, expression) => {
 				expression.assemble(program, scope);
-				scope.define_variable(variable_name); // Value left on the stack but cleaned up later by Scope::assemble_teardown
+
+				if let Some(relative) = scope.index_of(variable_name) {
+					// Existing variable: overwrite its value (index is relative to the stack after popping the new value)
+					program.record_scope(scope);
+					program.poke((relative - 1) as u8);
+					scope.level -= 1;
+				} else {
+					scope.define_variable(variable_name); // Value left on the stack but cleaned up later by Scope::assemble_teardown
+				}
+			}
+			Node::CompoundAssignment(variable_name, op, expression) => {
+				if scope.index_of(variable_name).is_none() {
+					scope.error(scope.variable_not_found(variable_name));
+					return;
+				}
+
+				// x op= e is equivalent to x = x op e
+				Node::Assignment(
+					variable_name.clone(),
+					Expression::binary(
+						Expression::Load(variable_name.clone()),
+						*op,
+						expression.clone(),
+					),
+				)
+				.assemble(program, scope);
+			}
+			Node::Line(line, node) => {
+				let outer = program.begin_line(*line);
+				node.assemble(program, scope);
+				program.end_line(outer);
 			}
 		}
 	}
@@ -225,6 +479,66 @@ impl Node {
 #[derive(Clone, Debug, PartialEq)]
 pub enum Intrinsic {
 	Clamp(Box<Expression>, Box<Expression>, Box<Expression>),
+	SaturatingAdd(Box<Expression>, Box<Expression>, Box<Expression>),
+	SaturatingSub(Box<Expression>, Box<Expression>, Box<Expression>),
+	WrapIndex(Box<Expression>),
+	LerpColor(Box<Expression>, Box<Expression>, Box<Expression>),
+}
+
+impl Intrinsic {
+	/* Expressions for sat_add and sat_sub, which refer to the limit and the (limited) first operand as variables
+	#limit and #value so these are only evaluated once. The limited value is returned first. */
+	fn saturating_expressions(&self) -> (Expression, Expression) {
+		let load = |name: &str| Box::new(Expression::Load(name.to_string()));
+		let sub = |lhs: &str, rhs: &str| {
+			Box::new(Expression::Binary(
+				load(lhs),
+				instructions::Binary::SUB,
+				load(rhs),
+			))
+		};
+		let zero = || Box::new(Expression::Literal(0));
+
+		match self {
+			// min(a, limit) + min(b, limit - min(a, limit))
+			Intrinsic::SaturatingAdd(a, b, _) => (
+				Expression::Intrinsic(Intrinsic::Clamp(a.clone(), zero(), load("#limit"))),
+				Expression::Binary(
+					load("#value"),
+					instructions::Binary::ADD,
+					Box::new(Expression::Intrinsic(Intrinsic::Clamp(
+						b.clone(),
+						zero(),
+						sub("#limit", "#value"),
+					))),
+				),
+			),
+			// max(a, limit) - min(b, max(a, limit) - limit)
+			Intrinsic::SaturatingSub(a, b, _) => (
+				Expression::Intrinsic(Intrinsic::Clamp(
+					a.clone(),
+					load("#limit"),
+					Box::new(Expression::Literal(u32::MAX)),
+				)),
+				Expression::Binary(
+					load("#value"),
+					instructions::Binary::SUB,
+					Box::new(Expression::Intrinsic(Intrinsic::Clamp(
+						b.clone(),
+						zero(),
+						sub("#value", "#limit"),
+					))),
+				),
+			),
+			_ => unreachable!(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Logical {
+	And,
+	Or,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -236,10 +550,130 @@ pub enum Expression {
 	UserCall(instructions::UserCommand, Vec<Expression>),
 	Load(String),
 	Intrinsic(Intrinsic),
+	Logical(Box<Expression>, Logical, Box<Expression>),
 }
 
 impl Expression {
-	fn assemble(&self, program: &mut Program, scope: &mut Scope) {
+	/* Builds a binary expression, using the cheaper SHL8/SHR8 instructions for shifts by a multiple of 8 bits */
+	pub fn binary(lhs: Expression, op: instructions::Binary, rhs: Expression) -> Expression {
+		let unary = match op {
+			instructions::Binary::SHL => instructions::Unary::SHL8,
+			instructions::Binary::SHR => instructions::Unary::SHR8,
+			_ => return Expression::Binary(Box::new(lhs), op, Box::new(rhs)),
+		};
+
+		match rhs {
+			Expression::Literal(n) if (n % 8) == 0 => {
+				let mut expr = lhs;
+				for _ in 0..(n / 8) {
+					expr = Expression::Unary(unary, Box::new(expr))
+				}
+				expr
+			}
+			_ => Expression::Binary(Box::new(lhs), op, Box::new(rhs)),
+		}
+	}
+
+	/* Packs the channels into a color value: (r & 0xFF) | (g & 0xFF) << 8 | (b & 0xFF) << 16 */
+	pub fn rgb(r: Expression, g: Expression, b: Expression) -> Expression {
+		let byte = |e: Expression| {
+			Box::new(Expression::Binary(
+				Box::new(e),
+				instructions::Binary::AND,
+				Box::new(Expression::Literal(0xFF)),
+			))
+		};
+		let shifted = |e: Expression, shift: u32| {
+			Box::new(Expression::Binary(
+				byte(e),
+				instructions::Binary::SHL,
+				Box::new(Expression::Literal(shift)),
+			))
+		};
+		Expression::Binary(
+			Box::new(Expression::Binary(
+				byte(r),
+				instructions::Binary::OR,
+				shifted(g, 8),
+			)),
+			instructions::Binary::OR,
+			shifted(b, 16),
+		)
+	}
+
+	/* Extracts the channel that starts at bit `shift` (0 for red, 8 for green, 16 for blue) from a color value:
+	(c >> shift) & 0xFF */
+	pub fn channel(color: Expression, shift: u32) -> Expression {
+		let mut shifted = color;
+		for _ in 0..(shift / 8) {
+			shifted = Expression::Unary(instructions::Unary::SHR8, Box::new(shifted));
+		}
+		Expression::Binary(
+			Box::new(shifted),
+			instructions::Binary::AND,
+			Box::new(Expression::Literal(0xFF)),
+		)
+	}
+
+	/* Interpolates each channel of two colors: (from * (255 - t) + to * t) / 255 */
+	fn lerp_color(from: &Expression, to: &Expression, t: &Expression) -> Expression {
+		let binary = |lhs: Expression, op, rhs: Expression| {
+			Expression::Binary(Box::new(lhs), op, Box::new(rhs))
+		};
+		let lerp = |shift: u32| {
+			let weighted = binary(
+				binary(
+					Expression::channel(from.clone(), shift),
+					instructions::Binary::MUL,
+					binary(
+						Expression::Literal(255),
+						instructions::Binary::SUB,
+						t.clone(),
+					),
+				),
+				instructions::Binary::ADD,
+				binary(
+					Expression::channel(to.clone(), shift),
+					instructions::Binary::MUL,
+					t.clone(),
+				),
+			);
+			binary(
+				weighted,
+				instructions::Binary::DIV,
+				Expression::Literal(255),
+			)
+		};
+		Expression::rgb(lerp(0), lerp(8), lerp(16))
+	}
+
+	/* Whether the expression always evaluates to either 0 or 1 */
+	fn is_boolean(&self) -> bool {
+		match self {
+			Expression::Logical(_, _, _) => true,
+			Expression::Binary(_, op, _) => matches!(
+				op,
+				instructions::Binary::EQ
+					| instructions::Binary::NEQ
+					| instructions::Binary::LT
+					| instructions::Binary::LTE
+					| instructions::Binary::GT
+					| instructions::Binary::GTE
+			),
+			_ => false,
+		}
+	}
+
+	/* Assembles the expression, converting its value to 0 or 1 */
+	fn assemble_boolean(&self, program: &mut Program, scope: &mut Scope) {
+		self.assemble(program, scope);
+		if !self.is_boolean() {
+			program.push(0);
+			program.binary(instructions::Binary::NEQ);
+		}
+	}
+
+	pub(crate) fn assemble(&self, program: &mut Program, scope: &mut Scope) {
 		// If we can be simplified to a constant expression, do that!
 		if let Some(c) = self.const_value() {
 			program.push(c);
@@ -277,12 +711,33 @@ impl Expression {
 			Expression::Load(variable_name) => {
 				if let Some(relative) = scope.index_of(variable_name) {
 					// println!("Index of {} is {}", variable_name, relative);
+					program.record_scope(scope);
 					program.peek(relative as u8);
 					scope.level += 1;
 				} else {
-					panic!("variable not found: {}", variable_name)
+					// Push a placeholder so that assembly (and stack accounting) can continue
+					scope.error(scope.variable_not_found(variable_name));
+					program.push(0);
+					scope.level += 1;
 				}
 			}
+			Expression::Logical(lhs, op, rhs) => {
+				let old_level = scope.level;
+				lhs.assemble_boolean(program, scope);
+
+				// Only evaluate the right hand side when the left hand side does not determine the result
+				let mut evaluate_rhs = |q: &mut Program| {
+					q.pop(1);
+					scope.level = old_level;
+					rhs.assemble_boolean(q, scope);
+				};
+
+				match op {
+					Logical::And => program.if_not_zero(&mut evaluate_rhs),
+					Logical::Or => program.if_zero(&mut evaluate_rhs),
+				};
+				scope.level = old_level + 1;
+			}
 			Expression::Intrinsic(intrinsic) => {
 				match intrinsic {
 					Intrinsic::Clamp(value, min, max) => {
@@ -308,6 +763,7 @@ impl Expression {
 						});
 
 						program.leave_on_stack(2);
+						scope.level = old_level + 1;
 
 						max.assemble(program, scope); // [max, previous_result]
 						program.peek(1); // [previous_result, max, previous_result]
@@ -331,6 +787,46 @@ impl Expression {
 						program.leave_on_stack(2);
 						scope.level = old_level + 1;
 					}
+					Intrinsic::SaturatingAdd(_, _, limit)
+					| Intrinsic::SaturatingSub(_, _, limit) => {
+						let old_level = scope.level;
+						let (limited, result) = intrinsic.saturating_expressions();
+						{
+							let mut temporaries = scope.nest();
+							limit.assemble(program, &mut temporaries); // [limit]
+							temporaries.define_variable("#limit");
+							limited.assemble(program, &mut temporaries); // [value, limit]
+							temporaries.define_variable("#value");
+							result.assemble(program, &mut temporaries); // [result, value, limit]
+						}
+						program.poke(1); // [value, result]
+						program.pop(1); // [result]
+						scope.level = old_level + 1;
+					}
+					Intrinsic::WrapIndex(value) => {
+						value.assemble(program, scope); // [value]
+						program.user(instructions::UserCommand::GET_LENGTH); // [length, value]
+						program.binary(instructions::Binary::MOD); // [value % length]
+					}
+					Intrinsic::LerpColor(from, to, t) => {
+						// Each argument is used for every channel, so evaluate it once and refer to it as a variable
+						let old_level = scope.level;
+						{
+							let mut temporaries = scope.nest();
+							from.assemble(program, &mut temporaries); // [from]
+							temporaries.define_variable("#from");
+							to.assemble(program, &mut temporaries); // [to, from]
+							temporaries.define_variable("#to");
+							t.assemble(program, &mut temporaries); // [t, to, from]
+							temporaries.define_variable("#t");
+							let load = |name: &str| Expression::Load(name.to_string());
+							Expression::lerp_color(&load("#from"), &load("#to"), &load("#t"))
+								.assemble(program, &mut temporaries); // [result, t, to, from]
+						}
+						program.poke(2); // [t, to, result]
+						program.pop(2); // [result]
+						scope.level = old_level + 1;
+					}
 				}
 			}
 		}
@@ -342,44 +838,22 @@ impl Expression {
 			Expression::UserCall(_, _) | Expression::User(_) => None,
 			Expression::Load(_var_name) => None,
 			Expression::Binary(lhs, op, rhs) => {
+				// Fold using the same implementation as the VM so that both always agree
 				if let (Some(lhc), Some(rhc)) = (lhs.const_value(), rhs.const_value()) {
-					match op {
-						instructions::Binary::ADD => Some(lhc.overflowing_add(rhc).0),
-						instructions::Binary::SUB => Some(lhc.overflowing_sub(rhc).0),
-						instructions::Binary::DIV => Some(lhc.overflowing_div(rhc).0),
-						instructions::Binary::MUL => Some(lhc.overflowing_mul(rhc).0),
-						instructions::Binary::MOD => Some(lhc % rhc),
-						instructions::Binary::EQ => Some(if lhc == rhc { 1 } else { 0 }),
-						instructions::Binary::NEQ => Some(if lhc != rhc { 1 } else { 0 }),
-						instructions::Binary::LT => Some(if lhc < rhc { 1 } else { 0 }),
-						instructions::Binary::LTE => Some(if lhc <= rhc { 1 } else { 0 }),
-						instructions::Binary::GT => Some(if lhc > rhc { 1 } else { 0 }),
-						instructions::Binary::GTE => Some(if lhc >= rhc { 1 } else { 0 }),
-						instructions::Binary::OR => Some(lhc | rhc),
-						instructions::Binary::XOR => Some(lhc ^ rhc),
-						instructions::Binary::AND => Some(lhc & rhc),
-						instructions::Binary::SHL => Some(lhc << rhc),
-						instructions::Binary::SHR => Some(lhc >> rhc),
-					}
+					Some(op.apply(lhc, rhc))
 				} else {
 					None
 				}
 			}
 
-			Expression::Unary(op, rhs) => {
-				if let Some(c) = rhs.const_value() {
-					match op {
-						instructions::Unary::INC => Some(c.overflowing_add(1).0),
-						instructions::Unary::DEC => Some(c.overflowing_sub(1).0),
-						instructions::Unary::NOT => Some(!c),
-						instructions::Unary::NEG => None, // TODO
-						instructions::Unary::SHL8 => Some(c << 8),
-						instructions::Unary::SHR8 => Some(c << 8),
-					}
-				} else {
-					None
-				}
-			}
+			Expression::Unary(op, rhs) => rhs.const_value().map(|c| op.apply(c)),
+
+			Expression::Logical(lhs, op, rhs) => match (op, lhs.const_value()) {
+				(Logical::And, Some(0)) => Some(0),
+				(Logical::Or, Some(l)) if l != 0 => Some(1),
+				(_, Some(_)) => rhs.const_value().map(|r| if r != 0 { 1 } else { 0 }),
+				(_, None) => None,
+			},
 
 			Expression::Intrinsic(intrinsic) => {
 				match intrinsic {
@@ -400,8 +874,291 @@ impl Expression {
 							None
 						}
 					}
+					Intrinsic::SaturatingAdd(a, b, limit) => {
+						if let (Some(a), Some(b), Some(limit)) =
+							(a.const_value(), b.const_value(), limit.const_value())
+						{
+							let a = std::cmp::min(a, limit);
+							Some(a + std::cmp::min(b, limit - a))
+						} else {
+							None
+						}
+					}
+					Intrinsic::SaturatingSub(a, b, limit) => {
+						if let (Some(a), Some(b), Some(limit)) =
+							(a.const_value(), b.const_value(), limit.const_value())
+						{
+							let a = std::cmp::max(a, limit);
+							Some(a - std::cmp::min(b, a - limit))
+						} else {
+							None
+						}
+					}
+					// The strip length is only known at runtime
+					Intrinsic::WrapIndex(_) => None,
+					Intrinsic::LerpColor(from, to, t) => {
+						if let (Some(from), Some(to), Some(t)) =
+							(from.const_value(), to.const_value(), t.const_value())
+						{
+							let literal = Expression::Literal;
+							Expression::lerp_color(&literal(from), &literal(to), &literal(t))
+								.const_value()
+						} else {
+							None
+						}
+					}
 				}
 			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pwlp::parser;
+	use crate::pwlp::strip::DummyStrip;
+	use crate::pwlp::vm::{Outcome, VM};
+	use rand::{Rng, SeedableRng};
+	use rand_chacha::ChaCha20Rng;
+
+	/* Parses `x = <source>` and returns the expression */
+	fn expression(source: &str) -> Expression {
+		match parser::parse(&format!("x = {}", source)).unwrap() {
+			Node::Statements(mut statements) => match statements.remove(0) {
+				Node::Line(_, node) => match *node {
+					Node::Assignment(_, e) => e,
+					n => panic!("unexpected node {:?}", n),
+				},
+				n => panic!("unexpected node {:?}", n),
+			},
+			n => panic!("unexpected node {:?}", n),
+		}
+	}
+
+	#[test]
+	fn color_channels_fold() {
+		assert_eq!(expression("rgb(1, 2, 3)").const_value(), Some(0x0003_0201));
+		assert_eq!(expression("red(rgb(1, 2, 3))").const_value(), Some(1));
+		assert_eq!(expression("green(rgb(1, 2, 3))").const_value(), Some(2));
+		assert_eq!(expression("blue(rgb(1, 2, 3))").const_value(), Some(3));
+		assert_eq!(expression("green(#102030)").const_value(), Some(0x20));
+		assert_eq!(expression("green(get_pixel(0))").const_value(), None);
+	}
+
+	#[test]
+	fn saturating_fold() {
+		assert_eq!(
+			expression("sat_add(200, 100, 255)").const_value(),
+			Some(255)
+		);
+		assert_eq!(
+			expression("sat_add(100, 100, 255)").const_value(),
+			Some(200)
+		);
+		assert_eq!(expression("sat_add(300, 0, 255)").const_value(), Some(255));
+		assert_eq!(
+			expression("sat_add(4000000000, 4000000000, 4294967295)").const_value(),
+			Some(4294967295)
+		);
+		assert_eq!(expression("sat_sub(100, 200, 0)").const_value(), Some(0));
+		assert_eq!(expression("sat_sub(100, 50, 20)").const_value(), Some(50));
+		assert_eq!(expression("sat_sub(100, 90, 20)").const_value(), Some(20));
+		assert_eq!(
+			expression("sat_add(get_length, 1, 255)").const_value(),
+			None
+		);
+	}
+
+	#[test]
+	fn lerp_color() {
+		let grey = 0x0080_8080;
+		assert_eq!(
+			expression("lerp_color(#000000, #FFFFFF, 128)").const_value(),
+			Some(grey)
+		);
+		assert_eq!(
+			expression("lerp_color(#102030, #FFFFFF, 0)").const_value(),
+			Some(0x0030_2010)
+		);
+		assert_eq!(
+			expression("lerp_color(#102030, #FF8000, 255)").const_value(),
+			Some(0x0000_80FF)
+		);
+		assert_eq!(
+			expression("lerp_color(#000000, get_pixel(0), 128)").const_value(),
+			None
+		);
+
+		// At runtime, each argument is evaluated once
+		let program = Program::from_source(
+			"set_pixel(0, 255, 255, 255); c = lerp_color(#000000, get_pixel(0), get_length * 64); set_pixel(1, red(c), green(c), blue(c))",
+		)
+		.unwrap();
+		let mut vm = VM::new(Box::new(DummyStrip::new(2, false)));
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+		assert_eq!(vm.strip().get_pixel(1).to_packed(), grey);
+	}
+
+	#[test]
+	fn division_by_zero_folds_to_zero() {
+		assert_eq!(expression("5 / 0").const_value(), Some(0));
+		assert_eq!(expression("5 % 0").const_value(), Some(0));
+		assert_eq!(expression("7 / 2").const_value(), Some(3));
+		assert_eq!(expression("7 % 2").const_value(), Some(1));
+	}
+
+	/* Runs the program in a VM and returns the value it leaves on top of the stack */
+	fn evaluate(program: Program) -> u32 {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut state = vm.start(program, Some(100));
+		assert!(matches!(state.run(None), Outcome::Ended));
+		*state.stack().last().unwrap()
+	}
+
+	#[test]
+	fn folding_matches_vm() {
+		let mut rng = ChaCha20Rng::from_seed([42u8; 32]);
+		let interesting = [
+			0,
+			1,
+			2,
+			7,
+			8,
+			31,
+			32,
+			33,
+			255,
+			256,
+			0x7FFF_FFFF,
+			0x8000_0000,
+			u32::MAX,
+		];
+		let random_value = |rng: &mut ChaCha20Rng| {
+			if rng.gen_bool(0.5) {
+				interesting[rng.gen_range(0, interesting.len())]
+			} else {
+				rng.gen()
+			}
+		};
+
+		for _ in 0..500 {
+			let (lhs, rhs) = (random_value(&mut rng), random_value(&mut rng));
+
+			for code in 0..16 {
+				let op = instructions::Binary::from(code).unwrap();
+				let folded = Expression::Binary(
+					Box::new(Expression::Literal(lhs)),
+					op,
+					Box::new(Expression::Literal(rhs)),
+				)
+				.const_value();
+				let mut program = Program::new();
+				program.push(lhs).push(rhs).binary(op);
+				assert_eq!(folded, Some(evaluate(program)), "{} {:?} {}", lhs, op, rhs);
+			}
+
+			for code in 0..6 {
+				let op = instructions::Unary::from(code).unwrap();
+				let folded =
+					Expression::Unary(op, Box::new(Expression::Literal(lhs))).const_value();
+				let mut program = Program::new();
+				program.push(lhs).unary(op);
+				assert_eq!(folded, Some(evaluate(program)), "{:?} {}", op, lhs);
+			}
+		}
+	}
+
+	#[test]
+	fn visible_variables() {
+		let mut outer = Scope::new();
+		outer.define_variable("a");
+		outer.define_variable("b");
+		let mut middle = outer.nest();
+		middle.define_variable("c");
+		middle.define_variable("a");
+		let mut inner = middle.nest();
+		inner.define_variable("d");
+		inner.define_variable(FOR_COUNTER);
+
+		assert_eq!(outer.depth(), 0);
+		assert_eq!(inner.depth(), 2);
+		assert_eq!(outer.visible_variables(), vec!["b", "a"]);
+		assert_eq!(inner.visible_variables(), vec!["d", "a", "c", "b"]);
+	}
+
+	#[test]
+	fn index_of() {
+		/* Defines a variable whose value was just pushed, like an assignment does */
+		fn define(scope: &mut Scope, name: &str) {
+			scope.level += 1;
+			scope.define_variable(name);
+		}
+
+		let mut outer = Scope::new();
+		define(&mut outer, "a");
+		define(&mut outer, "b");
+		assert_eq!(outer.index_of("b"), Some(0));
+		assert_eq!(outer.index_of("a"), Some(1));
+
+		// A temporary value on top of the stack moves the variables down
+		outer.level += 1;
+		assert_eq!(outer.index_of("b"), Some(1));
+		assert_eq!(outer.index_of("a"), Some(2));
+
+		let mut middle = outer.nest();
+		define(&mut middle, "c");
+		define(&mut middle, "a");
+		let mut inner = middle.nest();
+		define(&mut inner, "d");
+
+		// Stack (top first): d | a, c | temporary, b, a
+		assert_eq!(inner.index_of("d"), Some(0));
+		assert_eq!(inner.index_of("c"), Some(2));
+		assert_eq!(inner.index_of("b"), Some(4));
+
+		// Shadowed variables resolve to the innermost definition
+		assert_eq!(inner.index_of("a"), Some(1));
+		assert_eq!(middle.index_of("a"), Some(0));
+
+		assert_eq!(inner.index_of("e"), None);
+		assert_eq!(Scope::new().index_of("a"), None);
+
+		// The same layout, as compiled from source
+		let program = Program::from_source(
+			"a = 1; b = 2; for(c = 3) { a = 4; for(d = 2) { set_pixel(d, a, b, c) } }",
+		)
+		.unwrap();
+		let mut vm = VM::new(Box::new(DummyStrip::new(2, false)));
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+		assert_eq!(vm.strip().snapshot(), vec![4, 2, 2, 4, 2, 2]);
+	}
+
+	#[test]
+	fn variable_not_found() {
+		let mut scope = Scope::new();
+		scope.define_variable("counter");
+		scope.define_variable("x");
+		assert_eq!(
+			scope.variable_not_found("countr"),
+			"variable not found: countr (did you mean counter?)"
+		);
+		assert_eq!(scope.variable_not_found("y"), "variable not found: y");
+		assert_eq!(edit_distance("kitten", "sitting"), 3);
+
+		// Compiling a reference to an undefined variable fails instead of panicking
+		assert_eq!(
+			Program::from_source("x = y").unwrap_err(),
+			"variable not found: y"
+		);
+		assert_eq!(
+			Program::from_source("for(counter = 3) { countr += 1 }").unwrap_err(),
+			"variable not found: countr (did you mean counter?)"
+		);
+		assert!(Program::new().compile_expression("1 + y").is_err());
+	}
+}
@@ -1,57 +1,146 @@
 use super::program::Program;
-use super::protocol::{Message, MessageType};
-use super::strip::Strip;
+use super::protocol::{
+	bind_socket, resolve_address, Message, MessageType, Setting, MAX_MESSAGE_SIZE,
+};
+use super::transport::Transport;
 use super::vm::{Outcome, VM};
 use eui48::MacAddress;
 use mac_address::get_mac_address;
 use std::convert::TryInto;
 use std::error::Error;
-use std::net::UdpSocket;
-use std::sync::mpsc;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, SystemTime};
 
-pub struct Client {
-	vm: VM,
-	secret: Vec<u8>,
-	fps_limit: Option<usize>,
+/* Number of consecutive send/receive failures after which the client socket is recreated */
+const MAX_CONSECUTIVE_SOCKET_ERRORS: usize = 3;
+
+/* Capped exponential backoff: each delay is twice the previous one, up to the maximum */
+struct Backoff {
+	initial: Duration,
+	max: Duration,
+	current: Duration,
 }
 
-impl dyn Strip {
-	fn set_all_pixels_to(&mut self, r: u8, g: u8, b: u8) {
-		for i in 0..self.length() {
-			self.set_pixel(i, r, g, b);
+impl Backoff {
+	fn new(initial: Duration, max: Duration) -> Backoff {
+		Backoff {
+			initial,
+			max,
+			current: initial,
 		}
-		self.blit();
+	}
+
+	/* Returns the time to wait before the next attempt */
+	fn next_delay(&mut self) -> Duration {
+		let delay = self.current;
+		self.current = std::cmp::min(self.current * 2, self.max);
+		delay
+	}
+
+	/* Starts again at the initial delay (after a successful attempt) */
+	fn reset(&mut self) {
+		self.current = self.initial;
 	}
 }
 
+/* Address the client sends its pings to. With discovery enabled, pings go to a broadcast or multicast address until a
+server answers with a (correctly signed) pong. From then on the client talks to that server directly. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServerTarget {
+	Fixed(SocketAddr),
+	Pending(SocketAddr),
+	Discovered(SocketAddr),
+}
+
+impl ServerTarget {
+	fn new(address: SocketAddr, discover: bool) -> ServerTarget {
+		if discover {
+			ServerTarget::Pending(address)
+		} else {
+			ServerTarget::Fixed(address)
+		}
+	}
+
+	fn address(&self) -> SocketAddr {
+		match *self {
+			ServerTarget::Fixed(a) | ServerTarget::Pending(a) | ServerTarget::Discovered(a) => a,
+		}
+	}
+
+	/* Latches onto the server that sent a pong while discovery is pending. Returns true when this completed discovery. */
+	fn pong_received(&mut self, source_address: SocketAddr) -> bool {
+		match self {
+			ServerTarget::Pending(_) => {
+				*self = ServerTarget::Discovered(source_address);
+				true
+			}
+			_ => false,
+		}
+	}
+}
+
+/* Sent from the networking thread to the strip thread */
+enum Command {
+	Run(Program),
+	Set(Vec<Setting>),
+}
+
+pub struct Client {
+	vm: VM,
+	secret: Vec<u8>,
+	fps_limit: Option<usize>,
+	mac_address: Option<MacAddress>,
+	mtu: usize,
+	discover: bool,
+}
+
 impl Client {
 	pub fn new(vm: VM, secret: &[u8], fps_limit: Option<usize>) -> Client {
 		Client {
 			vm,
 			secret: secret.to_vec(),
 			fps_limit,
+			mac_address: None,
+			mtu: MAX_MESSAGE_SIZE,
+			discover: false,
 		}
 	}
 
+	/* Sets the size of the buffer used to receive messages (see protocol::buffer_size) */
+	pub fn set_mtu(&mut self, mtu: usize) {
+		self.mtu = mtu;
+	}
+
+	/* Identify as the given MAC address instead of the one detected from the network interface */
+	pub fn set_mac_address(&mut self, mac_address: Option<MacAddress>) {
+		self.mac_address = mac_address;
+	}
+
+	/* Treat the server address as a broadcast or multicast address to discover the server at (see ServerTarget) */
+	pub fn set_discovery(&mut self, discover: bool) {
+		self.discover = discover;
+	}
+
 	pub fn run(
 		&mut self,
 		bind_address: &str,
 		server_address: &str,
 		initial_program: Option<Program>,
 	) -> Result<(), Box<dyn Error>> {
-		// Set everything to the same color
-		self.vm.strip().set_all_pixels_to(0, 0, 0);
+		// Start with a dark strip
+		self.vm.strip().set_all_pixels_to(0, 0, 0, true);
 
-		let mac = get_mac_address()?.expect("could not obtain own MAC address");
-		let mac_address =
-			MacAddress::from_bytes(&mac.bytes()).expect("reading MAC address from bytes failed");
+		let mac_address = resolve_mac_address(self.mac_address, detect_mac_address);
 
 		// Start networking thread
-		let secret = self.secret.to_owned();
+		let mut secret = self.secret.to_owned();
 		let bind_address = bind_address.to_owned();
 		let server_address = server_address.to_owned();
+		let mtu = self.mtu;
+		let discover = self.discover;
 		log::info!(
 			"Running as client with MAC {} at {} with server {}",
 			mac_address,
@@ -60,93 +149,124 @@ impl Client {
 		);
 		let (tx, rx) = mpsc::channel();
 
+		// Hash of the running program, reported to the server so it does not need to send it again
+		let running_hash: Arc<Mutex<Option<u32>>> = Arc::new(Mutex::new(None));
+		let reported_hash = running_hash.clone();
+
+		// Length of the strip, which the server may change using a Set message
+		let running_length: Arc<Mutex<u32>> = Arc::new(Mutex::new(self.vm.strip().length()));
+		let reported_length = running_length.clone();
+
 		thread::spawn(move || {
-			log::info!("Client binding to address {}", bind_address);
-			let socket = UdpSocket::bind(bind_address).expect("could not bind to address");
+			let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
 
-			socket
-				.set_read_timeout(Some(Duration::from_secs(1)))
-				.unwrap();
+			'bind: loop {
+				log::info!("Client binding to address {}", bind_address);
+				// Joins the group when the server address is a multicast address
+				let (socket, mut target) =
+					match resolve_address(&server_address).and_then(|server| {
+						let socket = bind_udp(&bind_address, server, discover)?;
+						socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+						Ok((socket, ServerTarget::new(server, discover)))
+					}) {
+						Ok(s) => s,
+						Err(e) => {
+							let delay = backoff.next_delay();
+							log::error!(
+								"could not bind to address {}: {}. Retrying in {:?}",
+								bind_address,
+								e,
+								delay
+							);
+							thread::sleep(delay);
+							continue 'bind;
+						}
+					};
 
-			let mut last_ping_time = SystemTime::now();
-			let ping_interval = Duration::from_secs(30);
+				let mut last_ping_time = SystemTime::now();
+				let ping_interval = Duration::from_secs(30);
+				let mut consecutive_errors = 0;
 
-			loop {
-				// Send a welcome message
-				let welcome = Message::new(MessageType::Ping, mac_address, None)
+				loop {
+					// Send a welcome message
+					let welcome = Message::ping(
+						mac_address,
+						*reported_hash.lock().unwrap(),
+						Some(*reported_length.lock().unwrap()),
+					)
 					.expect("message construction failed");
-				let signed = welcome.signed(&secret);
-				log::info!("Sending welcome to server {}", server_address);
-				match socket.send_to(&signed, &server_address) {
-					Err(x) => log::error!("failed to send welcome: {}", x),
-					Ok(_) => {}
-				}
+					let signed = welcome.signed(&secret);
+					log::info!("Sending welcome to server {}", target.address());
+					match socket.send_to(&signed, target.address()) {
+						Err(x) => {
+							log::error!("failed to send welcome: {}", x);
+							consecutive_errors += 1;
+						}
+						Ok(_) => {
+							consecutive_errors = 0;
+							backoff.reset();
+						}
+					}
 
-				while SystemTime::now().duration_since(last_ping_time).unwrap() < ping_interval {
-					let mut buf = [0; 1500];
-					match socket.recv_from(&mut buf) {
-						Ok((amt, source_address)) => {
-							log::info!("Received {} bytes from {}", amt, source_address);
+					while SystemTime::now().duration_since(last_ping_time).unwrap() < ping_interval
+					{
+						// Recreate the socket when it keeps failing (e.g. because the interface went down)
+						if consecutive_errors >= MAX_CONSECUTIVE_SOCKET_ERRORS {
+							let delay = backoff.next_delay();
+							log::error!(
+								"socket failed {} times in a row, rebinding in {:?}",
+								consecutive_errors,
+								delay
+							);
+							thread::sleep(delay);
+							continue 'bind;
+						}
 
-							// Decode message (from_buffer verifies HMAC)
-							match Message::from_buffer(&buf[0..amt], &secret) {
-								Err(t) => log::error!(
-									"{} error {:?} (size={}b secret={:?})",
+						// One byte extra so that datagrams that do not fit can be detected
+						let mut buf = vec![0; mtu + 1];
+						match socket.recv_from(&mut buf) {
+							Ok((amt, source_address)) if amt > mtu => {
+								consecutive_errors = 0;
+								log::error!(
+									"{}: ignoring datagram larger than the MTU of {} bytes",
 									source_address,
-									t,
-									amt,
-									secret
-								),
-								Ok(m) => {
-									log::info!(
-										"{}: {:?} t={}",
-										source_address,
-										m.message_type,
-										m.unix_time
-									);
-
-									// TODO check message time
-									match m.message_type {
-										MessageType::Run => {
-											if let Some(payload) = m.payload {
-												tx.send(Program::from_binary(payload)).unwrap();
-											} else {
-												// Run empty program
-												tx.send(Program::new()).unwrap();
-											}
-										}
-										MessageType::Pong
-										| MessageType::Ping
-										| MessageType::Set
-										| MessageType::Unknown => {
-											// Ignore
-											log::warn!("Ignoring message");
-										}
-									}
+									mtu
+								);
+							}
+							Ok((amt, source_address)) => {
+								consecutive_errors = 0;
+								if let Some(command) = handle_datagram(
+									&buf[0..amt],
+									source_address,
+									&mut secret,
+									&mut target,
+								) {
+									tx.send(command).unwrap();
 								}
 							}
-						}
-						Err(e) => {
-							if e.kind() != std::io::ErrorKind::WouldBlock {
-								log::error!(
-									"could not receive from socket: {}. Sleeping for 1s",
-									e
-								);
-								std::thread::sleep(std::time::Duration::from_secs(1));
-							} else {
-								// Time-out, which is expected
+							Err(e) => {
+								if e.kind() != std::io::ErrorKind::WouldBlock {
+									consecutive_errors += 1;
+									log::error!(
+										"could not receive from socket: {}. Sleeping for 1s",
+										e
+									);
+									std::thread::sleep(std::time::Duration::from_secs(1));
+								} else {
+									// Time-out, which is expected
+								}
 							}
 						}
 					}
+					last_ping_time = SystemTime::now();
 				}
-				last_ping_time = SystemTime::now();
 			}
 		});
 
 		// Strip thread
 		let mut program = initial_program;
 		if program.is_none() {
-			program = Some(rx.recv().unwrap());
+			program = Some(await_program(&mut self.vm, &mut self.fps_limit, &rx));
 		}
 
 		loop {
@@ -155,24 +275,25 @@ impl Client {
 
 			if let Some(p) = &p {
 				log::info!("Starting program:\n{:?}", p);
+				*running_hash.lock().unwrap() = Some(p.code_hash());
 			}
 			let mut state = self.vm.start(p.unwrap(), None);
 			let mut last_yield_time = SystemTime::now();
-			let frame_time = if let Some(fps) = self.fps_limit {
-				Some(Duration::from_millis((1000 / fps).try_into().unwrap()))
-			} else {
-				None
-			};
 			let mut running = true;
 
 			let instruction_limit_per_cycle = 1000;
 
 			while running {
+				*running_length.lock().unwrap() = state.vm().strip().length();
 				let outcome = state.run(Some(instruction_limit_per_cycle));
 
-				// See if there is a new program waiting
-				if let Ok(p) = rx.try_recv() {
-					log::info!("set new program {:?}", p);
+				// See if there is a new program waiting (settings are applied right away)
+				let received = match rx.try_recv() {
+					Ok(command) => handle_command(state.vm(), &mut self.fps_limit, command),
+					Err(_) => None,
+				};
+
+				if let Some(p) = received {
 					program = Some(p);
 					running = false;
 				// Go into next iteration and start new program
@@ -182,7 +303,9 @@ impl Client {
 							// Just continue on a new cycle
 						}
 						Outcome::Yielded => {
-							if let Some(frame_time) = frame_time {
+							if let Some(fps) = self.fps_limit {
+								let frame_time =
+									Duration::from_millis((1000 / fps).try_into().unwrap());
 								let now = SystemTime::now();
 								let passed = now.duration_since(last_yield_time).unwrap();
 								if passed < frame_time {
@@ -192,18 +315,33 @@ impl Client {
 								last_yield_time = now;
 							}
 						}
+						Outcome::SleepRequested(duration) => {
+							// Sleep, but start a new program as soon as it arrives
+							let received = match rx.recv_timeout(duration) {
+								Ok(command) => {
+									handle_command(state.vm(), &mut self.fps_limit, command)
+								}
+								Err(_) => None,
+							};
+
+							if let Some(p) = received {
+								program = Some(p);
+								running = false;
+							}
+							last_yield_time = SystemTime::now();
+						}
 						Outcome::GlobalInstructionLimitReached | Outcome::Ended => {
 							// Await a new program
-							program = Some(rx.recv().unwrap());
+							program = Some(await_program(state.vm(), &mut self.fps_limit, &rx));
 							running = false;
 						}
 						Outcome::Error(e) => {
 							log::error!(
-								"Error in VM at pc={}: {:?}, awaiting next program",
+								"Error in VM at pc={}: {}, awaiting next program",
 								state.pc(),
 								e
 							);
-							program = Some(rx.recv().unwrap());
+							program = Some(await_program(state.vm(), &mut self.fps_limit, &rx));
 							running = false;
 						}
 					}
@@ -212,3 +350,373 @@ impl Client {
 		}
 	}
 }
+
+/* Binds a UDP socket that joins the multicast group of the server (if any) and may send broadcasts for discovery */
+fn bind_udp(
+	bind_address: &str,
+	server_address: SocketAddr,
+	discover: bool,
+) -> std::io::Result<Box<dyn Transport>> {
+	let socket = bind_socket(bind_address, Some(server_address))?;
+	socket.set_broadcast(discover)?;
+	Ok(Box::new(socket))
+}
+
+/* Verifies and handles a datagram received from the server. Returns the command for the strip thread, if any. */
+fn handle_datagram(
+	data: &[u8],
+	source_address: SocketAddr,
+	secret: &mut Vec<u8>,
+	target: &mut ServerTarget,
+) -> Option<Command> {
+	log::info!("Received {} bytes from {}", data.len(), source_address);
+
+	// Decode message (from_buffer verifies HMAC)
+	let m = match Message::from_buffer(data, secret) {
+		Err(t) => {
+			log::error!(
+				"{} error {:?} (size={}b secret={:?})",
+				source_address,
+				t,
+				data.len(),
+				secret
+			);
+			return None;
+		}
+		Ok(m) => m,
+	};
+	log::info!("{}: {:?} t={}", source_address, m.message_type, m.unix_time);
+
+	// TODO check message time
+	match m.message_type {
+		MessageType::Run => match m.payload {
+			Some(payload) => Some(Command::Run(Program::from_binary(payload))),
+			// Run empty program
+			None => Some(Command::Run(Program::new())),
+		},
+		MessageType::Set => match Setting::decode(&m.payload.unwrap_or_default()) {
+			Ok(settings) => Some(Command::Set(settings)),
+			Err(e) => {
+				log::error!("invalid settings: {:?}", e);
+				None
+			}
+		},
+		MessageType::Secret => {
+			match m.payload {
+				// Messages after this one are signed using the new secret
+				Some(new_secret) => {
+					log::info!("Secret changed by server");
+					*secret = new_secret;
+				}
+				None => log::error!("ignoring empty secret"),
+			}
+			None
+		}
+		MessageType::Pong => {
+			if target.pong_received(source_address) {
+				log::info!("Discovered server at {}", source_address);
+			}
+			None
+		}
+		MessageType::Ping | MessageType::Unknown => {
+			// Ignore
+			log::warn!("Ignoring message");
+			None
+		}
+	}
+}
+
+/* Applies settings received from the server to the VM and the frame rate limit */
+fn apply_settings(vm: &mut VM, fps_limit: &mut Option<usize>, settings: &[Setting]) {
+	for setting in settings {
+		log::info!("Applying setting {:?}", setting);
+		match setting {
+			Setting::FpsLimit(0) => *fps_limit = None,
+			Setting::FpsLimit(fps) => *fps_limit = Some(*fps as usize),
+			Setting::Brightness(brightness) => vm.set_brightness(*brightness),
+			Setting::StripLength(length) => {
+				if let Err(e) = vm.strip().set_length(*length) {
+					log::error!("could not change strip length to {}: {}", length, e);
+				}
+			}
+		}
+	}
+}
+
+/* Applies received settings, or returns the received program */
+fn handle_command(vm: &mut VM, fps_limit: &mut Option<usize>, command: Command) -> Option<Program> {
+	match command {
+		Command::Run(program) => {
+			log::info!("set new program {:?}", program);
+			Some(program)
+		}
+		Command::Set(settings) => {
+			apply_settings(vm, fps_limit, &settings);
+			None
+		}
+	}
+}
+
+/* Waits until a program is received, applying any settings received in the meantime */
+fn await_program(vm: &mut VM, fps_limit: &mut Option<usize>, rx: &Receiver<Command>) -> Program {
+	loop {
+		if let Some(program) = handle_command(vm, fps_limit, rx.recv().unwrap()) {
+			return program;
+		}
+	}
+}
+
+/* Determines the MAC address of the default network interface, if any */
+fn detect_mac_address() -> Option<MacAddress> {
+	match get_mac_address() {
+		Ok(Some(mac)) => MacAddress::from_bytes(&mac.bytes()).ok(),
+		Ok(None) => None,
+		Err(e) => {
+			log::warn!("could not obtain own MAC address: {}", e);
+			None
+		}
+	}
+}
+
+/* Picks the MAC address the client identifies as. A configured address always wins, otherwise the detected
+address is used. When neither is available, a locally administered address is derived from the host name so that
+it remains the same across restarts. */
+fn resolve_mac_address(
+	configured: Option<MacAddress>,
+	detect: impl FnOnce() -> Option<MacAddress>,
+) -> MacAddress {
+	if let Some(mac) = configured {
+		return mac;
+	}
+
+	if let Some(mac) = detect() {
+		return mac;
+	}
+
+	let host_name = std::fs::read_to_string("/etc/hostname")
+		.or_else(|_| std::env::var("HOSTNAME"))
+		.or_else(|_| std::env::var("COMPUTERNAME"))
+		.unwrap_or_default();
+	let mac = generated_mac_address(host_name.trim());
+	log::warn!(
+		"could not obtain own MAC address, using generated address {}",
+		mac
+	);
+	mac
+}
+
+/* Derives a locally administered unicast MAC address from the seed, using a 64-bit FNV-1a hash (like
+Program::code_hash). Unlike the standard library hashers, its value does not change between builds, so the device
+keeps its identity when the client is rebuilt using another toolchain. */
+fn generated_mac_address(seed: &str) -> MacAddress {
+	let hash = seed
+		.bytes()
+		.fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+			(hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+		})
+		.to_le_bytes();
+	let mut bytes = [0u8; 6];
+	bytes.copy_from_slice(&hash[0..6]);
+	bytes[0] = (bytes[0] | 0x02) & !0x01;
+	MacAddress::new(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		generated_mac_address, handle_command, handle_datagram, resolve_mac_address, Backoff,
+		Command, ServerTarget,
+	};
+	use crate::pwlp::program::Program;
+	use crate::pwlp::protocol::Setting;
+	use crate::pwlp::protocol::{Message, MessageType, MAX_MESSAGE_SIZE};
+	#[cfg(feature = "server")]
+	use crate::pwlp::server::Server;
+	use crate::pwlp::strip::DummyStrip;
+	use crate::pwlp::transport::{MemoryNetwork, Transport};
+	use crate::pwlp::vm::VM;
+	use eui48::MacAddress;
+	#[cfg(feature = "server")]
+	use std::collections::HashMap;
+	use std::net::SocketAddr;
+	use std::time::Duration;
+
+	#[test]
+	fn apply_settings() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(3, false)));
+		let mut fps_limit = Some(60);
+
+		let settings = Command::Set(vec![Setting::Brightness(100), Setting::FpsLimit(0)]);
+		assert!(handle_command(&mut vm, &mut fps_limit, settings).is_none());
+		assert_eq!(vm.strip().brightness(), 100);
+		assert_eq!(fps_limit, None);
+
+		let settings = Command::Set(vec![Setting::FpsLimit(30)]);
+		assert!(handle_command(&mut vm, &mut fps_limit, settings).is_none());
+		assert_eq!(fps_limit, Some(30));
+		assert_eq!(vm.strip().brightness(), 100);
+
+		let settings = Command::Set(vec![Setting::StripLength(5)]);
+		assert!(handle_command(&mut vm, &mut fps_limit, settings).is_none());
+		assert_eq!(vm.strip().length(), 5);
+
+		// Programs are passed on
+		let program = Program::from_source("loop{yield}").unwrap();
+		let received = handle_command(&mut vm, &mut fps_limit, Command::Run(program.clone()));
+		assert_eq!(received.unwrap().code, program.code);
+	}
+
+	#[test]
+	fn backoff() {
+		let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+		let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+		assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+
+		backoff.reset();
+		assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+		assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+	}
+
+	#[test]
+	fn configured_mac_address() {
+		let configured = MacAddress::parse_str("01:02:03:04:05:06").unwrap();
+		let detected = MacAddress::parse_str("0a:0b:0c:0d:0e:0f").unwrap();
+		assert_eq!(
+			resolve_mac_address(Some(configured), || Some(detected)),
+			configured
+		);
+		assert_eq!(resolve_mac_address(None, || Some(detected)), detected);
+	}
+
+	#[test]
+	fn fallback_mac_address() {
+		let configured = MacAddress::parse_str("01:02:03:04:05:06").unwrap();
+		assert_eq!(resolve_mac_address(Some(configured), || None), configured);
+
+		let generated = resolve_mac_address(None, || None);
+		assert!(generated.is_local());
+		assert!(generated.is_unicast());
+		assert_eq!(generated, resolve_mac_address(None, || None));
+		assert_eq!(generated_mac_address("a"), generated_mac_address("a"));
+		assert_ne!(generated_mac_address("a"), generated_mac_address("b"));
+
+		// The address derived from a host name must never change
+		assert_eq!(
+			generated_mac_address("raspberrypi"),
+			MacAddress::parse_str("be:c3:dc:f2:19:51").unwrap()
+		);
+	}
+
+	/* Sends a ping to the target and handles all replies the way the networking loop does */
+	fn ping_round(
+		target: &mut ServerTarget,
+		transport: &dyn Transport,
+		secret: &mut Vec<u8>,
+	) -> Vec<Command> {
+		let ping = Message::ping(MacAddress::nil(), None, None).unwrap();
+		transport
+			.send_to(&ping.signed(secret), target.address())
+			.unwrap();
+		let mut buf = vec![0; MAX_MESSAGE_SIZE];
+		let mut commands = vec![];
+		while let Ok((amt, source_address)) = transport.recv_from(&mut buf) {
+			commands.extend(handle_datagram(
+				&buf[0..amt],
+				source_address,
+				secret,
+				target,
+			));
+		}
+		commands
+	}
+
+	#[test]
+	fn discovery() {
+		let network = MemoryNetwork::new();
+		let client = network.bind("192.168.1.2:33332").unwrap();
+		client
+			.set_read_timeout(Some(Duration::from_millis(10)))
+			.unwrap();
+		let server = network.bind("192.168.1.10:33333").unwrap();
+		let other = network.bind("192.168.1.11:33333").unwrap();
+		let client_address = client.local_addr().unwrap();
+		let pong = |secret: &[u8]| {
+			Message::new(MessageType::Pong, MacAddress::nil(), None)
+				.unwrap()
+				.signed(secret)
+		};
+
+		let broadcast: SocketAddr = "255.255.255.255:33333".parse().unwrap();
+		let mut secret = b"secret".to_vec();
+		let mut target = ServerTarget::new(broadcast, true);
+		assert_eq!(target, ServerTarget::Pending(broadcast));
+
+		// Nobody answers: keep pinging the discovery address
+		ping_round(&mut target, &client, &mut secret);
+		assert_eq!(target, ServerTarget::Pending(broadcast));
+
+		// A pong signed with another secret is not from our server
+		other.send_to(&pong(b"other"), client_address).unwrap();
+		ping_round(&mut target, &client, &mut secret);
+		assert_eq!(target, ServerTarget::Pending(broadcast));
+
+		// The first server to answer wins, later pongs do not change the target
+		server.send_to(&pong(b"secret"), client_address).unwrap();
+		other.send_to(&pong(b"secret"), client_address).unwrap();
+		ping_round(&mut target, &client, &mut secret);
+		assert_eq!(
+			target,
+			ServerTarget::Discovered(server.local_addr().unwrap())
+		);
+
+		ping_round(&mut target, &client, &mut secret);
+		let mut buf = vec![0; MAX_MESSAGE_SIZE];
+		assert_eq!(server.recv_from(&mut buf).unwrap().1, client_address);
+
+		// Without discovery the configured address is never replaced
+		let mut fixed = ServerTarget::new(server.local_addr().unwrap(), false);
+		assert!(!fixed.pong_received(other.local_addr().unwrap()));
+		assert_eq!(fixed.address(), server.local_addr().unwrap());
+	}
+
+	#[test]
+	#[cfg(feature = "server")]
+	fn ping_pong_run() {
+		let network = MemoryNetwork::new();
+		let program = Program::from_source("loop{blit;yield}").unwrap();
+		let mut server = Server::with_transport(
+			HashMap::new(),
+			"secret",
+			program.clone(),
+			Box::new(network.bind("10.0.0.1:33333").unwrap()),
+		);
+		server.set_read_timeout(Duration::from_millis(10));
+		let server_socket = server.receive_socket().unwrap();
+
+		let client = network.bind("10.0.0.2:33332").unwrap();
+		client
+			.set_read_timeout(Some(Duration::from_millis(10)))
+			.unwrap();
+		let mut secret = b"secret".to_vec();
+		let mut target = ServerTarget::new(server_socket.local_addr().unwrap(), false);
+
+		// The server answers the ping with a pong, followed by the program to run
+		let ping = Message::ping(MacAddress::nil(), None, None).unwrap();
+		client
+			.send_to(&ping.signed(&secret), target.address())
+			.unwrap();
+		server.poll(&*server_socket).unwrap();
+		let mut buf = vec![0; MAX_MESSAGE_SIZE];
+		let (amt, source_address) = client.recv_from(&mut buf).unwrap();
+		let pong = Message::from_buffer(&buf[0..amt], &secret).unwrap();
+		assert!(matches!(pong.message_type, MessageType::Pong));
+		assert!(handle_datagram(&buf[0..amt], source_address, &mut secret, &mut target).is_none());
+
+		let (amt, source_address) = client.recv_from(&mut buf).unwrap();
+		match handle_datagram(&buf[0..amt], source_address, &mut secret, &mut target) {
+			Some(Command::Run(received)) => assert_eq!(received.code, program.code),
+			_ => panic!("expected the server to send the program"),
+		}
+		assert!(client.recv_from(&mut buf).is_err());
+	}
+}
@@ -0,0 +1,148 @@
+use super::instructions::Prefix;
+use super::vm::{Outcome, State};
+use std::io::{BufRead, Write};
+
+/* Executes a program one instruction at a time, printing each instruction and the resulting stack. After each
+instruction, a command is read from the input: an empty line executes the next instruction, 'c' continues until
+the program yields and 'q' quits. When the input is exhausted (e.g. stdin is not interactive), execution continues
+without prompting. */
+pub struct Stepper<R: BufRead, W: Write> {
+	input: R,
+	output: W,
+	prompt: bool,
+}
+
+impl<R: BufRead, W: Write> Stepper<R, W> {
+	pub fn new(input: R, output: W) -> Stepper<R, W> {
+		Stepper {
+			input,
+			output,
+			prompt: true,
+		}
+	}
+
+	/* Runs the program until it yields, ends or fails. Returns None when the user chose to quit. */
+	pub fn run(&mut self, state: &mut State) -> std::io::Result<Option<Outcome>> {
+		let mut continuing = false;
+		loop {
+			let pc = state.pc();
+			let instruction = state.program().code.get(pc).copied();
+			let variable = instruction.and_then(|i| variable_name(state, pc, i));
+			let outcome = state.step();
+
+			if let Some(instruction) = instruction {
+				let name = match Prefix::from(instruction) {
+					Some(prefix) => prefix.to_string(),
+					None => "(unknown)".to_string(),
+				};
+				let operand = match variable {
+					Some(variable) => format!("{} ({})", instruction & 0x0F, variable),
+					None => (instruction & 0x0F).to_string(),
+				};
+				writeln!(
+					self.output,
+					"{:04}.\t{:02x}\t{}\t{}\tstack: {:?}",
+					pc,
+					instruction,
+					name,
+					operand,
+					state.stack()
+				)?;
+			}
+
+			if let Some(outcome) = outcome {
+				return Ok(Some(outcome));
+			}
+
+			if self.prompt && !continuing {
+				write!(self.output, "> ")?;
+				self.output.flush()?;
+				let mut line = String::new();
+				if self.input.read_line(&mut line)? == 0 {
+					self.prompt = false;
+				}
+
+				match line.trim() {
+					"c" => continuing = true,
+					"q" => return Ok(None),
+					_ => {}
+				}
+			}
+		}
+	}
+}
+
+/* The name of the variable accessed by a PEEK or POKE instruction, if the program has debug information */
+fn variable_name(state: &State, pc: usize, instruction: u8) -> Option<String> {
+	let depth = u32::from(instruction & 0x0F);
+	let depth = match Prefix::from(instruction) {
+		Some(Prefix::PEEK) => depth,
+		// The operand of POKE is relative to the stack after popping the value
+		Some(Prefix::POKE) => depth + 1,
+		_ => return None,
+	};
+	state
+		.program()
+		.variable_at(pc, depth)
+		.map(|name| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pwlp::program::Program;
+	use crate::pwlp::strip::DummyStrip;
+	use crate::pwlp::vm::VM;
+	use std::io::Cursor;
+
+	fn program() -> Program {
+		Program::from_source("a = 1; a += 2; yield; set_pixel(0, a, 0, 0); yield; blit").unwrap()
+	}
+
+	#[test]
+	fn step_and_quit() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut state = vm.start(program(), None);
+		let mut output = vec![];
+		let mut stepper = Stepper::new(Cursor::new("\nq\n"), &mut output);
+
+		// Two instructions are executed (the second after pressing enter), then the user quits
+		assert!(stepper.run(&mut state).unwrap().is_none());
+		assert_eq!(state.pc(), 3);
+		let output = String::from_utf8(output).unwrap();
+		assert_eq!(output.lines().count(), 3);
+		assert!(output.starts_with("0000.\t11\tPUSHB\t1\tstack: [1]\n> 0002.\t"));
+	}
+
+	#[test]
+	fn continue_until_yield() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut state = vm.start(program(), None);
+		let mut output = vec![];
+		let mut stepper = Stepper::new(Cursor::new("c\n\nq\n"), &mut output);
+
+		assert!(matches!(
+			stepper.run(&mut state).unwrap(),
+			Some(Outcome::Yielded)
+		));
+		assert!(stepper.run(&mut state).unwrap().is_none());
+		assert_eq!(String::from_utf8(output).unwrap().matches("> ").count(), 3);
+	}
+
+	#[test]
+	fn exhausted_input() {
+		// Without input, the program runs without prompting for more than once
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut state = vm.start(program(), None);
+		let mut output = vec![];
+		let mut stepper = Stepper::new(Cursor::new(""), &mut output);
+
+		let mut yields = 0;
+		while let Some(Outcome::Yielded) = stepper.run(&mut state).unwrap() {
+			yields += 1;
+		}
+		assert_eq!(yields, 2);
+		assert_eq!(state.vm.strip().get_pixel(0).r, 3);
+		assert_eq!(String::from_utf8(output).unwrap().matches("> ").count(), 1);
+	}
+}
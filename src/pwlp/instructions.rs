@@ -1,7 +1,7 @@
 use std::fmt;
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Prefix {
 	POP = 0x0,
 	PUSHB = 0x10,
@@ -12,10 +12,15 @@ pub enum Prefix {
 	JNZ = 0x60,
 	UNARY = 0x70,
 	BINARY = 0x80,
+	POKE = 0x90,
 	USER = 0xE0,
 	SPECIAL = 0xF0,
 }
 
+/* Postfix flag for JMP, JZ and JNZ indicating that the operand is a signed (16-bit, little endian) offset relative to
+the address of the jump instruction itself, rather than an absolute address */
+pub const JUMP_RELATIVE: u8 = 0x01;
+
 impl Prefix {
 	pub fn from(code: u8) -> Option<Prefix> {
 		match code & 0xF0 {
@@ -28,6 +33,7 @@ impl Prefix {
 			0x60 => Some(Prefix::JNZ),
 			0x70 => Some(Prefix::UNARY),
 			0x80 => Some(Prefix::BINARY),
+			0x90 => Some(Prefix::POKE),
 			0xE0 => Some(Prefix::USER),
 			0xF0 => Some(Prefix::SPECIAL),
 			_ => None,
@@ -50,6 +56,7 @@ impl std::fmt::Display for Prefix {
 				Prefix::JNZ => "JNZ",
 				Prefix::UNARY => "UNARY",
 				Prefix::BINARY => "BINARY",
+				Prefix::POKE => "POKE",
 				Prefix::USER => "USER",
 				Prefix::SPECIAL => "SPECIAL",
 			}
@@ -60,6 +67,10 @@ impl std::fmt::Display for Prefix {
 #[allow(dead_code)]
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Special {
+	ASSERT = 8,
+	HALT = 9,
+	SLEEP = 10,
+	PRINT = 11,
 	SWAP = 12,
 	DUMP = 13,
 	YIELD = 14,
@@ -92,9 +103,9 @@ impl Unary {
 
 	pub fn apply(self, lhs: u32) -> u32 {
 		match self {
-			Unary::DEC => lhs - 1,
-			Unary::INC => lhs + 1,
-			Unary::NEG => unimplemented!(),
+			Unary::DEC => lhs.wrapping_sub(1),
+			Unary::INC => lhs.wrapping_add(1),
+			Unary::NEG => lhs.wrapping_neg(),
 			Unary::NOT => !lhs,
 			Unary::SHL8 => lhs << 8,
 			Unary::SHR8 => lhs >> 8,
@@ -165,15 +176,17 @@ impl Binary {
 
 	pub fn apply(self, lhs: u32, rhs: u32) -> u32 {
 		match self {
-			Binary::ADD => lhs + rhs,
-			Binary::SUB => lhs - rhs,
-			Binary::MUL => lhs * rhs,
-			Binary::DIV => lhs / rhs,
-			Binary::MOD => lhs % rhs,
+			Binary::ADD => lhs.wrapping_add(rhs),
+			Binary::SUB => lhs.wrapping_sub(rhs),
+			Binary::MUL => lhs.wrapping_mul(rhs),
+			// Division and modulo by zero result in zero (rather than a crash)
+			Binary::DIV => lhs.checked_div(rhs).unwrap_or(0),
+			Binary::MOD => lhs.checked_rem(rhs).unwrap_or(0),
 			Binary::AND => lhs & rhs,
 			Binary::OR => lhs | rhs,
-			Binary::SHL => lhs << rhs,
-			Binary::SHR => lhs >> rhs,
+			// Shifting by 32 bits or more shifts out all bits
+			Binary::SHL => lhs.checked_shl(rhs).unwrap_or(0),
+			Binary::SHR => lhs.checked_shr(rhs).unwrap_or(0),
 			Binary::XOR => lhs ^ rhs,
 			Binary::EQ => {
 				if lhs == rhs {
@@ -249,7 +262,7 @@ impl std::fmt::Display for Binary {
 }
 
 #[allow(dead_code, non_camel_case_types)]
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum UserCommand {
 	GET_LENGTH = 0,
 	GET_WALL_TIME = 1,
@@ -258,6 +271,9 @@ pub enum UserCommand {
 	BLIT = 4,
 	RANDOM_INT = 5,
 	GET_PIXEL = 6,
+	SELECT_STRIP = 7,
+	CLEAR = 8,
+	GET_FRAME = 9,
 }
 
 impl UserCommand {
@@ -270,14 +286,43 @@ impl UserCommand {
 			4 => Some(UserCommand::BLIT),
 			5 => Some(UserCommand::RANDOM_INT),
 			6 => Some(UserCommand::GET_PIXEL),
+			7 => Some(UserCommand::SELECT_STRIP),
+			8 => Some(UserCommand::CLEAR),
+			9 => Some(UserCommand::GET_FRAME),
 			_ => None,
 		}
 	}
+
+	/* Name of the command in scripts (and disassembly) */
+	pub fn name(self) -> &'static str {
+		match self {
+			UserCommand::GET_LENGTH => "get_length",
+			UserCommand::GET_WALL_TIME => "get_wall_time",
+			UserCommand::GET_PRECISE_TIME => "get_precise_time",
+			UserCommand::SET_PIXEL => "set_pixel",
+			UserCommand::BLIT => "blit",
+			UserCommand::RANDOM_INT => "random_int",
+			UserCommand::GET_PIXEL => "get_pixel",
+			UserCommand::SELECT_STRIP => "select_strip",
+			UserCommand::CLEAR => "clear",
+			UserCommand::GET_FRAME => "get_frame",
+		}
+	}
+}
+
+impl std::fmt::Display for UserCommand {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
 }
 
 impl Special {
 	pub fn from(code: u8) -> Option<Special> {
 		match code {
+			8 => Some(Special::ASSERT),
+			9 => Some(Special::HALT),
+			10 => Some(Special::SLEEP),
+			11 => Some(Special::PRINT),
 			12 => Some(Special::SWAP),
 			13 => Some(Special::DUMP),
 			14 => Some(Special::YIELD),
@@ -285,4 +330,263 @@ impl Special {
 			_ => None,
 		}
 	}
+
+	/* Name of the instruction in scripts (and disassembly) */
+	pub fn name(self) -> &'static str {
+		match self {
+			Special::ASSERT => "assert",
+			Special::HALT => "halt",
+			Special::SLEEP => "sleep",
+			Special::PRINT => "print",
+			Special::SWAP => "swap",
+			Special::DUMP => "dump",
+			Special::YIELD => "yield",
+			Special::TWOBYTE => "two-byte instruction",
+		}
+	}
+}
+
+impl std::fmt::Display for Special {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+/* The operand of a jump instruction: an absolute address, or an offset relative to the address of the jump */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Jump {
+	Absolute(u16),
+	Relative(i16),
+}
+
+impl Jump {
+	/* The address jumped to by a jump located at `address`, or None when a relative jump lands before the start */
+	pub fn target(&self, address: usize) -> Option<usize> {
+		match self {
+			Jump::Absolute(target) => Some(usize::from(*target)),
+			Jump::Relative(offset) => {
+				let target = address as i64 + i64::from(*offset);
+				if target < 0 {
+					None
+				} else {
+					Some(target as usize)
+				}
+			}
+		}
+	}
+}
+
+/* A decoded instruction together with its operands. Operands that are encoded in the postfix (e.g. the number of
+items to pop) must be at most 15, and PUSHB and PUSHI can push at most 15 values. An empty PushB pushes a zero. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+	Pop(u8),
+	PushB(Vec<u8>),
+	Peek(u8),
+	PushI(Vec<u32>),
+	Jmp(Jump),
+	Jz(Jump),
+	Jnz(Jump),
+	Unary(Unary),
+	Binary(Binary),
+	Poke(u8),
+	User(UserCommand),
+	Special(Special),
+}
+
+impl Instruction {
+	/* Decodes the instruction at `pc` and returns it together with its length in bytes. Returns None when the
+	instruction is unknown or its operands overrun the code. */
+	pub fn decode(code: &[u8], pc: usize) -> Option<(Instruction, usize)> {
+		let opcode = *code.get(pc)?;
+		let postfix = opcode & 0x0F;
+		let operands = |length: usize| code.get((pc + 1)..(pc + 1 + length));
+
+		let instruction = match Prefix::from(opcode)? {
+			Prefix::POP => Instruction::Pop(postfix),
+			Prefix::PUSHB => Instruction::PushB(operands(postfix as usize)?.to_vec()),
+			Prefix::PEEK => Instruction::Peek(postfix),
+			Prefix::PUSHI => Instruction::PushI(
+				operands(postfix as usize * 4)?
+					.chunks(4)
+					.map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+					.collect(),
+			),
+			Prefix::JMP | Prefix::JZ | Prefix::JNZ => {
+				let operand = operands(2)?;
+				let jump = match postfix {
+					0 => Jump::Absolute(u16::from_le_bytes([operand[0], operand[1]])),
+					JUMP_RELATIVE => Jump::Relative(i16::from_le_bytes([operand[0], operand[1]])),
+					_ => return None,
+				};
+				match Prefix::from(opcode)? {
+					Prefix::JMP => Instruction::Jmp(jump),
+					Prefix::JZ => Instruction::Jz(jump),
+					_ => Instruction::Jnz(jump),
+				}
+			}
+			Prefix::UNARY => Instruction::Unary(Unary::from(postfix)?),
+			Prefix::BINARY => Instruction::Binary(Binary::from(postfix)?),
+			Prefix::POKE => Instruction::Poke(postfix),
+			Prefix::USER => Instruction::User(UserCommand::from(postfix)?),
+			Prefix::SPECIAL => Instruction::Special(Special::from(postfix)?),
+		};
+		let length = instruction.byte_len();
+		Some((instruction, length))
+	}
+
+	/* The operand of a JMP, JZ or JNZ instruction */
+	pub fn jump(&self) -> Option<&Jump> {
+		match self {
+			Instruction::Jmp(jump) | Instruction::Jz(jump) | Instruction::Jnz(jump) => Some(jump),
+			_ => None,
+		}
+	}
+
+	/* Returns the length of the encoded instruction in bytes */
+	pub fn byte_len(&self) -> usize {
+		match self {
+			Instruction::PushB(values) => 1 + values.len(),
+			Instruction::PushI(values) => 1 + values.len() * 4,
+			Instruction::Jmp(_) | Instruction::Jz(_) | Instruction::Jnz(_) => 3,
+			_ => 1,
+		}
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let postfix = |n: usize| {
+			assert!(
+				n <= 0x0F,
+				"operand {} does not fit in an instruction postfix",
+				n
+			);
+			n as u8
+		};
+		let jump = |prefix: Prefix, jump: &Jump| {
+			let (flag, operand) = match jump {
+				Jump::Absolute(address) => (0, address.to_le_bytes()),
+				Jump::Relative(offset) => (JUMP_RELATIVE, offset.to_le_bytes()),
+			};
+			vec![prefix as u8 | flag, operand[0], operand[1]]
+		};
+
+		match self {
+			Instruction::Pop(n) => vec![Prefix::POP as u8 | postfix(*n as usize)],
+			Instruction::PushB(values) => {
+				let mut code = vec![Prefix::PUSHB as u8 | postfix(values.len())];
+				code.extend_from_slice(values);
+				code
+			}
+			Instruction::Peek(n) => vec![Prefix::PEEK as u8 | postfix(*n as usize)],
+			Instruction::PushI(values) => {
+				let mut code = vec![Prefix::PUSHI as u8 | postfix(values.len())];
+				for value in values.iter() {
+					code.extend_from_slice(&value.to_le_bytes());
+				}
+				code
+			}
+			Instruction::Jmp(j) => jump(Prefix::JMP, j),
+			Instruction::Jz(j) => jump(Prefix::JZ, j),
+			Instruction::Jnz(j) => jump(Prefix::JNZ, j),
+			Instruction::Unary(op) => vec![Prefix::UNARY as u8 | *op as u8],
+			Instruction::Binary(op) => vec![Prefix::BINARY as u8 | *op as u8],
+			Instruction::Poke(n) => vec![Prefix::POKE as u8 | postfix(*n as usize)],
+			Instruction::User(command) => vec![Prefix::USER as u8 | *command as u8],
+			Instruction::Special(special) => vec![Prefix::SPECIAL as u8 | *special as u8],
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn user_command_round_trip() {
+		let commands = [
+			UserCommand::GET_LENGTH,
+			UserCommand::GET_WALL_TIME,
+			UserCommand::GET_PRECISE_TIME,
+			UserCommand::SET_PIXEL,
+			UserCommand::BLIT,
+			UserCommand::RANDOM_INT,
+			UserCommand::GET_PIXEL,
+			UserCommand::SELECT_STRIP,
+			UserCommand::CLEAR,
+			UserCommand::GET_FRAME,
+		];
+		for command in commands.iter() {
+			assert_eq!(UserCommand::from(*command as u8), Some(*command));
+		}
+
+		// Every code that maps to a command is listed above
+		let known = (0..=0x0F).filter_map(UserCommand::from).count();
+		assert_eq!(known, commands.len());
+		assert_eq!(UserCommand::GET_PIXEL.to_string(), "get_pixel");
+	}
+
+	#[test]
+	fn special_round_trip() {
+		let specials = [
+			Special::ASSERT,
+			Special::HALT,
+			Special::SLEEP,
+			Special::PRINT,
+			Special::SWAP,
+			Special::DUMP,
+			Special::YIELD,
+			Special::TWOBYTE,
+		];
+		for special in specials.iter() {
+			assert_eq!(Special::from(*special as u8), Some(*special));
+		}
+
+		let known = (0..=0x0F).filter_map(Special::from).count();
+		assert_eq!(known, specials.len());
+		assert_eq!(Special::YIELD.to_string(), "yield");
+	}
+
+	#[test]
+	fn instruction_round_trip() {
+		let instructions = [
+			Instruction::Pop(3),
+			Instruction::PushB(vec![]),
+			Instruction::PushB(vec![1, 255]),
+			Instruction::Peek(15),
+			Instruction::PushI(vec![0x0102_0304, 0xFFFF_FFFF]),
+			Instruction::Jmp(Jump::Absolute(0x1234)),
+			Instruction::Jz(Jump::Relative(-6)),
+			Instruction::Jnz(Jump::Relative(300)),
+			Instruction::Unary(Unary::SHR8),
+			Instruction::Binary(Binary::NEQ),
+			Instruction::Poke(1),
+			Instruction::User(UserCommand::SET_PIXEL),
+			Instruction::Special(Special::YIELD),
+		];
+		let code: Vec<u8> = instructions.iter().flat_map(|i| i.encode()).collect();
+		assert_eq!(&code[0..3], &[0x03, 0x10, 0x12]);
+
+		let mut pc = 0;
+		for instruction in instructions.iter() {
+			let (decoded, length) = Instruction::decode(&code, pc).unwrap();
+			assert_eq!(decoded, *instruction);
+			assert_eq!(length, instruction.encode().len());
+			pc += length;
+		}
+		assert_eq!(pc, code.len());
+		assert_eq!(Instruction::decode(&code, pc), None);
+
+		// Unknown instructions and operands that overrun the code are not decoded
+		assert_eq!(Instruction::decode(&[0xA0], 0), None);
+		assert_eq!(Instruction::decode(&[0xEF], 0), None);
+		assert_eq!(Instruction::decode(&[0x31, 0x01, 0x02], 0), None);
+		assert_eq!(Instruction::decode(&[0x40, 0x00], 0), None);
+
+		let (jump, _) = Instruction::decode(&[0x41, 0xFA, 0xFF], 0).unwrap();
+		assert_eq!(jump.jump(), Some(&Jump::Relative(-6)));
+		assert_eq!(Jump::Relative(-6).target(10), Some(4));
+		assert_eq!(Jump::Relative(-6).target(5), None);
+		assert_eq!(Jump::Absolute(0x1234).target(10), Some(0x1234));
+		assert_eq!(Instruction::Pop(1).jump(), None);
+	}
 }
@@ -4,21 +4,31 @@ extern crate rand;
 
 pub mod instructions;
 
+#[macro_use]
+pub mod assembler;
+
 pub mod program;
 pub use program::*;
 
-#[cfg(feature = "client")]
+#[cfg(any(feature = "client", feature = "server"))]
 pub mod protocol;
 
-#[cfg(feature = "client")]
+#[cfg(any(feature = "client", feature = "server"))]
 pub use protocol::*;
 
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod transport;
+
 pub mod parser;
 pub use parser::*;
 
 pub mod vm;
 pub use vm::*;
 
+pub mod debugger;
+
+pub mod repl;
+
 pub mod ast;
 pub use ast::*;
 
@@ -39,3 +49,6 @@ pub use client::*;
 
 #[cfg(feature = "api")]
 pub mod api;
+
+#[cfg(feature = "watch")]
+pub mod watch;
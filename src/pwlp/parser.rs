@@ -1,15 +1,15 @@
 use nom::{
 	branch::alt,
-	bytes::complete::{is_not, tag, take_while, take_while1},
-	combinator::{map, map_res, opt},
-	multi::{fold_many0, separated_list},
+	bytes::complete::{is_not, tag, take_while, take_while1, take_while_m_n},
+	combinator::{map, map_res, not, opt},
+	multi::{fold_many0, many0, many1, separated_list},
 	sequence::{delimited, pair, preceded, terminated, tuple},
 	IResult,
 };
 
-use super::ast::{Expression, Intrinsic, Node, Scope};
+use super::ast::{Expression, Intrinsic, Logical, Node};
 use super::instructions;
-use super::program::Program;
+use std::path::{Path, PathBuf};
 
 fn from_hex(input: &str) -> Result<u32, std::num::ParseIntError> {
 	u32::from_str_radix(input, 16)
@@ -64,8 +64,19 @@ fn hex_literal(input: &str) -> IResult<&str, u32> {
 	Ok((input, num))
 }
 
+/* #RRGGBB, packed as 0x00BBGGRR (the layout used by get_pixel and the red/green/blue intrinsics) */
+fn color_literal(input: &str) -> IResult<&str, u32> {
+	let (input, _) = tag("#")(input)?;
+	let (input, rgb) = map_res(take_while_m_n(6, 6, is_hex_digit), from_hex)(input)?;
+	let (input, _) = not(take_while1(is_hex_digit))(input)?;
+	let r = (rgb >> 16) & 0xFF;
+	let g = (rgb >> 8) & 0xFF;
+	let b = rgb & 0xFF;
+	Ok((input, r | (g << 8) | (b << 16)))
+}
+
 fn literal(input: &str) -> IResult<&str, Expression> {
-	let (input, res) = alt((hex_literal, dec_number))(input)?;
+	let (input, res) = alt((hex_literal, color_literal, dec_number))(input)?;
 	Ok((input, Expression::Literal(res)))
 }
 
@@ -86,6 +97,24 @@ fn term(input: &str) -> IResult<&str, Expression> {
 	))(input)
 }
 
+/* Equality binds less tightly than the relational operators (as in C), so 1 < 2 == 1 means (1 < 2) == 1 */
+fn equality(input: &str) -> IResult<&str, Expression> {
+	let (input, init) = comparison(input)?;
+
+	fold_many0(
+		pair(
+			preceded(sp, terminated(alt((tag("=="), tag("!="))), sp)),
+			comparison,
+		),
+		init,
+		|acc, (op, val): (&str, Expression)| match op {
+			"==" => Expression::Binary(Box::new(acc), instructions::Binary::EQ, Box::new(val)),
+			"!=" => Expression::Binary(Box::new(acc), instructions::Binary::NEQ, Box::new(val)),
+			_ => unreachable!(),
+		},
+	)(input)
+}
+
 fn comparison(input: &str) -> IResult<&str, Expression> {
 	let (input, init) = unaries(input)?;
 
@@ -93,17 +122,7 @@ fn comparison(input: &str) -> IResult<&str, Expression> {
 		pair(
 			preceded(
 				sp,
-				terminated(
-					alt((
-						tag(">="),
-						tag("<="),
-						tag(">"),
-						tag("<"),
-						tag("=="),
-						tag("!="),
-					)),
-					sp,
-				),
+				terminated(alt((tag(">="), tag("<="), tag(">"), tag("<"))), sp),
 			),
 			unaries,
 		),
@@ -113,8 +132,6 @@ fn comparison(input: &str) -> IResult<&str, Expression> {
 			"<=" => Expression::Binary(Box::new(acc), instructions::Binary::LTE, Box::new(val)),
 			">" => Expression::Binary(Box::new(acc), instructions::Binary::GT, Box::new(val)),
 			"<" => Expression::Binary(Box::new(acc), instructions::Binary::LT, Box::new(val)),
-			"==" => Expression::Binary(Box::new(acc), instructions::Binary::EQ, Box::new(val)),
-			"!=" => Expression::Binary(Box::new(acc), instructions::Binary::NEQ, Box::new(val)),
 			_ => unreachable!(),
 		},
 	)(input)
@@ -195,41 +212,35 @@ fn multiplication(input: &str) -> IResult<&str, Expression> {
 			"*" => Expression::Binary(Box::new(acc), instructions::Binary::MUL, Box::new(val)),
 			"/" => Expression::Binary(Box::new(acc), instructions::Binary::DIV, Box::new(val)),
 			"%" => Expression::Binary(Box::new(acc), instructions::Binary::MOD, Box::new(val)),
-			"<<" | ">>" => {
-				let binary_op = match op {
-					"<<" => instructions::Binary::SHL,
-					">>" => instructions::Binary::SHR,
-					_ => unreachable!(),
-				};
-
-				if let Expression::Literal(n) = val {
-					let unary = match op {
-						"<<" => instructions::Unary::SHL8,
-						">>" => instructions::Unary::SHR8,
-						_ => unreachable!(),
-					};
-
-					if (n % 8) == 0 {
-						let times = n / 8;
-						let mut expr = acc;
-						for _ in 0..times {
-							expr = Expression::Unary(unary, Box::new(expr))
-						}
-						expr
-					} else {
-						Expression::Binary(Box::new(acc), binary_op, Box::new(val))
-					}
-				} else {
-					Expression::Binary(Box::new(acc), binary_op, Box::new(val))
-				}
-			}
+			"<<" => Expression::binary(acc, instructions::Binary::SHL, val),
+			">>" => Expression::binary(acc, instructions::Binary::SHR, val),
 			_ => unreachable!(),
 		},
 	)(input)
 }
 
+fn logical_and(input: &str) -> IResult<&str, Expression> {
+	let (input, init) = equality(input)?;
+
+	fold_many0(
+		preceded(preceded(sp, terminated(tag("&&"), sp)), equality),
+		init,
+		|acc, val: Expression| Expression::Logical(Box::new(acc), Logical::And, Box::new(val)),
+	)(input)
+}
+
+fn logical_or(input: &str) -> IResult<&str, Expression> {
+	let (input, init) = logical_and(input)?;
+
+	fold_many0(
+		preceded(preceded(sp, terminated(tag("||"), sp)), logical_and),
+		init,
+		|acc, val: Expression| Expression::Logical(Box::new(acc), Logical::Or, Box::new(val)),
+	)(input)
+}
+
 fn expression(input: &str) -> IResult<&str, Expression> {
-	comparison(input)
+	logical_or(input)
 }
 
 fn expression_statement(input: &str) -> IResult<&str, Node> {
@@ -238,16 +249,63 @@ fn expression_statement(input: &str) -> IResult<&str, Node> {
 
 fn special_statement(input: &str) -> IResult<&str, Node> {
 	alt((
+		// yield n
+		map(tuple((tag("yield"), sp, expression)), |t| Node::Yield(t.2)),
 		map(tag("yield"), |_| {
 			Node::Special(instructions::Special::YIELD)
 		}),
 		map(tag("dump"), |_| Node::Special(instructions::Special::DUMP)),
+		map(
+			terminated(
+				tag("nop"),
+				not(take_while1(|c: char| c.is_alphanumeric() || c == '_')),
+			),
+			|_| Node::Nop,
+		),
+		map(
+			terminated(
+				tag("halt"),
+				not(take_while1(|c: char| c.is_alphanumeric() || c == '_')),
+			),
+			|_| Node::Special(instructions::Special::HALT),
+		),
+		map(
+			tuple((
+				tag("print("),
+				preceded(sp, terminated(expression, sp)),
+				tag(")"),
+			)),
+			|t| Node::Print(t.1),
+		),
+		map(
+			tuple((
+				tag("assert("),
+				preceded(sp, terminated(expression, sp)),
+				tag(")"),
+			)),
+			|t| Node::Assert(t.1),
+		),
+		map(
+			tuple((
+				tag("sleep("),
+				preceded(sp, terminated(expression, sp)),
+				tag(")"),
+			)),
+			|t| Node::Sleep(t.1),
+		),
 	))(input)
 }
 
 fn user_statement(input: &str) -> IResult<&str, Node> {
 	alt((
 		map(tag("blit"), |_| Node::User(instructions::UserCommand::BLIT)),
+		map(
+			terminated(
+				tag("clear"),
+				not(take_while1(|c: char| c.is_alphanumeric() || c == '_')),
+			),
+			|_| Node::User(instructions::UserCommand::CLEAR),
+		),
 		// set_pixel(i, r, g, b)
 		map(
 			tuple((
@@ -268,6 +326,15 @@ fn user_statement(input: &str) -> IResult<&str, Node> {
 				)
 			},
 		),
+		// select_strip(i)
+		map(
+			tuple((
+				tag("select_strip("),
+				preceded(sp, terminated(expression, sp)),
+				tag(")"),
+			)),
+			|t| Node::UserCall(instructions::UserCommand::SELECT_STRIP, vec![t.1]),
+		),
 	))(input)
 }
 
@@ -279,17 +346,21 @@ fn user_expression(input: &str) -> IResult<&str, Expression> {
 		map(tuple((tag("get_pixel("), expression, tag(")"))), |t| {
 			Expression::UserCall(instructions::UserCommand::GET_PIXEL, vec![t.1])
 		}),
-		map(tag("get_length"), |_| {
+		// Functions without arguments may be called with or without empty parentheses
+		map(pair(tag("get_length"), opt(tag("()"))), |_| {
 			Expression::User(instructions::UserCommand::GET_LENGTH)
 		}),
-		map(tag("get_wall_time"), |_| {
+		map(pair(tag("get_wall_time"), opt(tag("()"))), |_| {
 			Expression::User(instructions::UserCommand::GET_WALL_TIME)
 		}),
-		map(tag("get_precise_time"), |_| {
+		map(pair(tag("get_precise_time"), opt(tag("()"))), |_| {
 			Expression::User(instructions::UserCommand::GET_PRECISE_TIME)
 		}),
+		map(pair(tag("get_frame"), opt(tag("()"))), |_| {
+			Expression::User(instructions::UserCommand::GET_FRAME)
+		}),
 		/* Compiler intrinsics: 'functions' that simply compile to an expression  */
-		// rgb(r, g, b) => color value (0xBBGGRRII)
+		// rgb(r, g, b) => color value (0x00BBGGRR)
 		map(
 			tuple((
 				tag("rgb("),
@@ -300,33 +371,25 @@ fn user_expression(input: &str) -> IResult<&str, Expression> {
 				preceded(sp, terminated(expression, sp)),
 				tag(")"),
 			)),
+			|t| Expression::rgb(t.1, t.3, t.5),
+		),
+		// lerp_color(from, to, t) => color between from (t = 0) and to (t = 255)
+		map(
+			tuple((
+				tag("lerp_color("),
+				preceded(sp, terminated(expression, sp)),
+				tag(","),
+				preceded(sp, terminated(expression, sp)),
+				tag(","),
+				preceded(sp, terminated(expression, sp)),
+				tag(")"),
+			)),
 			|t| {
-				// (r & 0xFF) | (g & 0xFF) << 8 | (b & 0xFF) << 16
-				let vals = vec![t.3, t.5];
-				let mut shift: u32 = 8;
-				let mut root: Box<Expression> = Box::new(Expression::Binary(
+				Expression::Intrinsic(Intrinsic::LerpColor(
 					Box::new(t.1),
-					instructions::Binary::AND,
-					Box::new(Expression::Literal(0xFF)),
-				));
-
-				for val in vals {
-					root = Box::new(Expression::Binary(
-						root,
-						instructions::Binary::OR,
-						Box::new(Expression::Binary(
-							Box::new(Expression::Binary(
-								Box::new(val),
-								instructions::Binary::AND,
-								Box::new(Expression::Literal(0xFF)),
-							)),
-							instructions::Binary::SHL,
-							Box::new(Expression::Literal(shift)),
-						)),
-					));
-					shift += 8;
-				}
-				*root
+					Box::new(t.3),
+					Box::new(t.5),
+				))
 			},
 		),
 		// clamp(value, min, max):
@@ -348,38 +411,97 @@ fn user_expression(input: &str) -> IResult<&str, Expression> {
 				))
 			},
 		),
-		//red(color)
+		// signed_lt(a, b), signed_lte(a, b), signed_gt(a, b), signed_gte(a, b): compare a and b as signed (two's
+		// complement) numbers. Flipping the sign bit maps signed order onto unsigned order, so these translate to e.g.
+		// (a ^ 0x80000000) < (b ^ 0x80000000)
+		map(
+			tuple((
+				alt((
+					tag("signed_lt("),
+					tag("signed_lte("),
+					tag("signed_gt("),
+					tag("signed_gte("),
+				)),
+				preceded(sp, terminated(expression, sp)),
+				tag(","),
+				preceded(sp, terminated(expression, sp)),
+				tag(")"),
+			)),
+			|t| {
+				let op = match t.0 {
+					"signed_lt(" => instructions::Binary::LT,
+					"signed_lte(" => instructions::Binary::LTE,
+					"signed_gt(" => instructions::Binary::GT,
+					"signed_gte(" => instructions::Binary::GTE,
+					_ => unreachable!(),
+				};
+				let flip_sign = |e: Expression| {
+					Box::new(Expression::Binary(
+						Box::new(e),
+						instructions::Binary::XOR,
+						Box::new(Expression::Literal(0x8000_0000)),
+					))
+				};
+				Expression::Binary(flip_sign(t.1), op, flip_sign(t.3))
+			},
+		),
+		// wrap_index(i) => i % get_length
+		map(tuple((tag("wrap_index("), expression, tag(")"))), |t| {
+			Expression::Intrinsic(Intrinsic::WrapIndex(Box::new(t.1)))
+		}),
+		// sat_add(a, b, limit), sat_sub(a, b, limit)
+		map(
+			tuple((
+				alt((tag("sat_add("), tag("sat_sub("))),
+				preceded(sp, terminated(expression, sp)),
+				tag(","),
+				preceded(sp, terminated(expression, sp)),
+				tag(","),
+				preceded(sp, terminated(expression, sp)),
+				tag(")"),
+			)),
+			|t| {
+				let (a, b, limit) = (Box::new(t.1), Box::new(t.3), Box::new(t.5));
+				Expression::Intrinsic(match t.0 {
+					"sat_add(" => Intrinsic::SaturatingAdd(a, b, limit),
+					_ => Intrinsic::SaturatingSub(a, b, limit),
+				})
+			},
+		),
+		// red(color), green(color), blue(color)
 		map(tuple((tag("red("), expression, tag(")"))), |t| {
-			// x 0xFF
-			Expression::Binary(
-				Box::new(t.1),
-				instructions::Binary::AND,
-				Box::new(Expression::Literal(0xFF)),
-			)
+			Expression::channel(t.1, 0)
 		}),
 		map(tuple((tag("green("), expression, tag(")"))), |t| {
-			// (x >> 8) & 0xFF
-			Expression::Binary(
-				Box::new(Expression::Unary(instructions::Unary::SHR8, Box::new(t.1))),
-				instructions::Binary::AND,
-				Box::new(Expression::Literal(0xFF)),
-			)
+			Expression::channel(t.1, 8)
 		}),
 		map(tuple((tag("blue("), expression, tag(")"))), |t| {
-			// (x >> 16) & 0xFF
-			Expression::Binary(
-				Box::new(Expression::Unary(
-					instructions::Unary::SHR8,
-					Box::new(Expression::Unary(instructions::Unary::SHR8, Box::new(t.1))),
-				)),
-				instructions::Binary::AND,
-				Box::new(Expression::Literal(0xFF)),
-			)
+			Expression::channel(t.1, 16)
 		}),
 	))(input)
 }
 
-fn if_statement(input: &str) -> IResult<&str, Node> {
+fn else_branch<'a>(source: &str, input: &'a str) -> IResult<&'a str, Vec<Node>> {
+	preceded(
+		pair(tag("else"), sp),
+		alt((
+			// else if(...) { ... } is desugared to else { if(...) { ... } }
+			map(|i| if_statement(source, i), |n| vec![n]),
+			map(
+				tuple((tag("{"), sp, |i| program(source, i), sp, tag("}"), sp)),
+				|t| {
+					if let Node::Statements(else_statements) = t.2 {
+						else_statements
+					} else {
+						unreachable!()
+					}
+				},
+			),
+		)),
+	)(input)
+}
+
+fn if_statement<'a>(source: &str, input: &'a str) -> IResult<&'a str, Node> {
 	map(
 		tuple((
 			tag("if("),
@@ -388,20 +510,16 @@ fn if_statement(input: &str) -> IResult<&str, Node> {
 			sp,
 			tag("{"),
 			sp,
-			program,
+			|i| program(source, i),
 			sp,
 			tag("}"),
 			sp,
-			opt(tuple((tag("else {"), sp, program, sp, tag("}"), sp))),
+			opt(|i| else_branch(source, i)),
 		)),
 		|t| {
 			if let Node::Statements(if_statements) = t.6 {
-				if let Some(else_tuple) = t.10 {
-					if let Node::Statements(else_statements) = else_tuple.2 {
-						Node::IfElse(t.1, if_statements, else_statements)
-					} else {
-						unreachable!()
-					}
+				if let Some(else_statements) = t.10 {
+					Node::IfElse(t.1, if_statements, else_statements)
 				} else {
 					Node::If(t.1, if_statements)
 				}
@@ -412,9 +530,16 @@ fn if_statement(input: &str) -> IResult<&str, Node> {
 	)(input)
 }
 
-fn loop_statement(input: &str) -> IResult<&str, Node> {
+fn loop_statement<'a>(source: &str, input: &'a str) -> IResult<&'a str, Node> {
 	map(
-		tuple((tag("loop"), sp, tag("{"), sp, program, tag("}"))),
+		tuple((
+			tag("loop"),
+			sp,
+			tag("{"),
+			sp,
+			|i| program(source, i),
+			tag("}"),
+		)),
 		|t| {
 			if let Node::Statements(ss) = t.4 {
 				Node::Loop(ss)
@@ -437,7 +562,46 @@ fn single_line_comment(input: &str) -> IResult<&str, &str> {
 	delimited(tag("//"), is_not("\n"), tag("\n"))(input)
 }
 
-fn for_statement(input: &str) -> IResult<&str, Node> {
+fn for_step_statement<'a>(source: &str, input: &'a str) -> IResult<&'a str, Node> {
+	map(
+		tuple((
+			tag("for("),
+			preceded(
+				sp,
+				terminated(
+					alt((assigment_statement, compound_assignment_statement)),
+					sp,
+				),
+			),
+			tag(";"),
+			preceded(sp, terminated(expression, sp)),
+			tag(";"),
+			preceded(
+				sp,
+				terminated(
+					alt((compound_assignment_statement, assigment_statement)),
+					sp,
+				),
+			),
+			tag(")"),
+			sp,
+			tag("{"),
+			sp,
+			|i| program(source, i),
+			sp,
+			tag("}"),
+		)),
+		|t| {
+			if let Node::Statements(ss) = t.10 {
+				Node::ForStep(Box::new(t.1), t.3, Box::new(t.5), ss)
+			} else {
+				unreachable!()
+			}
+		},
+	)(input)
+}
+
+fn for_statement<'a>(source: &str, input: &'a str) -> IResult<&'a str, Node> {
 	map(
 		tuple((
 			tag("for("),
@@ -448,7 +612,7 @@ fn for_statement(input: &str) -> IResult<&str, Node> {
 			sp,
 			tag("{"),
 			sp,
-			program,
+			|i| program(source, i),
 			sp,
 			tag("}"),
 		)),
@@ -473,66 +637,401 @@ fn assigment_statement(input: &str) -> IResult<&str, Node> {
 	)(input)
 }
 
-fn statement(input: &str) -> IResult<&str, Node> {
+fn compound_assignment_statement(input: &str) -> IResult<&str, Node> {
+	map(
+		tuple((
+			variable_name,
+			preceded(
+				sp,
+				terminated(
+					alt((
+						tag("+="),
+						tag("-="),
+						tag("*="),
+						tag("/="),
+						tag("%="),
+						tag("&="),
+						tag("|="),
+						tag("^="),
+						tag("<<="),
+						tag(">>="),
+					)),
+					sp,
+				),
+			),
+			expression,
+		)),
+		|t| {
+			let op = match t.1 {
+				"+=" => instructions::Binary::ADD,
+				"-=" => instructions::Binary::SUB,
+				"*=" => instructions::Binary::MUL,
+				"/=" => instructions::Binary::DIV,
+				"%=" => instructions::Binary::MOD,
+				"&=" => instructions::Binary::AND,
+				"|=" => instructions::Binary::OR,
+				"^=" => instructions::Binary::XOR,
+				"<<=" => instructions::Binary::SHL,
+				">>=" => instructions::Binary::SHR,
+				_ => unreachable!(),
+			};
+			Node::CompoundAssignment(t.0.to_string(), op, t.2)
+		},
+	)(input)
+}
+
+/* The (1-based) line number in `source` at which `input` (the part of `source` that is left to parse) starts */
+fn line_number(source: &str, input: &str) -> usize {
+	source[..(source.len() - input.len())].matches('\n').count() + 1
+}
+
+/* Statements are wrapped in Node::Line, which holds the line of `source` at which the statement starts */
+fn statement<'a>(source: &str, input: &'a str) -> IResult<&'a str, Node> {
+	let (input, _) = sp(input)?;
+	let line = line_number(source, input);
 	terminated(
-		preceded(
-			sp,
+		map(
 			alt((
 				user_statement,
 				special_statement,
 				assigment_statement,
-				if_statement,
-				for_statement,
-				loop_statement,
+				compound_assignment_statement,
+				|i| if_statement(source, i),
+				|i| for_step_statement(source, i),
+				|i| for_statement(source, i),
+				|i| loop_statement(source, i),
 				expression_statement,
 			)),
+			move |node| Node::Line(line, Box::new(node)),
 		),
 		sp,
 	)(input)
 }
 
-fn program(input: &str) -> IResult<&str, Node> {
+/* Statements are separated by one or more semicolons (empty statements are ignored) */
+fn program<'a>(source: &str, input: &'a str) -> IResult<&'a str, Node> {
 	terminated(
 		terminated(
 			terminated(
 				map(
-					separated_list(preceded(sp, tag(";")), preceded(sp, statement)),
+					separated_list(
+						many1(preceded(sp, tag(";"))),
+						preceded(sp, |i| statement(source, i)),
+					),
 					Node::Statements,
 				),
 				sp,
 			),
-			opt(tag(";")),
+			many0(preceded(sp, tag(";"))),
 		),
 		sp,
 	)(input)
 }
 
-impl Program {
-	pub fn from_source(source: &str) -> Result<Program, String> {
-		match program(source) {
-			Ok((remainder, n)) => {
-				if remainder != "" {
-					let err_string = format!("Could not parse, remainder: {}", remainder);
-					Err(err_string)
-				} else {
-					let mut p = Program::new();
-					let mut scope = Scope::new();
-					n.assemble(&mut p, &mut scope);
-					scope.assemble_teardown(&mut p);
-					Ok(p)
-				}
+pub fn parse(source: &str) -> Result<Node, String> {
+	match program(source, source) {
+		Ok((remainder, n)) => {
+			if remainder != "" {
+				Err(format!("Could not parse, remainder: {}", remainder))
+			} else {
+				Ok(n)
 			}
-			Err(x) => {
-				let err_string = format!("Parser error: {:?}", x);
-				Err(err_string)
+		}
+		Err(x) => Err(format!("Parser error: {:?}", x)),
+	}
+}
+
+/* Parses a single expression (e.g. `1 + 2 * 3`) */
+pub fn parse_expression(source: &str) -> Result<Expression, String> {
+	match delimited(sp, expression, sp)(source) {
+		Ok(("", e)) => Ok(e),
+		Ok((remainder, _)) => Err(format!("Could not parse, remainder: {}", remainder)),
+		Err(x) => Err(format!("Parser error: {:?}", x)),
+	}
+}
+
+/* Reads and parses a source file, including the files it references (see `read_source`) */
+pub fn parse_with_includes(path: &Path) -> Result<Node, String> {
+	parse(&read_source(path)?)
+}
+
+/* Reads a source file and replaces each `include "path"` directive with the contents of the referenced file. The
+directive must be on a line of its own (optionally followed by a semicolon). Paths are relative to the including
+file. */
+pub fn read_source(path: &Path) -> Result<String, String> {
+	read_source_in(path, &mut vec![])
+}
+
+/* Replaces `include "path"` directives in the source with the contents of the referenced files. Paths are relative
+to `directory`. */
+pub fn resolve_includes(source: &str, directory: &Path) -> Result<String, String> {
+	resolve_includes_in(source, directory, &mut vec![])
+}
+
+fn read_source_in(path: &Path, including: &mut Vec<PathBuf>) -> Result<String, String> {
+	let canonical = path
+		.canonicalize()
+		.map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+	if including.contains(&canonical) {
+		let cycle: Vec<String> = including
+			.iter()
+			.chain(std::iter::once(&canonical))
+			.map(|p| p.display().to_string())
+			.collect();
+		return Err(format!("include cycle: {}", cycle.join(" -> ")));
+	}
+
+	let source = std::fs::read_to_string(&canonical)
+		.map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+	let directory = canonical.parent().unwrap_or_else(|| Path::new("."));
+	including.push(canonical.clone());
+	let resolved = resolve_includes_in(&source, directory, including);
+	including.pop();
+	resolved
+}
+
+fn resolve_includes_in(
+	source: &str,
+	directory: &Path,
+	including: &mut Vec<PathBuf>,
+) -> Result<String, String> {
+	let mut resolved = String::new();
+	for line in source.split_inclusive('\n') {
+		let directive = line.trim();
+		let (directive, terminated) = match directive.strip_suffix(';') {
+			Some(d) => (d.trim_end(), true),
+			None => (directive, false),
+		};
+
+		let included = directive
+			.strip_prefix("include")
+			.map(|d| d.trim_start())
+			.filter(|d| d.len() >= 2 && d.starts_with('"') && d.ends_with('"'))
+			.map(|d| &d[1..(d.len() - 1)]);
+
+		match included {
+			Some(file) => {
+				let contents = read_source_in(&directory.join(file), including)?;
+
+				// The included code ends on a new line (it may end in a comment) and is terminated like the directive
+				resolved.push_str(contents.trim_end().trim_end_matches(';'));
+				resolved.push('\n');
+				if terminated {
+					resolved.push(';');
+				}
+				if line.ends_with('\n') {
+					resolved.push('\n');
+				}
 			}
+			None => resolved.push_str(line),
 		}
 	}
+	Ok(resolved)
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::pwlp::ast::Scope;
+	use crate::pwlp::program::Program;
+	use crate::pwlp::strip::Color;
+
+	#[test]
+	fn color_literal() {
+		assert_eq!(
+			expression("#FF0000"),
+			Ok(("", Expression::Literal(0x0000FF)))
+		);
+		assert_eq!(
+			expression("#00FF00"),
+			Ok(("", Expression::Literal(0x00FF00)))
+		);
+		// #00FF00 packs to the value set_pixel renders as green
+		assert_eq!(Color::from_packed(0x00FF00), Color::new(0, 0xFF, 0));
+		assert_eq!(
+			expression("#0000ff"),
+			Ok(("", Expression::Literal(0xFF0000)))
+		);
+		assert_eq!(
+			expression("#123456"),
+			Ok(("", Expression::Literal(0x563412)))
+		);
+		assert!(Program::from_source("a = #FFF").is_err());
+		assert!(Program::from_source("a = #FF00001").is_err());
+		assert!(Program::from_source("a = #GG0000").is_err());
+	}
+
+	#[test]
+	fn equality_precedence() {
+		let binary = |l, op, r| Expression::Binary(Box::new(l), op, Box::new(r));
+		assert_eq!(
+			expression("1 < 2 == 1"),
+			Ok((
+				"",
+				binary(
+					binary(
+						Expression::Literal(1),
+						instructions::Binary::LT,
+						Expression::Literal(2)
+					),
+					instructions::Binary::EQ,
+					Expression::Literal(1)
+				)
+			))
+		);
+		assert_eq!(
+			expression("1 != 2 >= 3"),
+			Ok((
+				"",
+				binary(
+					Expression::Literal(1),
+					instructions::Binary::NEQ,
+					binary(
+						Expression::Literal(2),
+						instructions::Binary::GTE,
+						Expression::Literal(3)
+					)
+				)
+			))
+		);
+	}
+
+	/* Creates an empty directory for include tests */
+	fn include_directory(name: &str) -> PathBuf {
+		let directory = std::env::temp_dir().join(format!("pwlp-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		std::fs::create_dir_all(directory.join("lib")).unwrap();
+		directory
+	}
+
+	#[test]
+	fn include() {
+		let directory = include_directory("include");
+		std::fs::write(
+			directory.join("lib").join("colors.txt"),
+			"red = 255;\ngreen = 128; // trailing comment\n",
+		)
+		.unwrap();
+		std::fs::write(
+			directory.join("main.txt"),
+			"include \"lib/colors.txt\";\nset_pixel(0, red, green, 0)\n",
+		)
+		.unwrap();
+
+		let source = read_source(&directory.join("main.txt")).unwrap();
+		assert_eq!(
+			source,
+			"red = 255;\ngreen = 128; // trailing comment\n;\nset_pixel(0, red, green, 0)\n"
+		);
+		assert_eq!(
+			Program::from_source(&source).unwrap().code,
+			Program::from_source("red = 255; green = 128; set_pixel(0, red, green, 0)")
+				.unwrap()
+				.code
+		);
+		assert!(parse_with_includes(&directory.join("main.txt")).is_ok());
+
+		// Missing files
+		std::fs::write(directory.join("missing.txt"), "include \"nothing.txt\"\n").unwrap();
+		assert!(read_source(&directory.join("missing.txt"))
+			.unwrap_err()
+			.starts_with("could not read"));
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn include_cycle() {
+		let directory = include_directory("include-cycle");
+		std::fs::write(directory.join("self.txt"), "include \"self.txt\"\nblit\n").unwrap();
+		assert!(read_source(&directory.join("self.txt"))
+			.unwrap_err()
+			.starts_with("include cycle"));
+
+		// Including the same file twice is not a cycle
+		std::fs::write(directory.join("lib").join("blit.txt"), "blit").unwrap();
+		std::fs::write(
+			directory.join("twice.txt"),
+			"include \"lib/blit.txt\";\ninclude \"lib/blit.txt\"\n",
+		)
+		.unwrap();
+		assert_eq!(
+			read_source(&directory.join("twice.txt")).unwrap(),
+			"blit\n;\nblit\n\n"
+		);
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn zero_argument_calls() {
+		let commands = [
+			("get_length", instructions::UserCommand::GET_LENGTH),
+			("get_wall_time", instructions::UserCommand::GET_WALL_TIME),
+			(
+				"get_precise_time",
+				instructions::UserCommand::GET_PRECISE_TIME,
+			),
+			("get_frame", instructions::UserCommand::GET_FRAME),
+		];
+		for (name, command) in commands.iter() {
+			let expected = Ok(("", Expression::User(*command)));
+			assert_eq!(expression(name), expected);
+			assert_eq!(expression(&format!("{}()", name)), expected);
+		}
+		assert_eq!(
+			Program::from_source("set_pixel(0, get_length() - 1, 0, 0)")
+				.unwrap()
+				.code,
+			Program::from_source("set_pixel(0, get_length - 1, 0, 0)")
+				.unwrap()
+				.code
+		);
+	}
+
+	#[test]
+	fn line_numbers() {
+		let line = |n: usize, node: Node| Node::Line(n, Box::new(node));
+		assert_eq!(
+			parse("// comment\nnop;\n\n  loop {\n\tnop; nop\n}; nop"),
+			Ok(Node::Statements(vec![
+				line(2, Node::Nop),
+				line(4, Node::Loop(vec![line(5, Node::Nop), line(5, Node::Nop)])),
+				line(6, Node::Nop),
+			]))
+		);
+	}
+
+	#[test]
+	fn nop() {
+		assert_eq!(
+			statement("nop", "nop"),
+			Ok(("", Node::Line(1, Box::new(Node::Nop))))
+		);
+		assert_eq!(
+			parse("nop; nope = 1"),
+			Ok(Node::Statements(vec![
+				Node::Line(1, Box::new(Node::Nop)),
+				Node::Line(
+					1,
+					Box::new(Node::Assignment("nope".to_string(), Expression::Literal(1)))
+				),
+			]))
+		);
+
+		// Assembles to POP 0, which is kept when the program is optimized
+		let mut program = Program::new();
+		let mut scope = Scope::new();
+		parse("nop").unwrap().assemble(&mut program, &mut scope);
+		assert_eq!(program.code, vec![0x00]);
+		assert_eq!(Program::from_source("nop").unwrap().code, vec![0x00]);
+		assert_eq!(
+			Program::new()
+				.with_optimization()
+				.compile("nop")
+				.unwrap()
+				.code,
+			vec![0x00]
+		);
+	}
 
 	#[test]
 	fn main() {
@@ -550,7 +1049,8 @@ mod tests {
 			))
 		);
 
-		if let Ok((remainder, n)) = program("loop{if(1+2*3>4){yield};\ndump}") {
+		let source = "loop{if(1+2*3>4){yield};\ndump}";
+		if let Ok((remainder, n)) = program(source, source) {
 			assert_eq!(remainder, "");
 			let mut program = Program::new();
 			let mut scope = Scope::new();
@@ -1,14 +1,82 @@
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::Path;
 
-use super::instructions::{Binary, Prefix, Special, Unary, UserCommand};
+use super::ast::{Node, Scope, Warning};
+use super::instructions::{
+	Binary, Instruction, Prefix, Special, Unary, UserCommand, JUMP_RELATIVE,
+};
+use super::parser;
+use serde::Serialize;
+
+/* Maps the address of an instruction that accesses variables to the stack depth (before the instruction executes)
+of each variable that is in scope there */
+pub type SymbolTable = BTreeMap<usize, BTreeMap<String, u32>>;
+
+/* Maps the address at which the code for a statement starts to its (1-based) source line. The code up to the next
+entry belongs to the same line. */
+pub type LineTable = BTreeMap<usize, usize>;
+
+/* Debug information recorded while a program is assembled (see Program::with_debug_info) */
+#[derive(Clone, Debug, Default)]
+pub struct DebugInfo {
+	pub symbols: SymbolTable,
+	pub lines: LineTable,
+	current_line: Option<usize>,
+}
+
+impl DebugInfo {
+	/* Empty debug information for a fragment, which continues the current line */
+	fn fragment(&self) -> DebugInfo {
+		DebugInfo {
+			current_line: self.current_line,
+			..DebugInfo::default()
+		}
+	}
+
+	/* Adds the debug information of code that was moved by `distance` bytes */
+	fn extend(&mut self, other: &DebugInfo, distance: isize) {
+		let relocate = |pc: &usize| (*pc as isize + distance) as usize;
+		self.symbols.extend(
+			other
+				.symbols
+				.iter()
+				.map(|(pc, variables)| (relocate(pc), variables.clone())),
+		);
+		self.lines
+			.extend(other.lines.iter().map(|(pc, line)| (relocate(pc), *line)));
+	}
+
+	/* Moves the symbols recorded for the instruction at `from` to the instruction at `to`, adjusting the stack depths
+	by `shift` (for when instructions that change the stack are merged into the instruction at `to`) */
+	fn move_symbols(&mut self, from: usize, to: usize, shift: i32) {
+		if let Some(variables) = self.symbols.remove(&from) {
+			let variables = variables
+				.into_iter()
+				.filter_map(|(name, depth)| {
+					let depth = depth as i32 + shift;
+					if depth >= 0 {
+						Some((name, depth as u32))
+					} else {
+						None
+					}
+				})
+				.collect();
+			self.symbols.insert(to, variables);
+		}
+	}
+}
 
 #[derive(Clone)]
 pub struct Program {
 	pub(crate) code: Vec<u8>,
 	pub(crate) stack_size: i32,
+	pub(crate) max_stack_size: i32,
 	pub(crate) offset: usize,
+	pub(crate) debug_info: Option<DebugInfo>,
+	optimized: bool,
 }
 
 #[allow(dead_code)]
@@ -18,29 +86,696 @@ impl Program {
 		self
 	}
 
+	fn grow_stack(&mut self, n: i32) {
+		self.stack_size += n;
+		self.max_stack_size = std::cmp::max(self.max_stack_size, self.stack_size);
+	}
+
+	/* Fragments track their stack depth relative to the stack size at the point where they are inserted */
+	fn merge_fragment_stack(&mut self, fragment: &Program) {
+		self.max_stack_size = std::cmp::max(
+			self.max_stack_size,
+			self.stack_size + fragment.max_stack_size,
+		);
+	}
+
+	/* The maximum number of stack items the program needs (only known for programs that were assembled) */
+	pub fn max_stack_depth(&self) -> i32 {
+		self.max_stack_size
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.code
+	}
+
+	/* A 32-bit FNV-1a hash of the program code. Unlike the standard library hashers, its value is stable between
+	builds, so devices and the server can compare hashes to see whether a device already runs a program. */
+	pub fn code_hash(&self) -> u32 {
+		self.code.iter().fold(0x811c_9dc5, |hash, byte| {
+			(hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+		})
+	}
+
+	/* The size of the program code in bytes */
+	pub fn byte_len(&self) -> usize {
+		self.code.len()
+	}
+
+	/* The number of instructions in the program (operands are counted as part of their instruction) */
+	pub fn instruction_count(&self) -> usize {
+		let mut count = 0;
+		let mut pc = 0;
+		while pc < self.code.len() {
+			pc += instruction_length(self.code[pc]);
+			count += 1;
+		}
+		count
+	}
+
 	pub fn from_binary(data: Vec<u8>) -> Program {
 		Program {
 			code: data,
 			stack_size: 0,
+			max_stack_size: 0,
 			offset: 0,
+			debug_info: None,
+			optimized: false,
+		}
+	}
+
+	/* Parses program code from hex bytes, which may be separated by whitespace (e.g. "e0 e5 10" or "e0e510") */
+	pub fn from_hex(s: &str) -> Result<Program, String> {
+		let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+		if let Some(c) = digits.iter().find(|c| !c.is_ascii_hexdigit()) {
+			return Err(format!("invalid hex digit '{}'", c));
+		}
+		if !digits.len().is_multiple_of(2) {
+			return Err(format!(
+				"hex input has an odd number of digits ({})",
+				digits.len()
+			));
+		}
+
+		let code = digits
+			.chunks(2)
+			.map(|pair| {
+				let hex: String = pair.iter().collect();
+				u8::from_str_radix(&hex, 16).unwrap()
+			})
+			.collect();
+		Ok(Program::from_binary(code))
+	}
+
+	/* Formats the program code as contiguous lowercase hex bytes (the inverse of from_hex) */
+	pub fn to_hex(&self) -> String {
+		self.code.iter().map(|b| format!("{:02x}", b)).collect()
+	}
+
+	pub fn from_source(source: &str) -> Result<Program, String> {
+		Program::new().compile(source)
+	}
+
+	/* Compiles a single expression. The program leaves the value of the expression on the stack when it ends (see
+	State::stack). */
+	pub fn from_expression(source: &str) -> Result<Program, String> {
+		Program::new().compile_expression(source)
+	}
+
+	/* Compiles a source file, including the files it references using `include "path"` */
+	pub fn from_source_file(path: &Path) -> Result<Program, String> {
+		Program::new().compile_file(path)
+	}
+
+	/* Compiles source code, appending it to this program (e.g. `Program::new().with_debug_info().compile(source)`) */
+	pub fn compile(self, source: &str) -> Result<Program, String> {
+		Ok(self.compile_with_warnings(source)?.0)
+	}
+
+	/* Like compile, but reads the source from a file (including the files it references) */
+	pub fn compile_file(self, path: &Path) -> Result<Program, String> {
+		Ok(self.compile_file_with_warnings(path)?.0)
+	}
+
+	/* Like compile, but also returns the (non-fatal) warnings found while compiling */
+	pub fn compile_with_warnings(self, source: &str) -> Result<(Program, Vec<Warning>), String> {
+		self.assemble_node(parser::parse(source)?)
+	}
+
+	pub fn compile_file_with_warnings(
+		self,
+		path: &Path,
+	) -> Result<(Program, Vec<Warning>), String> {
+		self.assemble_node(parser::parse_with_includes(path)?)
+	}
+
+	/* Like compile, but compiles a single expression and leaves its value on the stack */
+	pub fn compile_expression(mut self, source: &str) -> Result<Program, String> {
+		let expression = parser::parse_expression(source)?;
+		let mut scope = Scope::new();
+		expression.assemble(&mut self, &mut scope);
+		if let Some(error) = scope.errors().into_iter().next() {
+			return Err(error);
+		}
+		if self.optimized {
+			self.eliminate_dead_code();
+			self.optimize();
+		}
+		Ok(self)
+	}
+
+	fn assemble_node(mut self, node: Node) -> Result<(Program, Vec<Warning>), String> {
+		let mut scope = Scope::new();
+		node.assemble(&mut self, &mut scope);
+		if let Some(error) = scope.errors().into_iter().next() {
+			return Err(error);
+		}
+		scope.assemble_teardown(&mut self);
+		if self.optimized {
+			self.eliminate_dead_code();
+			self.optimize();
+		}
+		Ok((self, scope.warnings()))
+	}
+
+	/* Makes the program record debug information while it is being assembled, so that tracing and the step debugger
+	can show which variables instructions access, and errors can be reported at the source line that caused them */
+	pub fn with_debug_info(mut self) -> Program {
+		self.debug_info = Some(DebugInfo::default());
+		self
+	}
+
+	/* Makes compilation remove unreachable code and redundant instruction sequences (see eliminate_dead_code and
+	optimize). Without it, code is emitted exactly as the compiler generates it. */
+	pub fn with_optimization(mut self) -> Program {
+		self.optimized = true;
+		self
+	}
+
+	pub fn debug_info(&self) -> Option<&DebugInfo> {
+		self.debug_info.as_ref()
+	}
+
+	pub fn symbols(&self) -> Option<&SymbolTable> {
+		self.debug_info.as_ref().map(|d| &d.symbols)
+	}
+
+	/* Records the stack depths of the variables in scope for the instruction that is assembled next */
+	pub(crate) fn record_scope(&mut self, scope: &Scope) {
+		let pc = self.current_pc();
+		if let Some(debug_info) = &mut self.debug_info {
+			debug_info.symbols.insert(pc, scope.variable_depths());
+		}
+	}
+
+	/* The name of the variable at the given stack depth (before the instruction at `pc` executes), if known */
+	pub fn variable_at(&self, pc: usize, depth: u32) -> Option<&str> {
+		self.symbols()?
+			.get(&pc)?
+			.iter()
+			.find(|(_, d)| **d == depth)
+			.map(|(name, _)| name.as_str())
+	}
+
+	/* Marks the code that is assembled next as belonging to the given source line. Returns the line that was current
+	before, which should be passed to `end_line` when the code for the line has been assembled. */
+	pub(crate) fn begin_line(&mut self, line: usize) -> Option<usize> {
+		let pc = self.current_pc();
+		match &mut self.debug_info {
+			Some(debug_info) => {
+				debug_info.lines.insert(pc, line);
+				debug_info.current_line.replace(line)
+			}
+			None => None,
+		}
+	}
+
+	/* Marks the code that is assembled next as belonging to the enclosing statement's line again */
+	pub(crate) fn end_line(&mut self, outer: Option<usize>) {
+		let pc = self.current_pc();
+		if let Some(debug_info) = &mut self.debug_info {
+			debug_info.current_line = outer;
+			if let Some(outer) = outer {
+				debug_info.lines.insert(pc, outer);
+			}
+		}
+	}
+
+	/* The source line of the statement that produced the instruction at `pc`, if known */
+	pub fn source_line(&self, pc: usize) -> Option<usize> {
+		self.debug_info
+			.as_ref()?
+			.lines
+			.range(..=pc)
+			.next_back()
+			.map(|(_, line)| *line)
+	}
+
+	/* An empty program for code that will be written at `offset`, which records debug information if this program does */
+	fn fragment(&self, offset: usize) -> Program {
+		Program {
+			code: Vec::<u8>::new(),
+			stack_size: 0,
+			max_stack_size: 0,
+			offset,
+			debug_info: self.debug_info.as_ref().map(|d| d.fragment()),
+			optimized: false,
 		}
 	}
 
+	/* Writes the code of a fragment created using `fragment`, which must be located at the current position */
+	fn write_fragment(&mut self, fragment: &Program) -> &mut Program {
+		debug_assert_eq!(fragment.offset, self.current_pc());
+		if let (Some(debug_info), Some(fragment_debug_info)) =
+			(&mut self.debug_info, &fragment.debug_info)
+		{
+			debug_info.extend(fragment_debug_info, 0);
+		}
+		self.write(&fragment.code)
+	}
+
+	/* Reads a compiled program. Errors keep their kind, but mention the path of the file. */
 	pub fn from_file(path: &str) -> std::io::Result<Program> {
 		let mut stored_bin = Vec::<u8>::new();
-		File::open(path)?.read_to_end(&mut stored_bin)?;
+		File::open(path)
+			.and_then(|mut f| f.read_to_end(&mut stored_bin))
+			.map_err(|e| {
+				std::io::Error::new(
+					e.kind(),
+					format!("could not read program file '{}': {}", path, e),
+				)
+			})?;
 		Ok(Program {
 			code: stored_bin,
 			stack_size: 0,
+			max_stack_size: 0,
 			offset: 0,
+			debug_info: None,
+			optimized: false,
 		})
 	}
 
+	/* Checks that the code consists of known instructions whose operands and jump targets lie within the program */
+	pub fn validate(&self) -> Result<(), String> {
+		let mut pc = 0;
+		while pc < self.code.len() {
+			let (instruction, length) = match Instruction::decode(&self.code, pc) {
+				Some(decoded) => decoded,
+				None => return Err(self.describe_invalid(pc)),
+			};
+
+			if let Some(jump) = instruction.jump() {
+				match jump.target(pc) {
+					Some(target) if target <= self.code.len() => {}
+					_ => return Err(format!("jump at {} is outside of program", pc)),
+				}
+			}
+			pc += length;
+		}
+		Ok(())
+	}
+
+	/* Explains why the instruction at `pc` cannot be decoded */
+	fn describe_invalid(&self, pc: usize) -> String {
+		let opcode = self.code[pc];
+		let postfix = opcode & 0x0F;
+		match Prefix::from(opcode) {
+			None => format!("unknown instruction {:02x} at {}", opcode, pc),
+			Some(Prefix::BINARY) => format!("unknown binary operator {} at {}", postfix, pc),
+			Some(Prefix::UNARY) => format!("unknown unary operator {} at {}", postfix, pc),
+			Some(Prefix::USER) => format!("unknown user command {} at {}", postfix, pc),
+			Some(Prefix::SPECIAL) => format!("unknown special instruction {} at {}", postfix, pc),
+			Some(Prefix::JMP) | Some(Prefix::JZ) | Some(Prefix::JNZ)
+				if postfix & !JUMP_RELATIVE != 0 =>
+			{
+				format!("unknown jump flags {} at {}", postfix, pc)
+			}
+			Some(_) => format!("instruction at {} overruns code", pc),
+		}
+	}
+
+	/* Checks that execution can start at the offset, i.e. that it is the start of an instruction (or the end of the
+	code) */
+	pub fn check_entry_offset(&self, offset: usize) -> Result<(), String> {
+		let mut pc = 0;
+		while pc < offset && pc < self.code.len() {
+			pc += instruction_length(self.code[pc]);
+		}
+
+		if pc == offset && offset <= self.code.len() {
+			Ok(())
+		} else {
+			Err(format!(
+				"entry offset {} is not at an instruction boundary",
+				offset
+			))
+		}
+	}
+
+	/* Removes redundant instruction sequences (such as a push that is immediately popped, consecutive pops, double
+	bitwise negation and adding zero) from the code, and updates jump targets accordingly. Sequences are only rewritten when no jump
+	lands in their middle. POP 0 instructions (`nop`) are kept. */
+	pub fn optimize(&mut self) {
+		let (addresses, mut instructions) = self.split_instructions();
+
+		// Find out which instructions are jumped to
+		let targets: Vec<usize> = addresses
+			.iter()
+			.zip(instructions.iter())
+			.filter_map(|(a, i)| jump_target(*a, i))
+			.collect();
+		let is_target: Vec<bool> = addresses.iter().map(|a| targets.contains(a)).collect();
+
+		// Removed instructions are left empty so that indices stay the same
+		let mut changed = true;
+		while changed {
+			changed = false;
+			let live: Vec<usize> = (0..instructions.len())
+				.filter(|i| !instructions[*i].is_empty())
+				.collect();
+
+			for (n, &i) in live.iter().enumerate() {
+				if instructions[i].is_empty() {
+					continue;
+				}
+
+				let j = match live.get(n + 1) {
+					Some(&j) if !instructions[j].is_empty() => j,
+					_ => continue,
+				};
+				if is_target[(i + 1)..=j].iter().any(|t| *t) {
+					continue;
+				}
+
+				let (first, second) = (
+					decode_instruction(&instructions[i]),
+					decode_instruction(&instructions[j]),
+				);
+				let pushes_one = match &first {
+					Some(Instruction::PushB(values)) => values.len() <= 1,
+					Some(Instruction::PushI(values)) => values.len() == 1,
+					Some(Instruction::Peek(_)) => true,
+					_ => false,
+				};
+				let pushes_zero = match &first {
+					Some(Instruction::PushB(values)) => values.is_empty() || *values == [0],
+					_ => false,
+				};
+				let pops = |i: &Option<Instruction>| match i {
+					Some(Instruction::Pop(n)) => *n,
+					_ => 0,
+				};
+				let is_not = |i: &Option<Instruction>| *i == Some(Instruction::Unary(Unary::NOT));
+				let is_identity_with_zero = |i: &Option<Instruction>| match i {
+					Some(Instruction::Binary(op)) => matches!(
+						op,
+						Binary::ADD
+							| Binary::SUB | Binary::OR
+							| Binary::XOR | Binary::SHL
+							| Binary::SHR
+					),
+					_ => false,
+				};
+
+				if pushes_one && pops(&second) > 0 {
+					// PUSH x, POP n => POP n-1 (or nothing at all for POP 1; an explicit POP 0 is a nop that is kept)
+					let n = pops(&second);
+					instructions[i].clear();
+					if n == 1 {
+						instructions[j].clear();
+					} else {
+						instructions[j] = Instruction::Pop(n - 1).encode();
+					}
+					if let Some(debug_info) = &mut self.debug_info {
+						debug_info.move_symbols(addresses[j], addresses[j], -1);
+					}
+				} else if pops(&first) > 0
+					&& pops(&second) > 0
+					&& pops(&first) + pops(&second) <= 15
+				{
+					// POP a, POP b => POP a+b
+					let n = pops(&first) + pops(&second);
+					if let Some(debug_info) = &mut self.debug_info {
+						if debug_info.symbols.contains_key(&addresses[i]) {
+							debug_info.move_symbols(addresses[i], addresses[j], 0);
+						} else {
+							debug_info.move_symbols(
+								addresses[j],
+								addresses[j],
+								i32::from(pops(&first)),
+							);
+						}
+					}
+					instructions[i].clear();
+					instructions[j] = Instruction::Pop(n).encode();
+				} else if (is_not(&first) && is_not(&second))
+					|| (pushes_zero && is_identity_with_zero(&second))
+				{
+					// NOT, NOT => (nothing); PUSH 0, ADD => (nothing)
+					instructions[i].clear();
+					instructions[j].clear();
+				} else {
+					continue;
+				}
+				changed = true;
+			}
+		}
+
+		self.replace_instructions(&addresses, &instructions);
+	}
+
+	/* Removes instructions that can never be reached from the start of the program (by falling through from the
+	previous instruction or by a jump), and updates jump targets accordingly */
+	pub fn eliminate_dead_code(&mut self) {
+		let (addresses, mut instructions) = self.split_instructions();
+		let mut reachable = vec![false; instructions.len()];
+		let mut queue = vec![0];
+
+		while let Some(index) = queue.pop() {
+			if index >= instructions.len() || reachable[index] {
+				continue;
+			}
+			reachable[index] = true;
+			queue.extend(successors(&addresses, &instructions, index));
+		}
+
+		for (instruction, reachable) in instructions.iter_mut().zip(reachable.iter()) {
+			if !reachable {
+				instruction.clear();
+			}
+		}
+		self.replace_instructions(&addresses, &instructions);
+	}
+
+	/* Checks that the program cannot get stuck in a loop without ever yielding (which would prevent the strip from
+	being updated and keep the device busy). Loops that contain a branch out of the loop are assumed to terminate at
+	some point; only loops that cannot be left and do not contain a yield (or sleep) are reported. */
+	pub fn has_yield_in_loops(&self) -> bool {
+		let (addresses, instructions) = self.split_instructions();
+		let yields: Vec<bool> = instructions
+			.iter()
+			.map(|i| match decode_instruction(i) {
+				Some(Instruction::Special(special)) => {
+					special == Special::YIELD || special == Special::SLEEP
+				}
+				_ => false,
+			})
+			.collect();
+
+		// Determine which instructions can be reached from each instruction without passing a yield
+		let reach: Vec<Vec<bool>> = (0..instructions.len())
+			.map(|start| {
+				let mut reached = vec![false; instructions.len()];
+				let mut queue = successors(&addresses, &instructions, start);
+				while let Some(index) = queue.pop() {
+					if index >= instructions.len() || yields[index] || reached[index] {
+						continue;
+					}
+					reached[index] = true;
+					queue.extend(successors(&addresses, &instructions, index));
+				}
+				reached
+			})
+			.collect();
+
+		for index in 0..instructions.len() {
+			if yields[index] || !reach[index][index] {
+				continue;
+			}
+
+			// The instructions that are in a loop with this instruction
+			let in_loop: Vec<usize> = (0..instructions.len())
+				.filter(|other| reach[index][*other] && reach[*other][index])
+				.collect();
+			let can_leave = in_loop.iter().any(|i| {
+				successors(&addresses, &instructions, *i)
+					.iter()
+					.any(|s| !in_loop.contains(s))
+			});
+			if !can_leave {
+				return false;
+			}
+		}
+		true
+	}
+
+	/* Returns the highest pixel index passed to set_pixel that is known without running the program (e.g. a literal
+	index, or a variable that is not changed in a loop), or None when there are no such indices. The values on the
+	stack are tracked through the code; at jump targets all values are considered unknown. */
+	pub fn max_static_pixel_index(&self) -> Option<u32> {
+		let (addresses, instructions) = self.split_instructions();
+		let targets: Vec<usize> = addresses
+			.iter()
+			.zip(instructions.iter())
+			.filter_map(|(address, instruction)| jump_target(*address, instruction))
+			.collect();
+
+		let mut stack: Vec<Option<u32>> = vec![];
+		let mut max_index = None;
+		for (address, instruction) in addresses.iter().zip(self.instructions()) {
+			if targets.contains(address) {
+				stack.iter_mut().for_each(|v| *v = None);
+			}
+
+			let depth = stack.len();
+			let (pops, pushes): (usize, Vec<Option<u32>>) = match instruction {
+				Instruction::PushB(values) if values.is_empty() => (0, vec![Some(0)]),
+				Instruction::PushB(values) => {
+					(0, values.iter().map(|b| Some(u32::from(*b))).collect())
+				}
+				Instruction::PushI(values) => (0, values.into_iter().map(Some).collect()),
+				Instruction::Pop(n) => (n as usize, vec![]),
+				Instruction::Peek(n) if (n as usize) < depth => {
+					(0, vec![stack[depth - 1 - n as usize]])
+				}
+				Instruction::Poke(n) if (n as usize) + 1 < depth => {
+					stack[depth - 2 - n as usize] = stack[depth - 1];
+					(1, vec![])
+				}
+				Instruction::Jmp(_) | Instruction::Jz(_) | Instruction::Jnz(_) => (0, vec![]),
+				Instruction::Unary(op) if depth > 0 => {
+					(1, vec![stack[depth - 1].map(|v| op.apply(v))])
+				}
+				Instruction::Binary(op) if depth > 1 => (
+					2,
+					vec![stack[depth - 2].and_then(|l| Some(op.apply(l, stack[depth - 1]?)))],
+				),
+				Instruction::User(command) => match command {
+					UserCommand::GET_LENGTH
+					| UserCommand::GET_WALL_TIME
+					| UserCommand::GET_PRECISE_TIME
+					| UserCommand::GET_FRAME => (0, vec![None]),
+					UserCommand::RANDOM_INT | UserCommand::GET_PIXEL if depth > 0 => {
+						(1, vec![None])
+					}
+					UserCommand::SET_PIXEL if depth > 1 => {
+						if let Some(index) = stack[depth - 2] {
+							max_index = max_index.max(Some(index));
+						}
+						(1, vec![])
+					}
+					UserCommand::BLIT | UserCommand::CLEAR | UserCommand::SELECT_STRIP => {
+						(0, vec![])
+					}
+					_ => break,
+				},
+				Instruction::Special(special) => match special {
+					Special::PRINT | Special::SLEEP | Special::ASSERT => (1, vec![]),
+					Special::SWAP if depth > 1 => {
+						stack.swap(depth - 1, depth - 2);
+						(0, vec![])
+					}
+					Special::DUMP | Special::YIELD | Special::HALT => (0, vec![]),
+					_ => break,
+				},
+				_ => break,
+			};
+
+			// Stop at code that would underflow the stack (it is not what the compiler generates)
+			if pops > stack.len() {
+				break;
+			}
+			stack.truncate(stack.len() - pops);
+			stack.extend(pushes);
+		}
+		max_index
+	}
+
+	/* Splits the code into instructions, and returns these together with their addresses */
+	fn split_instructions(&self) -> (Vec<usize>, Vec<Vec<u8>>) {
+		let mut addresses = vec![];
+		let mut instructions = vec![];
+		let mut pc = 0;
+		while pc < self.code.len() {
+			let length = std::cmp::min(instruction_length(self.code[pc]), self.code.len() - pc);
+			addresses.push(self.offset + pc);
+			instructions.push(self.code[pc..(pc + length)].to_vec());
+			pc += length;
+		}
+		(addresses, instructions)
+	}
+
+	/* Replaces the code with the given instructions, which were originally located at `addresses`. Instructions
+	may be removed by leaving them empty; jumps to removed instructions will target the instruction that follows. */
+	fn replace_instructions(&mut self, addresses: &[usize], instructions: &[Vec<u8>]) {
+		let mut new_addresses = vec![];
+		let mut address = self.offset;
+		for instruction in instructions.iter() {
+			new_addresses.push(address);
+			address += instruction.len();
+		}
+		let end = address;
+
+		let old_end = self.offset + self.code.len();
+		let relocate = |target: usize| -> usize {
+			match addresses.iter().position(|a| *a == target) {
+				Some(index) => new_addresses[index],
+				None if target == old_end => end,
+				None => target,
+			}
+		};
+
+		let mut code = Vec::<u8>::with_capacity(self.code.len());
+		for (index, instruction) in instructions.iter().enumerate() {
+			if let Some(target) = jump_target(addresses[index], instruction) {
+				let new_target = relocate(target);
+				code.extend_from_slice(&encode_jump(
+					instruction[0],
+					new_addresses[index],
+					new_target,
+				));
+			} else {
+				code.extend_from_slice(instruction);
+			}
+		}
+
+		// Symbols for removed instructions are dropped, lines move to the instruction that follows
+		if let Some(debug_info) = &mut self.debug_info {
+			debug_info.symbols = std::mem::take(&mut debug_info.symbols)
+				.into_iter()
+				.filter_map(|(pc, variables)| {
+					let index = addresses.iter().position(|a| *a == pc)?;
+					if instructions[index].is_empty() {
+						return None;
+					}
+					Some((new_addresses[index], variables))
+				})
+				.collect();
+			debug_info.lines = std::mem::take(&mut debug_info.lines)
+				.into_iter()
+				.map(|(pc, line)| (relocate(pc), line))
+				.collect();
+		}
+		self.code = code;
+	}
+
+	/* Converts absolute jumps to relative jumps (where the distance allows), so that the code can be moved (e.g.
+	appended to another program) without changing it */
+	pub fn make_position_independent(&mut self) {
+		let (addresses, mut instructions) = self.split_instructions();
+		for (address, instruction) in addresses.iter().zip(instructions.iter_mut()) {
+			if is_relative_jump(instruction) {
+				continue;
+			}
+
+			if let Some(target) = jump_target(*address, instruction) {
+				let offset = target as i64 - *address as i64;
+				if offset >= i64::from(i16::MIN) && offset <= i64::from(i16::MAX) {
+					*instruction =
+						encode_jump(instruction[0] | JUMP_RELATIVE, *address, target).to_vec();
+				}
+			}
+		}
+		self.code = instructions.concat();
+	}
+
 	pub fn new() -> Program {
 		Program {
 			code: Vec::<u8>::new(),
 			stack_size: 0,
+			max_stack_size: 0,
 			offset: 0,
+			debug_info: None,
+			optimized: false,
 		}
 	}
 
@@ -62,10 +797,17 @@ impl Program {
 
 	pub fn peek(&mut self, n: u8) -> &mut Program {
 		assert!(n <= 15, "cannot peek more than 15 stack items");
-		self.stack_size += 1;
+		self.grow_stack(1);
 		self.write(&[Prefix::PEEK as u8 | n]) // PEEK n
 	}
 
+	/* Pops the top of the stack and stores it at index n (relative to the new top of the stack) */
+	pub fn poke(&mut self, n: u8) -> &mut Program {
+		assert!(n <= 15, "cannot poke more than 15 stack items");
+		self.stack_size -= 1;
+		self.write(&[Prefix::POKE as u8 | n]) // POKE n
+	}
+
 	pub fn unary(&mut self, u: Unary) -> &mut Program {
 		self.write(&[Prefix::UNARY as u8 | u as u8]) // UNARY u
 	}
@@ -76,17 +818,21 @@ impl Program {
 	}
 
 	pub fn special(&mut self, u: Special) -> &mut Program {
-		self.stack_size += match u {
+		self.grow_stack(match u {
+			Special::ASSERT => -1,
+			Special::HALT => 0,
 			Special::DUMP => 0,
+			Special::PRINT => -1,
+			Special::SLEEP => -1,
 			Special::SWAP => 0,
 			Special::YIELD => 0,
 			Special::TWOBYTE => unimplemented!(),
-		};
+		});
 		self.write(&[Prefix::SPECIAL as u8 | u as u8]) // SPECIAL u
 	}
 
 	pub fn user(&mut self, u: UserCommand) -> &mut Program {
-		self.stack_size += match u {
+		self.grow_stack(match u {
 			UserCommand::GET_LENGTH => 1,
 			UserCommand::GET_PRECISE_TIME => 1,
 			UserCommand::GET_WALL_TIME => 1,
@@ -94,7 +840,10 @@ impl Program {
 			UserCommand::SET_PIXEL => -1,
 			UserCommand::RANDOM_INT => 0,
 			UserCommand::GET_PIXEL => 0,
-		};
+			UserCommand::SELECT_STRIP => 0,
+			UserCommand::CLEAR => 0,
+			UserCommand::GET_FRAME => 1,
+		});
 		self.write(&[Prefix::USER as u8 | u as u8]) // SPECIAL u
 	}
 
@@ -102,16 +851,13 @@ impl Program {
 	where
 		F: FnMut(&mut Program),
 	{
-		let mut fragment = Program {
-			code: Vec::<u8>::new(),
-			stack_size: 0,
-			offset: self.current_pc() + 3,
-		};
+		let mut fragment = self.fragment(self.current_pc() + 3);
 		builder(&mut fragment);
 		assert_eq!(
 			fragment.stack_size, 0,
 			"fragment in branch cannot modify stack size"
 		);
+		self.merge_fragment_stack(&fragment);
 
 		// Always write three-byte jumps for now
 		let address = self.current_pc() + 3 + fragment.code.len();
@@ -120,7 +866,7 @@ impl Program {
 			(address & 0xFF) as u8,
 			((address >> 8) & 0xFF) as u8,
 		]);
-		self.write(&fragment.code)
+		self.write_fragment(&fragment)
 	}
 
 	pub fn if_zero<F>(&mut self, builder: F) -> &mut Program
@@ -141,19 +887,16 @@ impl Program {
 	where
 		F: FnMut(&mut Program),
 	{
-		let mut fragment = Program {
-			code: Vec::<u8>::new(),
-			stack_size: 0,
-			offset: self.current_pc(),
-		};
+		let mut fragment = self.fragment(self.current_pc());
 		builder(&mut fragment);
 		assert!(
 			fragment.stack_size == 0,
 			"fragment in forever loop cannot modify stack size"
 		);
+		self.merge_fragment_stack(&fragment);
 
 		let start = self.current_pc();
-		self.write(&fragment.code);
+		self.write_fragment(&fragment);
 		self.write(&[
 			Prefix::JMP as u8,
 			(start & 0xFF) as u8,
@@ -166,23 +909,54 @@ impl Program {
 		self.offset + self.code.len()
 	}
 
+	/* Appends the code of another program. Jump targets in the appended code are relocated, so that they keep
+	pointing at the same instructions. */
+	pub fn append(&mut self, other: &Program) -> &mut Program {
+		let base = self.current_pc();
+		let (addresses, instructions) = other.split_instructions();
+		self.merge_fragment_stack(other);
+		self.stack_size += other.stack_size;
+		if let (Some(debug_info), Some(other_debug_info)) =
+			(&mut self.debug_info, &other.debug_info)
+		{
+			debug_info.extend(other_debug_info, base as isize - other.offset as isize);
+		}
+
+		for (address, instruction) in addresses.iter().zip(instructions.iter()) {
+			match jump_target(*address, instruction) {
+				// Relative jumps do not need to be changed
+				Some(target) if !is_relative_jump(instruction) => {
+					let target = target + base - other.offset;
+					assert!(
+						target <= 0xFFFF,
+						"jump target {} exceeds the maximum program size",
+						target
+					);
+					let address = address + base - other.offset;
+					self.write(&encode_jump(instruction[0], address, target));
+				}
+				_ => {
+					self.write(instruction);
+				}
+			}
+		}
+		self
+	}
+
 	pub fn repeat<F>(&mut self, mut builder: F) -> &mut Program
 	where
 		F: FnMut(&mut Program),
 	{
-		let mut fragment = Program {
-			code: Vec::<u8>::new(),
-			stack_size: 0,
-			offset: self.current_pc(),
-		};
+		let mut fragment = self.fragment(self.current_pc());
 		builder(&mut fragment);
 		assert!(
 			fragment.stack_size == 0,
 			"fragment in loop cannot modify stack size"
 		);
+		self.merge_fragment_stack(&fragment);
 
 		let start = self.current_pc();
-		self.write(&fragment.code);
+		self.write_fragment(&fragment);
 		self.write(&[Prefix::UNARY as u8 | Unary::DEC as u8]);
 		self.write(&[
 			Prefix::JNZ as u8,
@@ -192,6 +966,47 @@ impl Program {
 		self
 	}
 
+	/* Repeats the fragment for as long as the value pushed by the condition fragment is non-zero */
+	pub fn repeat_while<C, F>(&mut self, mut condition: C, mut builder: F) -> &mut Program
+	where
+		C: FnMut(&mut Program),
+		F: FnMut(&mut Program),
+	{
+		let start = self.current_pc();
+		let mut condition_fragment = self.fragment(start);
+		condition(&mut condition_fragment);
+		assert!(
+			condition_fragment.stack_size == 1,
+			"condition fragment in loop must push exactly one value"
+		);
+		self.merge_fragment_stack(&condition_fragment);
+
+		// The fragment follows the condition, a three-byte jump and a pop
+		let mut fragment = self.fragment(start + condition_fragment.code.len() + 4);
+		builder(&mut fragment);
+		assert!(
+			fragment.stack_size == 0,
+			"fragment in loop cannot modify stack size"
+		);
+		self.merge_fragment_stack(&fragment);
+
+		let end = fragment.offset + fragment.code.len() + 3;
+		self.write_fragment(&condition_fragment);
+		self.write(&[
+			Prefix::JZ as u8,
+			(end & 0xFF) as u8,
+			((end >> 8) & 0xFF) as u8,
+		]);
+		self.write(&[Prefix::POP as u8 | 1]);
+		self.write_fragment(&fragment);
+		self.write(&[
+			Prefix::JMP as u8,
+			(start & 0xFF) as u8,
+			((start >> 8) & 0xFF) as u8,
+		]);
+		self.write(&[Prefix::POP as u8 | 1])
+	}
+
 	pub fn repeat_times<F>(&mut self, times: u32, builder: F) -> &mut Program
 	where
 		F: FnMut(&mut Program),
@@ -273,10 +1088,22 @@ impl Program {
 		self.peek(0)
 	}
 
+	pub fn print(&mut self) -> &mut Program {
+		self.special(Special::PRINT)
+	}
+
+	pub fn assert(&mut self) -> &mut Program {
+		self.special(Special::ASSERT)
+	}
+
 	pub fn swap(&mut self) -> &mut Program {
 		self.special(Special::SWAP)
 	}
 
+	pub fn halt(&mut self) -> &mut Program {
+		self.special(Special::HALT)
+	}
+
 	pub fn r#yield(&mut self) -> &mut Program {
 		self.special(Special::YIELD)
 	}
@@ -289,6 +1116,10 @@ impl Program {
 		self.user(UserCommand::BLIT)
 	}
 
+	pub fn clear(&mut self) -> &mut Program {
+		self.user(UserCommand::CLEAR)
+	}
+
 	pub fn get_length(&mut self) -> &mut Program {
 		self.user(UserCommand::GET_LENGTH)
 	}
@@ -297,12 +1128,16 @@ impl Program {
 		self.user(UserCommand::GET_PRECISE_TIME)
 	}
 
+	pub fn get_frame(&mut self) -> &mut Program {
+		self.user(UserCommand::GET_FRAME)
+	}
+
 	pub fn get_wall_time(&mut self) -> &mut Program {
 		self.user(UserCommand::GET_WALL_TIME)
 	}
 
 	pub fn push(&mut self, b: u32) -> &mut Program {
-		self.stack_size += 1;
+		self.grow_stack(1);
 		match b {
 			0 => self.code.write(&[Prefix::PUSHB as u8]).unwrap(),
 			_ if b <= 0xFF => self
@@ -324,110 +1159,733 @@ impl Program {
 	}
 }
 
-impl fmt::Debug for Program {
+/* An operand of a disassembled instruction */
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Operand {
+	/* Immediate value bytes (PUSHB, PUSHI) */
+	Bytes(Vec<u8>),
+	/* Jump target address */
+	Target(usize),
+	/* Value encoded in the instruction postfix (e.g. the number of items to pop) */
+	Number(u8),
+	/* Name of the operation (BINARY, UNARY, USER, SPECIAL) */
+	Name(String),
+	/* The instruction is invalid; disassembly ends here */
+	Invalid(String),
+}
+
+impl fmt::Display for Operand {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Operand::Bytes(bytes) => write!(f, "{:02x?}", bytes),
+			Operand::Target(target) => write!(f, "to {}", target),
+			Operand::Number(n) => write!(f, "{}", n),
+			Operand::Name(name) => write!(f, "{}", name),
+			Operand::Invalid(reason) => write!(f, "{}", reason),
+		}
+	}
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+	pub pc: usize,
+	pub opcode: u8,
+	pub mnemonic: String,
+	pub operands: Vec<Operand>,
+}
+
+impl DisassembledInstruction {
+	fn is_invalid(&self) -> bool {
+		self.operands
+			.iter()
+			.any(|operand| matches!(operand, Operand::Invalid(_)))
+	}
+}
+
+/* Formats the mnemonic followed by the operands, separated by tabs */
+impl fmt::Display for DisassembledInstruction {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.mnemonic)?;
+		for operand in self.operands.iter() {
+			write!(f, "\t{}", operand)?;
+		}
+		Ok(())
+	}
+}
+
+impl Program {
+	/* Decodes the code into a list of instructions. Decoding stops after the first unknown or invalid instruction. */
+	pub fn to_instructions(&self) -> Vec<DisassembledInstruction> {
+		let mut instructions = vec![];
 		let mut pc = 0;
 		while pc < self.code.len() {
-			let ins = Prefix::from(self.code[pc]);
-			if let Some(i) = ins {
-				let postfix = self.code[pc] & 0x0F;
-				write!(f, "{:04}.\t{:02x}\t{}", pc, self.code[pc], i)?;
-				match i {
-					Prefix::PUSHI => {
-						let end = (postfix as usize) * 4 + pc + 1;
-						if end > self.code.len() {
-							write!(f, "\t(invalid, overruns code; size={})", (postfix as usize))?;
-							return Ok(());
-						} else {
-							write!(
-								f,
-								"\t{:02x?}",
-								&self.code[(pc + 1)..(pc + 1 + (postfix as usize) * 4)]
-							)?;
-							pc += (postfix as usize) * 4;
-						}
-					}
-					Prefix::PUSHB => {
-						if postfix == 0 {
-							write!(f, "\t0")?;
-						} else {
-							let end = (postfix as usize) + pc + 1;
-							if end > self.code.len() {
-								write!(
-									f,
-									"\t(invalid, overruns code; size={})",
-									(postfix as usize)
-								)?;
-								return Ok(());
-							} else {
-								write!(
-									f,
-									"\t{:02x?}",
-									&self.code[(pc + 1)..(pc + 1 + (postfix as usize))]
-								)?;
-								pc += postfix as usize;
-							}
-						}
-					}
-					Prefix::JMP | Prefix::JZ | Prefix::JNZ => {
-						if self.code.len() < (pc + 1) {
-							write!(f, "\t(invalid, overruns code)")?;
-							return Ok(());
-						}
-						let target =
-							u32::from(self.code[pc + 1]) | u32::from(self.code[pc + 2]) << 8;
-						write!(f, "\tto {}", target)?;
-						pc += 2
-					}
-					Prefix::BINARY => {
-						if let Some(op) = Binary::from(postfix) {
-							write!(f, "\t{}", op)?;
-						} else {
-							write!(f, "\tunknown {}", postfix)?;
-						}
-					}
-					Prefix::UNARY => {
-						if let Some(op) = Unary::from(postfix) {
-							write!(f, "\t{}", op)?;
-						} else {
-							write!(f, "\tunknown {}", postfix)?;
-						}
-					}
-					Prefix::USER => {
-						let name = match postfix {
-							0 => "get_length",
-							1 => "get_wall_time",
-							2 => "get_precise_time",
-							3 => "set_pixel",
-							4 => "blit",
-							5 => "random_int",
-							6 => "get_pixel",
-							_ => "(unknown user function)",
-						};
-						write!(f, "\t{}", name)?;
-					}
-					Prefix::SPECIAL => {
-						let name = match postfix {
-							12 => "swap",
-							13 => "dump",
-							14 => "yield",
-							15 => "two-byte instruction",
-							_ => "(unknown special function)",
-						};
-						write!(f, "\t{}", name)?;
-					}
-					_ => {
-						write!(f, "\t{}", postfix)?;
+			let instruction = self.decode(pc);
+			let end = Prefix::from(instruction.opcode).is_none() || instruction.is_invalid();
+			instructions.push(instruction);
+			if end {
+				break;
+			}
+			pc += instruction_length(self.code[pc]);
+		}
+		instructions
+	}
+
+	/* Decodes the code into typed instructions. Decoding stops at the first unknown or invalid instruction. */
+	pub fn instructions(&self) -> impl Iterator<Item = Instruction> + '_ {
+		let mut pc = 0;
+		std::iter::from_fn(move || {
+			let (instruction, length) = Instruction::decode(&self.code, pc)?;
+			pc += length;
+			Some(instruction)
+		})
+	}
+
+	/* Decodes the single instruction at `pc`, returning it (its Display impl shows the mnemonic and operands) and
+	the number of bytes it occupies, or None when `pc` lies beyond the end of the code. An instruction whose operands
+	overrun the code occupies the remaining bytes. */
+	pub fn disassemble_one(&self, pc: usize) -> Option<(DisassembledInstruction, usize)> {
+		if pc >= self.code.len() {
+			return None;
+		}
+		let length = std::cmp::min(instruction_length(self.code[pc]), self.code.len() - pc);
+		Some((self.decode(pc), length))
+	}
+
+	/* Decodes the instruction at `pc`, which must lie within the code */
+	fn decode(&self, pc: usize) -> DisassembledInstruction {
+		let opcode = self.code[pc];
+		let postfix = opcode & 0x0F;
+		let prefix = match Prefix::from(opcode) {
+			Some(p) => p,
+			None => {
+				return DisassembledInstruction {
+					pc,
+					opcode,
+					mnemonic: "unknown".to_string(),
+					operands: vec![],
+				};
+			}
+		};
+
+		let operand = match Instruction::decode(&self.code, pc) {
+			Some((Instruction::PushB(values), _)) if values.is_empty() => Operand::Number(0),
+			Some((Instruction::PushB(_), length)) | Some((Instruction::PushI(_), length)) => {
+				Operand::Bytes(self.code[(pc + 1)..(pc + length)].to_vec())
+			}
+			Some((Instruction::Jmp(jump), _))
+			| Some((Instruction::Jz(jump), _))
+			| Some((Instruction::Jnz(jump), _)) => match jump.target(pc) {
+				Some(target) => Operand::Target(target),
+				None => Operand::Invalid("(invalid, jumps before start)".to_string()),
+			},
+			Some((Instruction::Binary(op), _)) => Operand::Name(op.to_string()),
+			Some((Instruction::Unary(op), _)) => Operand::Name(op.to_string()),
+			Some((Instruction::User(command), _)) => Operand::Name(command.to_string()),
+			Some((Instruction::Special(special), _)) => Operand::Name(special.to_string()),
+			Some((Instruction::Pop(n), _))
+			| Some((Instruction::Peek(n), _))
+			| Some((Instruction::Poke(n), _)) => Operand::Number(n),
+			None => self.invalid_operand(pc),
+		};
+
+		let mnemonic = match prefix {
+			Prefix::JMP | Prefix::JZ | Prefix::JNZ if postfix & JUMP_RELATIVE != 0 => {
+				format!("{}R", prefix)
+			}
+			_ => prefix.to_string(),
+		};
+		DisassembledInstruction {
+			pc,
+			opcode,
+			mnemonic,
+			operands: vec![operand],
+		}
+	}
+
+	/* The operand shown for an instruction that cannot be decoded (see Instruction::decode) */
+	fn invalid_operand(&self, pc: usize) -> Operand {
+		let opcode = self.code[pc];
+		let postfix = opcode & 0x0F;
+		match Prefix::from(opcode) {
+			Some(Prefix::PUSHI) | Some(Prefix::PUSHB) => Operand::Invalid(format!(
+				"(invalid, overruns code; size={})",
+				postfix as usize
+			)),
+			Some(Prefix::JMP) | Some(Prefix::JZ) | Some(Prefix::JNZ) => {
+				let instruction = &self.code[pc..std::cmp::min(pc + 3, self.code.len())];
+				match jump_target(pc, instruction) {
+					Some(target) => Operand::Target(target),
+					None if instruction.len() == 3 => {
+						Operand::Invalid("(invalid, jumps before start)".to_string())
 					}
+					None => Operand::Invalid("(invalid, overruns code)".to_string()),
 				}
-				writeln!(f)?;
-			} else {
-				writeln!(f, "{:04}.\t{:02x}\tUnknown instruction", pc, self.code[pc])?;
+			}
+			Some(Prefix::USER) => Operand::Name("(unknown user function)".to_string()),
+			Some(Prefix::SPECIAL) => Operand::Name("(unknown special function)".to_string()),
+			_ => Operand::Name(format!("unknown {}", postfix)),
+		}
+	}
+}
+
+impl fmt::Debug for Program {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut pc = 0;
+		while let Some((instruction, length)) = self.disassemble_one(pc) {
+			if Prefix::from(instruction.opcode).is_none() {
+				writeln!(
+					f,
+					"{:04}.\t{:02x}\tUnknown instruction",
+					pc, instruction.opcode
+				)?;
 				break;
 			}
 
-			pc += 1;
+			write!(f, "{:04}.\t{:02x}\t{}", pc, instruction.opcode, instruction)?;
+
+			// Nothing sensible can be decoded after an instruction with invalid operands
+			if instruction.is_invalid() {
+				return Ok(());
+			}
+			writeln!(f)?;
+			pc += length;
 		}
 		Ok(())
 	}
 }
+
+/* Returns the length in bytes of the instruction (including operands) that starts with the given byte */
+fn instruction_length(instruction: u8) -> usize {
+	let postfix = (instruction & 0x0F) as usize;
+	match Prefix::from(instruction) {
+		Some(Prefix::PUSHI) => 1 + postfix * 4,
+		Some(Prefix::PUSHB) => 1 + postfix,
+		Some(Prefix::JMP) | Some(Prefix::JZ) | Some(Prefix::JNZ) => 3,
+		_ => 1,
+	}
+}
+
+/* Returns the indices of the instructions that may be executed after the instruction at `index` (an index equal
+to the number of instructions indicates the end of the program) */
+fn successors(addresses: &[usize], instructions: &[Vec<u8>], index: usize) -> Vec<usize> {
+	let mut next = vec![];
+	let instruction = &instructions[index];
+	if let Some(target) = jump_target(addresses[index], instruction) {
+		if let Some(target_index) = addresses.iter().position(|a| *a == target) {
+			next.push(target_index);
+		}
+	}
+
+	// Execution continues with the next instruction, unless this is an unconditional jump, halt or invalid
+	match decode_instruction(instruction) {
+		Some(Instruction::Jmp(_)) | Some(Instruction::Special(Special::HALT)) | None => {}
+		_ => next.push(index + 1),
+	}
+	next
+}
+
+/* Decodes an instruction split off by Program::split_instructions, or returns None when it is unknown or invalid */
+fn decode_instruction(instruction: &[u8]) -> Option<Instruction> {
+	Instruction::decode(instruction, 0).map(|(instruction, _)| instruction)
+}
+
+/* Returns the target address of a jump instruction located at `address` */
+fn jump_target(address: usize, instruction: &[u8]) -> Option<usize> {
+	match instruction.first().and_then(|i| Prefix::from(*i)) {
+		Some(Prefix::JMP) | Some(Prefix::JZ) | Some(Prefix::JNZ) if instruction.len() == 3 => {
+			if is_relative_jump(instruction) {
+				let offset = i16::from_le_bytes([instruction[1], instruction[2]]);
+				let target = address as i64 + i64::from(offset);
+				if target < 0 {
+					None
+				} else {
+					Some(target as usize)
+				}
+			} else {
+				Some((usize::from(instruction[1])) | (usize::from(instruction[2]) << 8))
+			}
+		}
+		_ => None,
+	}
+}
+
+fn is_relative_jump(instruction: &[u8]) -> bool {
+	match instruction.first() {
+		Some(opcode) => {
+			matches!(
+				Prefix::from(*opcode),
+				Some(Prefix::JMP) | Some(Prefix::JZ) | Some(Prefix::JNZ)
+			) && opcode & JUMP_RELATIVE != 0
+		}
+		None => false,
+	}
+}
+
+/* Encodes a jump from `address` to `target`. The jump is relative when the opcode has the JUMP_RELATIVE flag. */
+fn encode_jump(opcode: u8, address: usize, target: usize) -> [u8; 3] {
+	let operand = if opcode & JUMP_RELATIVE != 0 {
+		let offset = target as i64 - address as i64;
+		assert!(
+			offset >= i64::from(i16::MIN) && offset <= i64::from(i16::MAX),
+			"relative jump offset {} out of range",
+			offset
+		);
+		offset as i16 as u16
+	} else {
+		target as u16
+	};
+	[opcode, (operand & 0xFF) as u8, (operand >> 8) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pwlp::strip::DummyStrip;
+	use crate::pwlp::vm::{Outcome, VM};
+
+	#[test]
+	fn from_file() {
+		let program = Program::from_file("test/blink.bin").unwrap();
+		assert_eq!(program.code, std::fs::read("test/blink.bin").unwrap());
+
+		let error = Program::from_file("test/does_not_exist.bin").unwrap_err();
+		assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+		assert!(error.to_string().contains("test/does_not_exist.bin"));
+	}
+
+	#[test]
+	fn size() {
+		// pushb 5; pushi [0x01020304]; add; jmp 0; pop 1
+		let program = Program::from_binary(vec![
+			0x11, 0x05, 0x31, 0x04, 0x03, 0x02, 0x01, 0x80, 0x40, 0x00, 0x00, 0x01,
+		]);
+		assert_eq!(program.byte_len(), 12);
+		assert_eq!(program.as_bytes(), &program.code[..]);
+		assert_eq!(program.instruction_count(), 5);
+
+		let program = Program::from_source("set_pixel(0, 1, 2, 3); blit").unwrap();
+		assert_eq!(program.instruction_count(), program.to_instructions().len());
+		assert_eq!(Program::new().instruction_count(), 0);
+	}
+
+	#[test]
+	fn hex() {
+		let program = Program::from_source("loop{set_pixel(0, 255, 0, 0); blit; yield}").unwrap();
+		let hex = program.to_hex();
+		assert_eq!(hex.len(), program.code.len() * 2);
+		assert_eq!(Program::from_hex(&hex).unwrap().code, program.code);
+
+		assert_eq!(
+			Program::from_hex("e0 E5\n10").unwrap().code,
+			vec![0xe0, 0xe5, 0x10]
+		);
+		assert_eq!(Program::from_hex("").unwrap().code, Vec::<u8>::new());
+		assert_eq!(Program::from_binary(vec![0x0a, 0xff]).to_hex(), "0aff");
+
+		assert!(Program::from_hex("e0e")
+			.unwrap_err()
+			.contains("odd number of digits"));
+		assert_eq!(
+			Program::from_hex("e0 +1").unwrap_err(),
+			"invalid hex digit '+'"
+		);
+	}
+
+	#[test]
+	fn code_hash() {
+		assert_eq!(Program::new().code_hash(), 0x811c_9dc5);
+		assert_eq!(Program::from_binary(vec![0x61]).code_hash(), 0xe40c_292c);
+
+		let a = Program::from_source("loop{blit;yield}").unwrap();
+		let b = Program::from_source("loop{yield;blit}").unwrap();
+		assert_eq!(a.code_hash(), a.clone().code_hash());
+		assert_ne!(a.code_hash(), b.code_hash());
+	}
+
+	#[test]
+	fn has_yield_in_loops() {
+		let check = |source: &str| Program::from_source(source).unwrap().has_yield_in_loops();
+		assert!(check("loop { blit; yield }"));
+		assert!(check(
+			"loop { for(i=get_length) { set_pixel(i, 1, 2, 3) }; blit; yield }"
+		));
+		assert!(check(
+			"loop { if(get_length > 5) { yield } else { sleep(10) } }"
+		));
+		assert!(check("x = 1; for(i = 0; i < 10; i = i + 1) { x = x * 2 }"));
+		assert!(!check("loop { blit }"));
+		assert!(!check("x = 1; loop { x = x + 1 }"));
+
+		// A jump to itself
+		assert!(!Program::from_binary(vec![0x40, 0x00, 0x00]).has_yield_in_loops());
+	}
+
+	#[test]
+	fn max_static_pixel_index() {
+		let index = |source| {
+			Program::from_source(source)
+				.unwrap()
+				.max_static_pixel_index()
+		};
+		assert_eq!(index("set_pixel(50, 255, 0, 0)"), Some(50));
+		assert_eq!(
+			index("x = 7; set_pixel(x, 1, 2, 3); set_pixel(x - 5, 1, 2, 3)"),
+			Some(7)
+		);
+		assert_eq!(index("loop { set_pixel(12, 1, 0, 0); yield }"), Some(12));
+		assert_eq!(
+			index("set_pixel(2, 1, 0, 0); set_pixel(get_length - 1, 1, 0, 0)"),
+			Some(2)
+		);
+		assert_eq!(index("for(i = get_length) { set_pixel(i, 1, 0, 0) }"), None);
+		assert_eq!(index("blit"), None);
+	}
+
+	#[test]
+	fn disassemble_get_pixel() {
+		let program = Program::from_source("set_pixel(1, get_pixel(0), 0, 0)").unwrap();
+		let listing = format!("{:?}", program);
+		assert!(listing.contains("USER\tget_pixel\n"), "{}", listing);
+		assert!(!listing.contains("unknown"));
+	}
+
+	#[test]
+	fn disassemble_specials() {
+		for code in 0..=0x0F {
+			let instructions =
+				Program::from_binary(vec![Prefix::SPECIAL as u8 | code]).to_instructions();
+			let expected = match Special::from(code) {
+				Some(special) => special.name(),
+				None => "(unknown special function)",
+			};
+			assert_eq!(
+				instructions[0].operands,
+				vec![Operand::Name(expected.to_string())]
+			);
+		}
+	}
+
+	#[test]
+	fn to_instructions() {
+		let program = Program::from_binary(vec![
+			0x11, 0x05, 0x30, 0x50, 0x08, 0x00, 0x80, 0x71, 0xe3, 0x02, 0xfe, 0x40, 0x00, 0x00,
+			0xa0,
+		]);
+		let instructions = program.to_instructions();
+		let expected = [
+			(0, 0x11, "PUSHB", Operand::Bytes(vec![5])),
+			(2, 0x30, "PUSHI", Operand::Bytes(vec![])),
+			(3, 0x50, "JZ", Operand::Target(8)),
+			(6, 0x80, "BINARY", Operand::Name("ADD".to_string())),
+			(7, 0x71, "UNARY", Operand::Name("DEC".to_string())),
+			(8, 0xe3, "USER", Operand::Name("set_pixel".to_string())),
+			(9, 0x02, "POP", Operand::Number(2)),
+			(10, 0xfe, "SPECIAL", Operand::Name("yield".to_string())),
+			(11, 0x40, "JMP", Operand::Target(0)),
+		];
+		assert_eq!(instructions.len(), expected.len() + 1);
+		for (instruction, (pc, opcode, mnemonic, operand)) in
+			instructions.iter().zip(expected.iter())
+		{
+			assert_eq!(instruction.pc, *pc);
+			assert_eq!(instruction.opcode, *opcode);
+			assert_eq!(instruction.mnemonic, *mnemonic);
+			assert_eq!(instruction.operands, vec![operand.clone()]);
+		}
+
+		// Decoding stops at an unknown instruction
+		assert_eq!(instructions[9].pc, 14);
+		assert_eq!(instructions[9].mnemonic, "unknown");
+
+		let json = serde_json::to_value(&instructions[2]).unwrap();
+		assert_eq!(
+			json,
+			serde_json::json!({"pc": 3, "opcode": 0x50, "mnemonic": "JZ", "operands": [{"type": "target", "value": 8}]})
+		);
+	}
+
+	#[test]
+	fn instructions() {
+		let sources = [
+			"loop{set_pixel(0, 255, 0, 0); blit; yield}",
+			"x = 1000000; for(i = get_length) { set_pixel(i, x >> 8, i * 3, get_pixel(i) & 255) }; blit",
+			"a = random(10); if(a > 5) { print(a) } else { sleep(a) }; dump",
+		];
+		for source in sources.iter() {
+			let mut program = Program::from_source(source).unwrap();
+			let encoded: Vec<u8> = program.instructions().flat_map(|i| i.encode()).collect();
+			assert_eq!(encoded, program.code);
+
+			program.make_position_independent();
+			let encoded: Vec<u8> = program.instructions().flat_map(|i| i.encode()).collect();
+			assert_eq!(encoded, program.code);
+		}
+
+		// Decoding stops at an unknown instruction
+		let program = Program::from_binary(vec![0x11, 0x05, 0xa0, 0x80]);
+		assert_eq!(
+			program.instructions().collect::<Vec<Instruction>>(),
+			vec![Instruction::PushB(vec![5])]
+		);
+	}
+
+	#[test]
+	fn disassemble_one() {
+		// pushb 5; pushi [0x01020304]; add; jmp 0; pop 1
+		let program = Program::from_binary(vec![
+			0x11, 0x05, 0x31, 0x04, 0x03, 0x02, 0x01, 0x80, 0x40, 0x00, 0x00, 0x01,
+		]);
+		let mut pc = 0;
+		let mut decoded = vec![];
+		while let Some((instruction, length)) = program.disassemble_one(pc) {
+			decoded.push(instruction.to_string());
+			pc += length;
+		}
+		assert_eq!(pc, program.byte_len());
+		assert_eq!(
+			decoded,
+			vec![
+				"PUSHB\t[05]",
+				"PUSHI\t[04, 03, 02, 01]",
+				"BINARY\tADD",
+				"JMP\tto 0",
+				"POP\t1"
+			]
+		);
+		assert_eq!(program.disassemble_one(program.byte_len()), None);
+
+		// Operands that overrun the code occupy the remaining bytes
+		let program = Program::from_binary(vec![0x31, 0x04, 0x03]);
+		let (instruction, length) = program.disassemble_one(0).unwrap();
+		assert_eq!(length, 3);
+		assert!(instruction.is_invalid());
+		assert!(instruction.to_string().starts_with("PUSHI\t(invalid"));
+		let (instruction, length) = Program::from_binary(vec![0xa0]).disassemble_one(0).unwrap();
+		assert_eq!((instruction.mnemonic.as_str(), length), ("unknown", 1));
+		assert!(!instruction.is_invalid());
+	}
+
+	#[test]
+	fn debug_info() {
+		let source = "a = 1; b = 2; if(a) { c = 3; set_pixel(a, b, c, 0) }";
+		let program = Program::new().with_debug_info().compile(source).unwrap();
+		let symbols = program.symbols().unwrap();
+
+		// PUSHB 1; PUSHB 2; PEEK 1 (a)
+		assert_eq!(program.code[4], Prefix::PEEK as u8 | 1);
+		assert_eq!(symbols[&4]["a"], 1);
+		assert_eq!(symbols[&4]["b"], 0);
+		assert_eq!(program.variable_at(4, 1), Some("a"));
+
+		// In the body of the if statement, the condition value sits between a, b and c (PEEK 3 at 10 loads a)
+		assert_eq!(program.code[10], Prefix::PEEK as u8 | 3);
+		assert_eq!(symbols[&10]["a"], 3);
+		assert_eq!(symbols[&10]["c"], 0);
+
+		// Debug information does not change the code
+		let plain = Program::from_source(source).unwrap();
+		assert!(plain.symbols().is_none());
+		assert_eq!(plain.code, program.code);
+	}
+
+	#[test]
+	fn source_lines() {
+		let source = "a = 1;\nb = 2;\n\nset_pixel(a, b, 0, 0);\nloop {\n\tyield\n}";
+		let program = Program::new().with_debug_info().compile(source).unwrap();
+		let position = |opcode: u8| program.code.iter().position(|c| *c == opcode).unwrap();
+
+		let set_pixel = position(Prefix::USER as u8 | UserCommand::SET_PIXEL as u8);
+		assert_eq!(program.source_line(set_pixel), Some(4));
+		assert_eq!(program.source_line(0), Some(1));
+		assert_eq!(program.source_line(2), Some(2));
+
+		// The jump back to the start of the loop belongs to the loop statement, the yield to the statement inside it
+		let yield_ = position(Prefix::SPECIAL as u8 | Special::YIELD as u8);
+		assert_eq!(program.source_line(yield_), Some(6));
+		assert_eq!(program.source_line(yield_ + 1), Some(5));
+		assert_eq!(program.code[yield_ + 1], Prefix::JMP as u8);
+
+		assert_eq!(Program::from_source(source).unwrap().source_line(0), None);
+	}
+
+	#[test]
+	fn shadow_warning() {
+		let source = "x = 1; loop { for(x = 3) { set_pixel(x, 0, 0, 0) }; for(i = 2) { yield } }";
+		let (_, warnings) = Program::new().compile_with_warnings(source).unwrap();
+		assert_eq!(warnings, vec![Warning::ShadowedVariable("x".to_string())]);
+
+		// Nested for loops have hidden counters with the same name, which do not count
+		let (_, warnings) = Program::new()
+			.compile_with_warnings("for(i = 3) { for(j = 2) { yield } }")
+			.unwrap();
+		assert!(warnings.is_empty());
+	}
+
+	#[test]
+	fn relative_jumps() {
+		// pushb 1; jzr +4; pop 1; jmpr -6
+		let program =
+			Program::from_binary(vec![0x11, 0x01, 0x51, 0x04, 0x00, 0x01, 0x41, 0xfa, 0xff]);
+		assert!(program.validate().is_ok());
+		let instructions = program.to_instructions();
+		assert_eq!(instructions[1].mnemonic, "JZR");
+		assert_eq!(instructions[1].operands, vec![Operand::Target(6)]);
+		assert_eq!(instructions[3].mnemonic, "JMPR");
+		assert_eq!(instructions[3].operands, vec![Operand::Target(0)]);
+
+		// Jumps before the start of the program are invalid
+		assert!(Program::from_binary(vec![0x41, 0xff, 0xff])
+			.validate()
+			.is_err());
+
+		let mut absolute =
+			Program::from_binary(vec![0x11, 0x01, 0x50, 0x06, 0x00, 0x01, 0x40, 0x00, 0x00]);
+		absolute.make_position_independent();
+		assert_eq!(absolute.code, program.code);
+	}
+
+	#[test]
+	fn from_source() {
+		let program = Program::from_source("loop{set_pixel(0, 255, 0, 0);blit;yield}").unwrap();
+		assert!(!program.code.is_empty());
+		assert!(Program::from_source("loop{").is_err());
+	}
+
+	#[test]
+	fn from_expression() {
+		let result = |source: &str| {
+			let program = Program::from_expression(source).unwrap();
+			let mut vm = VM::new(Box::new(DummyStrip::new(5, false)));
+			let mut state = vm.start(program, None);
+			assert!(matches!(state.run(None), Outcome::Ended));
+			state.stack().to_vec()
+		};
+		assert_eq!(result("1 + 2 * 3"), vec![7]);
+		assert_eq!(result(" get_length * (2 + 1) "), vec![15]);
+		assert_eq!(result("red(rgb(get_length, 1, 2)) == 5"), vec![1]);
+
+		assert!(Program::from_expression("1 +").is_err());
+		assert!(Program::from_expression("1; 2").is_err());
+	}
+
+	#[test]
+	fn max_stack_depth() {
+		// Three values are on the stack before MUL and ADD reduce them
+		let program = Program::from_source("x = get_length + get_length * get_length").unwrap();
+		assert_eq!(program.max_stack_depth(), 3);
+
+		// clamp keeps value, bound and two copies of both on the stack to compare them
+		let program = Program::from_source("x = clamp(get_length, 1, 5)").unwrap();
+		assert_eq!(program.max_stack_depth(), 4);
+
+		// Variables defined in a loop add to the depth of the enclosing scope
+		let program =
+			Program::from_source("a = get_length; loop { b = a + get_length; yield }").unwrap();
+		assert_eq!(program.max_stack_depth(), 3);
+	}
+
+	fn optimized(code: &[u8]) -> Vec<u8> {
+		let mut program = Program::from_binary(code.to_vec());
+		program.optimize();
+		program.code
+	}
+
+	#[test]
+	fn optimize() {
+		// PUSHB 0, POP 1
+		assert_eq!(optimized(&[0x10, 0x01]), Vec::<u8>::new());
+		// PUSHB 5, PEEK 0, POP 2 => PUSHB 5, POP 1 => (nothing)
+		assert_eq!(optimized(&[0x11, 0x05, 0x20, 0x02]), Vec::<u8>::new());
+		// POP 0 (nop) is kept
+		assert_eq!(optimized(&[0xe0, 0x00, 0xe4]), vec![0xe0, 0x00, 0xe4]);
+		// PUSHI 1, POP 3 => POP 2
+		assert_eq!(optimized(&[0x31, 1, 2, 3, 4, 0x03]), vec![0x02]);
+		// POP 1, POP 2 => POP 3, but POP 15 cannot be merged
+		assert_eq!(optimized(&[0x01, 0x02]), vec![0x03]);
+		assert_eq!(optimized(&[0x0f, 0x01]), vec![0x0f, 0x01]);
+		// PUSHB 2 pushes two values and is left alone
+		assert_eq!(optimized(&[0x12, 1, 2, 0x01]), vec![0x12, 1, 2, 0x01]);
+		// NOT, NOT
+		assert_eq!(optimized(&[0xe2, 0x72, 0x72, 0xe4]), vec![0xe2, 0xe4]);
+		// PUSHB 0, ADD and PUSHB [0], OR
+		assert_eq!(optimized(&[0xe0, 0x10, 0x80, 0x11, 0x00, 0x86]), vec![0xe0]);
+		// PUSHB 0, MUL is not an identity
+		assert_eq!(optimized(&[0xe0, 0x10, 0x83]), vec![0xe0, 0x10, 0x83]);
+	}
+
+	#[test]
+	fn optimize_jumps() {
+		// Jump targets after removed code are moved
+		assert_eq!(
+			optimized(&[0xe0, 0x10, 0x01, 0x50, 0x07, 0x00, 0xe4, 0xe4, 0x40, 0x00, 0x00]),
+			vec![0xe0, 0x50, 0x05, 0x00, 0xe4, 0xe4, 0x40, 0x00, 0x00]
+		);
+
+		// Jumps to removed code land on the instruction that follows it; jumps to the end remain at the end
+		assert_eq!(
+			optimized(&[0xe0, 0x50, 0x04, 0x00, 0x10, 0x01, 0x60, 0x0a, 0x00, 0xe4]),
+			vec![0xe0, 0x50, 0x04, 0x00, 0x60, 0x08, 0x00, 0xe4]
+		);
+		assert_eq!(
+			optimized(&[0xe0, 0x50, 0x07, 0x00, 0x10, 0x01, 0xe4]),
+			vec![0xe0, 0x50, 0x05, 0x00, 0xe4]
+		);
+
+		// Sequences are left alone when a jump lands in between
+		let code = vec![0xe0, 0x10, 0x01, 0x50, 0x02, 0x00];
+		assert_eq!(optimized(&code), code);
+	}
+
+	#[test]
+	fn eliminate_dead_code() {
+		// Nothing after an unconditional jump back is reachable
+		let mut program = Program::from_binary(vec![
+			0xe0, 0xfe, 0x40, 0x00, 0x00, // 0: get_length; yield; jmp 0
+			0x11, 0x05, 0xe4, // 5: unreachable
+		]);
+		program.eliminate_dead_code();
+		assert_eq!(program.code, vec![0xe0, 0xfe, 0x40, 0x00, 0x00]);
+
+		// The target of a conditional jump is preserved (even if it is never actually taken), and jumps across
+		// removed code are updated
+		let mut program = Program::from_binary(vec![
+			0x11, 0x01, 0x50, 0x09, 0x00, // 0: push 1; jz 9
+			0x40, 0x0b, 0x00, // 5: jmp 11
+			0xe4, // 8: unreachable
+			0x11, 0x02, // 9: push 2 (reachable, but never taken)
+			0x01, // 11: pop 1
+		]);
+		program.eliminate_dead_code();
+		assert_eq!(
+			program.code,
+			vec![0x11, 0x01, 0x50, 0x08, 0x00, 0x40, 0x0a, 0x00, 0x11, 0x02, 0x01]
+		);
+	}
+
+	#[test]
+	fn optimize_from_source() {
+		let source = "a = 1; a; if(a) { a = !!a + 0 }; a";
+		let double_not = |p: &Program| {
+			p.code
+				.windows(2)
+				.any(|w| w == [Prefix::UNARY as u8 | Unary::NOT as u8; 2])
+		};
+
+		// Code is only optimized on request
+		let plain = Program::from_source(source).unwrap();
+		assert!(double_not(&plain));
+
+		// Program behavior does not change (stack size is still tracked correctly)
+		let optimized = Program::new().with_optimization().compile(source).unwrap();
+		assert!(optimized.validate().is_ok());
+		assert!(!double_not(&optimized));
+		assert!(optimized.code.len() < plain.code.len());
+
+		let expression = Program::new()
+			.with_optimization()
+			.compile_expression("!!1 + 0")
+			.unwrap();
+		assert!(!double_not(&expression));
+	}
+}
@@ -4,6 +4,8 @@ use hmacsha1::hmac_sha1;
 use eui48::MacAddress;
 use std::convert::TryInto;
 use std::error::Error;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::time::SystemTime;
 
 #[derive(Debug)]
@@ -13,6 +15,7 @@ pub enum MessageType {
 	Pong,
 	Set,
 	Run,
+	Secret, // Replaces the secret of the device by the payload (signed using the old secret)
 	Unknown,
 }
 
@@ -23,6 +26,7 @@ impl MessageType {
 			0x02 => MessageType::Pong,
 			0x03 => MessageType::Set,
 			0x04 => MessageType::Run,
+			0x05 => MessageType::Secret,
 			_ => MessageType::Unknown,
 		}
 	}
@@ -35,6 +39,7 @@ impl From<&MessageType> for u8 {
 			MessageType::Pong => 0x02,
 			MessageType::Set => 0x03,
 			MessageType::Run => 0x04,
+			MessageType::Secret => 0x05,
 			_ => panic!("invalid message type"),
 		}
 	}
@@ -45,6 +50,62 @@ pub enum MessageError {
 	SignatureInvalid,
 	MessageTooShort,
 	MacAddressInvalid,
+	PayloadInvalid,
+}
+
+/* Device parameter that can be changed using a Set message without changing the running program */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Setting {
+	FpsLimit(u32),    // Maximum number of frames per second (0 = no limit)
+	Brightness(u8),   // Global brightness of the strip (255 = full brightness)
+	StripLength(u32), // Number of LEDs (only applied by devices that can change the length of their strip)
+}
+
+const SETTING_FPS_LIMIT: u8 = 0x01;
+const SETTING_BRIGHTNESS: u8 = 0x02;
+const SETTING_STRIP_LENGTH: u8 = 0x03;
+const SETTING_SIZE: usize = 5;
+
+/* Keys of the fields in the payload of a Ping message that reports more than just the program hash */
+const PING_PROGRAM_HASH: u8 = 0x01;
+const PING_STRIP_LENGTH: u8 = 0x02;
+
+impl Setting {
+	/* Encodes settings as the payload of a Set message: for each setting a key byte, followed by the value as
+	32-bit little-endian integer */
+	pub fn encode(settings: &[Setting]) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(settings.len() * SETTING_SIZE);
+		for setting in settings {
+			let (key, value) = match setting {
+				Setting::FpsLimit(v) => (SETTING_FPS_LIMIT, *v),
+				Setting::Brightness(v) => (SETTING_BRIGHTNESS, u32::from(*v)),
+				Setting::StripLength(v) => (SETTING_STRIP_LENGTH, *v),
+			};
+			buf.push(key);
+			buf.write_u32::<LittleEndian>(value).unwrap();
+		}
+		buf
+	}
+
+	/* Decodes the payload of a Set message. Settings with an unknown key are skipped. */
+	pub fn decode(payload: &[u8]) -> Result<Vec<Setting>, MessageError> {
+		if !payload.len().is_multiple_of(SETTING_SIZE) {
+			return Err(MessageError::PayloadInvalid);
+		}
+
+		Ok(payload
+			.chunks(SETTING_SIZE)
+			.filter_map(|chunk| {
+				let value = u32::from_le_bytes(chunk[1..SETTING_SIZE].try_into().unwrap());
+				match chunk[0] {
+					SETTING_FPS_LIMIT => Some(Setting::FpsLimit(value)),
+					SETTING_BRIGHTNESS => Some(Setting::Brightness(value.min(255) as u8)),
+					SETTING_STRIP_LENGTH => Some(Setting::StripLength(value)),
+					_ => None,
+				}
+			})
+			.collect())
+	}
 }
 
 #[allow(dead_code)]
@@ -61,20 +122,176 @@ const MAC_SIZE: usize = 6;
 const MESSAGE_TYPE_SIZE: usize = 1;
 const TIME_SIZE: usize = 4;
 
+/// Size of the buffers used to receive messages
+pub const MAX_MESSAGE_SIZE: usize = 1500;
+
+/// Largest configurable receive buffer size (for networks that support jumbo frames)
+pub const MAX_JUMBO_MESSAGE_SIZE: usize = 9000;
+
+/// Size of a message without payload
+const MIN_MESSAGE_SIZE: usize = MAC_SIZE + TIME_SIZE + MESSAGE_TYPE_SIZE + SHA1_SIZE;
+
+/* Determines the size of the buffer used to receive messages from the configured MTU (or the default when none is
+configured). The buffer must at least fit an empty message and cannot exceed the jumbo frame size. */
+pub fn buffer_size(mtu: Option<usize>) -> Result<usize, String> {
+	match mtu {
+		None => Ok(MAX_MESSAGE_SIZE),
+		Some(m) if m < MIN_MESSAGE_SIZE => Err(format!(
+			"MTU of {} bytes is too small to hold a message (minimum is {})",
+			m, MIN_MESSAGE_SIZE
+		)),
+		Some(m) if m > MAX_JUMBO_MESSAGE_SIZE => Err(format!(
+			"MTU of {} bytes is too large (maximum is {})",
+			m, MAX_JUMBO_MESSAGE_SIZE
+		)),
+		Some(m) => Ok(m),
+	}
+}
+
+/// How datagrams sent to an address are delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressKind {
+	Unicast,
+	MulticastV4,
+	MulticastV6,
+}
+
+impl AddressKind {
+	pub fn of(address: &SocketAddr) -> AddressKind {
+		match address.ip() {
+			IpAddr::V4(ip) if ip.is_multicast() => AddressKind::MulticastV4,
+			IpAddr::V6(ip) if ip.is_multicast() => AddressKind::MulticastV6,
+			_ => AddressKind::Unicast,
+		}
+	}
+}
+
+/* Resolves an address string such as "0.0.0.0:33333", "[::1]:33333" or "localhost:33333" to the first socket address
+it refers to. */
+pub fn resolve_address(address: &str) -> io::Result<SocketAddr> {
+	address.to_socket_addrs()?.next().ok_or_else(|| {
+		io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("address {} does not resolve to anything", address),
+		)
+	})
+}
+
+/* Binds a UDP socket to `bind_address`. When the bind address is a multicast group, the socket is bound to the
+unspecified address of the same family and port instead (binding to a group address is not portable) and joins the
+group. When `group` is a multicast address (e.g. the server address a client sends to) the socket joins it as well. */
+pub fn bind_socket(bind_address: &str, group: Option<SocketAddr>) -> io::Result<UdpSocket> {
+	let bind_address = resolve_address(bind_address)?;
+	let socket = match AddressKind::of(&bind_address) {
+		AddressKind::Unicast => UdpSocket::bind(bind_address)?,
+		AddressKind::MulticastV4 => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, bind_address.port()))?,
+		AddressKind::MulticastV6 => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, bind_address.port()))?,
+	};
+
+	for address in std::iter::once(bind_address).chain(group) {
+		match (AddressKind::of(&address), address.ip()) {
+			(AddressKind::MulticastV4, IpAddr::V4(ip)) => {
+				log::info!("Joining multicast group {}", ip);
+				socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+			}
+			(AddressKind::MulticastV6, IpAddr::V6(ip)) => {
+				log::info!("Joining multicast group {}", ip);
+				socket.join_multicast_v6(&ip, 0)?;
+			}
+			_ => {}
+		}
+	}
+	Ok(socket)
+}
+
+/// Largest payload that still fits in a single message
+pub const MAX_PAYLOAD_SIZE: usize =
+	MAX_MESSAGE_SIZE - MAC_SIZE - TIME_SIZE - MESSAGE_TYPE_SIZE - SHA1_SIZE;
+
 impl Message {
+	/* Creates a message timestamped with the current time (in seconds since the UNIX epoch) */
 	pub fn new(
 		message_type: MessageType,
 		address: MacAddress,
 		payload: Option<&[u8]>,
 	) -> Result<Message, Box<dyn Error>> {
-		Ok(Message {
+		let unix_time = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)?
+			.as_secs() as u32;
+		Ok(Message::new_with_time(
+			message_type,
+			address,
+			payload,
+			unix_time,
+		))
+	}
+
+	/* Creates a message with the given timestamp (e.g. to reply with the time of the message being answered) */
+	pub fn new_with_time(
+		message_type: MessageType,
+		address: MacAddress,
+		payload: Option<&[u8]>,
+		unix_time: u32,
+	) -> Message {
+		Message {
 			mac_address: address,
 			message_type,
 			payload: payload.map(|x| x.to_vec()),
-			unix_time: SystemTime::now()
-				.duration_since(SystemTime::UNIX_EPOCH)?
-				.as_secs() as u32,
-		})
+			unix_time,
+		}
+	}
+
+	/* Creates a Ping message. The payload carries the hash (see Program::code_hash) of the program the device
+	currently runs, if any, so the server can skip re-sending it. When the length of the strip of the device is
+	reported, the payload consists of fields like the payload of a Set message (a key byte followed by a 32-bit
+	little-endian value), which cannot be confused with the four-byte payload carrying just the program hash (as sent
+	by devices that do not report their strip length). */
+	pub fn ping(
+		address: MacAddress,
+		program_hash: Option<u32>,
+		strip_length: Option<u32>,
+	) -> Result<Message, Box<dyn Error>> {
+		let strip_length = match strip_length {
+			Some(l) => l,
+			None => {
+				let payload = program_hash.map(|h| h.to_le_bytes());
+				return Message::new(MessageType::Ping, address, payload.as_ref().map(|p| &p[..]));
+			}
+		};
+
+		let mut payload = vec![];
+		if let Some(hash) = program_hash {
+			payload.push(PING_PROGRAM_HASH);
+			payload.write_u32::<LittleEndian>(hash)?;
+		}
+		payload.push(PING_STRIP_LENGTH);
+		payload.write_u32::<LittleEndian>(strip_length)?;
+		Message::new(MessageType::Ping, address, Some(&payload))
+	}
+
+	/* Returns the program hash carried by a Ping message */
+	pub fn ping_program_hash(&self) -> Option<u32> {
+		match (&self.message_type, &self.payload) {
+			(MessageType::Ping, Some(p)) if p.len() == 4 => {
+				Some(u32::from_le_bytes(p[0..4].try_into().unwrap()))
+			}
+			_ => self.ping_field(PING_PROGRAM_HASH),
+		}
+	}
+
+	/* Returns the strip length reported in a Ping message */
+	pub fn ping_strip_length(&self) -> Option<u32> {
+		self.ping_field(PING_STRIP_LENGTH)
+	}
+
+	fn ping_field(&self, key: u8) -> Option<u32> {
+		match (&self.message_type, &self.payload) {
+			(MessageType::Ping, Some(p)) if p.len().is_multiple_of(SETTING_SIZE) => p
+				.chunks(SETTING_SIZE)
+				.find(|field| field[0] == key)
+				.map(|field| u32::from_le_bytes(field[1..SETTING_SIZE].try_into().unwrap())),
+			_ => None,
+		}
 	}
 
 	// Wire format is [MAC: 6] [TIME: 4] [TYPE: 1] .... [SHA1: 20]
@@ -126,7 +343,8 @@ impl Message {
 
 	pub fn signed(&self, key: &[u8]) -> Vec<u8> {
 		let data_size = MAC_SIZE
-			+ TIME_SIZE + MESSAGE_TYPE_SIZE
+			+ TIME_SIZE
+			+ MESSAGE_TYPE_SIZE
 			+ match &self.message_type {
 				MessageType::Ping => 0,
 				MessageType::Pong => 0,
@@ -151,3 +369,113 @@ impl Message {
 		buf
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mtu_buffer_size() {
+		assert_eq!(buffer_size(None), Ok(1500));
+		assert_eq!(buffer_size(Some(9000)), Ok(9000));
+		assert_eq!(buffer_size(Some(MIN_MESSAGE_SIZE)), Ok(MIN_MESSAGE_SIZE));
+		assert!(buffer_size(Some(MIN_MESSAGE_SIZE - 1)).is_err());
+		assert!(buffer_size(Some(9001)).is_err());
+	}
+
+	#[test]
+	fn address_kind() {
+		let kind = |a: &str| AddressKind::of(&resolve_address(a).unwrap());
+		assert_eq!(kind("127.0.0.1:33333"), AddressKind::Unicast);
+		assert_eq!(kind("0.0.0.0:33332"), AddressKind::Unicast);
+		assert_eq!(kind("255.255.255.255:33333"), AddressKind::Unicast);
+		assert_eq!(kind("224.0.0.1:33333"), AddressKind::MulticastV4);
+		assert_eq!(kind("239.255.255.250:1900"), AddressKind::MulticastV4);
+		assert_eq!(kind("[::1]:33333"), AddressKind::Unicast);
+		assert_eq!(kind("[::]:33333"), AddressKind::Unicast);
+		assert_eq!(kind("[fe80::1]:33333"), AddressKind::Unicast);
+		assert_eq!(kind("[ff02::1]:33333"), AddressKind::MulticastV6);
+		assert_eq!(kind("[ff05::1:3]:547"), AddressKind::MulticastV6);
+
+		assert!(resolve_address("[::1]").is_err());
+		assert!(resolve_address("not an address").is_err());
+	}
+
+	#[test]
+	fn bind_unicast_socket() {
+		let socket = bind_socket("127.0.0.1:0", resolve_address("127.0.0.1:33333").ok()).unwrap();
+		assert!(socket.local_addr().unwrap().ip().is_loopback());
+	}
+
+	#[test]
+	fn settings() {
+		let settings = vec![
+			Setting::FpsLimit(30),
+			Setting::Brightness(128),
+			Setting::StripLength(60),
+		];
+		let payload = Setting::encode(&settings);
+		assert_eq!(
+			payload,
+			vec![0x01, 30, 0, 0, 0, 0x02, 128, 0, 0, 0, 0x03, 60, 0, 0, 0]
+		);
+		assert_eq!(Setting::decode(&payload).unwrap(), settings);
+
+		// Unknown settings are skipped, truncated payloads are rejected
+		assert_eq!(
+			Setting::decode(&[0xFF, 1, 2, 3, 4, 0x02, 64, 0, 0, 0]).unwrap(),
+			vec![Setting::Brightness(64)]
+		);
+		assert!(Setting::decode(&payload[0..7]).is_err());
+
+		let message = Message::new(MessageType::Set, MacAddress::nil(), Some(&payload)).unwrap();
+		let decoded = Message::from_buffer(&message.signed(b"secret"), b"secret").unwrap();
+		assert!(matches!(decoded.message_type, MessageType::Set));
+		assert_eq!(
+			Setting::decode(&decoded.payload.unwrap()).unwrap(),
+			settings
+		);
+	}
+
+	#[test]
+	fn timestamps() {
+		let now = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap()
+			.as_secs() as u32;
+		let message = Message::new(MessageType::Ping, MacAddress::nil(), None).unwrap();
+		assert!(message.unix_time >= now && message.unix_time <= now + 1);
+
+		let message =
+			Message::new_with_time(MessageType::Run, MacAddress::nil(), Some(&[1, 2]), 1234);
+		assert_eq!(message.unix_time, 1234);
+		assert_eq!(message.payload, Some(vec![1, 2]));
+		let decoded = Message::from_buffer(&message.signed(b"secret"), b"secret").unwrap();
+		assert_eq!(decoded.unix_time, 1234);
+	}
+
+	#[test]
+	fn ping_program_hash() {
+		let ping = Message::ping(MacAddress::nil(), Some(0x1234_5678), None).unwrap();
+		let decoded = Message::from_buffer(&ping.signed(b"secret"), b"secret").unwrap();
+		assert_eq!(decoded.ping_program_hash(), Some(0x1234_5678));
+
+		let ping = Message::ping(MacAddress::nil(), None, None).unwrap();
+		let decoded = Message::from_buffer(&ping.signed(b"secret"), b"secret").unwrap();
+		assert_eq!(decoded.ping_program_hash(), None);
+		assert_eq!(decoded.ping_strip_length(), None);
+	}
+
+	#[test]
+	fn ping_strip_length() {
+		let ping = Message::ping(MacAddress::nil(), Some(0x1234_5678), Some(60)).unwrap();
+		let decoded = Message::from_buffer(&ping.signed(b"secret"), b"secret").unwrap();
+		assert_eq!(decoded.ping_program_hash(), Some(0x1234_5678));
+		assert_eq!(decoded.ping_strip_length(), Some(60));
+
+		let ping = Message::ping(MacAddress::nil(), None, Some(10)).unwrap();
+		let decoded = Message::from_buffer(&ping.signed(b"secret"), b"secret").unwrap();
+		assert_eq!(decoded.ping_program_hash(), None);
+		assert_eq!(decoded.ping_strip_length(), Some(10));
+	}
+}
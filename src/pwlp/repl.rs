@@ -0,0 +1,101 @@
+use super::ast::Scope;
+use super::parser;
+use super::program::Program;
+use super::vm::{Outcome, State, VM};
+
+/* The maximum number of instructions a single line may execute (protects against endless loops) */
+const LINE_INSTRUCTION_LIMIT: usize = 1_000_000;
+
+/* Compiles and executes statements one line at a time. All lines run in a single VM state, so that variables and
+the contents of the strip persist between lines. The code for each line is appended to the program and executed
+from where the previous line ended. */
+pub struct Repl<'a> {
+	state: State<'a>,
+	scope: Scope<'static>,
+}
+
+impl<'a> Repl<'a> {
+	pub fn new(vm: &'a mut VM) -> Repl<'a> {
+		Repl {
+			state: vm.start(Program::new(), None),
+			scope: Scope::new(),
+		}
+	}
+
+	pub fn state(&mut self) -> &mut State<'a> {
+		&mut self.state
+	}
+
+	/* Compiles and runs the statements in `source` until they end. When compilation or execution fails, the
+	variables defined by the line are discarded. */
+	pub fn eval(&mut self, source: &str) -> Result<(), String> {
+		let node = parser::parse(source)?;
+		let mut scope = self.scope.clone();
+		// Errors are shared with the scopes of earlier lines, which may have failed as well
+		let earlier_errors = scope.errors().len();
+		let mut fragment = Program::new();
+		fragment.offset = self.state.program().code.len();
+		fragment.stack_size = self.state.stack().len() as i32;
+		node.assemble(&mut fragment, &mut scope);
+		if let Some(error) = scope.errors().into_iter().nth(earlier_errors) {
+			return Err(error);
+		}
+
+		let stack_depth = self.state.stack().len();
+		let limit = self.state.instruction_count() + LINE_INSTRUCTION_LIMIT;
+		self.state.append(&fragment.code);
+		loop {
+			let remaining = limit.saturating_sub(self.state.instruction_count());
+			let error = match self.state.run(Some(remaining)) {
+				Outcome::Yielded | Outcome::SleepRequested(_) => continue,
+				Outcome::Ended => {
+					self.scope = scope;
+					return Ok(());
+				}
+				Outcome::GlobalInstructionLimitReached | Outcome::LocalInstructionLimitReached => {
+					"instruction limit reached".to_string()
+				}
+				Outcome::Error(e) => format!("error at pc={}: {}", self.state.pc(), e),
+			};
+			self.state.abandon(stack_depth);
+			return Err(error);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pwlp::strip::DummyStrip;
+
+	#[test]
+	fn persistent_state() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(2, false)));
+		let mut repl = Repl::new(&mut vm);
+		repl.eval("x = 5").unwrap();
+		repl.eval("set_pixel(0, x, 0, 0)").unwrap();
+		repl.eval("blit").unwrap();
+		assert_eq!(repl.state().vm.strip().get_pixel(0).r, 5);
+
+		repl.eval("x += 1; y = x * 2").unwrap();
+		repl.eval("set_pixel(1, x, y, 0)").unwrap();
+		let pixel = repl.state().vm.strip().get_pixel(1);
+		assert_eq!((pixel.r, pixel.g), (6, 12));
+		assert_eq!(repl.state().stack(), &[6, 12]);
+	}
+
+	#[test]
+	fn errors() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut repl = Repl::new(&mut vm);
+		repl.eval("x = 5").unwrap();
+		assert!(repl.eval("x = (").is_err());
+		assert!(repl.eval("y = 1; loop { yield }").is_err());
+		assert!(repl.eval("set_pixel(0, y, 0, 0)").is_err());
+
+		// Variables defined by failed lines are discarded, others remain intact
+		assert_eq!(repl.state().stack(), &[5]);
+		repl.eval("y = x + 1; set_pixel(0, y, 0, 0)").unwrap();
+		assert_eq!(repl.state().vm.strip().get_pixel(0).r, 6);
+	}
+}
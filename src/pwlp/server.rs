@@ -1,11 +1,22 @@
 use super::program::Program;
-use super::protocol::{Message, MessageType};
+use super::protocol::{bind_socket, Message, MessageType, MAX_MESSAGE_SIZE};
+use super::transport::Transport;
 use eui48::MacAddress;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Default time after which a device that has not sent a message is considered offline
+pub const DEFAULT_OFFLINE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Default time the server waits for a message before it wakes up to perform periodic work
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DeviceConfig {
@@ -13,6 +24,25 @@ pub struct DeviceConfig {
 	secret: Option<String>,
 }
 
+/* Configuration shared by all devices whose MAC address starts with the prefix (e.g. "5c-cf-7f" for all devices of a
+vendor). Device-specific configuration takes precedence. */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupConfig {
+	prefix: String,
+	program: Option<String>,
+}
+
+impl GroupConfig {
+	/* The prefix in the canonical MAC address format (lower case, separated by dashes) */
+	fn canonical_prefix(&self) -> String {
+		self.prefix.to_lowercase().replace(':', "-")
+	}
+
+	fn matches(&self, canonical_mac: &str) -> bool {
+		canonical_mac.starts_with(&self.canonical_prefix())
+	}
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct DeviceStatus {
 	pub address: SocketAddr,
@@ -21,8 +51,41 @@ pub struct DeviceStatus {
 	#[serde(skip)]
 	pub secret: String,
 
+	/* New secret that was sent to the device, but that it has not used to sign a message yet. Until it does, messages
+	signed using `secret` are accepted as well (the message carrying the new secret may have been lost). */
+	#[serde(skip)]
+	pub pending_secret: Option<String>,
+
 	#[serde(skip)]
 	pub last_seen: Instant,
+
+	/* Hash (see Program::code_hash) of the program that was last sent to the device */
+	#[serde(skip)]
+	pub sent_program_hash: Option<u32>,
+
+	/* Length of the strip of the device, when reported in its Ping messages */
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub strip_length: Option<u32>,
+
+	pub online: bool,
+}
+
+impl DeviceStatus {
+	pub fn is_online(&self, timeout: Duration) -> bool {
+		self.last_seen.elapsed() < timeout
+	}
+
+	/* Warns when the program sets a pixel (at a constant index) beyond the end of the strip of the device */
+	pub fn strip_length_warning(&self, program: &Program) -> Option<String> {
+		let length = self.strip_length?;
+		match program.max_static_pixel_index() {
+			Some(index) if index >= length => Some(format!(
+				"program sets pixel {} but the strip of the device has only {} pixels",
+				index, length
+			)),
+			_ => None,
+		}
+	}
 }
 
 impl Serialize for Program {
@@ -31,16 +94,195 @@ impl Serialize for Program {
 	}
 }
 
+struct ProgramVisitor;
+
+impl<'de> Visitor<'de> for ProgramVisitor {
+	type Value = Program;
+
+	fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+		formatter.write_str("program code as a sequence of bytes")
+	}
+
+	fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Program, E> {
+		Ok(Program::from_binary(v.to_vec()))
+	}
+
+	fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Program, E> {
+		Ok(Program::from_binary(v))
+	}
+
+	fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Program, A::Error> {
+		let mut code = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+		while let Some(byte) = seq.next_element::<u8>()? {
+			code.push(byte);
+		}
+		Ok(Program::from_binary(code))
+	}
+}
+
+impl<'de> Deserialize<'de> for Program {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Program, D::Error> {
+		deserializer.deserialize_byte_buf(ProgramVisitor)
+	}
+}
+
+/// Sent to subscribers whenever the status of a device changes
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceEvent {
+	pub device: String,
+	pub status: DeviceStatus,
+}
+
+/// Counters describing the traffic handled by the server (exposed by the HTTP API at /metrics)
+#[derive(Debug, Default)]
+pub struct Metrics {
+	pub messages_received: AtomicU64,
+	pub signature_failures: AtomicU64,
+	pub pings: AtomicU64,
+	pub runs_sent: AtomicU64,
+	pub previews_started: AtomicU64,
+	pub previews_finished: AtomicU64,
+}
+
+impl Metrics {
+	pub(crate) fn increment(counter: &AtomicU64) {
+		counter.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/* Renders the counters in the Prometheus text exposition format */
+	pub fn render(&self) -> String {
+		let counters = [
+			(
+				"pwlp_messages_received_total",
+				"Messages received from devices",
+				&self.messages_received,
+			),
+			(
+				"pwlp_signature_failures_total",
+				"Messages that could not be decoded or had an invalid signature",
+				&self.signature_failures,
+			),
+			("pwlp_pings_total", "Ping messages received", &self.pings),
+			(
+				"pwlp_runs_sent_total",
+				"Run messages sent in reply to pings",
+				&self.runs_sent,
+			),
+			(
+				"pwlp_previews_started_total",
+				"Live previews started using the HTTP API",
+				&self.previews_started,
+			),
+			(
+				"pwlp_previews_finished_total",
+				"Live previews that stopped running",
+				&self.previews_finished,
+			),
+		];
+
+		let mut text = String::new();
+		for (name, help, value) in counters.iter() {
+			writeln!(text, "# HELP {} {}", name, help).unwrap();
+			writeln!(text, "# TYPE {} counter", name).unwrap();
+			writeln!(text, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+		}
+		text
+	}
+}
+
 pub struct ServerState {
 	pub config: HashMap<String, DeviceConfig>,
 	pub devices: HashMap<String, DeviceStatus>,
-	pub socket: UdpSocket,
+	pub socket: Box<dyn Transport>,
+	pub offline_timeout: Duration,
+	pub events: broadcast::Sender<DeviceEvent>,
+	pub heartbeat_interval: Option<Duration>,
+	pub last_heartbeat: Instant,
+	pub metrics: Arc<Metrics>,
+}
+
+impl ServerState {
+	pub fn new(config: HashMap<String, DeviceConfig>, socket: Box<dyn Transport>) -> ServerState {
+		let (events, _) = broadcast::channel(16);
+		ServerState {
+			config,
+			devices: HashMap::new(),
+			socket,
+			offline_timeout: DEFAULT_OFFLINE_TIMEOUT,
+			events,
+			heartbeat_interval: None,
+			last_heartbeat: Instant::now(),
+			metrics: Arc::new(Metrics::default()),
+		}
+	}
+
+	/* Stores the new status for a device and notifies subscribers of the change */
+	pub fn update_device(&mut self, device: String, status: DeviceStatus) {
+		// Sending only fails when there are no subscribers, which is fine
+		let _ = self.events.send(DeviceEvent {
+			device: device.clone(),
+			status: status.clone(),
+		});
+		self.devices.insert(device, status);
+	}
+
+	/* Returns the secret used to sign messages to and from the device: the (possibly rotated) secret of a known
+	device, otherwise the secret from the device configuration or the default secret. Rotated secrets are only kept in
+	memory: after a restart, the server uses the configured secret again. */
+	pub fn device_secret(&self, mac: &str, default_secret: &str) -> String {
+		if let Some(status) = self.devices.get(mac) {
+			return status.secret.clone();
+		}
+
+		match self.config.get(mac).and_then(|c| c.secret.as_ref()) {
+			Some(s) => s.clone(),
+			None => default_secret.to_string(),
+		}
+	}
+
+	/* Marks devices that have not been seen within the offline timeout as offline */
+	pub fn update_online_status(&mut self) {
+		let timeout = self.offline_timeout;
+		for status in self.devices.values_mut() {
+			status.online = status.is_online(timeout);
+		}
+	}
+
+	/* When the heartbeat interval has elapsed, returns signed Run messages that re-send the assigned program to
+	each online device (so that devices that missed the initial program eventually receive it) */
+	pub fn due_heartbeats(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+		let interval = match self.heartbeat_interval {
+			Some(i) => i,
+			None => return vec![],
+		};
+
+		if now.saturating_duration_since(self.last_heartbeat) < interval {
+			return vec![];
+		}
+		self.last_heartbeat = now;
+
+		let timeout = self.offline_timeout;
+		self.devices
+			.values()
+			.filter(|status| status.is_online(timeout))
+			.filter_map(|status| {
+				let program = status.program.as_ref()?;
+				let run = Message::new(MessageType::Run, MacAddress::nil(), Some(&program.code))
+					.expect("message construction failed");
+				Some((status.address, run.signed(status.secret.as_bytes())))
+			})
+			.collect()
+	}
 }
 
 pub struct Server {
 	state: Arc<Mutex<ServerState>>,
 	default_secret: String,
 	default_program: Program,
+	mtu: usize,
+	read_timeout: Duration,
+	metrics: Arc<Metrics>,
+	groups: HashMap<String, GroupConfig>,
 }
 
 impl Server {
@@ -50,155 +292,675 @@ impl Server {
 		default_program: Program,
 		bind_address: &str,
 	) -> std::io::Result<Server> {
-		Ok(Server {
-			state: Arc::new(Mutex::new(ServerState {
-				config: devices,
-				devices: HashMap::new(),
-				socket: UdpSocket::bind(bind_address)?,
-			})),
+		let socket = bind_socket(bind_address, None)?;
+		Ok(Server::with_transport(
+			devices,
+			default_secret,
+			default_program,
+			Box::new(socket),
+		))
+	}
+
+	/* Creates a server that communicates with devices through the given transport instead of a UDP socket */
+	pub fn with_transport(
+		devices: HashMap<String, DeviceConfig>,
+		default_secret: &str,
+		default_program: Program,
+		transport: Box<dyn Transport>,
+	) -> Server {
+		let state = ServerState::new(devices, transport);
+		Server {
+			metrics: state.metrics.clone(),
+			state: Arc::new(Mutex::new(state)),
 			default_secret: default_secret.to_string(),
 			default_program,
-		})
+			mtu: MAX_MESSAGE_SIZE,
+			read_timeout: DEFAULT_READ_TIMEOUT,
+			groups: HashMap::new(),
+		}
+	}
+
+	/* Sets the size of the buffer used to receive messages (see protocol::buffer_size) */
+	pub fn set_mtu(&mut self, mtu: usize) {
+		self.mtu = mtu;
+	}
+
+	/* Sets the maximum time to wait for a message before performing periodic work (such as sending heartbeats) */
+	pub fn set_read_timeout(&mut self, timeout: Duration) {
+		self.read_timeout = timeout;
+	}
+
+	/* Sets the groups (by name) that assign programs to devices by MAC address prefix */
+	pub fn set_groups(&mut self, groups: HashMap<String, GroupConfig>) {
+		self.groups = groups;
+	}
+
+	/* Returns the program path of the group the device belongs to. When the MAC address matches multiple groups, the
+	group with the longest prefix (and then the first name) wins. */
+	fn group_program(&self, canonical_mac: &str) -> Option<&String> {
+		self.groups
+			.iter()
+			.filter(|(_, g)| g.program.is_some() && g.matches(canonical_mac))
+			.min_by_key(|(name, g)| (std::cmp::Reverse(g.canonical_prefix().len()), name.as_str()))
+			.and_then(|(_, g)| g.program.as_ref())
 	}
 
 	pub fn state(&mut self) -> Arc<Mutex<ServerState>> {
 		self.state.clone()
 	}
 
-	pub fn run(&mut self) -> std::io::Result<()> {
-		let socket = {
-			let m = self.state.lock().unwrap();
-			m.socket.try_clone()?
+	pub fn set_offline_timeout(&mut self, timeout: Duration) {
+		self.state.lock().unwrap().offline_timeout = timeout;
+	}
+
+	/* Periodically re-sends the assigned program to all online devices */
+	pub fn set_heartbeat_interval(&mut self, interval: Option<Duration>) {
+		self.state.lock().unwrap().heartbeat_interval = interval;
+	}
+
+	/* Assigns a program to the device (when it has none yet) and returns the Run message to send in reply to a
+	Ping. Returns None when the device reports that it already runs the program that was last sent to it. The program
+	configured for the device takes precedence over the program of its group, which takes precedence over the default
+	program. */
+	fn run_message(
+		&self,
+		status: &mut DeviceStatus,
+		device_config: &Option<DeviceConfig>,
+		ping: &Message,
+	) -> Option<Message> {
+		let device_program = match status.program.take() {
+			Some(p) => p,
+			None => match device_config
+				.as_ref()
+				.and_then(|c| c.program.as_ref())
+				.or_else(|| self.group_program(&ping.mac_address.to_canonical()))
+			{
+				Some(path) => match Program::from_file(path) {
+					Ok(p) => p,
+					Err(e) => {
+						log::error!("{}; sending the default program instead", e);
+						self.default_program.clone()
+					}
+				},
+				None => self.default_program.clone(),
+			},
 		};
 
-		loop {
-			let mut buf = [0; 1500];
-			let (amt, source_address) = socket.recv_from(&mut buf)?;
-
-			match Message::peek_mac_address(&buf[0..amt]) {
-				Err(t) => log::error!("\tError reading MAC address: {:?}", t),
-				Ok(mac) => {
-					// Do we have a config for this mac?
-					let canonical_mac = mac.to_canonical();
-					let device_config: Option<DeviceConfig> = {
-						let m = self.state.lock().unwrap();
-						if m.config.contains_key(&canonical_mac) {
-							Some(m.config[&canonical_mac].clone())
-						} else {
-							None
-						}
-					};
-
-					// Find the secret to use to verify the message signature
-					let secret = match &device_config {
-						Some(d) => match &d.secret {
-							Some(s) => s.clone(),
-							None => self.default_secret.clone(),
-						},
-						None => self.default_secret.clone(),
-					};
-
-					// Decode message
-					match Message::from_buffer(&buf[0..amt], secret.as_bytes()) {
-						Err(t) => log::error!(
+		let hash = device_program.code_hash();
+		let payload = device_program.code.clone();
+		let warning = status.strip_length_warning(&device_program);
+		status.program = Some(device_program);
+
+		if ping.ping_program_hash() == Some(hash) && status.sent_program_hash == Some(hash) {
+			log::info!("Device already runs program {:08x}, not sending it", hash);
+			return None;
+		}
+
+		if let Some(warning) = warning {
+			log::warn!("Program {:08x}: {}", hash, warning);
+		}
+
+		status.sent_program_hash = Some(hash);
+		Some(Message::new_with_time(
+			MessageType::Run,
+			MacAddress::nil(),
+			Some(&payload),
+			ping.unix_time,
+		))
+	}
+
+	/* Sends a new secret to a device, signed using the secret the device currently uses */
+	fn send_secret(
+		&self,
+		socket: &dyn Transport,
+		new_secret: &str,
+		secret: &str,
+		address: SocketAddr,
+	) {
+		let msg = Message::new(
+			MessageType::Secret,
+			MacAddress::nil(),
+			Some(new_secret.as_bytes()),
+		)
+		.expect("message construction failed");
+		if let Err(t) = socket.send_to(&msg.signed(secret.as_bytes()), address) {
+			log::error!("Send secret to {} failed: {:?}", address, t);
+		}
+	}
+
+	/* Verifies and handles a single datagram received from a device, replying to it through the socket */
+	fn handle_datagram(&self, socket: &dyn Transport, data: &[u8], source_address: SocketAddr) {
+		Metrics::increment(&self.metrics.messages_received);
+		match Message::peek_mac_address(data) {
+			Err(t) => log::error!("\tError reading MAC address: {:?}", t),
+			Ok(mac) => {
+				// Do we have a config for this mac? Also find the secret to use to verify the message signature
+				let canonical_mac = mac.to_canonical();
+				let (device_config, secret, pending_secret) = {
+					let m = self.state.lock().unwrap();
+					(
+						m.config.get(&canonical_mac).cloned(),
+						m.device_secret(&canonical_mac, &self.default_secret),
+						m.devices
+							.get(&canonical_mac)
+							.and_then(|d| d.pending_secret.clone()),
+					)
+				};
+
+				// A device that received its new secret signs using it, otherwise it still uses the old secret
+				let (decoded, secret) = match pending_secret {
+					Some(pending) => match Message::from_buffer(data, pending.as_bytes()) {
+						Ok(msg) => (Ok(msg), pending),
+						Err(_) => (Message::from_buffer(data, secret.as_bytes()), secret),
+					},
+					None => (Message::from_buffer(data, secret.as_bytes()), secret),
+				};
+
+				// Decode message
+				match decoded {
+					Err(t) => {
+						Metrics::increment(&self.metrics.signature_failures);
+						log::error!(
 							"{} error {:?} (size={}b source={} secret={:?})",
 							source_address,
 							t,
-							amt,
+							data.len(),
 							mac,
 							secret
-						),
-						Ok(msg) => {
-							let mac_identifier = mac.to_canonical();
-							log::info!(
-								"{} @ {}: {:?} t={}",
-								&mac_identifier,
-								&source_address,
-								msg.message_type,
-								msg.unix_time
-							);
-
-							// Update or create device status
-							{
-								let mut m = self.state.lock().unwrap();
-								let mut new_status = match m.devices.get(&mac_identifier) {
-									Some(status) => (*status).clone(),
-									None => DeviceStatus {
-										address: source_address,
-										program: None,
-										secret: secret.clone(),
-										last_seen: Instant::now(),
-									},
-								};
-								new_status.last_seen = Instant::now();
-
-								match msg.message_type {
-									MessageType::Ping => {
-										let pong = Message {
-											message_type: MessageType::Pong,
-											unix_time: msg.unix_time,
-											mac_address: MacAddress::nil(),
-											payload: None,
-										};
-
-										// Check deserialize
-										let secret_bytes = secret.as_bytes();
-										assert!(
-											Message::from_buffer(
-												&pong.signed(secret_bytes),
-												secret_bytes
-											)
-											.is_ok(),
-											"deserialize own message"
-										);
+						)
+					}
+					Ok(msg) => {
+						let mac_identifier = mac.to_canonical();
+						log::info!(
+							"{} @ {}: {:?} t={}",
+							&mac_identifier,
+							&source_address,
+							msg.message_type,
+							msg.unix_time
+						);
+
+						// Update or create device status
+						{
+							let mut m = self.state.lock().unwrap();
+							let mut new_status = match m.devices.get(&mac_identifier) {
+								Some(status) => (*status).clone(),
+								None => DeviceStatus {
+									address: source_address,
+									program: None,
+									secret: secret.clone(),
+									pending_secret: None,
+									last_seen: Instant::now(),
+									sent_program_hash: None,
+									strip_length: None,
+									online: true,
+								},
+							};
+							new_status.last_seen = Instant::now();
+							new_status.online = true;
+
+							if let Some(pending) = new_status.pending_secret.take() {
+								if pending == secret {
+									log::info!("{} switched to its new secret", &mac_identifier);
+									new_status.secret = pending;
+								} else {
+									// The device did not receive its new secret, send it again
+									self.send_secret(socket, &pending, &secret, source_address);
+									new_status.pending_secret = Some(pending);
+								}
+							}
 
-										if let Err(t) = socket.send_to(
-											&pong.signed(secret.as_bytes()),
+							match msg.message_type {
+								MessageType::Ping => {
+									Metrics::increment(&self.metrics.pings);
+									if let Some(length) = msg.ping_strip_length() {
+										new_status.strip_length = Some(length);
+									}
+									let pong = Message::new_with_time(
+										MessageType::Pong,
+										MacAddress::nil(),
+										None,
+										msg.unix_time,
+									);
+
+									// Check deserialize
+									let secret_bytes = secret.as_bytes();
+									assert!(
+										Message::from_buffer(
+											&pong.signed(secret_bytes),
+											secret_bytes
+										)
+										.is_ok(),
+										"deserialize own message"
+									);
+
+									if let Err(t) = socket
+										.send_to(&pong.signed(secret.as_bytes()), source_address)
+									{
+										log::error!(
+											"Send pong to {} failed: {:?}",
 											source_address,
-										) {
-											println!("Send pong failed: {:?}", t);
-										}
+											t
+										);
+									}
 
-										let device_program = if let Some(p) = new_status.program {
-											p
-										} else if let Some(config) = &device_config {
-											if let Some(path) = &config.program {
-												Program::from_file(&path)
-													.expect("error loading device-specific program")
-											} else {
-												self.default_program.clone()
-											}
-										} else {
-											self.default_program.clone()
-										};
-
-										let run = Message {
-											message_type: MessageType::Run,
-											unix_time: msg.unix_time,
-											mac_address: MacAddress::nil(),
-											payload: Some(device_program.clone().code),
-										};
-
-										new_status.program = Some(device_program);
-
-										if let Err(t) = socket
+									if let Some(run) =
+										self.run_message(&mut new_status, &device_config, &msg)
+									{
+										match socket
 											.send_to(&run.signed(secret.as_bytes()), source_address)
 										{
-											println!("Send pong failed: {:?}", t);
+											Ok(_) => Metrics::increment(&self.metrics.runs_sent),
+											Err(t) => log::error!(
+												"Send run to {} failed: {:?}",
+												source_address,
+												t
+											),
 										}
 									}
-									MessageType::Pong => {
-										// Ignore
-									}
-									_ => {}
 								}
-
-								m.devices.insert(mac_identifier, new_status);
+								MessageType::Pong => {
+									// Ignore
+								}
+								_ => {}
 							}
+
+							m.update_device(mac_identifier, new_status);
 						}
 					}
 				}
 			}
 		}
 	}
+
+	pub fn run(&mut self) -> std::io::Result<()> {
+		let socket = self.receive_socket()?;
+		loop {
+			self.poll(&*socket)?;
+		}
+	}
+
+	/* Returns a handle to the server socket that stops waiting for messages after the read timeout */
+	pub(crate) fn receive_socket(&self) -> std::io::Result<Box<dyn Transport>> {
+		let state = self.state.lock().unwrap();
+		let socket = state.socket.try_clone()?;
+
+		// Wake up regularly to send heartbeats even when no messages arrive
+		let timeout = match state.heartbeat_interval {
+			Some(interval) => std::cmp::min(
+				self.read_timeout,
+				std::cmp::min(interval, Duration::from_secs(1)),
+			),
+			None => self.read_timeout,
+		};
+		socket.set_read_timeout(Some(timeout))?;
+		Ok(socket)
+	}
+
+	/* Sends the heartbeats that are due, then waits for a single message (until the read timeout of the socket
+	elapses) and handles it */
+	pub(crate) fn poll(&mut self, socket: &dyn Transport) -> std::io::Result<()> {
+		let heartbeats = self.state.lock().unwrap().due_heartbeats(Instant::now());
+		for (address, run) in heartbeats {
+			if let Err(t) = socket.send_to(&run, address) {
+				log::error!("Send heartbeat to {} failed: {:?}", address, t);
+			}
+		}
+
+		// One byte extra so that datagrams that do not fit can be detected
+		let mut buf = vec![0; self.mtu + 1];
+		let (amt, source_address) = match socket.recv_from(&mut buf) {
+			Ok((amt, source_address)) if amt > self.mtu => {
+				log::error!(
+					"{}: ignoring datagram larger than the MTU of {} bytes",
+					source_address,
+					self.mtu
+				);
+				return Ok(());
+			}
+			Ok(r) => r,
+			Err(e)
+				if e.kind() == std::io::ErrorKind::WouldBlock
+					|| e.kind() == std::io::ErrorKind::TimedOut =>
+			{
+				return Ok(())
+			}
+			Err(e) => return Err(e),
+		};
+
+		self.handle_datagram(socket, &buf[0..amt], source_address);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::UdpSocket;
+
+	fn status_seen_at(last_seen: Instant) -> DeviceStatus {
+		DeviceStatus {
+			address: "127.0.0.1:33332".parse().unwrap(),
+			program: None,
+			secret: String::from("secret"),
+			pending_secret: None,
+			last_seen,
+			sent_program_hash: None,
+			strip_length: None,
+			online: true,
+		}
+	}
+
+	#[test]
+	fn program_serde() {
+		let program = Program::from_source("loop{set_pixel(0, 255, 0, 0); blit; yield}").unwrap();
+		let json = serde_json::to_string(&program).unwrap();
+		let deserialized: Program = serde_json::from_str(&json).unwrap();
+		assert_eq!(deserialized.as_bytes(), program.as_bytes());
+
+		assert!(serde_json::from_str::<Program>("[256]").is_err());
+	}
+
+	#[test]
+	fn offline_detection() {
+		let timeout = Duration::from_secs(60);
+		let recent = status_seen_at(Instant::now());
+		let stale = status_seen_at(Instant::now() - Duration::from_secs(120));
+		assert!(recent.is_online(timeout));
+		assert!(!stale.is_online(timeout));
+
+		let mut state = ServerState::new(
+			HashMap::new(),
+			Box::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+		);
+		state.offline_timeout = timeout;
+		state.devices.insert("recent".to_string(), recent);
+		state.devices.insert("stale".to_string(), stale);
+		state.update_online_status();
+		assert!(state.devices["recent"].online);
+		assert!(!state.devices["stale"].online);
+	}
+
+	#[test]
+	fn heartbeat() {
+		let interval = Duration::from_secs(30);
+		let mut state = ServerState::new(
+			HashMap::new(),
+			Box::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+		);
+		let program = Program::from_source("loop{yield}").unwrap();
+
+		let mut online = status_seen_at(Instant::now());
+		online.program = Some(program.clone());
+		let mut other = status_seen_at(Instant::now());
+		other.address = "127.0.0.1:33334".parse().unwrap();
+		other.program = Some(program.clone());
+		let mut offline = status_seen_at(Instant::now() - Duration::from_secs(120));
+		offline.program = Some(program.clone());
+		state.devices.insert("online".to_string(), online);
+		state.devices.insert("other".to_string(), other);
+		state.devices.insert("offline".to_string(), offline);
+
+		// Disabled by default
+		let start = state.last_heartbeat;
+		assert!(state.due_heartbeats(start + interval).is_empty());
+
+		state.heartbeat_interval = Some(interval);
+		assert!(state.due_heartbeats(start + interval / 2).is_empty());
+
+		let heartbeats = state.due_heartbeats(start + interval);
+		assert_eq!(heartbeats.len(), 2);
+		for (address, run) in &heartbeats {
+			assert!(address.port() == 33332 || address.port() == 33334);
+			let message = Message::from_buffer(run, b"secret").unwrap();
+			assert!(matches!(message.message_type, MessageType::Run));
+			assert_eq!(message.payload.as_ref().unwrap(), &program.code);
+		}
+
+		// Next heartbeat is only due after another interval
+		assert!(state.due_heartbeats(start + interval).is_empty());
+		assert_eq!(state.due_heartbeats(start + interval * 2).len(), 2);
+	}
+
+	#[test]
+	fn group_program() {
+		let program = Program::from_source("loop{blit;yield}").unwrap();
+		let mut server =
+			Server::new(HashMap::new(), "secret", program.clone(), "127.0.0.1:0").unwrap();
+		let group = |prefix: &str, program: &str| GroupConfig {
+			prefix: prefix.to_string(),
+			program: Some(program.to_string()),
+		};
+		let mut groups = HashMap::new();
+		groups.insert("vendor".to_string(), group("5C:CF:7F", "test/blink.bin"));
+		groups.insert("batch".to_string(), group("5c-cf-7f-0b", "test/clock.bin"));
+		server.set_groups(groups);
+
+		let device_config = Some(DeviceConfig {
+			program: Some("test/set_all.bin".to_string()),
+			secret: None,
+		});
+		let assigned = |mac: &str, config: &Option<DeviceConfig>| {
+			let ping = Message::ping(MacAddress::parse_str(mac).unwrap(), None, None).unwrap();
+			let mut status = status_seen_at(Instant::now());
+			server
+				.run_message(&mut status, config, &ping)
+				.unwrap()
+				.payload
+				.unwrap()
+		};
+		let code = |path| Program::from_file(path).unwrap().code;
+
+		// Device-specific > most specific group > group > default
+		assert_eq!(
+			assigned("5c-cf-7f-0b-33-d5", &device_config),
+			code("test/set_all.bin")
+		);
+		assert_eq!(assigned("5c-cf-7f-0b-33-d5", &None), code("test/clock.bin"));
+		assert_eq!(assigned("5c-cf-7f-01-02-03", &None), code("test/blink.bin"));
+		assert_eq!(assigned("18-fe-34-f5-c1-79", &None), program.code);
+	}
+
+	#[test]
+	fn unchanged_program_is_not_resent() {
+		let program = Program::from_source("loop{blit;yield}").unwrap();
+		let server = Server::new(HashMap::new(), "secret", program.clone(), "127.0.0.1:0").unwrap();
+		let mut status = status_seen_at(Instant::now());
+		let ping = |hash| Message::ping(MacAddress::nil(), hash, None).unwrap();
+
+		// Device without a program
+		let run = server.run_message(&mut status, &None, &ping(None)).unwrap();
+		assert_eq!(run.payload.unwrap(), program.code);
+		assert_eq!(status.sent_program_hash, Some(program.code_hash()));
+
+		// Device reports running the program it was sent
+		assert!(server
+			.run_message(&mut status, &None, &ping(Some(program.code_hash())))
+			.is_none());
+
+		// Device runs another program
+		assert!(server
+			.run_message(&mut status, &None, &ping(Some(0x1234_5678)))
+			.is_some());
+
+		// Device program was changed
+		let changed = Program::from_source("loop{yield}").unwrap();
+		status.program = Some(changed.clone());
+		let run = server
+			.run_message(&mut status, &None, &ping(Some(program.code_hash())))
+			.unwrap();
+		assert_eq!(run.payload.unwrap(), changed.code);
+		assert!(server
+			.run_message(&mut status, &None, &ping(Some(changed.code_hash())))
+			.is_none());
+	}
+
+	#[test]
+	fn read_timeout() {
+		let program = Program::from_source("loop{blit;yield}").unwrap();
+		let mut server = Server::new(HashMap::new(), "secret", program, "127.0.0.1:0").unwrap();
+		server.set_read_timeout(Duration::from_millis(50));
+		// The operating system may round the timeout up
+		let timeout = |socket: &dyn Transport| socket.read_timeout().unwrap().unwrap();
+		let socket = server.receive_socket().unwrap();
+		assert!(timeout(&*socket) >= Duration::from_millis(50));
+		assert!(timeout(&*socket) < Duration::from_millis(100));
+
+		// Without messages, polling returns after the timeout
+		let start = Instant::now();
+		server.poll(&*socket).unwrap();
+		assert!(start.elapsed() < DEFAULT_READ_TIMEOUT);
+		assert_eq!(server.metrics.messages_received.load(Ordering::Relaxed), 0);
+
+		// Messages that arrive are handled
+		let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let ping = Message::ping(MacAddress::nil(), None, None).unwrap();
+		device
+			.send_to(&ping.signed(b"secret"), socket.local_addr().unwrap())
+			.unwrap();
+		server.poll(&*socket).unwrap();
+		assert_eq!(server.metrics.messages_received.load(Ordering::Relaxed), 1);
+
+		// Heartbeats require waking up at least every second
+		server.set_read_timeout(DEFAULT_READ_TIMEOUT);
+		server.set_heartbeat_interval(Some(Duration::from_secs(60)));
+		let socket = server.receive_socket().unwrap();
+		assert!(timeout(&*socket) >= Duration::from_secs(1));
+		assert!(timeout(&*socket) < Duration::from_secs(2));
+	}
+
+	#[test]
+	fn device_update_is_broadcast() {
+		let mut state = ServerState::new(
+			HashMap::new(),
+			Box::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+		);
+		let mut events = state.events.subscribe();
+		state.update_device(
+			"aa-bb-cc-dd-ee-ff".to_string(),
+			status_seen_at(Instant::now()),
+		);
+
+		let event = events.try_recv().unwrap();
+		assert_eq!(event.device, "aa-bb-cc-dd-ee-ff");
+		assert!(state.devices.contains_key("aa-bb-cc-dd-ee-ff"));
+	}
+
+	#[test]
+	fn metrics() {
+		let program = Program::from_source("loop{blit;yield}").unwrap();
+		let server = Server::new(HashMap::new(), "secret", program, "127.0.0.1:0").unwrap();
+		let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let device_address = device.local_addr().unwrap();
+		let ping = Message::ping(MacAddress::nil(), None, None).unwrap();
+		let socket = server.state.lock().unwrap().socket.try_clone().unwrap();
+
+		// Message signed with the wrong secret
+		server.handle_datagram(&*socket, &ping.signed(b"wrong"), device_address);
+		assert_eq!(server.metrics.messages_received.load(Ordering::Relaxed), 1);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 1);
+		assert_eq!(server.metrics.pings.load(Ordering::Relaxed), 0);
+
+		server.handle_datagram(&*socket, &ping.signed(b"secret"), device_address);
+		assert_eq!(server.metrics.messages_received.load(Ordering::Relaxed), 2);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 1);
+		assert_eq!(server.metrics.pings.load(Ordering::Relaxed), 1);
+		assert_eq!(server.metrics.runs_sent.load(Ordering::Relaxed), 1);
+
+		let text = server.metrics.render();
+		assert!(text.contains("# TYPE pwlp_signature_failures_total counter\n"));
+		assert!(text.contains("\npwlp_messages_received_total 2\n"));
+		assert!(text.contains("\npwlp_runs_sent_total 1\n"));
+	}
+
+	#[test]
+	fn strip_length_reported() {
+		let program = Program::from_source("set_pixel(50, 255, 0, 0)").unwrap();
+		let server = Server::new(HashMap::new(), "secret", program, "127.0.0.1:0").unwrap();
+		let socket = server.state.lock().unwrap().socket.try_clone().unwrap();
+		let device_address = socket.local_addr().unwrap();
+		let ping = Message::ping(MacAddress::nil(), None, Some(10)).unwrap();
+		server.handle_datagram(&*socket, &ping.signed(b"secret"), device_address);
+
+		let state = server.state.lock().unwrap();
+		let status = &state.devices[&MacAddress::nil().to_canonical()];
+		assert_eq!(status.strip_length, Some(10));
+		assert!(status
+			.strip_length_warning(status.program.as_ref().unwrap())
+			.is_some());
+	}
+
+	#[test]
+	fn rotated_secret() {
+		let program = Program::from_source("loop{blit;yield}").unwrap();
+		let server = Server::new(HashMap::new(), "secret", program, "127.0.0.1:0").unwrap();
+		let socket = server.state.lock().unwrap().socket.try_clone().unwrap();
+		let device_address = socket.local_addr().unwrap();
+		let ping = Message::ping(MacAddress::nil(), None, None).unwrap();
+
+		// Unknown devices use the default secret
+		server.handle_datagram(&*socket, &ping.signed(b"secret"), device_address);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 0);
+
+		let device = MacAddress::nil().to_canonical();
+		server
+			.state
+			.lock()
+			.unwrap()
+			.devices
+			.get_mut(&device)
+			.unwrap()
+			.secret = "rotated".to_string();
+		server.handle_datagram(&*socket, &ping.signed(b"secret"), device_address);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 1);
+		server.handle_datagram(&*socket, &ping.signed(b"rotated"), device_address);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 1);
+		assert_eq!(server.metrics.pings.load(Ordering::Relaxed), 2);
+	}
+
+	#[test]
+	fn lost_secret() {
+		let program = Program::from_source("loop{blit;yield}").unwrap();
+		let server = Server::new(HashMap::new(), "secret", program, "127.0.0.1:0").unwrap();
+		let socket = server.state.lock().unwrap().socket.try_clone().unwrap();
+		socket
+			.set_read_timeout(Some(Duration::from_millis(100)))
+			.unwrap();
+		let device_address = socket.local_addr().unwrap();
+		let ping = Message::ping(MacAddress::nil(), None, None).unwrap();
+		server.handle_datagram(&*socket, &ping.signed(b"secret"), device_address);
+
+		// The message carrying the new secret never arrived at the device
+		let device = MacAddress::nil().to_canonical();
+		server
+			.state
+			.lock()
+			.unwrap()
+			.devices
+			.get_mut(&device)
+			.unwrap()
+			.pending_secret = Some("rotated".to_string());
+		let mut buf = [0u8; 1500];
+		while socket.recv_from(&mut buf).is_ok() {}
+
+		// The device still uses its old secret, and is sent the new secret again
+		server.handle_datagram(&*socket, &ping.signed(b"secret"), device_address);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 0);
+		let mut resent = None;
+		while let Ok((amt, _)) = socket.recv_from(&mut buf) {
+			let msg = Message::from_buffer(&buf[0..amt], b"secret").unwrap();
+			if let MessageType::Secret = msg.message_type {
+				resent = msg.payload;
+			}
+		}
+		assert_eq!(resent.unwrap(), b"rotated");
+
+		// Once the device uses the new secret, the old one is no longer accepted
+		server.handle_datagram(&*socket, &ping.signed(b"rotated"), device_address);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 0);
+		server.handle_datagram(&*socket, &ping.signed(b"secret"), device_address);
+		assert_eq!(server.metrics.signature_failures.load(Ordering::Relaxed), 1);
+		let state = server.state.lock().unwrap();
+		assert_eq!(state.devices[&device].secret, "rotated");
+		assert_eq!(state.devices[&device].pending_secret, None);
+	}
 }
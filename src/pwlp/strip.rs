@@ -1,16 +1,133 @@
 use std::fmt::Display;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Color {
 	pub r: u8,
 	pub g: u8,
 	pub b: u8,
 }
 
+impl Color {
+	pub fn new(r: u8, g: u8, b: u8) -> Color {
+		Color { r, g, b }
+	}
+
+	/* Unpacks a color from the 0x00BBGGRR format used by the VM (the upper byte is ignored) */
+	pub fn from_packed(value: u32) -> Color {
+		Color {
+			r: (value & 0xFF) as u8,
+			g: ((value >> 8) & 0xFF) as u8,
+			b: ((value >> 16) & 0xFF) as u8,
+		}
+	}
+
+	/* Packs the color in the 0x00BBGGRR format used by the VM */
+	pub fn to_packed(&self) -> u32 {
+		u32::from(self.r) | u32::from(self.g) << 8 | u32::from(self.b) << 16
+	}
+
+	/* Creates a color from hue (in degrees, wrapping around at 360), saturation and value (both 0...1) */
+	pub fn from_hsv(h: f32, s: f32, v: f32) -> Color {
+		let h = h.rem_euclid(360.0);
+		let s = s.clamp(0.0, 1.0);
+		let v = v.clamp(0.0, 1.0);
+
+		let c = v * s;
+		let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+		let m = v - c;
+		let (r, g, b) = match (h / 60.0) as u32 {
+			0 => (c, x, 0.0),
+			1 => (x, c, 0.0),
+			2 => (0.0, c, x),
+			3 => (0.0, x, c),
+			4 => (x, 0.0, c),
+			_ => (c, 0.0, x),
+		};
+
+		let channel = |f: f32| ((f + m) * 255.0).round() as u8;
+		Color::new(channel(r), channel(g), channel(b))
+	}
+
+	/* Returns hue (in degrees, 0...360), saturation and value (both 0...1). Only used by library users, not by the
+	command line tool. */
+	#[allow(dead_code)]
+	pub fn to_hsv(&self) -> (f32, f32, f32) {
+		let r = f32::from(self.r) / 255.0;
+		let g = f32::from(self.g) / 255.0;
+		let b = f32::from(self.b) / 255.0;
+		let max = r.max(g).max(b);
+		let min = r.min(g).min(b);
+		let delta = max - min;
+
+		let h = if delta == 0.0 {
+			0.0
+		} else if max == r {
+			60.0 * ((g - b) / delta).rem_euclid(6.0)
+		} else if max == g {
+			60.0 * ((b - r) / delta + 2.0)
+		} else {
+			60.0 * ((r - g) / delta + 4.0)
+		};
+		let s = if max == 0.0 { 0.0 } else { delta / max };
+		(h, s, max)
+	}
+}
+
 pub trait Strip {
 	fn length(&self) -> u32;
 	fn blit(&mut self);
 	fn set_pixel(&mut self, idx: u32, r: u8, g: u8, b: u8);
 	fn get_pixel(&self, idx: u32) -> Color;
+
+	/* Returns the color of all pixels as RGB bytes */
+	fn snapshot(&self) -> Vec<u8> {
+		let mut data = Vec::with_capacity(self.length() as usize * 3);
+		for idx in 0..self.length() {
+			let color = self.get_pixel(idx);
+			data.extend_from_slice(&[color.r, color.g, color.b]);
+		}
+		data
+	}
+
+	/* Sets all pixels to the same color (the strip is not blitted) */
+	fn fill(&mut self, r: u8, g: u8, b: u8) {
+		for idx in 0..self.length() {
+			self.set_pixel(idx, r, g, b);
+		}
+	}
+
+	/* Sets all pixels to black (the strip is not blitted) */
+	fn clear(&mut self) {
+		self.fill(0, 0, 0);
+	}
+
+	/* Sets all pixels to the same color and optionally blits the strip */
+	fn set_all_pixels_to(&mut self, r: u8, g: u8, b: u8, blit: bool) {
+		self.fill(r, g, b);
+		if blit {
+			self.blit();
+		}
+	}
+
+	/* Sets the global brightness (0...255) that is applied when pixels are sent to the LEDs. Pixel values as seen
+	by programs are not affected. Strips that cannot be dimmed ignore this. */
+	fn set_brightness(&mut self, _brightness: u8) {}
+
+	/* The global brightness set using set_brightness (255 for strips that cannot be dimmed) */
+	fn brightness(&self) -> u8 {
+		255
+	}
+
+	/* Changes the number of pixels. Existing pixels keep their color; added pixels are black. Strips with a fixed
+	length (e.g. hardware strips) return an error. */
+	fn set_length(&mut self, _length: u32) -> Result<(), String> {
+		Err("the length of this strip cannot be changed".to_string())
+	}
+}
+
+/* Scales a color component by the given brightness (0...255) */
+pub fn dim(value: u8, brightness: u8) -> u8 {
+	((u16::from(value) * u16::from(brightness) + 127) / 255) as u8
 }
 
 impl Display for dyn Strip {
@@ -28,6 +145,7 @@ pub struct DummyStrip {
 	trace: bool,
 	length: u32,
 	data: Vec<u8>,
+	brightness: u8,
 }
 
 impl DummyStrip {
@@ -36,6 +154,7 @@ impl DummyStrip {
 			trace,
 			length,
 			data: vec![0u8; (length as usize) * 3],
+			brightness: 255,
 		}
 	}
 }
@@ -64,11 +183,29 @@ impl Strip for DummyStrip {
 			idx,
 			self.length
 		);
-		Color {
-			r: self.data[(idx as usize) * 3],
-			g: self.data[(idx as usize) * 3 + 1],
-			b: self.data[(idx as usize) * 3 + 2],
-		}
+		Color::new(
+			self.data[(idx as usize) * 3],
+			self.data[(idx as usize) * 3 + 1],
+			self.data[(idx as usize) * 3 + 2],
+		)
+	}
+
+	fn snapshot(&self) -> Vec<u8> {
+		self.data.clone()
+	}
+
+	fn set_length(&mut self, length: u32) -> Result<(), String> {
+		self.length = length;
+		self.data.resize((length as usize) * 3, 0);
+		Ok(())
+	}
+
+	fn set_brightness(&mut self, brightness: u8) {
+		self.brightness = brightness;
+	}
+
+	fn brightness(&self) -> u8 {
+		self.brightness
 	}
 
 	fn blit(&mut self) {
@@ -76,9 +213,9 @@ impl Strip for DummyStrip {
 			for idx in 0..self.length {
 				print!(
 					"{:02x}{:02x}{:02x} ",
-					self.data[(idx as usize) * 3],
-					self.data[(idx as usize) * 3 + 1],
-					self.data[(idx as usize) * 3 + 2]
+					dim(self.data[(idx as usize) * 3], self.brightness),
+					dim(self.data[(idx as usize) * 3 + 1], self.brightness),
+					dim(self.data[(idx as usize) * 3 + 2], self.brightness)
 				);
 			}
 			println!();
@@ -86,14 +223,168 @@ impl Strip for DummyStrip {
 	}
 }
 
+/* Strip that discards all pixel data, for measuring VM performance without any I/O cost */
+pub struct NullStrip {
+	length: u32,
+}
+
+impl NullStrip {
+	pub fn new(length: u32) -> NullStrip {
+		NullStrip { length }
+	}
+}
+
+impl Strip for NullStrip {
+	fn length(&self) -> u32 {
+		self.length
+	}
+
+	fn set_pixel(&mut self, _idx: u32, _r: u8, _g: u8, _b: u8) {}
+
+	fn get_pixel(&self, _idx: u32) -> Color {
+		Color::new(0, 0, 0)
+	}
+
+	fn blit(&mut self) {}
+
+	fn set_length(&mut self, length: u32) -> Result<(), String> {
+		self.length = length;
+		Ok(())
+	}
+}
+
+/* Addresses several strips (wired in sequence) as one long strip */
+pub struct ConcatStrip {
+	strips: Vec<Box<dyn Strip>>,
+}
+
+impl ConcatStrip {
+	pub fn new(strips: Vec<Box<dyn Strip>>) -> ConcatStrip {
+		ConcatStrip { strips }
+	}
+
+	/* Returns the strip that holds the pixel at `idx`, and the index of the pixel in that strip */
+	fn locate(&self, idx: u32) -> Option<(usize, u32)> {
+		let mut offset = 0;
+		for (n, strip) in self.strips.iter().enumerate() {
+			let length = strip.length();
+			if idx < offset + length {
+				return Some((n, idx - offset));
+			}
+			offset += length;
+		}
+		None
+	}
+}
+
+impl Strip for ConcatStrip {
+	fn length(&self) -> u32 {
+		self.strips.iter().map(|s| s.length()).sum()
+	}
+
+	fn set_pixel(&mut self, idx: u32, r: u8, g: u8, b: u8) {
+		let (n, local_idx) = self.locate(idx).unwrap_or_else(|| {
+			panic!(
+				"set_pixel: index {} exceeds strip length {}",
+				idx,
+				self.length()
+			)
+		});
+		self.strips[n].set_pixel(local_idx, r, g, b)
+	}
+
+	fn get_pixel(&self, idx: u32) -> Color {
+		let (n, local_idx) = self.locate(idx).unwrap_or_else(|| {
+			panic!(
+				"get_pixel: index {} exceeds strip length {}",
+				idx,
+				self.length()
+			)
+		});
+		self.strips[n].get_pixel(local_idx)
+	}
+
+	fn blit(&mut self) {
+		for strip in self.strips.iter_mut() {
+			strip.blit();
+		}
+	}
+
+	fn set_brightness(&mut self, brightness: u8) {
+		for strip in self.strips.iter_mut() {
+			strip.set_brightness(brightness);
+		}
+	}
+
+	fn brightness(&self) -> u8 {
+		self.strips.first().map(|s| s.brightness()).unwrap_or(255)
+	}
+}
+
+/* Builds the SPI frame for a strip of APA102/SK9822 LEDs: a start frame of 32 zero bits, a four-byte frame for
+each LED (0xE0 | 5-bit brightness, followed by blue, green and red) and an end frame. The end frame must provide
+at least one clock edge per two LEDs (as data is delayed by half a clock cycle at each LED), with a minimum of
+32 bits (which SK9822 requires to latch the data). */
+#[cfg(any(test, feature = "raspberrypi"))]
+pub fn apa102_frame(pixels: &[(u8, u8, u8)], brightness: u8) -> Vec<u8> {
+	let end_frame_length = std::cmp::max(4, pixels.len().div_ceil(16));
+	let mut frame = Vec::with_capacity(4 + pixels.len() * 4 + end_frame_length);
+	frame.extend_from_slice(&[0u8; 4]);
+	for (r, g, b) in pixels.iter() {
+		frame.extend_from_slice(&[0xE0 | (brightness & 0x1F), *b, *g, *r]);
+	}
+	frame.resize(frame.len() + end_frame_length, 0xFF);
+	frame
+}
+
+/* WS2812 LEDs are driven by a single data line, on which each bit is encoded as a pulse of a specific width. When
+the SPI bus is clocked at 2.4 MHz, each of these pulses can be represented using three SPI bits (of ~417 ns each):
+a zero bit is sent as 100 and a one bit as 110. */
+#[cfg(feature = "raspberrypi")]
+pub const WS2812_SPI_CLOCK_SPEED: u32 = 2_400_000;
+
+/* Number of zero bytes to send after the pixel data to make the LEDs latch their values (a low signal of at least
+50 µs, which at 2.4 MHz amounts to 120 bits) */
+#[cfg(any(test, feature = "raspberrypi"))]
+const WS2812_RESET_LENGTH: usize = 16;
+
+/* Expands each bit of `data` (most significant bit first) into the three-bit SPI pattern for a WS2812 bit (see
+WS2812_SPI_CLOCK_SPEED). Each input byte therefore becomes three output bytes. */
+#[cfg(any(test, feature = "raspberrypi"))]
+pub fn encode_ws2812(data: &[u8]) -> Vec<u8> {
+	let mut encoded = Vec::with_capacity(data.len() * 3);
+	for byte in data.iter() {
+		let mut bits: u32 = 0;
+		for bit in (0..8).rev() {
+			bits = (bits << 3) | if (byte >> bit) & 1 == 1 { 0b110 } else { 0b100 };
+		}
+		encoded.extend_from_slice(&[(bits >> 16) as u8, (bits >> 8) as u8, bits as u8]);
+	}
+	encoded
+}
+
+/* Builds the SPI frame for a strip of WS2812 LEDs: the encoded pixel data (in green, red, blue order) followed by
+the reset signal */
+#[cfg(any(test, feature = "raspberrypi"))]
+pub fn ws2812_frame(pixels: &[(u8, u8, u8)]) -> Vec<u8> {
+	let data: Vec<u8> = pixels
+		.iter()
+		.flat_map(|(r, g, b)| vec![*g, *r, *b])
+		.collect();
+	let mut frame = encode_ws2812(&data);
+	frame.resize(frame.len() + WS2812_RESET_LENGTH, 0);
+	frame
+}
+
 #[cfg(feature = "raspberrypi")]
 pub mod spi_strip {
-	use super::Color;
+	use super::{apa102_frame, dim, ws2812_frame, Color};
 	use rppal::spi::Spi;
 	pub struct SPIStrip {
 		spi: Spi,
 		data: Vec<u8>,
 		length: u32,
+		brightness: u8,
 	}
 
 	impl SPIStrip {
@@ -102,6 +393,7 @@ pub mod spi_strip {
 				spi,
 				length,
 				data: vec![0u8; (length as usize) * 3],
+				brightness: 255,
 			}
 		}
 	}
@@ -118,11 +410,11 @@ pub mod spi_strip {
 				idx,
 				self.length
 			);
-			Color {
-				r: self.data[(idx as usize) * 3],
-				g: self.data[(idx as usize) * 3 + 1],
-				b: self.data[(idx as usize) * 3 + 2],
-			}
+			Color::new(
+				self.data[(idx as usize) * 3],
+				self.data[(idx as usize) * 3 + 1],
+				self.data[(idx as usize) * 3 + 2],
+			)
 		}
 
 		fn set_pixel(&mut self, idx: u32, r: u8, g: u8, b: u8) {
@@ -138,7 +430,394 @@ pub mod spi_strip {
 		}
 
 		fn blit(&mut self) {
-			self.spi.write(&self.data).unwrap();
+			let data: Vec<u8> = self.data.iter().map(|v| dim(*v, self.brightness)).collect();
+			self.spi.write(&data).unwrap();
+		}
+
+		fn set_brightness(&mut self, brightness: u8) {
+			self.brightness = brightness;
+		}
+
+		fn brightness(&self) -> u8 {
+			self.brightness
+		}
+	}
+
+	/* Strip of APA102 or SK9822 LEDs, which are driven using a clock and data line */
+	pub struct Apa102Strip {
+		spi: Spi,
+		pixels: Vec<(u8, u8, u8)>,
+		brightness: u8,
+	}
+
+	impl Apa102Strip {
+		pub fn new(spi: Spi, length: u32) -> Apa102Strip {
+			Apa102Strip {
+				spi,
+				pixels: vec![(0, 0, 0); length as usize],
+				brightness: 255,
+			}
+		}
+	}
+
+	impl super::Strip for Apa102Strip {
+		fn length(&self) -> u32 {
+			self.pixels.len() as u32
+		}
+
+		fn get_pixel(&self, idx: u32) -> Color {
+			assert!(
+				idx < self.length(),
+				"get_pixel: index {} exceeds strip length {}",
+				idx,
+				self.length()
+			);
+			let (r, g, b) = self.pixels[idx as usize];
+			Color::new(r, g, b)
+		}
+
+		fn set_pixel(&mut self, idx: u32, r: u8, g: u8, b: u8) {
+			assert!(
+				idx < self.length(),
+				"set_pixel: index {} exceeds strip length {}",
+				idx,
+				self.length()
+			);
+			self.pixels[idx as usize] = (r, g, b);
+		}
+
+		fn blit(&mut self) {
+			// The LEDs support a 5-bit global brightness value that is sent along with each pixel
+			let brightness = dim(0x1F, self.brightness);
+			self.spi
+				.write(&apa102_frame(&self.pixels, brightness))
+				.unwrap();
+		}
+
+		fn set_brightness(&mut self, brightness: u8) {
+			self.brightness = brightness;
+		}
+
+		fn brightness(&self) -> u8 {
+			self.brightness
+		}
+	}
+
+	/* Strip of WS2812 LEDs connected to the MOSI line of an SPI bus. The bus must be clocked at
+	WS2812_SPI_CLOCK_SPEED. */
+	pub struct Ws2812Strip {
+		spi: Spi,
+		pixels: Vec<(u8, u8, u8)>,
+		brightness: u8,
+	}
+
+	impl Ws2812Strip {
+		pub fn new(spi: Spi, length: u32) -> Ws2812Strip {
+			Ws2812Strip {
+				spi,
+				pixels: vec![(0, 0, 0); length as usize],
+				brightness: 255,
+			}
+		}
+	}
+
+	impl super::Strip for Ws2812Strip {
+		fn length(&self) -> u32 {
+			self.pixels.len() as u32
+		}
+
+		fn get_pixel(&self, idx: u32) -> Color {
+			assert!(
+				idx < self.length(),
+				"get_pixel: index {} exceeds strip length {}",
+				idx,
+				self.length()
+			);
+			let (r, g, b) = self.pixels[idx as usize];
+			Color::new(r, g, b)
+		}
+
+		fn set_pixel(&mut self, idx: u32, r: u8, g: u8, b: u8) {
+			assert!(
+				idx < self.length(),
+				"set_pixel: index {} exceeds strip length {}",
+				idx,
+				self.length()
+			);
+			self.pixels[idx as usize] = (r, g, b);
+		}
+
+		fn blit(&mut self) {
+			let brightness = self.brightness;
+			let pixels: Vec<(u8, u8, u8)> = self
+				.pixels
+				.iter()
+				.map(|(r, g, b)| {
+					(
+						dim(*r, brightness),
+						dim(*g, brightness),
+						dim(*b, brightness),
+					)
+				})
+				.collect();
+			self.spi.write(&ws2812_frame(&pixels)).unwrap();
+		}
+
+		fn set_brightness(&mut self, brightness: u8) {
+			self.brightness = brightness;
+		}
+
+		fn brightness(&self) -> u8 {
+			self.brightness
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+	use std::rc::Rc;
+
+	/* Strip that counts how often it has been blitted */
+	struct CountingStrip {
+		inner: DummyStrip,
+		blits: Rc<Cell<usize>>,
+	}
+
+	impl Strip for CountingStrip {
+		fn length(&self) -> u32 {
+			self.inner.length()
+		}
+
+		fn set_pixel(&mut self, idx: u32, r: u8, g: u8, b: u8) {
+			self.inner.set_pixel(idx, r, g, b)
+		}
+
+		fn get_pixel(&self, idx: u32) -> Color {
+			self.inner.get_pixel(idx)
+		}
+
+		fn blit(&mut self) {
+			self.blits.set(self.blits.get() + 1)
 		}
 	}
+
+	#[test]
+	fn color_packing() {
+		let color = Color::new(0x12, 0x34, 0x56);
+		assert_eq!(color.to_packed(), 0x0056_3412);
+		assert_eq!(Color::from_packed(0x0056_3412), color);
+		assert_eq!(Color::from_packed(0xFF00_00FF), Color::new(0xFF, 0, 0));
+		assert_eq!(Color::from_packed(0x0000_FF00), Color::new(0, 0xFF, 0));
+		assert_eq!(Color::from_packed(0x00FF_0000), Color::new(0, 0, 0xFF));
+	}
+
+	#[test]
+	fn hsv() {
+		let colors = [
+			(0.0, Color::new(255, 0, 0)),
+			(60.0, Color::new(255, 255, 0)),
+			(120.0, Color::new(0, 255, 0)),
+			(180.0, Color::new(0, 255, 255)),
+			(240.0, Color::new(0, 0, 255)),
+			(300.0, Color::new(255, 0, 255)),
+		];
+		for (hue, color) in colors.iter() {
+			assert_eq!(Color::from_hsv(*hue, 1.0, 1.0), *color);
+			assert_eq!(color.to_hsv(), (*hue, 1.0, 1.0));
+		}
+		assert_eq!(Color::from_hsv(360.0, 1.0, 1.0), Color::new(255, 0, 0));
+		assert_eq!(Color::from_hsv(30.0, 1.0, 1.0), Color::new(255, 128, 0));
+
+		// Grey has no hue or saturation
+		assert_eq!(Color::from_hsv(200.0, 0.0, 0.5), Color::new(128, 128, 128));
+		let (h, s, v) = Color::new(128, 128, 128).to_hsv();
+		assert_eq!((h, s), (0.0, 0.0));
+		assert!((v - 0.502).abs() < 0.001);
+	}
+
+	#[test]
+	fn snapshot() {
+		let mut strip = DummyStrip::new(3, false);
+		strip.set_pixel(0, 1, 2, 3);
+		strip.set_pixel(2, 7, 8, 9);
+		let snapshot = strip.snapshot();
+		assert_eq!(snapshot, vec![1, 2, 3, 0, 0, 0, 7, 8, 9]);
+
+		// The default implementation returns the same
+		let counting = CountingStrip {
+			inner: strip,
+			blits: Rc::new(Cell::new(0)),
+		};
+		assert_eq!(counting.snapshot(), snapshot);
+		for idx in 0..3 {
+			let color = counting.get_pixel(idx);
+			assert_eq!(
+				&snapshot[(idx as usize * 3)..(idx as usize * 3 + 3)],
+				&[color.r, color.g, color.b]
+			);
+		}
+	}
+
+	#[test]
+	fn clear() {
+		let mut strip: Box<dyn Strip> = Box::new(DummyStrip::new(3, false));
+		strip.fill(1, 2, 3);
+		assert_eq!(strip.to_string(), "010203 010203 010203 ");
+		strip.clear();
+		assert_eq!(strip.to_string(), "000000 000000 000000 ");
+	}
+
+	#[test]
+	fn set_length() {
+		let mut strip: Box<dyn Strip> = Box::new(DummyStrip::new(2, false));
+		strip.fill(1, 2, 3);
+		strip.set_length(4).unwrap();
+		assert_eq!(strip.length(), 4);
+		assert_eq!(strip.to_string(), "010203 010203 000000 000000 ");
+		assert_eq!(strip.snapshot().len(), 12);
+
+		strip.set_pixel(3, 4, 5, 6);
+		strip.set_length(1).unwrap();
+		assert_eq!(strip.to_string(), "010203 ");
+
+		// Pixels removed by shrinking do not reappear when growing again
+		strip.set_length(4).unwrap();
+		assert_eq!(strip.to_string(), "010203 000000 000000 000000 ");
+
+		let mut strip: Box<dyn Strip> =
+			Box::new(ConcatStrip::new(vec![Box::new(NullStrip::new(2))]));
+		assert!(strip.set_length(3).is_err());
+		assert_eq!(strip.length(), 2);
+	}
+
+	#[test]
+	fn set_all_pixels_to() {
+		let blits = Rc::new(Cell::new(0));
+		let mut strip: Box<dyn Strip> = Box::new(CountingStrip {
+			inner: DummyStrip::new(2, false),
+			blits: blits.clone(),
+		});
+
+		strip.set_all_pixels_to(1, 2, 3, false);
+		assert_eq!(strip.to_string(), "010203 010203 ");
+		assert_eq!(blits.get(), 0);
+
+		strip.set_all_pixels_to(4, 5, 6, true);
+		assert_eq!(strip.to_string(), "040506 040506 ");
+		assert_eq!(blits.get(), 1);
+	}
+
+	#[test]
+	fn apa102_frame() {
+		let frame = super::apa102_frame(&[(1, 2, 3), (4, 5, 6)], 0x10);
+		assert_eq!(
+			frame,
+			vec![
+				0, 0, 0, 0, // start frame
+				0xF0, 3, 2, 1, // LED 0
+				0xF0, 6, 5, 4, // LED 1
+				0xFF, 0xFF, 0xFF, 0xFF // end frame
+			]
+		);
+
+		// Brightness is limited to five bits
+		assert_eq!(super::apa102_frame(&[(0, 0, 0)], 0xFF)[4], 0xFF);
+		assert_eq!(super::apa102_frame(&[(0, 0, 0)], 0x20)[4], 0xE0);
+
+		// End frame grows with the strip length (at least one bit per two LEDs)
+		let frame = super::apa102_frame(&[(0, 0, 0); 100], 0x1F);
+		assert_eq!(frame.len(), 4 + 100 * 4 + 7);
+		assert!(frame[404..].iter().all(|b| *b == 0xFF));
+	}
+
+	#[test]
+	fn encode_ws2812() {
+		assert_eq!(
+			super::encode_ws2812(&[0x00]),
+			vec![0b1001_0010, 0b0100_1001, 0b0010_0100]
+		);
+		assert_eq!(
+			super::encode_ws2812(&[0xFF]),
+			vec![0b1101_1011, 0b0110_1101, 0b1011_0110]
+		);
+		assert_eq!(
+			super::encode_ws2812(&[0x80]),
+			vec![0b1101_0010, 0b0100_1001, 0b0010_0100]
+		);
+		assert_eq!(
+			super::encode_ws2812(&[0x01]),
+			vec![0b1001_0010, 0b0100_1001, 0b0010_0110]
+		);
+	}
+
+	#[test]
+	fn ws2812_frame() {
+		// A red pixel is sent as green (0x00), red (0xFF), blue (0x00)
+		let frame = super::ws2812_frame(&[(0xFF, 0x00, 0x00)]);
+		assert_eq!(frame.len(), 9 + 16);
+		assert_eq!(&frame[0..3], &super::encode_ws2812(&[0x00])[..]);
+		assert_eq!(&frame[3..6], &super::encode_ws2812(&[0xFF])[..]);
+		assert_eq!(&frame[6..9], &super::encode_ws2812(&[0x00])[..]);
+		assert!(frame[9..].iter().all(|b| *b == 0));
+	}
+
+	#[test]
+	fn brightness() {
+		assert_eq!(dim(200, 255), 200);
+		assert_eq!(dim(200, 128), 100);
+		assert_eq!(dim(255, 0), 0);
+
+		let mut strip = ConcatStrip::new(vec![
+			Box::new(DummyStrip::new(1, false)),
+			Box::new(DummyStrip::new(1, false)),
+		]);
+		assert_eq!(strip.brightness(), 255);
+		strip.set_brightness(64);
+		assert_eq!(strip.brightness(), 64);
+		assert_eq!(strip.strips[1].brightness(), 64);
+		assert_eq!(NullStrip::new(1).brightness(), 255);
+	}
+
+	#[test]
+	fn concat_strip() {
+		let mut strip = ConcatStrip::new(vec![
+			Box::new(DummyStrip::new(10, false)),
+			Box::new(DummyStrip::new(10, false)),
+		]);
+		assert_eq!(strip.length(), 20);
+
+		strip.set_pixel(12, 1, 2, 3);
+		strip.set_pixel(9, 4, 5, 6);
+		assert_eq!(strip.strips[1].get_pixel(2).r, 1);
+		assert_eq!(strip.strips[1].get_pixel(2).b, 3);
+		assert_eq!(strip.strips[0].get_pixel(9).g, 5);
+		assert_eq!(strip.get_pixel(12).g, 2);
+		assert_eq!(strip.get_pixel(2).r, 0);
+	}
+
+	#[test]
+	#[should_panic]
+	fn concat_strip_out_of_bounds() {
+		let mut strip = ConcatStrip::new(vec![Box::new(DummyStrip::new(10, false))]);
+		strip.set_pixel(10, 1, 1, 1);
+	}
+
+	#[test]
+	fn concat_strip_blit() {
+		let blits = Rc::new(Cell::new(0));
+		let mut strip = ConcatStrip::new(
+			(0..3)
+				.map(|_| {
+					Box::new(CountingStrip {
+						inner: DummyStrip::new(5, false),
+						blits: blits.clone(),
+					}) as Box<dyn Strip>
+				})
+				.collect(),
+		);
+		strip.blit();
+		assert_eq!(blits.get(), 3);
+	}
 }
@@ -0,0 +1,186 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+#[cfg(test)]
+use std::collections::{HashMap, VecDeque};
+#[cfg(test)]
+use std::net::ToSocketAddrs;
+#[cfg(test)]
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(test)]
+use std::time::Instant;
+
+/* Sends and receives datagrams on behalf of the client and server. Implemented by UdpSocket and (for tests) by
+MemoryTransport, which delivers datagrams in memory. */
+pub trait Transport: Send {
+	fn send_to(&self, buf: &[u8], address: SocketAddr) -> io::Result<usize>;
+
+	/* Waits for a datagram until the read timeout elapses (in which case an error of kind WouldBlock or TimedOut is
+	returned) */
+	fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+	#[cfg(test)]
+	fn local_addr(&self) -> io::Result<SocketAddr>;
+	#[cfg(test)]
+	fn read_timeout(&self) -> io::Result<Option<Duration>>;
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+	/* Returns another handle to the same transport (settings such as the read timeout are shared) */
+	fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+impl Transport for UdpSocket {
+	fn send_to(&self, buf: &[u8], address: SocketAddr) -> io::Result<usize> {
+		UdpSocket::send_to(self, buf, address)
+	}
+
+	fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+		UdpSocket::recv_from(self, buf)
+	}
+
+	#[cfg(test)]
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		UdpSocket::local_addr(self)
+	}
+
+	#[cfg(test)]
+	fn read_timeout(&self) -> io::Result<Option<Duration>> {
+		UdpSocket::read_timeout(self)
+	}
+
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		UdpSocket::set_read_timeout(self, timeout)
+	}
+
+	fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+		Ok(Box::new(UdpSocket::try_clone(self)?))
+	}
+}
+
+#[cfg(test)]
+type Queues = HashMap<SocketAddr, VecDeque<(Vec<u8>, SocketAddr)>>;
+
+/* A network that only exists in memory. Datagrams sent to an address that no transport is bound to are dropped. */
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+	queues: Arc<(Mutex<Queues>, Condvar)>,
+}
+
+#[cfg(test)]
+impl MemoryNetwork {
+	pub fn new() -> MemoryNetwork {
+		MemoryNetwork::default()
+	}
+
+	pub fn bind(&self, address: &str) -> io::Result<MemoryTransport> {
+		let address = address.to_socket_addrs()?.next().unwrap();
+		let mut queues = self.queues.0.lock().unwrap();
+		if queues.contains_key(&address) {
+			return Err(io::Error::new(
+				io::ErrorKind::AddrInUse,
+				format!("address {} is already bound", address),
+			));
+		}
+		queues.insert(address, VecDeque::new());
+		Ok(MemoryTransport {
+			network: self.clone(),
+			address,
+			read_timeout: Arc::new(Mutex::new(None)),
+		})
+	}
+}
+
+#[cfg(test)]
+pub struct MemoryTransport {
+	network: MemoryNetwork,
+	address: SocketAddr,
+	read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+	fn send_to(&self, buf: &[u8], address: SocketAddr) -> io::Result<usize> {
+		let (queues, arrived) = &*self.network.queues;
+		if let Some(queue) = queues.lock().unwrap().get_mut(&address) {
+			queue.push_back((buf.to_vec(), self.address));
+			arrived.notify_all();
+		}
+		Ok(buf.len())
+	}
+
+	fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+		let deadline = self.read_timeout()?.map(|t| Instant::now() + t);
+		let (queues, arrived) = &*self.network.queues;
+		let mut queues = queues.lock().unwrap();
+		loop {
+			if let Some((data, source_address)) = queues.get_mut(&self.address).unwrap().pop_front()
+			{
+				// Like UDP, datagrams that do not fit the buffer are truncated
+				let amt = std::cmp::min(data.len(), buf.len());
+				buf[0..amt].copy_from_slice(&data[0..amt]);
+				return Ok((amt, source_address));
+			}
+
+			queues = match deadline {
+				None => arrived.wait(queues).unwrap(),
+				Some(deadline) => {
+					let now = Instant::now();
+					if now >= deadline {
+						return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+					}
+					arrived.wait_timeout(queues, deadline - now).unwrap().0
+				}
+			};
+		}
+	}
+
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		Ok(self.address)
+	}
+
+	fn read_timeout(&self) -> io::Result<Option<Duration>> {
+		Ok(*self.read_timeout.lock().unwrap())
+	}
+
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		*self.read_timeout.lock().unwrap() = timeout;
+		Ok(())
+	}
+
+	fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+		Ok(Box::new(MemoryTransport {
+			network: self.network.clone(),
+			address: self.address,
+			read_timeout: self.read_timeout.clone(),
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn memory_transport() {
+		let network = MemoryNetwork::new();
+		let a = network.bind("10.0.0.1:1000").unwrap();
+		let b = network.bind("10.0.0.2:1000").unwrap();
+		assert!(network.bind("10.0.0.1:1000").is_err());
+
+		a.send_to(b"hello", b.local_addr().unwrap()).unwrap();
+		a.send_to(b"nobody", "10.0.0.3:1000".parse().unwrap())
+			.unwrap();
+		let mut buf = [0u8; 3];
+		assert_eq!(b.recv_from(&mut buf).unwrap(), (3, a.local_addr().unwrap()));
+		assert_eq!(&buf, b"hel");
+
+		// Clones share the read timeout
+		let c = b.try_clone().unwrap();
+		c.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+		assert_eq!(b.read_timeout().unwrap(), Some(Duration::from_millis(10)));
+		let error = b.recv_from(&mut buf).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+	}
+}
@@ -1,9 +1,14 @@
-use super::instructions::{Binary, Prefix, Special, Unary, UserCommand};
+use super::instructions::{Binary, Prefix, Special, Unary, UserCommand, JUMP_RELATIVE};
 use super::program::Program;
-use super::strip::Strip;
+use super::strip::{dim, Color, Strip};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/* Maximum number of instructions a frame may take in run_until_stable */
+const STABLE_FRAME_INSTRUCTION_LIMIT: usize = 100_000;
 
 pub struct State<'a> {
 	pub vm: &'a mut VM,
@@ -14,26 +19,103 @@ pub struct State<'a> {
 	instruction_count: usize,
 	instruction_limit: Option<usize>,
 	deterministic_rng: ChaCha20Rng,
+	selected_strip: usize,
+	trace_note: Option<String>,
+	frame_count: u32,
+}
+
+/* Describes an instruction executed while tracing: its address and opcode, notes on what it did (e.g. the values
+pushed) and the stack after executing it */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+	pub pc: usize,
+	pub opcode: u8,
+	pub note: Option<String>,
+	pub stack: Vec<u32>,
+}
+
+impl TraceEvent {
+	/* The default trace handler, which prints each event to stdout */
+	pub fn print(self) {
+		let name = match Prefix::from(self.opcode) {
+			Some(prefix) => prefix.to_string(),
+			None => "unknown".to_string(),
+		};
+		match self.note {
+			Some(note) => println!(
+				"{:04}.\t{:02x}\t{}\t{}\tstack: {:?}",
+				self.pc, self.opcode, name, note, self.stack
+			),
+			None => println!(
+				"{:04}.\t{:02x}\t{}\tstack: {:?}",
+				self.pc, self.opcode, name, self.stack
+			),
+		}
+	}
 }
 
 pub struct VM {
-	trace: bool,
-	strip: Box<dyn Strip>,
+	trace_handler: Option<Box<dyn FnMut(TraceEvent)>>,
+	strips: Vec<Box<dyn Strip>>,
 	deterministic: bool,
+	deterministic_time: Option<(u32, u32)>,
+	print_handler: Option<Box<dyn FnMut(usize, u32)>>,
+	frame_sink: Option<Sender<Vec<u8>>>,
+	word_width: WordWidth,
+	fuel_costs: HashMap<Fuel, usize>,
+}
+
+/* An entry in the table of fuel costs. The cost of a user command overrides the cost of the USER instruction. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Fuel {
+	Instruction(Prefix),
+	User(UserCommand),
+}
+
+/* Number of bits the VM computes with. Devices with a 16-bit implementation of the VM truncate literals and the
+results of arithmetic to 16 bits. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WordWidth {
+	U16,
+	U32,
+}
+
+impl WordWidth {
+	fn truncate(self, value: u32) -> u32 {
+		match self {
+			WordWidth::U16 => value & 0xFFFF,
+			WordWidth::U32 => value,
+		}
+	}
 }
 
 #[derive(Debug)]
 pub enum VMError {
 	UnknownInstruction,
 	StackUnderflow,
+	/* An assert instruction (at the given address) found a zero value */
+	AssertionFailed(usize),
 	RuntimeError(String),
 }
 
+impl std::fmt::Display for VMError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			VMError::UnknownInstruction => write!(f, "unknown instruction"),
+			VMError::StackUnderflow => write!(f, "stack underflow"),
+			VMError::AssertionFailed(pc) => write!(f, "assertion failed at pc={}", pc),
+			VMError::RuntimeError(message) => write!(f, "{}", message),
+		}
+	}
+}
+
 pub enum Outcome {
 	Ended,
 	GlobalInstructionLimitReached,
 	LocalInstructionLimitReached,
 	Yielded,
+	/* The program asks to be resumed only after the specified time has passed */
+	SleepRequested(Duration),
 	Error(VMError),
 }
 
@@ -53,39 +135,129 @@ impl<'a> State<'a> {
 			instruction_limit,
 			instruction_count: 0,
 			deterministic_rng: ChaCha20Rng::from_seed([0u8; 32]),
+			selected_strip: 0,
+			trace_note: None,
+			frame_count: 0,
 		}
 	}
 	pub fn pc(&self) -> usize {
 		self.pc
 	}
 
-	fn pushi(&mut self, postfix: u8) {
+	pub fn stack(&self) -> &[u32] {
+		&self.stack
+	}
+
+	pub fn program(&self) -> &Program {
+		&self.program
+	}
+
+	/* Describes the current position in the program, including the source line when the program has debug info */
+	pub fn location(&self) -> String {
+		match self.program.source_line(self.pc) {
+			Some(line) => format!("pc={} (line {})", self.pc, line),
+			None => format!("pc={}", self.pc),
+		}
+	}
+
+	/* Returns the VM that executes the program */
+	pub fn vm(&mut self) -> &mut VM {
+		self.vm
+	}
+
+	/* Appends code to the program. When the program has ended, execution continues with the appended code. */
+	pub fn append(&mut self, code: &[u8]) {
+		self.program.code.extend_from_slice(code);
+	}
+
+	/* Stops executing the remainder of the program and truncates the stack to the given depth */
+	pub fn abandon(&mut self, stack_depth: usize) {
+		self.pc = self.program.code.len();
+		self.stack.truncate(stack_depth);
+	}
+
+	/* Returns the total number of instructions executed so far */
+	pub fn instruction_count(&self) -> usize {
+		self.instruction_count
+	}
+
+	fn tracing(&self) -> bool {
+		self.vm.trace_handler.is_some()
+	}
+
+	/* Describes the variable at the given stack depth for the instruction being executed (when the program has
+	debug information) */
+	fn variable_note(&self, depth: u32) -> String {
+		match self.program.variable_at(self.pc, depth) {
+			Some(name) => format!(" ({})", name),
+			None => String::new(),
+		}
+	}
+
+	/* Adds a note to the trace event for the instruction being executed */
+	fn note(&mut self, note: String) {
+		match &mut self.trace_note {
+			Some(existing) => {
+				existing.push(' ');
+				existing.push_str(&note);
+			}
+			None => self.trace_note = Some(note),
+		}
+	}
+
+	#[allow(clippy::borrowed_box)]
+	fn strip(&mut self) -> &mut Box<dyn Strip> {
+		&mut self.vm.strips[self.selected_strip]
+	}
+
+	/* Returns an error when the `size` bytes of operands of the current instruction extend beyond the end of the code */
+	fn check_operands(&self, size: usize) -> Option<Outcome> {
+		if self.pc + size >= self.program.code.len() {
+			Some(Outcome::Error(VMError::RuntimeError(format!(
+				"operands of instruction at {} overrun the end of the program",
+				self.pc
+			))))
+		} else {
+			None
+		}
+	}
+
+	fn pushi(&mut self, postfix: u8) -> Option<Outcome> {
+		if let Some(outcome) = self.check_operands(postfix as usize * 4) {
+			return Some(outcome);
+		}
 		for _ in 0..postfix {
 			let value = u32::from(self.program.code[self.pc + 1])
 				| u32::from(self.program.code[self.pc + 2]) << 8
 				| u32::from(self.program.code[self.pc + 3]) << 16
 				| u32::from(self.program.code[self.pc + 4]) << 24;
+			let value = self.vm.word_width.truncate(value);
 			self.stack.push(value);
 
-			if self.vm.trace {
-				print!("\tv={}", value);
+			if self.tracing() {
+				self.note(format!("v={}", value));
 			}
 			self.pc += 4;
 		}
+		None
 	}
 
-	fn pushb(&mut self, postfix: u8) {
+	fn pushb(&mut self, postfix: u8) -> Option<Outcome> {
 		if postfix == 0 {
 			self.stack.push(0);
 		} else {
+			if let Some(outcome) = self.check_operands(postfix as usize) {
+				return Some(outcome);
+			}
 			for _ in 0..postfix {
 				self.pc += 1;
-				if self.vm.trace {
-					print!("\tv={}", self.program.code[self.pc]);
+				if self.tracing() {
+					self.note(format!("v={}", self.program.code[self.pc]));
 				}
 				self.stack.push(u32::from(self.program.code[self.pc]));
 			}
 		}
+		None
 	}
 
 	fn user(&mut self, postfix: u8) -> Option<Outcome> {
@@ -94,12 +266,20 @@ impl<'a> State<'a> {
 		match user {
 			None => Some(Outcome::Error(VMError::UnknownInstruction)),
 			Some(UserCommand::GET_LENGTH) => {
-				self.stack.push(self.vm.strip.length() as u32);
+				let length = self.strip().length();
+				self.stack.push(length as u32);
 				None
 			}
 			Some(UserCommand::GET_WALL_TIME) => {
 				if self.vm.deterministic {
-					self.stack.push((self.instruction_count / 10) as u32);
+					let time = match self.vm.deterministic_time {
+						Some((base, tick)) => {
+							u64::from(base)
+								+ (self.instruction_count as u64) * u64::from(tick) / 1000
+						}
+						None => (self.instruction_count / 10) as u64,
+					};
+					self.stack.push(time as u32); // Wrap around when we exceed u32::MAX
 				} else {
 					let time = SystemTime::now()
 						.duration_since(UNIX_EPOCH)
@@ -111,7 +291,11 @@ impl<'a> State<'a> {
 			}
 			Some(UserCommand::GET_PRECISE_TIME) => {
 				if self.vm.deterministic {
-					self.stack.push(self.instruction_count as u32);
+					let time = match self.vm.deterministic_time {
+						Some((_, tick)) => (self.instruction_count as u64) * u64::from(tick),
+						None => self.instruction_count as u64,
+					};
+					self.stack.push(time as u32); // Wrap around when we exceed u32::MAX
 				} else {
 					let time = SystemTime::now()
 						.duration_since(self.start_time)
@@ -122,35 +306,62 @@ impl<'a> State<'a> {
 				None
 			}
 			Some(UserCommand::SET_PIXEL) => {
-				if self.stack.is_empty() {
+				if self.stack.len() < 2 {
 					return Some(Outcome::Error(VMError::StackUnderflow));
 				}
 				let v = self.stack.pop().unwrap();
-				let r = (((v >> 0) as u32) & 0xFF) as u8;
-				let g = (((v >> 8) as u32) & 0xFF) as u8;
-				let b = (((v >> 16) as u32) & 0xFF) as u8;
-				let idx = self.stack.last().unwrap();
+				let color = Color::from_packed(v);
+				let idx = *self.stack.last().unwrap();
 
-				if self.vm.trace {
-					print!("\tset_pixel {} idx={} r={} g={}, b={}", v, idx, r, g, b);
+				if self.tracing() {
+					self.note(format!(
+						"set_pixel {} idx={} r={} g={} b={}",
+						v, idx, color.r, color.g, color.b
+					));
 				}
 
-				if *idx >= self.vm.strip.length() {
+				if idx >= self.strip().length() {
 					return Some(Outcome::Error(VMError::RuntimeError(format!(
 						"index {} exceeds strip length {}",
-						*idx,
-						self.vm.strip.length()
+						idx,
+						self.strip().length()
 					))));
 				}
 
-				self.vm.strip.set_pixel(*idx, r, g, b);
+				self.strip().set_pixel(idx, color.r, color.g, color.b);
 				None
 			}
 			Some(UserCommand::BLIT) => {
-				if self.vm.trace {
-					print!("\tblit");
+				if self.tracing() {
+					self.note("blit".to_string());
+				}
+				self.strip().blit();
+				self.frame_count = self.frame_count.wrapping_add(1);
+
+				if self.vm.frame_sink.is_some() {
+					// Frames are sent as the LEDs show them, i.e. with the global brightness applied
+					let brightness = self.strip().brightness();
+					let frame = self
+						.strip()
+						.snapshot()
+						.into_iter()
+						.map(|v| dim(v, brightness))
+						.collect();
+
+					// The receiving end may have gone away, which is fine
+					let _ = self.vm.frame_sink.as_ref().unwrap().send(frame);
 				}
-				self.vm.strip.blit();
+				None
+			}
+			Some(UserCommand::GET_FRAME) => {
+				self.stack.push(self.frame_count);
+				None
+			}
+			Some(UserCommand::CLEAR) => {
+				if self.tracing() {
+					self.note("clear".to_string());
+				}
+				self.strip().clear();
 				None
 			}
 			Some(UserCommand::RANDOM_INT) => {
@@ -158,7 +369,13 @@ impl<'a> State<'a> {
 					return Some(Outcome::Error(VMError::StackUnderflow));
 				}
 				let v = self.stack.pop().unwrap();
-				self.stack.push(self.deterministic_rng.gen_range(0, v));
+				// random(0) returns zero
+				let r = if v == 0 {
+					0
+				} else {
+					self.deterministic_rng.gen_range(0, v)
+				};
+				self.stack.push(r);
 				None
 			}
 			Some(UserCommand::GET_PIXEL) => {
@@ -166,12 +383,33 @@ impl<'a> State<'a> {
 					return Some(Outcome::Error(VMError::StackUnderflow));
 				}
 				let v = self.stack.pop().unwrap();
-				let color = self.vm.strip.get_pixel(v);
-				let color_value = (v & 0xFF)
-					| (color.r as u32) << 8
-					| (color.g as u32) << 16
-					| (color.b as u32) << 24;
-				self.stack.push(color_value);
+				if v >= self.strip().length() {
+					return Some(Outcome::Error(VMError::RuntimeError(format!(
+						"index {} exceeds strip length {}",
+						v,
+						self.strip().length()
+					))));
+				}
+				let color = self.strip().get_pixel(v);
+				self.stack.push(color.to_packed());
+				None
+			}
+			Some(UserCommand::SELECT_STRIP) => {
+				if self.stack.is_empty() {
+					return Some(Outcome::Error(VMError::StackUnderflow));
+				}
+				let idx = *self.stack.last().unwrap() as usize;
+				if self.tracing() {
+					self.note(format!("select_strip idx={}", idx));
+				}
+				if idx >= self.vm.strips.len() {
+					return Some(Outcome::Error(VMError::RuntimeError(format!(
+						"strip {} does not exist (VM has {} strips)",
+						idx,
+						self.vm.strips.len()
+					))));
+				}
+				self.selected_strip = idx;
 				None
 			}
 		}
@@ -197,167 +435,282 @@ impl<'a> State<'a> {
 				println!("DUMP: {:?}", self.stack);
 				None
 			}
+			Some(Special::PRINT) => {
+				if self.stack.is_empty() {
+					return Some(Outcome::Error(VMError::StackUnderflow));
+				}
+				let v = self.stack.pop().unwrap();
+				match &mut self.vm.print_handler {
+					Some(handler) => handler(self.pc, v),
+					None => println!("PRINT {:04}: {}", self.pc, v),
+				}
+				None
+			}
 			Some(Special::YIELD) => {
 				self.pc += 1;
 				Some(Outcome::Yielded)
 			}
+			Some(Special::ASSERT) => match self.stack.pop() {
+				None => Some(Outcome::Error(VMError::StackUnderflow)),
+				Some(0) => Some(Outcome::Error(VMError::AssertionFailed(self.pc))),
+				Some(_) => None,
+			},
+			Some(Special::SLEEP) => {
+				if self.stack.is_empty() {
+					return Some(Outcome::Error(VMError::StackUnderflow));
+				}
+				let ms = self.stack.pop().unwrap();
+				self.pc += 1;
+				Some(Outcome::SleepRequested(Duration::from_millis(u64::from(
+					ms,
+				))))
+			}
+			Some(Special::HALT) => {
+				// Running the program again does not continue after the halt
+				self.pc = self.program.code.len();
+				Some(Outcome::Ended)
+			}
 			Some(Special::TWOBYTE) => Some(Outcome::Error(VMError::UnknownInstruction)),
 		}
 	}
 
-	pub fn run(&mut self, local_instruction_limit: Option<usize>) -> Outcome {
-		let mut local_instruction_count = 0;
-		while self.pc < self.program.code.len() {
-			// Enforce global instruction count limit
-			if let Some(limit) = self.instruction_limit {
-				if self.instruction_count >= limit {
-					return Outcome::GlobalInstructionLimitReached;
-				}
-			}
+	/* Executes a single instruction. Returns an outcome when execution cannot simply continue at the next
+	instruction. */
+	pub fn step(&mut self) -> Option<Outcome> {
+		if self.pc >= self.program.code.len() {
+			return Some(Outcome::Ended);
+		}
 
-			// Enforce local instruction count limit
-			if let Some(limit) = local_instruction_limit {
-				if local_instruction_count >= limit {
-					return Outcome::LocalInstructionLimitReached;
-				}
+		if !self.tracing() {
+			return self.execute();
+		}
+
+		let pc = self.pc;
+		let opcode = self.program.code[pc];
+		let outcome = self.execute();
+		let event = TraceEvent {
+			pc,
+			opcode,
+			note: self.trace_note.take(),
+			stack: self.stack.clone(),
+		};
+		if let Some(handler) = &mut self.vm.trace_handler {
+			handler(event);
+		}
+		outcome
+	}
+
+	fn fuel_cost(&self, prefix: Prefix, postfix: u8) -> usize {
+		let costs = &self.vm.fuel_costs;
+		if costs.is_empty() {
+			return 1;
+		}
+
+		let user_cost = match prefix {
+			Prefix::USER => {
+				UserCommand::from(postfix).and_then(|command| costs.get(&Fuel::User(command)))
 			}
+			_ => None,
+		};
+		*user_cost
+			.or_else(|| costs.get(&Fuel::Instruction(prefix)))
+			.unwrap_or(&1)
+	}
 
-			let ins = Prefix::from(self.program.code[self.pc]);
-			if let Some(i) = ins {
-				self.instruction_count += 1;
-				local_instruction_count += 1;
-				let postfix = self.program.code[self.pc] & 0x0F;
+	fn execute(&mut self) -> Option<Outcome> {
+		let ins = Prefix::from(self.program.code[self.pc]);
+		if let Some(i) = ins {
+			let postfix = self.program.code[self.pc] & 0x0F;
+			self.instruction_count += self.fuel_cost(i, postfix);
 
-				if self.vm.trace {
-					print!("{:04}.\t{:02x}\t{}", self.pc, self.program.code[self.pc], i);
+			match i {
+				Prefix::PUSHI => {
+					if let Some(outcome) = self.pushi(postfix) {
+						return Some(outcome);
+					}
+				}
+				Prefix::PUSHB => {
+					if let Some(outcome) = self.pushb(postfix) {
+						return Some(outcome);
+					}
 				}
+				Prefix::POP => {
+					if self.tracing() {
+						let names: Vec<&str> = (0..u32::from(postfix))
+							.filter_map(|depth| self.program.variable_at(self.pc, depth))
+							.collect();
+						if !names.is_empty() {
+							self.note(format!("variables: {}", names.join(", ")));
+						}
+					}
+					if (postfix as usize) > self.stack.len() {
+						return Some(Outcome::Error(VMError::StackUnderflow));
+					}
 
-				match i {
-					Prefix::PUSHI => {
-						self.pushi(postfix);
+					for _ in 0..postfix {
+						let _ = self.stack.pop();
 					}
-					Prefix::PUSHB => {
-						self.pushb(postfix);
+				}
+				Prefix::PEEK => {
+					if (postfix as usize) >= self.stack.len() {
+						return Some(Outcome::Error(VMError::StackUnderflow));
 					}
-					Prefix::POP => {
-						assert!(
-							(postfix as usize) <= self.stack.len(),
-							"cannot pop beyond stack (pop {} elements > stack size {})!",
+					let val = self.stack[self.stack.len() - (postfix as usize) - 1];
+					if self.tracing() {
+						self.note(format!(
+							"index={}{} v={}",
 							postfix,
-							self.stack.len()
-						);
-
-						for _ in 0..postfix {
-							let _ = self.stack.pop();
-						}
+							self.variable_note(u32::from(postfix)),
+							val
+						));
 					}
-					Prefix::PEEK => {
-						assert!(
-							(postfix as usize) < self.stack.len(),
-							"cannot peek beyond stack (index {} > stack size {})!",
+					self.stack.push(val);
+				}
+				Prefix::POKE => {
+					if self.stack.is_empty() {
+						return Some(Outcome::Error(VMError::StackUnderflow));
+					}
+					let val = self.stack.pop().unwrap();
+					if (postfix as usize) >= self.stack.len() {
+						return Some(Outcome::Error(VMError::StackUnderflow));
+					}
+					let index = self.stack.len() - (postfix as usize) - 1;
+					if self.tracing() {
+						// The index is relative to the stack after popping the value, the symbol table to the stack before
+						self.note(format!(
+							"index={}{} v={}",
 							postfix,
-							self.stack.len()
-						);
-						let val = self.stack[self.stack.len() - (postfix as usize) - 1];
-						if self.vm.trace {
-							print!("\tindex={} v={}", postfix, val);
-						}
-						self.stack.push(val);
+							self.variable_note(u32::from(postfix) + 1),
+							val
+						));
 					}
-					Prefix::JMP | Prefix::JZ | Prefix::JNZ => {
-						let target = (u32::from(self.program.code[self.pc + 1])
-							| (u32::from(self.program.code[self.pc + 2]) << 8)) as usize;
-
-						self.pc = match i {
-							Prefix::JMP => target,
-							Prefix::JZ => {
-								if self.stack.is_empty() {
-									return Outcome::Error(VMError::StackUnderflow);
-								}
-								let head = self.stack.last().unwrap();
-								if *head == 0 {
-									target
-								} else {
-									self.pc + 3
-								}
-							}
-							Prefix::JNZ => {
-								if self.stack.is_empty() {
-									return Outcome::Error(VMError::StackUnderflow);
-								}
-								let head = self.stack.last().unwrap();
-								if *head != 0 {
-									target
-								} else {
-									self.pc + 3
-								}
-							}
-							_ => return Outcome::Error(VMError::UnknownInstruction),
-						};
-
-						if self.vm.trace {
-							println!();
-						}
-						continue;
+					self.stack[index] = val;
+				}
+				Prefix::JMP | Prefix::JZ | Prefix::JNZ => {
+					if let Some(outcome) = self.check_operands(2) {
+						return Some(outcome);
 					}
-					Prefix::BINARY => {
-						if let Some(op) = Binary::from(postfix) {
-							if self.stack.len() < 2 {
-								return Outcome::Error(VMError::StackUnderflow);
+					let operand = [
+						self.program.code[self.pc + 1],
+						self.program.code[self.pc + 2],
+					];
+					let target = if postfix & JUMP_RELATIVE != 0 {
+						let target = self.pc as i64 + i64::from(i16::from_le_bytes(operand));
+						if target < 0 {
+							return Some(Outcome::Error(VMError::RuntimeError(format!(
+								"relative jump at {} to {} is before the start of the program",
+								self.pc, target
+							))));
+						}
+						target as usize
+					} else {
+						usize::from(u16::from_le_bytes(operand))
+					};
+
+					self.pc = match i {
+						Prefix::JMP => target,
+						Prefix::JZ => {
+							if self.stack.is_empty() {
+								return Some(Outcome::Error(VMError::StackUnderflow));
 							}
-							let rhs = self.stack.pop().unwrap();
-							let lhs = self.stack.pop().unwrap();
-							self.stack.push(op.apply(lhs, rhs))
-						} else {
-							if self.vm.trace {
-								println!("invalid binary postfix: {}", postfix);
+							let head = self.stack.last().unwrap();
+							if *head == 0 {
+								target
+							} else {
+								self.pc + 3
 							}
-							return Outcome::Error(VMError::UnknownInstruction);
 						}
-					}
-					Prefix::UNARY => {
-						if let Some(op) = Unary::from(postfix) {
+						Prefix::JNZ => {
 							if self.stack.is_empty() {
-								return Outcome::Error(VMError::StackUnderflow);
+								return Some(Outcome::Error(VMError::StackUnderflow));
 							}
-							let lhs = self.stack.pop().unwrap();
-							self.stack.push(op.apply(lhs));
-						} else {
-							if self.vm.trace {
-								println!("invalid binary postfix: {}", postfix);
+							let head = self.stack.last().unwrap();
+							if *head != 0 {
+								target
+							} else {
+								self.pc + 3
 							}
-							return Outcome::Error(VMError::UnknownInstruction);
 						}
-					}
-					Prefix::USER => {
-						if let Some(outcome) = self.user(postfix) {
-							return outcome;
+						_ => return Some(Outcome::Error(VMError::UnknownInstruction)),
+					};
+
+					return None;
+				}
+				Prefix::BINARY => {
+					if let Some(op) = Binary::from(postfix) {
+						if self.stack.len() < 2 {
+							return Some(Outcome::Error(VMError::StackUnderflow));
 						}
+						let rhs = self.stack.pop().unwrap();
+						let lhs = self.stack.pop().unwrap();
+						self.stack
+							.push(self.vm.word_width.truncate(op.apply(lhs, rhs)))
+					} else {
+						if self.tracing() {
+							self.note(format!("invalid binary postfix: {}", postfix));
+						}
+						return Some(Outcome::Error(VMError::UnknownInstruction));
 					}
-					Prefix::SPECIAL => {
-						if let Some(outcome) = self.special(postfix) {
-							return outcome;
+				}
+				Prefix::UNARY => {
+					if let Some(op) = Unary::from(postfix) {
+						if self.stack.is_empty() {
+							return Some(Outcome::Error(VMError::StackUnderflow));
+						}
+						let lhs = self.stack.pop().unwrap();
+						self.stack.push(self.vm.word_width.truncate(op.apply(lhs)));
+					} else {
+						if self.tracing() {
+							self.note(format!("invalid unary postfix: {}", postfix));
 						}
+						return Some(Outcome::Error(VMError::UnknownInstruction));
 					}
 				}
-			} else {
-				if self.vm.trace {
-					println!(
-						"{:04}.\t{:02x}\tUnknown instruction\n",
-						self.pc, self.program.code[self.pc]
-					);
+				Prefix::USER => {
+					if let Some(outcome) = self.user(postfix) {
+						return Some(outcome);
+					}
+				}
+				Prefix::SPECIAL => {
+					if let Some(outcome) = self.special(postfix) {
+						return Some(outcome);
+					}
 				}
-				break;
 			}
-
-			if self.vm.trace {
-				println!("\tstack: {:?}", self.stack);
+		} else {
+			if self.tracing() {
+				self.note("unknown instruction".to_string());
 			}
-			self.pc += 1;
+			return Some(Outcome::Error(VMError::UnknownInstruction));
 		}
 
-		if self.vm.trace {
-			println!("Ended; {} instructions executed", self.instruction_count);
+		self.pc += 1;
+		None
+	}
+
+	pub fn run(&mut self, local_instruction_limit: Option<usize>) -> Outcome {
+		let mut local_instruction_count = 0;
+		while self.pc < self.program.code.len() {
+			// Enforce global instruction count limit
+			if let Some(limit) = self.instruction_limit {
+				if self.instruction_count >= limit {
+					return Outcome::GlobalInstructionLimitReached;
+				}
+			}
+
+			// Enforce local instruction count limit
+			if let Some(limit) = local_instruction_limit {
+				if local_instruction_count >= limit {
+					return Outcome::LocalInstructionLimitReached;
+				}
+			}
+
+			local_instruction_count += 1;
+			match self.step() {
+				None => {}
+				Some(Outcome::Ended) => break,
+				Some(outcome) => return outcome,
+			}
 		}
 
 		Outcome::Ended
@@ -366,27 +719,941 @@ impl<'a> State<'a> {
 
 impl<'a> VM {
 	pub fn new(strip: Box<dyn Strip>) -> VM {
+		VM::with_strips(vec![strip])
+	}
+
+	/* Creates a VM that drives multiple strips. Programs select the strip to operate on using select_strip; the
+	first strip is selected when a program starts. */
+	pub fn with_strips(strips: Vec<Box<dyn Strip>>) -> VM {
+		assert!(!strips.is_empty(), "a VM requires at least one strip");
 		VM {
-			trace: false,
-			strip,
+			trace_handler: None,
+			strips,
 			deterministic: false,
+			deterministic_time: None,
+			print_handler: None,
+			frame_sink: None,
+			word_width: WordWidth::U32,
+			fuel_costs: HashMap::new(),
 		}
 	}
 
+	/* Returns the first strip */
 	#[allow(clippy::borrowed_box)]
 	pub fn strip(&'a mut self) -> &'a mut Box<dyn Strip> {
-		&mut self.strip
+		&mut self.strips[0]
 	}
 
+	#[allow(clippy::borrowed_box)]
+	pub fn strip_at(&'a mut self, index: usize) -> Option<&'a mut Box<dyn Strip>> {
+		self.strips.get_mut(index)
+	}
+
+	/* Sets the global brightness (0...255) of all strips */
+	pub fn set_brightness(&mut self, brightness: u8) {
+		for strip in self.strips.iter_mut() {
+			strip.set_brightness(brightness);
+		}
+	}
+
+	/* Enables or disables printing executed instructions to stdout */
 	pub fn set_trace(&mut self, trace: bool) {
-		self.trace = trace
+		self.trace_handler = if trace {
+			Some(Box::new(TraceEvent::print))
+		} else {
+			None
+		}
+	}
+
+	/* Sets a function that receives an event for each executed instruction (replacing the printing trace) */
+	pub fn set_trace_handler(&mut self, handler: Box<dyn FnMut(TraceEvent)>) {
+		self.trace_handler = Some(handler)
 	}
 
 	pub fn set_deterministic(&mut self, d: bool) {
 		self.deterministic = d
 	}
 
+	/* Configures the clock simulated in deterministic mode: get_wall_time starts at `base_secs`, and each executed
+	instruction advances the clock by `tick_per_instruction` milliseconds. When not configured, get_wall_time returns
+	the number of instructions divided by ten and get_precise_time returns the number of instructions. */
+	pub fn set_deterministic_time(&mut self, base_secs: u32, tick_per_instruction: u32) {
+		self.deterministic_time = Some((base_secs, tick_per_instruction))
+	}
+
+	/* Sets the number of bits literals and the results of arithmetic are truncated to (default: 32 bits) */
+	pub fn set_word_width(&mut self, width: WordWidth) {
+		self.word_width = width
+	}
+
+	/* Sets the amount of fuel each instruction or user command consumes. The instruction count (and limit) of a
+	program is expressed in fuel; instructions not in the table cost 1. */
+	pub fn set_fuel_costs(&mut self, costs: HashMap<Fuel, usize>) {
+		self.fuel_costs = costs
+	}
+
+	/* Sets a channel that receives a snapshot of the pixels (as RGB bytes) of the selected strip on each blit */
+	pub fn set_frame_sink(&mut self, sink: Sender<Vec<u8>>) {
+		self.frame_sink = Some(sink)
+	}
+
+	/* Sets a function that receives the program counter and value of each executed print statement (instead of
+	printing these to stdout) */
+	pub fn set_print_handler(&mut self, handler: Box<dyn FnMut(usize, u32)>) {
+		self.print_handler = Some(handler)
+	}
+
 	pub fn start(&mut self, program: Program, instruction_limit: Option<usize>) -> State {
 		State::new(self, program, instruction_limit)
 	}
+
+	/* Like start, but begins execution at the given offset, which must be the start of an instruction (e.g. the address
+	of a subroutine in a library of routines) */
+	pub fn start_at(
+		&mut self,
+		program: Program,
+		offset: usize,
+		instruction_limit: Option<usize>,
+	) -> Result<State<'_>, String> {
+		program.check_entry_offset(offset)?;
+		let mut state = State::new(self, program, instruction_limit);
+		state.pc = offset;
+		Ok(state)
+	}
+}
+
+/* Runs the program (deterministically) for at most `max_frames` frames and returns the number of the frame after which
+the pixels of the strip no longer change. Frames are counted at each yield; frame 0 is the strip before the program
+starts. Returns None when the output still changes in the last frame, or when the program fails or does not yield. */
+pub fn run_until_stable(
+	program: Program,
+	strip: Box<dyn Strip>,
+	max_frames: usize,
+) -> Option<usize> {
+	let mut vm = VM::new(strip);
+	vm.set_deterministic(true);
+	let mut last_frame = vm.strip().snapshot();
+	let mut last_change = 0;
+	let mut state = vm.start(program, None);
+
+	for frame in 1..=max_frames {
+		let ended = match state.run(Some(STABLE_FRAME_INSTRUCTION_LIMIT)) {
+			Outcome::Yielded | Outcome::SleepRequested(_) => false,
+			Outcome::Ended => true,
+			_ => return None,
+		};
+
+		let snapshot = state.vm().strip().snapshot();
+		if snapshot != last_frame {
+			last_change = frame;
+			last_frame = snapshot;
+		}
+
+		// Output of a program that has ended cannot change anymore
+		if ended {
+			return Some(last_change);
+		}
+	}
+
+	if last_change < max_frames {
+		Some(last_change)
+	} else {
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pwlp::strip::{DummyStrip, NullStrip};
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	/* Runs a program until it ends and returns the resulting pixel colors as 0x00BBGGRR */
+	fn run(source: &str, length: u32) -> Vec<u32> {
+		run_program(Program::from_source(source).unwrap(), length)
+	}
+
+	fn run_program(program: Program, length: u32) -> Vec<u32> {
+		let mut vm = VM::new(Box::new(DummyStrip::new(length, false)));
+		vm.set_deterministic(true);
+		let mut state = vm.start(program, Some(10_000));
+		loop {
+			match state.run(None) {
+				Outcome::Yielded | Outcome::SleepRequested(_) => {}
+				Outcome::Ended => break,
+				Outcome::GlobalInstructionLimitReached | Outcome::LocalInstructionLimitReached => {
+					panic!("instruction limit reached")
+				}
+				Outcome::Error(e) => panic!("error at pc={}: {:?}", state.pc(), e),
+			}
+		}
+
+		(0..length)
+			.map(|i| {
+				let c = vm.strip().get_pixel(i);
+				u32::from(c.r) | u32::from(c.g) << 8 | u32::from(c.b) << 16
+			})
+			.collect()
+	}
+
+	#[test]
+	fn append() {
+		let first = Program::from_source("for(i=0; i<3; i+=1) { set_pixel(i, 1, 0, 0) }").unwrap();
+		let second = Program::from_source("for(n=2) { set_pixel(n + 3, 2, 0, 0) }").unwrap();
+
+		let mut combined = first.clone();
+		combined.append(&second);
+		assert_eq!(combined.code.len(), first.code.len() + second.code.len());
+		assert_eq!(run_program(combined, 5), vec![1, 1, 1, 2, 2]);
+
+		// Appending in the other order moves the first program instead
+		let mut combined = second.clone();
+		combined.append(&first);
+		assert_eq!(run_program(combined, 5), vec![1, 1, 1, 2, 2]);
+	}
+
+	#[test]
+	fn relative_jumps() {
+		let source = "for(i=0; i<3; i+=1) { if(i == 1) { set_pixel(i, 1, 0, 0) } else { set_pixel(i, 2, 0, 0) } }";
+		let absolute = Program::from_source(source).unwrap();
+		let mut relative = absolute.clone();
+		relative.make_position_independent();
+		assert_ne!(relative.code, absolute.code);
+		assert!(relative.validate().is_ok());
+		assert_eq!(run_program(relative.clone(), 3), run_program(absolute, 3));
+		assert_eq!(run_program(relative.clone(), 3), vec![2, 1, 2]);
+
+		// Position-independent code is appended without changes
+		let mut combined = Program::from_source("for(n=2) { set_pixel(n + 3, 3, 0, 0) }").unwrap();
+		let prologue_length = combined.code.len();
+		combined.append(&relative);
+		assert_eq!(&combined.code[prologue_length..], &relative.code[..]);
+		assert_eq!(run_program(combined, 5), vec![2, 1, 2, 3, 3]);
+	}
+
+	#[test]
+	fn wrap_index() {
+		let pixels = run("set_pixel(wrap_index(12), 1, 0, 0)", 10);
+		assert_eq!(pixels[2], 1);
+		assert_eq!(pixels.iter().sum::<u32>(), 1);
+
+		assert_eq!(
+			run(
+				"for(i=0; i<4; i+=1) { set_pixel(wrap_index(i + 1), i + 1, 0, 0) }",
+				3
+			),
+			vec![3, 4, 2]
+		);
+	}
+
+	#[test]
+	fn signed_comparison() {
+		assert_eq!(
+			run(
+				"set_pixel(0, signed_lt(-1, 0), -1 < 0, signed_gt(0, -1)); \
+				a = -5; b = 3; set_pixel(1, signed_lte(a, b), signed_gte(a, b), signed_lt(b, a))",
+				2
+			),
+			vec![0x01_00_01, 0x00_00_01]
+		);
+	}
+
+	#[test]
+	fn halt() {
+		let source = "x = 0; loop { x += 1; set_pixel(0, x, 0, 0); halt; yield }";
+		assert_eq!(run(source, 1), vec![1]);
+
+		let program = Program::from_source(source).unwrap();
+		assert!(program.has_yield_in_loops());
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert!(matches!(state.run(None), Outcome::Ended));
+
+		// Variables may start with 'halt'
+		assert_eq!(run("halted = 3; set_pixel(0, halted, 0, 0)", 1), vec![3]);
+	}
+
+	#[test]
+	fn get_frame() {
+		let printed = Rc::new(RefCell::new(vec![]));
+		let printed_handler = printed.clone();
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		vm.set_print_handler(Box::new(move |_, v| printed_handler.borrow_mut().push(v)));
+
+		let program =
+			Program::from_source("print(get_frame); for(i = 3) { blit; print(get_frame()) }")
+				.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert_eq!(*printed.borrow(), vec![0, 1, 2, 3]);
+
+		// Each run starts counting from zero again
+		let mut state = vm.start(Program::from_source("print(get_frame)").unwrap(), None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert_eq!(printed.borrow().last(), Some(&0));
+	}
+
+	#[test]
+	fn fuel_costs() {
+		let fuel = |vm: &mut VM, program: Program| {
+			let mut state = vm.start(program, None);
+			assert!(matches!(state.run(None), Outcome::Ended));
+			state.instruction_count()
+		};
+		let pop = program! { push 1; pop 1 };
+		let clear = program! { clear };
+
+		// By default, each instruction costs one unit of fuel
+		let mut vm = VM::new(Box::new(DummyStrip::new(10, false)));
+		assert_eq!(fuel(&mut vm, pop.clone()), 2);
+		assert_eq!(fuel(&mut vm, clear.clone()), 1);
+
+		let mut costs = HashMap::new();
+		costs.insert(Fuel::Instruction(Prefix::USER), 5);
+		costs.insert(Fuel::User(UserCommand::CLEAR), 100);
+		costs.insert(Fuel::Instruction(Prefix::PUSHB), 2);
+		vm.set_fuel_costs(costs);
+		assert_eq!(fuel(&mut vm, pop), 3);
+		assert_eq!(fuel(&mut vm, clear), 100);
+		assert_eq!(fuel(&mut vm, program! { blit }), 5);
+
+		// The instruction limit is a fuel budget
+		let mut state = vm.start(program! { clear; clear; clear }, Some(150));
+		assert!(matches!(
+			state.run(None),
+			Outcome::GlobalInstructionLimitReached
+		));
+	}
+
+	#[test]
+	fn assert() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut state = vm.start(Program::from_source("assert(1 + 1 == 2)").unwrap(), None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert!(state.stack().is_empty());
+		drop(state);
+
+		let program = Program::from_source("x = get_length; assert(x == 1); assert(0)").unwrap();
+		let assert_pc = program.code.len() - 2;
+		assert_eq!(
+			program.code[assert_pc],
+			Prefix::SPECIAL as u8 | Special::ASSERT as u8
+		);
+		let mut state = vm.start(program, None);
+		match state.run(None) {
+			Outcome::Error(error @ VMError::AssertionFailed(_)) => assert_eq!(
+				error.to_string(),
+				format!("assertion failed at pc={}", assert_pc)
+			),
+			_ => panic!("expected assertion to fail"),
+		}
+		assert_eq!(state.pc(), assert_pc);
+	}
+
+	#[test]
+	fn malformed_programs() {
+		let outcome = |code: Vec<u8>| {
+			let mut vm = VM::new(Box::new(DummyStrip::new(2, false)));
+			let mut state = vm.start(Program::from_binary(code), None);
+			state.run(None)
+		};
+
+		// Operands that overrun the code
+		let runtime_error =
+			|code: Vec<u8>| matches!(outcome(code), Outcome::Error(VMError::RuntimeError(_)));
+		assert!(runtime_error(vec![0x31, 0x01, 0x02, 0x03]));
+		assert!(runtime_error(vec![0x12, 0x01]));
+		assert!(runtime_error(vec![0x40, 0x00]));
+
+		// Popping or peeking beyond the stack, set_pixel with only one argument
+		let underflow =
+			|code: Vec<u8>| matches!(outcome(code), Outcome::Error(VMError::StackUnderflow));
+		assert!(underflow(vec![0x11, 0x01, 0x02]));
+		assert!(underflow(vec![0x11, 0x01, 0x21]));
+		assert!(underflow(vec![0x11, 0x01, 0xe3]));
+
+		// random(0) is zero, get_pixel beyond the strip is an error
+		assert_eq!(run("set_pixel(0, random(0) + 1, 0, 0)", 1), vec![1]);
+		assert!(runtime_error(vec![0x11, 0x05, 0xe6]));
+	}
+
+	#[test]
+	fn unknown_instruction() {
+		let mut code = Program::from_source("set_pixel(0, 1, 0, 0)").unwrap().code;
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let mut state = vm.start(Program::from_binary(code.clone()), None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+
+		// Trailing invalid opcode
+		code.push(0xA0);
+		let mut state = vm.start(Program::from_binary(code.clone()), None);
+		assert!(matches!(
+			state.run(None),
+			Outcome::Error(VMError::UnknownInstruction)
+		));
+		assert_eq!(state.pc(), code.len() - 1);
+	}
+
+	#[test]
+	fn word_width() {
+		let result = |width| {
+			let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+			vm.set_word_width(width);
+			let mut state = vm.start(
+				program! { push 0xFFFF; push 1; add; push 0x12345; dec },
+				None,
+			);
+			assert!(matches!(state.run(None), Outcome::Ended));
+			state.stack().to_vec()
+		};
+
+		assert_eq!(result(WordWidth::U32), vec![0x10000, 0x12344]);
+		assert_eq!(result(WordWidth::U16), vec![0, 0x2344]);
+	}
+
+	#[test]
+	fn division_by_zero() {
+		assert_eq!(
+			run("a = 5; b = 0; set_pixel(0, a / b + 1, a % b + 1, 0)", 1),
+			vec![0x0101]
+		);
+	}
+
+	#[test]
+	fn start_at_offset() {
+		let first = Program::from_source("set_pixel(0, 1, 0, 0)").unwrap();
+		let second = Program::from_source("set_pixel(1, 2, 0, 0)").unwrap();
+		let mut code = first.code.clone();
+		code.extend_from_slice(&second.code);
+		let program = Program::from_binary(code);
+
+		let mut vm = VM::new(Box::new(DummyStrip::new(2, false)));
+		let mut state = vm
+			.start_at(program.clone(), first.code.len(), None)
+			.unwrap();
+		assert_eq!(state.pc(), first.code.len());
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+		assert_eq!(vm.strip().get_pixel(0).r, 0);
+		assert_eq!(vm.strip().get_pixel(1).r, 2);
+
+		// Offsets must be at an instruction boundary within the program
+		assert!(vm
+			.start_at(program.clone(), program.code.len(), None)
+			.is_ok());
+		assert!(vm
+			.start_at(program.clone(), first.code.len() + 1, None)
+			.is_err());
+		assert!(vm
+			.start_at(program.clone(), program.code.len() + 1, None)
+			.is_err());
+	}
+
+	#[test]
+	fn assignment_mutates_variable() {
+		assert_eq!(run("a = 1; a = a + 2; set_pixel(0, a, 0, 0)", 1), vec![3]);
+
+		// Assigning to a variable from an enclosing scope changes that variable
+		assert_eq!(
+			run("a = 0; for(i = 5) { a = a + i; }; set_pixel(0, a, 0, 0)", 1),
+			vec![10]
+		);
+	}
+
+	#[test]
+	fn else_if_chain() {
+		let source = "for(n = 3) {
+			if(n == 0) {
+				set_pixel(0, 1, 0, 0);
+			}
+			else if(n == 1) {
+				set_pixel(1, 2, 0, 0);
+			}
+			else {
+				x = n + 1;
+				set_pixel(2, x, 0, 0);
+			};
+		};
+		set_pixel(3, get_length, 0, 0)";
+		assert_eq!(run(source, 4), vec![1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn shifts() {
+		assert_eq!(run("set_pixel(0, 1 << 5, 0, 0)", 1), vec![32]);
+		assert_eq!(
+			run("a = get_length; b = 3; set_pixel(0, a << b, 0, 0)", 1),
+			vec![8]
+		);
+		assert_eq!(
+			run("a = get_length << 7; b = 3; set_pixel(0, a >> b, 0, 0)", 1),
+			vec![16]
+		);
+		assert_eq!(
+			run(
+				"a = get_length; b = 32; set_pixel(0, (a << b) | (a >> 40), 0, 0)",
+				1
+			),
+			vec![0]
+		);
+	}
+
+	#[test]
+	fn logical_operators() {
+		let source = "a = get_length; b = 0;
+			set_pixel(0, (a && b) | (b && a) << 1 | (a && 5) << 2, (a || b) | (b || b) << 1 | (b || 7) << 2, 0)";
+		assert_eq!(run(source, 1), vec![0b100 | 0b101 << 8]);
+
+		// && binds stronger than ||, comparisons stronger than both
+		assert_eq!(
+			run(
+				"a = get_length; set_pixel(0, a == 1 || a == 2 && 0, 0, 0)",
+				1
+			),
+			vec![1]
+		);
+	}
+
+	#[test]
+	fn logical_operators_short_circuit() {
+		// random advances the random number generator, so it changes the next value when it is evaluated
+		let reference = run("set_pixel(0, random(255), 0, 0)", 1);
+		assert_eq!(
+			run(
+				"a = get_length; b = (a - 1) && random(100); set_pixel(0, random(255), 0, 0)",
+				1
+			),
+			reference
+		);
+		assert_eq!(
+			run(
+				"a = get_length; b = a || random(100); set_pixel(0, random(255), 0, 0)",
+				1
+			),
+			reference
+		);
+		assert_ne!(
+			run(
+				"a = get_length; b = a && random(100); set_pixel(0, random(255), 0, 0)",
+				1
+			),
+			reference
+		);
+	}
+
+	#[test]
+	fn color_literal() {
+		assert_eq!(
+			run("c = #00FF00; set_pixel(0, red(c), green(c), blue(c))", 1),
+			vec![0x00FF00]
+		);
+		assert_eq!(
+			run("c = #FF8000; set_pixel(0, red(c), green(c), blue(c))", 1),
+			vec![0x0080FF]
+		);
+
+		// Literals use the layout in which get_pixel returns colors
+		assert_eq!(
+			run(
+				"set_pixel(0, 0, 255, 0); set_pixel(1, get_pixel(0) == #00FF00, 0, 0)",
+				2
+			),
+			vec![0x00FF00, 1]
+		);
+	}
+
+	#[test]
+	fn get_pixel_packed() {
+		let printed = Rc::new(RefCell::new(vec![]));
+		let printed_handler = printed.clone();
+		let mut vm = VM::new(Box::new(DummyStrip::new(2, false)));
+		vm.set_print_handler(Box::new(move |_, v| printed_handler.borrow_mut().push(v)));
+
+		// Pixels set from the host are seen by programs in the packed layout of Color
+		let color = Color::new(0x12, 0x34, 0x56);
+		vm.strip().set_pixel(0, color.r, color.g, color.b);
+		let program = Program::from_source(
+			"c = get_pixel(0); print(c); set_pixel(1, red(c), green(c), blue(c))",
+		)
+		.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+		assert_eq!(*printed.borrow(), vec![color.to_packed()]);
+		assert_eq!(vm.strip().get_pixel(1), color);
+	}
+
+	#[test]
+	fn print() {
+		let printed = Rc::new(RefCell::new(vec![]));
+		let printed_handler = printed.clone();
+		let mut vm = VM::new(Box::new(DummyStrip::new(3, false)));
+		vm.set_print_handler(Box::new(move |pc, v| {
+			printed_handler.borrow_mut().push((pc, v))
+		}));
+
+		let program =
+			Program::from_source("a = 5; print(a * get_length); print(a); set_pixel(0, a, 0, 0)")
+				.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert_eq!(
+			printed.borrow().iter().map(|p| p.1).collect::<Vec<u32>>(),
+			vec![15, 5]
+		);
+		assert!(printed.borrow()[0].0 < printed.borrow()[1].0);
+
+		// Print pops the value, keeping the stack balanced
+		drop(state);
+		assert_eq!(vm.strip().get_pixel(0).r, 5);
+	}
+
+	#[test]
+	fn select_strip() {
+		let mut vm = VM::with_strips(vec![
+			Box::new(DummyStrip::new(2, false)),
+			Box::new(DummyStrip::new(3, false)),
+		]);
+		let program = Program::from_source(
+			"a = 7; set_pixel(0, 1, 0, 0); select_strip(1); b = 9; set_pixel(2, get_length, 0, 0); set_pixel(0, a, b, 0); select_strip(0); set_pixel(1, get_length, 0, 0)",
+		)
+		.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+
+		let first = vm.strip_at(0).unwrap();
+		assert_eq!(first.get_pixel(0).r, 1);
+		assert_eq!(first.get_pixel(1).r, 2);
+		let second = vm.strip_at(1).unwrap();
+		assert_eq!(second.get_pixel(2).r, 3);
+
+		// Variables defined before and after selecting a strip are both addressed correctly
+		assert_eq!(second.get_pixel(0), Color::new(7, 9, 0));
+
+		// Selecting a strip that does not exist is an error
+		let program = Program::from_source("select_strip(2); set_pixel(0, 1, 0, 0)").unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(
+			state.run(None),
+			Outcome::Error(VMError::RuntimeError(_))
+		));
+	}
+
+	#[test]
+	fn null_strip() {
+		let mut vm = VM::new(Box::new(NullStrip::new(100)));
+		let program = Program::from_source(
+			"for(i = get_length) { set_pixel(i, get_pixel(i) + 1, 2, 3) }; blit; set_pixel(0, get_length, 0, 0); blit",
+		)
+		.unwrap();
+		let mut state = vm.start(program, Some(100_000));
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert!(state.instruction_count() > 100);
+		drop(state);
+		assert_eq!(vm.strip().get_pixel(0).r, 0);
+	}
+
+	#[test]
+	fn for_loop_variable() {
+		let printed = Rc::new(RefCell::new(vec![]));
+		let printed_handler = printed.clone();
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		vm.set_print_handler(Box::new(move |_, v| printed_handler.borrow_mut().push(v)));
+
+		// The loop variable takes ascending values starting at zero
+		let program = Program::from_source(
+			"for(i = 4) { print(i) }; for(i = 2) { for(j = 3) { print(i * 10 + j) } }; n = 0; for(i = n) { print(99) }; print(n)",
+		)
+		.unwrap();
+		let mut state = vm.start(program, Some(1000));
+		assert!(matches!(state.run(None), Outcome::Ended));
+
+		// A loop with a count of zero does not run its body
+		assert_eq!(*printed.borrow(), vec![0, 1, 2, 3, 0, 1, 2, 10, 11, 12, 0]);
+
+		assert_eq!(
+			run("for(i = get_length) { set_pixel(i, i + 1, 0, 0) }", 4),
+			vec![1, 2, 3, 4]
+		);
+	}
+
+	#[test]
+	fn for_step_loop() {
+		assert_eq!(
+			run(
+				"for(i = 0; i < get_length; i += 1) { set_pixel(i, i + 1, 0, 0) }",
+				5
+			),
+			vec![1, 2, 3, 4, 5]
+		);
+
+		// Condition is evaluated before the first iteration; step can be any assignment
+		assert_eq!(
+			run("a = 0; for(i = 7; i < 3; i += 1) { a = 1 }; for(j = 1; j < 50; j *= 3) { a += j }; set_pixel(0, a, 0, 0)", 1),
+			vec![1 + 3 + 9 + 27]
+		);
+
+		// Loops can be nested and use variables from the enclosing scope
+		assert_eq!(
+			run("n = 0; for(i = 0; i < 3; i += 1) { for(j = i; j < 3; j += 1) { n += 1; set_pixel(j, n, i, j) } }", 3),
+			vec![1, 0x0001_0104, 0x0002_0206]
+		);
+	}
+
+	#[test]
+	fn yield_count() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let program =
+			Program::from_source("yield 3; set_pixel(0, 1, 0, 0); yield; yield 0; n = 2; yield n")
+				.unwrap();
+		let mut state = vm.start(program, None);
+		for _ in 0..3 {
+			assert!(matches!(state.run(None), Outcome::Yielded));
+		}
+		assert!(matches!(state.run(None), Outcome::Yielded));
+		assert_eq!(state.vm.strip().get_pixel(0).r, 1);
+		assert!(matches!(state.run(None), Outcome::Yielded));
+		assert!(matches!(state.run(None), Outcome::Yielded));
+		assert!(matches!(state.run(None), Outcome::Ended));
+	}
+
+	#[test]
+	fn sleep() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let program =
+			Program::from_source("a = 50; sleep(250); sleep(a * 2); set_pixel(0, a, 0, 0)")
+				.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(
+			matches!(state.run(None), Outcome::SleepRequested(d) if d == Duration::from_millis(250))
+		);
+		assert!(
+			matches!(state.run(None), Outcome::SleepRequested(d) if d == Duration::from_millis(100))
+		);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert_eq!(state.vm.strip().get_pixel(0).r, 50);
+	}
+
+	#[test]
+	fn frame_sink() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(2, false)));
+		let (tx, rx) = std::sync::mpsc::channel();
+		vm.set_frame_sink(tx);
+
+		let program =
+			Program::from_source("set_pixel(0, 1, 2, 3); blit; set_pixel(1, 4, 5, 6); yield; blit")
+				.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Yielded));
+		assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3, 0, 0, 0]);
+		assert!(rx.try_recv().is_err());
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+		drop(state);
+
+		vm.set_brightness(128);
+		let program = Program::from_source("set_pixel(0, 255, 128, 0); blit").unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		assert_eq!(rx.try_recv().unwrap(), vec![128, 64, 0, 2, 3, 3]);
+	}
+
+	#[test]
+	fn trace_handler() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let events = Rc::new(RefCell::new(vec![]));
+		let e = events.clone();
+		vm.set_trace_handler(Box::new(move |event| e.borrow_mut().push(event)));
+
+		// pushb 3; jz 6; pushb 0; pop 2; blit
+		let program = Program::from_binary(vec![0x11, 0x03, 0x50, 0x06, 0x00, 0x10, 0x02, 0xe4]);
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+
+		let events = events.borrow();
+		let pcs: Vec<usize> = events.iter().map(|e| e.pc).collect();
+		assert_eq!(pcs, vec![0, 2, 5, 6, 7]);
+		assert_eq!(
+			events[0],
+			TraceEvent {
+				pc: 0,
+				opcode: 0x11,
+				note: Some("v=3".to_string()),
+				stack: vec![3]
+			}
+		);
+		assert_eq!(events[4].note, Some("blit".to_string()));
+		assert!(events[4].stack.is_empty());
+	}
+
+	#[test]
+	fn trace_variable_names() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let events = Rc::new(RefCell::new(vec![]));
+		let e = events.clone();
+		vm.set_trace_handler(Box::new(move |event| e.borrow_mut().push(event)));
+
+		let program = Program::new()
+			.with_debug_info()
+			.compile("a = 5; b = 7; a = b; set_pixel(0, a, 0, 0)")
+			.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		drop(state);
+
+		let notes: Vec<String> = events
+			.borrow()
+			.iter()
+			.filter_map(|e| e.note.clone())
+			.collect();
+		assert!(notes.contains(&"index=0 (b) v=7".to_string()));
+		assert!(notes.contains(&"index=1 (a) v=7".to_string()));
+		assert!(notes.contains(&"variables: b, a".to_string()));
+	}
+
+	#[test]
+	fn error_location() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		let program = Program::new()
+			.with_debug_info()
+			.compile("a = 1;\nselect_strip(a)")
+			.unwrap();
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Error(_)));
+		assert!(state.location().ends_with("(line 2)"));
+	}
+
+	#[test]
+	fn deterministic_time() {
+		let mut vm = VM::new(Box::new(DummyStrip::new(1, false)));
+		vm.set_deterministic(true);
+		vm.set_deterministic_time(1_600_000_000, 250);
+
+		// The first instruction reads the time after one instruction was executed
+		let program = Program::from_source("a = get_wall_time; b = get_precise_time; print(a); print(b); x = 0; for(i=8) { x += 1 }; print(get_wall_time)").unwrap();
+		let printed = Rc::new(RefCell::new(vec![]));
+		let p = printed.clone();
+		vm.set_print_handler(Box::new(move |_, v| p.borrow_mut().push(v)));
+		let mut state = vm.start(program, None);
+		assert!(matches!(state.run(None), Outcome::Ended));
+		let count = state.instruction_count() as u32;
+		drop(state);
+
+		let printed = printed.borrow();
+		assert_eq!(printed[0], 1_600_000_000);
+		assert_eq!(printed[1], 500);
+		assert_eq!(printed[2], 1_600_000_000 + (count - 2) * 250 / 1000);
+		assert!(printed[2] > printed[0]);
+	}
+
+	#[test]
+	fn clear() {
+		assert_eq!(
+			run("set_pixel(0, 1, 2, 3); set_pixel(1, 4, 5, 6); clear; set_pixel(1, 7, 0, 0); cleared = 1", 2),
+			vec![0, 7]
+		);
+	}
+
+	#[test]
+	fn color_channels() {
+		assert_eq!(
+			run("set_pixel(1, 10, 20, 30); p = get_pixel(1); set_pixel(0, blue(p), green(p) + 1, red(p) + 2)", 2),
+			vec![0x000c_151e, 0x001e_140a]
+		);
+		assert_eq!(
+			run(
+				"set_pixel(0, 1, 2, 3); set_pixel(1, green(get_pixel(0)), 0, 0)",
+				2
+			),
+			vec![0x0003_0201, 2]
+		);
+	}
+
+	#[test]
+	fn saturating_arithmetic() {
+		assert_eq!(
+			run("a = 200; b = 100; set_pixel(0, sat_add(a, b, 255), sat_add(a, 20, 255), sat_sub(b, a, 5)); set_pixel(1, sat_sub(a, 150, 10), sat_add(sat_add(a, b, 250), 1, 251), a)", 2),
+			vec![0x0005_dcff, 0x00c8_fb32]
+		);
+
+		// Bounds of clamp may refer to variables
+		assert_eq!(
+			run(
+				"a = 200; b = 100; set_pixel(0, clamp(a, 0, b), clamp(b, a, 255), 0)",
+				1
+			),
+			vec![0x0000_c864]
+		);
+
+		// Operands are evaluated only once (otherwise the random number generator would advance further)
+		assert_eq!(
+			run("x = sat_add(random(100), random(100), 255); y = random(100); set_pixel(0, y, 0, 0)", 1),
+			run("x = random(100) + random(100); y = random(100); set_pixel(0, y, 0, 0)", 1)
+		);
+		assert_eq!(
+			run(
+				"x = sat_sub(random(100), random(100), 0); y = random(100); set_pixel(0, y, 0, 0)",
+				1
+			),
+			run(
+				"x = random(100) + random(100); y = random(100); set_pixel(0, y, 0, 0)",
+				1
+			)
+		);
+	}
+
+	#[test]
+	fn compound_assignment() {
+		assert_eq!(
+			run("a = get_length; a += 3; set_pixel(0, a, 0, 0)", 1),
+			vec![4]
+		);
+		assert_eq!(
+			run(
+				"a = get_length; b = 2; a <<= 8; a <<= b; a >>= 8; set_pixel(0, a, 0, 0)",
+				1
+			),
+			vec![4]
+		);
+		assert_eq!(
+			run(
+				"a = 100; a -= 10; a /= 3; a *= 2; a %= 7; set_pixel(0, a, 0, 0)",
+				1
+			),
+			vec![4]
+		);
+		assert_eq!(
+			run(
+				"a = 0xF0; a &= 0x3C; a |= 1; a ^= 0x31; set_pixel(0, a, 0, 0)",
+				1
+			),
+			vec![0x00]
+		);
+	}
+
+	#[test]
+	fn stable_output() {
+		let stable = |source: &str, max_frames| {
+			let program = Program::from_source(source).unwrap();
+			run_until_stable(program, Box::new(DummyStrip::new(2, false)), max_frames)
+		};
+
+		// Settles after three frames
+		let settles = "x = 0; loop { if(x < 3) { x += 1 }; set_pixel(0, x, 0, 0); blit; yield }";
+		assert_eq!(stable(settles, 10), Some(3));
+		assert_eq!(stable(settles, 3), None);
+
+		// Animates forever
+		let animates = "x = 0; loop { x += 1; set_pixel(1, x, 0, 0); blit; yield }";
+		assert_eq!(stable(animates, 100), None);
+
+		// Programs that end are stable, programs that do not yield are not
+		assert_eq!(stable("set_pixel(0, 1, 2, 3); blit", 10), Some(1));
+		assert_eq!(stable("loop { blit }", 10), None);
+	}
 }
@@ -0,0 +1,134 @@
+use super::program::Program;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::Duration;
+
+/* Watches a program source file and reparses it when it changes. The directory containing the file is watched
+rather than the file itself, so that changes made by editors that replace the file (rather than write to it) are
+also picked up. */
+pub struct SourceWatcher {
+	path: PathBuf,
+	events: Receiver<DebouncedEvent>,
+	_watcher: Option<RecommendedWatcher>,
+}
+
+impl SourceWatcher {
+	pub fn new(path: &Path) -> notify::Result<SourceWatcher> {
+		let (tx, rx) = channel();
+		let mut watcher = notify::watcher(tx, Duration::from_millis(200))?;
+		let directory = match path.parent() {
+			Some(p) if p != Path::new("") => p,
+			_ => Path::new("."),
+		};
+		watcher.watch(directory, RecursiveMode::NonRecursive)?;
+		Ok(SourceWatcher {
+			path: path.to_path_buf(),
+			events: rx,
+			_watcher: Some(watcher),
+		})
+	}
+
+	/* Creates a watcher for `path` that receives its file events from the provided channel (for testing without
+	touching the file system) */
+	#[cfg(test)]
+	fn with_events(path: &Path, events: Receiver<DebouncedEvent>) -> SourceWatcher {
+		SourceWatcher {
+			path: path.to_path_buf(),
+			events,
+			_watcher: None,
+		}
+	}
+
+	/* Reads and parses the source file (and the files it includes), with debug information for error reporting */
+	pub fn load(&self) -> Result<Program, String> {
+		Program::new().with_debug_info().compile_file(&self.path)
+	}
+
+	/* Returns the reparsed program when the source file has changed since the last call, None otherwise. Does not
+	block. */
+	pub fn poll(&self) -> Option<Result<Program, String>> {
+		let mut changed = false;
+		loop {
+			match self.events.try_recv() {
+				Ok(event) => changed |= self.is_change(&event),
+				Err(TryRecvError::Empty) => break,
+				Err(TryRecvError::Disconnected) => break,
+			}
+		}
+
+		if changed {
+			Some(self.load())
+		} else {
+			None
+		}
+	}
+
+	/* Blocks until the source file changes and returns the reparsed program. Returns None when no more events can
+	be received. */
+	pub fn wait(&self) -> Option<Result<Program, String>> {
+		loop {
+			let event = self.events.recv().ok()?;
+			if self.is_change(&event) {
+				// Collect any other pending events for the same change
+				let _ = self.poll();
+				return Some(self.load());
+			}
+		}
+	}
+
+	fn is_change(&self, event: &DebouncedEvent) -> bool {
+		match event {
+			DebouncedEvent::Create(p) | DebouncedEvent::Write(p) => self.is_source(p),
+			DebouncedEvent::Rename(_, to) => self.is_source(to),
+			DebouncedEvent::Rescan => true,
+			_ => false,
+		}
+	}
+
+	fn is_source(&self, path: &Path) -> bool {
+		path.file_name() == self.path.file_name()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs::File;
+	use std::io::Write;
+
+	#[test]
+	fn reparse_on_change() {
+		let path = std::env::temp_dir().join(format!("pwlp-watch-{}.txt", std::process::id()));
+		File::create(&path).unwrap().write_all(b"yield").unwrap();
+
+		let (tx, rx) = channel();
+		let watcher = SourceWatcher::with_events(&path, rx);
+		assert!(watcher.poll().is_none());
+
+		// Events for other files are ignored
+		tx.send(DebouncedEvent::Write(path.with_file_name("other.txt")))
+			.unwrap();
+		assert!(watcher.poll().is_none());
+
+		// A changed file is reparsed
+		File::create(&path).unwrap().write_all(b"blit").unwrap();
+		tx.send(DebouncedEvent::Write(path.clone())).unwrap();
+		let program = watcher.poll().unwrap().unwrap();
+		assert_eq!(program.code, Program::from_source("blit").unwrap().code);
+		assert!(watcher.poll().is_none());
+
+		// Parse errors are reported
+		File::create(&path).unwrap().write_all(b"blit(").unwrap();
+		tx.send(DebouncedEvent::Rename(
+			path.with_extension("swp"),
+			path.clone(),
+		))
+		.unwrap();
+		assert!(watcher.wait().unwrap().is_err());
+
+		std::fs::remove_file(&path).unwrap();
+		drop(tx);
+		assert!(watcher.wait().is_none());
+	}
+}
@@ -10,6 +10,40 @@ use super::pwlp::program::Program;
 #[cfg(test)]
 use std::io::Read;
 
+#[cfg(test)]
+use super::pwlp::strip::DummyStrip;
+
+#[cfg(test)]
+use super::pwlp::vm::{Outcome, VM};
+
+#[cfg(test)]
+use std::sync::mpsc::channel;
+
+#[cfg(test)]
+use rand::{Rng, SeedableRng};
+
+#[cfg(test)]
+use rand_chacha::ChaCha20Rng;
+
+#[cfg(test)]
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/* Length of the strip programs run on when comparing their frames to a stored 'frames' file */
+#[cfg(test)]
+const FRAMES_STRIP_LENGTH: u32 = 4;
+
+/* Maximum number of instructions a program may execute to produce the stored frames */
+#[cfg(test)]
+const FRAMES_INSTRUCTION_LIMIT: usize = 100_000;
+
+/* Number of random (and randomly mutated) programs the VM runs in the fuzz test */
+#[cfg(test)]
+const FUZZ_ITERATIONS: usize = 5_000;
+
+/* Maximum number of instructions each program may execute in the fuzz test */
+#[cfg(test)]
+const FUZZ_INSTRUCTION_LIMIT: usize = 10_000;
+
 #[test]
 fn compare_output_of_compiler_to_stored_binaries() {
 	// Read txt files in the 'tests' folder, compile them, then compare to the stored 'bin' file
@@ -71,3 +105,132 @@ fn compare_output_of_compiler_to_stored_binaries() {
 		}
 	}
 }
+
+#[test]
+fn compare_builtin_programs_to_their_sources() {
+	for name in super::BUILTIN_PROGRAMS.iter() {
+		let (program, bin_path) = super::compile_builtin(name).unwrap();
+		let stored_bin = fs::read(&bin_path).unwrap();
+		assert!(
+			stored_bin == program.code,
+			"[{}] Built-in binary is out of date (run `cargo run -- regen-builtins`)\nCompiled: {:?}\nStored: {:?}",
+			bin_path.display(),
+			program.code,
+			stored_bin
+		);
+	}
+}
+
+#[test]
+fn compare_frames_of_programs_to_stored_frames() {
+	// Run txt files in the 'tests' folder that have a 'frames' file and compare each blitted frame (one line each)
+	let paths = fs::read_dir("./test").unwrap();
+	for path in paths {
+		let path = path.unwrap().path();
+		let frames_path = path.with_extension("frames");
+		if path.extension().and_then(|e| e.to_str()) != Some("txt") || !frames_path.exists() {
+			continue;
+		}
+
+		let program = Program::from_source_file(&path)
+			.unwrap_or_else(|s| panic!("[{}] Parse error: {}", path.display(), s));
+		let stored_frames: Vec<String> = fs::read_to_string(&frames_path)
+			.unwrap()
+			.lines()
+			.map(|line| line.trim().to_string())
+			.filter(|line| !line.is_empty())
+			.collect();
+		let frames = capture_frames(program, stored_frames.len());
+		assert_eq!(
+			frames,
+			stored_frames,
+			"[{}] Frames are different",
+			path.display()
+		);
+	}
+}
+
+/* Runs the program deterministically until it has blitted `count` frames (or ends), and returns each frame as the
+hex RGB values of its pixels, separated by spaces */
+#[cfg(test)]
+fn capture_frames(program: Program, count: usize) -> Vec<String> {
+	let (sender, receiver) = channel();
+	let mut vm = VM::new(Box::new(DummyStrip::new(FRAMES_STRIP_LENGTH, false)));
+	vm.set_deterministic(true);
+	vm.set_frame_sink(sender);
+
+	let mut state = vm.start(program, Some(FRAMES_INSTRUCTION_LIMIT));
+	let mut frames = vec![];
+	while frames.len() < count {
+		let outcome = state.run(None);
+		frames.extend(receiver.try_iter().map(|frame| {
+			frame
+				.chunks(3)
+				.map(|p| format!("{:02x}{:02x}{:02x}", p[0], p[1], p[2]))
+				.collect::<Vec<String>>()
+				.join(" ")
+		}));
+
+		match outcome {
+			Outcome::Yielded | Outcome::SleepRequested(_) => {}
+			Outcome::Error(e) => panic!("Error in VM at pc={}: {:?}", state.pc(), e),
+			_ => break,
+		}
+	}
+	frames.truncate(count);
+	frames
+}
+
+#[test]
+fn run_random_programs_without_panicking() {
+	// Programs in 'test/corpus' are mutated to obtain inputs that resemble valid programs
+	let mut corpus = vec![];
+	for path in fs::read_dir("./test/corpus").unwrap() {
+		let path = path.unwrap().path();
+		if path.extension().and_then(|e| e.to_str()) == Some("bin") {
+			corpus.push(fs::read(path).unwrap());
+		}
+	}
+	assert!(!corpus.is_empty());
+
+	let mut rng = ChaCha20Rng::from_seed([0u8; 32]);
+	for iteration in 0..FUZZ_ITERATIONS {
+		let code = if iteration % 2 == 0 {
+			let length = rng.gen_range(0, 64);
+			(0..length).map(|_| rng.gen()).collect()
+		} else {
+			let mut code = corpus[rng.gen_range(0, corpus.len())].clone();
+			for _ in 0..rng.gen_range(1, 5) {
+				let index = rng.gen_range(0, code.len() + 1);
+				match rng.gen_range(0, 4) {
+					0 if index < code.len() => code[index] = rng.gen(),
+					1 => code.insert(index, rng.gen()),
+					2 if index < code.len() => {
+						code.remove(index);
+					}
+					_ => code.truncate(index),
+				}
+			}
+			code
+		};
+
+		let result = catch_unwind(AssertUnwindSafe(|| run_fuzzed(code.clone())));
+		if result.is_err() {
+			panic!(
+				"VM panicked on program {}",
+				Program::from_binary(code).to_hex()
+			);
+		}
+	}
+}
+
+/* Runs the program until it ends, fails or reaches the instruction limit */
+#[cfg(test)]
+fn run_fuzzed(code: Vec<u8>) {
+	let mut vm = VM::new(Box::new(DummyStrip::new(5, false)));
+	vm.set_deterministic(true);
+	vm.set_print_handler(Box::new(|_, _| {}));
+
+	let mut state = vm.start(Program::from_binary(code), Some(FUZZ_INSTRUCTION_LIMIT));
+	while let Outcome::Yielded | Outcome::SleepRequested(_) = state.run(None) {}
+}